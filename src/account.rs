@@ -0,0 +1,153 @@
+//! # Unix accounts of the subscribed users
+//!
+//! **Author**: "Dany LE"
+//!
+use std::ffi::{CStr, CString};
+use std::fs;
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::process::Command;
+
+/// Names of the Unix groups the user belongs to, including
+/// its primary group. Empty when the user is unknown
+pub fn groups_of(user: &str) -> Vec<String> {
+    let name = match CString::new(user) {
+        Ok(name) => name,
+        Err(_) => return Vec::new(),
+    };
+    unsafe {
+        let pw = libc::getpwnam(name.as_ptr());
+        if pw.is_null() {
+            return Vec::new();
+        }
+        group_ids(&name, (*pw).pw_gid)
+            .iter()
+            .filter_map(|gid| {
+                let gr = libc::getgrgid(*gid);
+                if gr.is_null() {
+                    None
+                } else {
+                    Some(CStr::from_ptr((*gr).gr_name).to_string_lossy().into_owned())
+                }
+            })
+            .collect()
+    }
+}
+
+/// Ids of the Unix groups of a user, including its primary
+/// group `gid`
+fn group_ids(name: &CStr, gid: libc::gid_t) -> Vec<libc::gid_t> {
+    let mut ngroups: libc::c_int = 32;
+    let mut gids: Vec<libc::gid_t> = vec![0; ngroups as usize];
+    // ngroups is set to the required size when the list is too small
+    while unsafe { libc::getgrouplist(name.as_ptr(), gid, gids.as_mut_ptr(), &mut ngroups) } < 0 {
+        let size = std::cmp::max(ngroups as usize, gids.len() * 2);
+        gids.resize(size, 0);
+        ngroups = size as libc::c_int;
+    }
+    gids.truncate(ngroups as usize);
+    gids
+}
+
+/// Name of the user running the backend, its uid when it has
+/// no entry in the password database
+pub fn current_user() -> String {
+    unsafe {
+        let uid = libc::getuid();
+        let pw = libc::getpwuid(uid);
+        if pw.is_null() {
+            return uid.to_string();
+        }
+        CStr::from_ptr((*pw).pw_name).to_string_lossy().into_owned()
+    }
+}
+
+/// Whether a user is designated by one of the entries of a
+/// list, an entry is either a user name or `@group`
+pub fn matches(user: &str, entries: &[String]) -> bool {
+    if entries.iter().any(|entry| entry == user) {
+        return true;
+    }
+    let groups: Vec<&str> = entries.iter().filter_map(|e| e.strip_prefix('@')).collect();
+    !groups.is_empty()
+        && groups_of(user)
+            .iter()
+            .any(|group| groups.contains(&group.as_str()))
+}
+
+/// Unix account under which a process is run
+pub struct Account {
+    name: CString,
+    uid: libc::uid_t,
+    gid: libc::gid_t,
+    home: String,
+}
+
+impl Account {
+    /// Find the account of a user in the password database
+    pub fn lookup(user: &str) -> Result<Account, Box<dyn std::error::Error>> {
+        let name = CString::new(user)?;
+        unsafe {
+            let pw = libc::getpwnam(name.as_ptr());
+            if pw.is_null() {
+                return Err(format!("Unknown user: {}", user).into());
+            }
+            Ok(Account {
+                uid: (*pw).pw_uid,
+                gid: (*pw).pw_gid,
+                home: CStr::from_ptr((*pw).pw_dir).to_string_lossy().into_owned(),
+                name,
+            })
+        }
+    }
+
+    /// User and group ids of the account
+    pub fn ids(&self) -> (libc::uid_t, libc::gid_t) {
+        (self.uid, self.gid)
+    }
+
+    /// Whether the account may execute a file, from the
+    /// permission bits of the file that apply to its ids
+    pub fn can_execute(&self, path: &Path) -> bool {
+        let meta = match fs::metadata(path) {
+            Ok(meta) if meta.is_file() => meta,
+            _ => return false,
+        };
+        let mode = meta.mode();
+        let bits = if self.uid == 0 {
+            // root may execute any file with an execute bit
+            0o111
+        } else if meta.uid() == self.uid {
+            0o100
+        } else if group_ids(&self.name, self.gid).contains(&meta.gid()) {
+            0o010
+        } else {
+            0o001
+        };
+        mode & bits != 0
+    }
+
+    /// Run the command under this account: switch the groups
+    /// and the ids in the child, then set the usual variables
+    pub fn apply(&self, cmd: &mut Command) {
+        let (name, uid, gid) = (self.name.clone(), self.uid, self.gid);
+        cmd.env("HOME", &self.home)
+            .env("USER", self.name.to_string_lossy().as_ref())
+            .env("LOGNAME", self.name.to_string_lossy().as_ref())
+            .current_dir(&self.home);
+        // only async-signal-safe calls are made in the child
+        unsafe {
+            cmd.pre_exec(move || {
+                if libc::initgroups(name.as_ptr(), gid) != 0
+                    || libc::setgid(gid) != 0
+                    || libc::setuid(uid) != 0
+                {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+    }
+}
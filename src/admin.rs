@@ -0,0 +1,334 @@
+//! # Admin control topic and socket
+//!
+//! With `admin_users`, the backend also opens the topic
+//! `<topic>.admin`, on which those users can list the sessions,
+//! read the metrics and manage the backend with text commands,
+//! without touching the data channel.
+//!
+//! The events of the admin topic are queued here and handled
+//! by the event loop of the data topic, which owns the backend.
+//!
+//! With `admin_socket`, the same commands are accepted on a
+//! Unix socket, one per connection, e.g. from `shctl`
+//!
+//! **Author**: "Dany LE"
+//!
+use crate::clients;
+use crate::config::Config;
+use crate::frame;
+use crate::log::Level;
+use crate::{INFO, WARN};
+use clap::ValueEnum;
+use latpr::tunnel::{CallbackEvent, Msg, MsgKind, Topic};
+use latpr::utils::{LogLevel, LOG};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::Shutdown;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// time given to a client of the socket to send its command
+/// and read the reply
+const SOCKET_TIMEOUT: Duration = Duration::from_millis(500);
+/// longest command line of the socket
+const MAX_COMMAND: usize = 4096;
+
+/// A command of an admin client
+pub enum Command {
+    Help,
+    /// state of the backend, one line per session
+    Sessions,
+    /// metrics in the Prometheus text format
+    Metrics,
+    /// rolling throughput and write latency of the topic
+    Throughput,
+    /// refuse new clients and exit after the last one
+    Drain,
+    /// reload the configuration
+    Reload,
+    /// change the log level, none when the level is invalid
+    LogLevel(Option<Level>),
+    /// kill the process of a client with a reason sent to it,
+    /// none when the client id is invalid
+    Kill(Option<u16>, String),
+    /// same as `Kill` then spawn a new process
+    Restart(Option<u16>, String),
+    Unknown(String),
+}
+
+pub const HELP: &str = "Commands:
+  help      this help
+  sessions  state of the backend and counters of its sessions
+  metrics   metrics in the Prometheus text format
+  throughput
+            bytes per second in and out and write latency of the topic
+  drain     refuse new clients and exit after the last one
+  reload    reload the configuration
+  log-level error|warn|info|debug
+            change the log level until the next reload
+  kill <client_id> [reason]
+            kill the process of a client, the reason is sent to it
+  restart <client_id> [reason]
+            kill the process of a client and spawn a new one";
+
+impl Command {
+    fn parse(line: &str) -> Command {
+        let mut words = line.split_whitespace();
+        match words.next().unwrap_or_default() {
+            "help" | "" => Command::Help,
+            "sessions" => Command::Sessions,
+            "metrics" => Command::Metrics,
+            "throughput" => Command::Throughput,
+            "drain" => Command::Drain,
+            "reload" => Command::Reload,
+            "log-level" => Command::LogLevel(
+                words
+                    .next()
+                    .and_then(|level| Level::from_str(level, true).ok()),
+            ),
+            name @ ("kill" | "restart") => {
+                let client_id = words.next().and_then(|id| id.parse().ok());
+                let reason = words.collect::<Vec<&str>>().join(" ");
+                if name == "kill" {
+                    Command::Kill(client_id, reason)
+                } else {
+                    Command::Restart(client_id, reason)
+                }
+            }
+            other => Command::Unknown(String::from(other)),
+        }
+    }
+}
+
+/// An event of the admin topic
+enum Event {
+    Subscribe(u16, String),
+    Unsubscribe(u16),
+    UnsubscribeAll,
+    Line(u16, String),
+}
+
+pub struct Admin {
+    name: String,
+    /// events received on the admin topic
+    inbox: RefCell<Vec<Event>>,
+    /// messages to write to the admin topic
+    outbox: RefCell<Vec<Msg>>,
+    /// admin clients and their user
+    clients: RefCell<HashMap<u16, String>>,
+}
+
+impl Admin {
+    pub fn new(topic: &str) -> Admin {
+        Admin {
+            name: format!("{}.admin", topic),
+            inbox: RefCell::new(Vec::new()),
+            outbox: RefCell::new(Vec::new()),
+            clients: RefCell::new(HashMap::new()),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Handle an event of the admin topic: queue the messages of
+    /// the clients and write the pending replies
+    pub fn step_handle(
+        &self,
+        evt: &CallbackEvent,
+        topic: &mut Topic,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if let Some(msg) = evt.msg {
+            frame::trace("<-", msg);
+            let event = match msg.kind {
+                MsgKind::ChannelSubscribe => {
+                    Some(Event::Subscribe(msg.client_id, clients::subscriber(msg)?))
+                }
+                MsgKind::ChannelUnsubscribe => Some(Event::Unsubscribe(msg.client_id)),
+                MsgKind::ChannelUnsubscribeAll => Some(Event::UnsubscribeAll),
+                MsgKind::ChannelData => {
+                    let line = String::from_utf8_lossy(&msg.data);
+                    Some(Event::Line(msg.client_id, String::from(line.trim())))
+                }
+                _ => None,
+            };
+            self.inbox.borrow_mut().extend(event);
+        }
+        for msg in self.outbox.take() {
+            frame::send(topic, &msg)?;
+        }
+        Ok(())
+    }
+
+    /// Handle the queued events, return the commands of the
+    /// admin clients. Only `admin_users` are let in
+    pub fn commands(&self, config: &Config) -> Vec<(u16, Command)> {
+        let mut commands = Vec::new();
+        let mut clients = self.clients.borrow_mut();
+        for event in self.inbox.take() {
+            match event {
+                Event::Subscribe(client_id, user) => {
+                    if !config.admin_users.contains(&user) || config.is_denied(&user) {
+                        WARN!(
+                            "Refuse client ({}) {} on channel {}: not an admin user",
+                            user,
+                            client_id,
+                            self.name
+                        );
+                        self.refuse(client_id, "Access denied");
+                        continue;
+                    }
+                    INFO!("Admin ({}) {} subscribed to {}", user, client_id, self.name);
+                    clients.insert(client_id, user);
+                    self.reply(client_id, HELP);
+                }
+                Event::Unsubscribe(client_id) => {
+                    clients.remove(&client_id);
+                }
+                Event::UnsubscribeAll => clients.clear(),
+                Event::Line(client_id, line) => match clients.get(&client_id) {
+                    Some(user) => {
+                        INFO!("Admin command of ({}) {}: {}", user, client_id, line);
+                        commands.push((client_id, Command::parse(&line)));
+                    }
+                    None => self.refuse(client_id, "Not subscribed"),
+                },
+            }
+        }
+        commands
+    }
+
+    /// Send some text to an admin client
+    pub fn reply(&self, client_id: u16, text: &str) {
+        let mut data = text.as_bytes().to_vec();
+        if !text.ends_with('\n') {
+            data.push(b'\n');
+        }
+        let msg = Msg::create(MsgKind::ChannelData, 0, client_id, data);
+        self.outbox.borrow_mut().push(msg);
+    }
+
+    fn refuse(&self, client_id: u16, reason: &str) {
+        let mut outbox = self.outbox.borrow_mut();
+        outbox.push(frame::error(client_id, reason));
+        outbox.push(Msg::create(
+            MsgKind::ChannelUnsubscribe,
+            0,
+            client_id,
+            Vec::new(),
+        ));
+    }
+}
+
+/// Unix socket accepting the admin commands, readable by the
+/// user of the backend only
+pub struct AdminSocket {
+    listener: UnixListener,
+    path: PathBuf,
+}
+
+impl AdminSocket {
+    /// Listen on a socket, replacing the one left by a previous
+    /// instance of the backend
+    pub fn bind(path: &Path) -> Result<AdminSocket, Box<dyn std::error::Error>> {
+        if path.exists() {
+            fs::remove_file(path)?;
+        }
+        let listener = UnixListener::bind(path)
+            .map_err(|e| format!("Unable to listen on {}: {}", path.display(), e))?;
+        fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+        listener.set_nonblocking(true)?;
+        Ok(AdminSocket {
+            listener,
+            path: path.to_path_buf(),
+        })
+    }
+
+    pub fn fd(&self) -> RawFd {
+        self.listener.as_raw_fd()
+    }
+
+    /// Read the command of each pending connection, to be
+    /// answered with [`answer`]
+    pub fn accept(&self) -> Vec<(UnixStream, Command)> {
+        let mut commands = Vec::new();
+        loop {
+            let stream = match self.listener.accept() {
+                Ok((stream, _)) => stream,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return commands,
+                Err(error) => {
+                    WARN!("Unable to accept an admin connection: {}", error);
+                    return commands;
+                }
+            };
+            let read = stream
+                .set_nonblocking(false)
+                .and_then(|_| stream.set_write_timeout(Some(SOCKET_TIMEOUT)))
+                .and_then(|_| read_command(&stream));
+            match read {
+                Ok(line) => {
+                    INFO!("Admin command on {}: {}", self.path.display(), line.trim());
+                    commands.push((stream, Command::parse(&line)));
+                }
+                Err(error) => WARN!("Unable to read an admin command: {}", error),
+            }
+        }
+    }
+}
+
+/// Read the command line of a connection. The timeout bounds the
+/// whole command, not each read, as it is read by the event loop
+fn read_command(mut stream: &UnixStream) -> io::Result<String> {
+    let deadline = Instant::now() + SOCKET_TIMEOUT;
+    let mut line = Vec::new();
+    let mut buf = [0u8; 256];
+    while !line.contains(&b'\n') && line.len() < MAX_COMMAND {
+        let left = deadline
+            .checked_duration_since(Instant::now())
+            .filter(|left| !left.is_zero())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::TimedOut, "command timed out"))?;
+        stream.set_read_timeout(Some(left))?;
+        let n = stream.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        line.extend_from_slice(&buf[..n]);
+    }
+    let end = line.iter().position(|b| *b == b'\n').unwrap_or(line.len());
+    Ok(String::from_utf8_lossy(&line[..end]).into_owned())
+}
+
+impl Drop for AdminSocket {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Send the reply to a command of the socket and close it
+pub fn answer(mut stream: UnixStream, text: &str) {
+    let mut data = text.as_bytes().to_vec();
+    if !text.ends_with('\n') {
+        data.push(b'\n');
+    }
+    if let Err(error) = stream.write_all(&data) {
+        WARN!("Unable to answer an admin command: {}", error);
+    }
+}
+
+/// Send a command to the admin socket of a backend, return its
+/// reply
+pub fn request(path: &Path, line: &str) -> Result<String, Box<dyn std::error::Error>> {
+    let mut stream = UnixStream::connect(path)
+        .map_err(|e| format!("Unable to connect to {}: {}", path.display(), e))?;
+    stream.write_all(format!("{}\n", line).as_bytes())?;
+    stream.shutdown(Shutdown::Write)?;
+    let mut reply = String::new();
+    stream.read_to_string(&mut reply)?;
+    Ok(reply)
+}
@@ -0,0 +1,644 @@
+//! # Entry point shared by all the backend executables
+//!
+//! **Author**: "Dany LE"
+//!
+use crate::admin::{self, Admin, AdminSocket};
+use crate::backend::Backend;
+use crate::bridge::Bridge;
+use crate::broadcast::Broadcast;
+use crate::capabilities;
+use crate::cli::{Action, Cli};
+use crate::clients;
+use crate::config::{Config, Mode};
+use crate::cron::Scheduled;
+use crate::error::{self, Class};
+use crate::exec::Exec;
+use crate::fifo::Fifo;
+use crate::frame;
+use crate::health::{HealthSocket, Report};
+use crate::history;
+use crate::log;
+use crate::metrics::{self, Exporter, Statsd};
+use crate::mux::Mux;
+use crate::notify::{self, Notifier};
+use crate::p2p::P2p;
+use crate::playback::Playback;
+use crate::process;
+use crate::profile::{self, Phase, Profiler};
+use crate::proxy::Proxy;
+use crate::queue::Queue;
+use crate::rotate::Rotation;
+use crate::session::Sessions;
+use crate::signals::Signals;
+use crate::spawn;
+use crate::sysstats::SysStats;
+use crate::tail::Tail;
+use crate::telemetry;
+use crate::transfer::FileTransfer;
+use crate::unit;
+use crate::validate;
+use crate::watch::Watch;
+use crate::webhook;
+use crate::{ERROR, EXIT, INFO, WARN};
+use clap::Parser;
+use latpr::tunnel::{CallbackEvent, IOInterest, MsgKind, Topic};
+use latpr::utils::{LogLevel, LOG};
+use std::cell::Cell;
+use std::env;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::RawFd;
+use std::os::unix::process::CommandExt;
+use std::panic;
+use std::path::PathBuf;
+use std::process::Command;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const STEP_TO_MS: u64 = 100;
+/// timeout of the steps of the admin topic, stepped after each
+/// step of the data topic
+const ADMIN_STEP_TO_MS: u64 = 10;
+/// delay before the first attempt to reconnect to the hub,
+/// doubled on each failed attempt
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(60);
+/// interval at which the output of the processes is read
+/// while the hub is unreachable
+const OFFLINE_STEP: Duration = Duration::from_millis(100);
+
+/// Run the backend, `mode` is the mode used when none is
+/// given on the command line
+pub fn main(mode: Option<Mode>) -> Result<(), Box<dyn std::error::Error>> {
+    // init the system log
+    // Create an empty log object and keep it alive in the scope
+    // of `main`. When this object is dropped, the syslog will
+    // be closed automatically
+    let _log = LOG::init_log();
+    // never leave the processes running behind a panic
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        default_hook(info);
+        ERROR!("Backend panicked, killing the processes: {}", info);
+        process::kill_all();
+    }));
+    // read all the arguments
+    let mut cli = Cli::parse();
+    if cli.mode.is_none() {
+        cli.mode = mode;
+    }
+    match cli.action.as_ref() {
+        Some(Action::Validate) => {
+            std::process::exit(if validate::run(&cli) { 0 } else { 1 });
+        }
+        Some(Action::GenerateUnit { user }) => {
+            print!("{}", unit::generate(&cli, user.as_deref())?);
+            return Ok(());
+        }
+        Some(Action::Capabilities) => {
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&capabilities::describe(mode))?
+            );
+            return Ok(());
+        }
+        Some(Action::History { user, limit, json }) => {
+            let config = Config::load(&cli)?;
+            let path = config
+                .history_file
+                .as_ref()
+                .ok_or("no history_file is configured")?;
+            let entries: Vec<history::Entry> = history::read(path)?
+                .into_iter()
+                .filter(|e| user.as_ref().is_none_or(|u| &e.user == u))
+                .collect();
+            for entry in &entries[entries.len().saturating_sub(*limit)..] {
+                if *json {
+                    println!("{}", serde_json::to_string(entry)?);
+                } else {
+                    println!("{}", history::describe(entry));
+                }
+            }
+            return Ok(());
+        }
+        None => {}
+    }
+    // log the configuration errors to the target given on the
+    // command line, if any
+    if let Some(target) = cli.log_target.as_ref() {
+        if let Err(error) = log::set_target(target, &Rotation::default()) {
+            EXIT!("Unable to open the log file {}: {}", target, error);
+        }
+    }
+    let config = match Config::load(&cli) {
+        Ok(config) => config,
+        Err(error) => EXIT!("Invalid arguments: {}", error),
+    };
+    if let Err(error) = telemetry::init(&config) {
+        ERROR!("Unable to start the span exporter: {}", error);
+    }
+    if let Err(error) = webhook::init(&config) {
+        ERROR!("Unable to start the webhook: {}", error);
+    }
+    let mut backend: Box<dyn Backend> = match config.mode {
+        Mode::P2p | Mode::P2pUser | Mode::Kube | Mode::Wasm => {
+            let mut p2p = P2p::new(config.mode == Mode::P2pUser);
+            if let Err(error) = p2p.restore(&config) {
+                let class = error::report(&*error, Class::Internal);
+                ERROR!("Unable to restore the sessions: {}: {}", class, error);
+            }
+            Box::new(p2p)
+        }
+        Mode::Broadcast => match Broadcast::new(&config) {
+            Ok(backend) => Box::new(backend),
+            Err(error) => EXIT!("Unable to run {}: {}", config.command, error),
+        },
+        Mode::Journal => match Broadcast::new(&config) {
+            Ok(backend) => Box::new(backend),
+            Err(error) => EXIT!("Unable to follow the journal: {}", error),
+        },
+        Mode::File => Box::new(FileTransfer::new()),
+        Mode::Tail => match Tail::new(&config) {
+            Ok(backend) => Box::new(backend),
+            Err(error) => EXIT!("Unable to follow the file: {}", error),
+        },
+        Mode::Sock | Mode::Tcp => Box::new(Proxy::new()),
+        Mode::Fifo => match Fifo::new(&config) {
+            Ok(backend) => Box::new(backend),
+            Err(error) => EXIT!("Unable to read the named pipe: {}", error),
+        },
+        Mode::Cron => match Scheduled::new(&config) {
+            Ok(backend) => Box::new(backend),
+            Err(error) => EXIT!("Unable to schedule {}: {}", config.command, error),
+        },
+        Mode::Exec | Mode::Rpc | Mode::Git | Mode::Svc => Box::new(Exec::new()),
+        Mode::Playback => Box::new(Playback::new()),
+        Mode::Sysstats => Box::new(SysStats::new()),
+        Mode::Mux => Box::new(Mux::new()),
+        Mode::Session => Box::new(Sessions::new()),
+        Mode::Queue => Box::new(Queue::new()),
+        Mode::Watch => match Watch::new(&config) {
+            Ok(backend) => Box::new(backend),
+            Err(error) => EXIT!("Unable to watch the paths: {}", error),
+        },
+        Mode::Bridge => match Bridge::new(&config) {
+            Ok(backend) => Box::new(backend),
+            Err(error) => EXIT!("Unable to relay the peer topic: {}", error),
+        },
+    };
+    serve(backend.as_mut(), &cli, config)
+}
+
+/// Run the event loop of the topic. When the connection to
+/// the hub is lost, the topic is re-opened with an exponential
+/// backoff and the processes still running are served again.
+/// Return after a graceful shutdown on SIGTERM or SIGINT
+fn serve(
+    backend: &mut dyn Backend,
+    cli: &Cli,
+    mut config: Config,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let signals = Signals::register()?;
+    let (name, socket) = (config.topic.clone(), config.socket.clone());
+    let peer = backend.peer();
+    let mut delay = RECONNECT_DELAY;
+    let stopped = Cell::new(false);
+    let notifier = Notifier::from_env();
+    let activation = notify::listen_fds();
+    let idle_since = Cell::new(Instant::now());
+    let draining = Cell::new(false);
+    let exporter = match config.metrics_listen.as_ref() {
+        Some(addr) => Some(Exporter::bind(addr)?),
+        None => None,
+    };
+    let health = match config.health_socket.as_ref() {
+        Some(path) => Some(HealthSocket::bind(path)?),
+        None => None,
+    };
+    let admin_socket = match config.admin_socket.as_ref() {
+        Some(path) => Some(AdminSocket::bind(path)?),
+        None => None,
+    };
+    // drain requested on the admin socket
+    let socket_drain = Cell::new(false);
+    let last_step = Cell::new(Instant::now());
+    let mut profiler = config
+        .profile_interval
+        .map(|secs| Profiler::new(Duration::from_secs(secs)));
+    let mut statsd = match config.statsd_addr.as_ref() {
+        Some(addr) => Some(Statsd::connect(
+            addr,
+            config.statsd_format,
+            Duration::from_secs(config.statsd_interval),
+        )?),
+        None => None,
+    };
+    loop {
+        let fds: Vec<RawFd> = backend
+            .fds()
+            .into_iter()
+            .chain(activation.clone())
+            .chain([signals.child_fd()])
+            .chain(exporter.as_ref().map(|e| e.fd()))
+            .chain(health.as_ref().map(|h| h.fd()))
+            .chain(admin_socket.as_ref().map(|s| s.fd()))
+            .collect();
+        let admin = (!config.admin_users.is_empty()).then(|| Admin::new(&name));
+        let mut msg_handle = |evt: &CallbackEvent, topic: &mut Topic| {
+            if stopped.get() {
+                return Ok(());
+            }
+            last_step.set(Instant::now());
+            if let Some(msg) = evt.msg {
+                frame::trace("<-", msg);
+            }
+            // the connections to the activation sockets only
+            // serve to start the backend
+            if let Some(fd) = evt.fd.filter(|fd| activation.contains(fd)) {
+                notify::discard(fd);
+                return Ok(());
+            }
+            if evt.fd == Some(signals.child_fd()) {
+                if signals.child_exited() {
+                    process::reap_dropped();
+                    return backend.children_exited(&config, topic);
+                }
+                return Ok(());
+            }
+            if let Some(exporter) = exporter.as_ref().filter(|e| evt.fd == Some(e.fd())) {
+                exporter.serve(&config.topic, backend.clients());
+                return Ok(());
+            }
+            if let Some(health) = health.as_ref().filter(|h| evt.fd == Some(h.fd())) {
+                health.serve(&Report {
+                    hub: true,
+                    process: backend.process_alive(),
+                    last_step: last_step.get().elapsed(),
+                    clients: backend.clients(),
+                    draining: draining.get(),
+                });
+                return Ok(());
+            }
+            if let Some(socket) = admin_socket.as_ref().filter(|s| evt.fd == Some(s.fd())) {
+                for (stream, command) in socket.accept() {
+                    let mut drain = false;
+                    let reply =
+                        admin_command(command, &mut *backend, cli, &mut config, topic, &mut drain);
+                    socket_drain.set(socket_drain.get() || drain);
+                    admin::answer(stream, &reply);
+                }
+                return Ok(());
+            }
+            let mut drain = signals.drain_requested() || socket_drain.take();
+            if let Some(admin) = admin.as_ref() {
+                drain |= admin_commands(admin, &mut *backend, cli, &mut config, topic);
+            }
+            if backend.clients() > 0 {
+                idle_since.set(Instant::now());
+            }
+            let idle = config
+                .idle_exit
+                .is_some_and(|secs| idle_since.get().elapsed() >= Duration::from_secs(secs));
+            if idle {
+                INFO!(
+                    "No client on {} for {}s, exiting",
+                    config.topic,
+                    idle_since.get().elapsed().as_secs()
+                );
+            }
+            if drain && !draining.get() {
+                INFO!(
+                    "Draining {}: refuse new clients, exit after the last one",
+                    config.topic
+                );
+                draining.set(true);
+            }
+            let drained = draining.get() && backend.clients() == 0;
+            if drained {
+                INFO!("No client left on {}, exiting", config.topic);
+            }
+            if signals.terminate_requested() || idle || drained {
+                stopped.set(true);
+                notifier.notify("STOPPING=1");
+                return backend.shutdown(&config, topic);
+            }
+            if draining.get() {
+                let subscribe = evt
+                    .msg
+                    .filter(|msg| matches!(msg.kind, MsgKind::ChannelSubscribe));
+                if let Some(msg) = subscribe {
+                    WARN!(
+                        "Refuse client {} on channel {}: draining",
+                        msg.client_id,
+                        config.topic
+                    );
+                    return clients::expel(msg.client_id, "Temporarily unavailable", topic);
+                }
+            }
+            if signals.upgrade_requested() {
+                let error = upgrade(&*backend, &config);
+                ERROR!("Unable to upgrade the backend: {}", error);
+            }
+            if signals.dump_requested() {
+                INFO!("State of the backend of {}:", config.topic);
+                for line in backend.dump() {
+                    INFO!("  {}", line);
+                }
+            }
+            if signals.reload_requested() {
+                notifier.notify("RELOADING=1");
+                if let Err(error) = config.reload(cli) {
+                    let class = error::report(&*error, Class::Config);
+                    ERROR!("Unable to reload configuration: {}: {}", class, error);
+                }
+                notifier.notify("READY=1");
+            }
+            notifier.step(format!(
+                "{}{} client(s) on {}",
+                if draining.get() { "Draining, " } else { "" },
+                backend.clients(),
+                config.topic
+            ));
+            metrics::sample();
+            if let Some(statsd) = statsd.as_mut() {
+                statsd.step(&config.topic, backend.clients());
+            }
+            if let Some(profiler) = profiler.as_mut() {
+                profiler.step();
+            }
+            let started = Instant::now();
+            let result = backend.step_handle(evt, &config, topic);
+            metrics::observe_step(started.elapsed());
+            profile::record(Phase::Step, started.elapsed());
+            result
+        };
+        let mut admin_handle = |evt: &CallbackEvent, topic: &mut Topic| match admin.as_ref() {
+            Some(admin) => admin.step_handle(evt, topic),
+            None => Ok(()),
+        };
+        let mut peer_handle = |evt: &CallbackEvent, topic: &mut Topic| match peer.as_ref() {
+            Some(peer) => peer.step_handle(evt, topic),
+            None => Ok(()),
+        };
+        {
+            let mut admin_topic = admin.as_ref().and_then(|admin| {
+                let mut topic = Topic::create(admin.name(), &socket);
+                topic.on_message(&mut admin_handle);
+                topic.set_step_to(Duration::from_millis(ADMIN_STEP_TO_MS));
+                match topic.open() {
+                    Ok(()) => Some(topic),
+                    Err(error) => {
+                        let class = error::report(&*error, Class::Tunnel);
+                        ERROR!(
+                            "Unable to open topic {}: {}: {}",
+                            admin.name(),
+                            class,
+                            error
+                        );
+                        None
+                    }
+                }
+            });
+            let mut peer_topic = peer.as_ref().and_then(|peer| {
+                let mut topic = Topic::create(peer.name(), peer.socket());
+                topic.on_message(&mut peer_handle);
+                topic.set_step_to(Duration::from_millis(ADMIN_STEP_TO_MS));
+                match topic.open() {
+                    Ok(()) => Some(topic),
+                    Err(error) => {
+                        let class = error::report(&*error, Class::Tunnel);
+                        // the hub takes a single publisher per topic
+                        ERROR!(
+                            "Unable to open topic {} on {}, is it served by another backend? {}: {}",
+                            peer.name(),
+                            peer.socket(),
+                            class,
+                            error
+                        );
+                        None
+                    }
+                }
+            });
+            let mut topic = Topic::create(&name, &socket);
+            topic.on_message(&mut msg_handle);
+            topic.set_step_to(Duration::from_millis(STEP_TO_MS));
+            // a bridge is of no use without its peer topic
+            let opened = match peer_topic {
+                None if peer.is_some() => Err("the peer topic is not open".into()),
+                _ => open(&mut topic, &fds),
+            };
+            match opened {
+                Ok(()) => {
+                    INFO!("Topic {} opened on {}", name, socket);
+                    notifier.notify("READY=1");
+                    delay = RECONNECT_DELAY;
+                    while !stopped.get() {
+                        if let Err(error) = topic.step() {
+                            let class = error::report(&*error, Class::Tunnel);
+                            ERROR!("Error step: {}: {}", class, error);
+                            break;
+                        }
+                        if let Some(admin_topic) = admin_topic.as_mut() {
+                            if let Err(error) = admin_topic.step() {
+                                let class = error::report(&*error, Class::Tunnel);
+                                ERROR!("Error step on the admin topic: {}: {}", class, error);
+                                break;
+                            }
+                        }
+                        if let Some(peer_topic) = peer_topic.as_mut() {
+                            if let Err(error) = peer_topic.step() {
+                                let class = error::report(&*error, Class::Tunnel);
+                                ERROR!("Error step on the peer topic: {}: {}", class, error);
+                                break;
+                            }
+                        }
+                    }
+                }
+                Err(error) => {
+                    let class = error::report(&*error, Class::Tunnel);
+                    ERROR!("Unable to open topic {}: {}: {}", name, class, error)
+                }
+            }
+        }
+        if stopped.get() {
+            INFO!("Topic {} closed", name);
+            return Ok(());
+        }
+        WARN!("Reconnecting to the hub in {}s", delay.as_secs());
+        backend.disconnected(&config);
+        // keep pinging the watchdog and buffering the output of
+        // the processes while waiting
+        let until = Instant::now() + delay;
+        while Instant::now() < until {
+            // there is no client to wait for while the hub is away
+            let drain = signals.drain_requested() || socket_drain.take();
+            let idle = config
+                .idle_exit
+                .is_some_and(|secs| idle_since.get().elapsed() >= Duration::from_secs(secs));
+            if signals.terminate_requested() || idle || drain || draining.get() {
+                INFO!(
+                    "Exiting while the hub on {} is unreachable, terminate the processes",
+                    socket
+                );
+                notifier.notify("STOPPING=1");
+                backend.terminate(&config);
+                return Ok(());
+            }
+            notifier.step(format!("Reconnecting to the hub on {}", socket));
+            backend.step_offline(&config);
+            metrics::sample();
+            if let Some(statsd) = statsd.as_mut() {
+                statsd.step(&config.topic, 0);
+            }
+            if let Some(profiler) = profiler.as_mut() {
+                profiler.step();
+            }
+            if let Some(health) = health.as_ref() {
+                health.serve(&Report {
+                    hub: false,
+                    process: backend.process_alive(),
+                    last_step: last_step.get().elapsed(),
+                    clients: 0,
+                    draining: draining.get(),
+                });
+            }
+            thread::sleep(OFFLINE_STEP.min(until - Instant::now()));
+        }
+        delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+    }
+}
+
+/// Run the commands of the admin clients, return whether a
+/// drain was requested
+fn admin_commands(
+    admin: &Admin,
+    backend: &mut dyn Backend,
+    cli: &Cli,
+    config: &mut Config,
+    topic: &mut Topic,
+) -> bool {
+    let mut drain = false;
+    for (client_id, command) in admin.commands(config) {
+        let reply = admin_command(command, backend, cli, config, topic, &mut drain);
+        admin.reply(client_id, &reply);
+    }
+    drain
+}
+
+/// Run a command of the admin topic or socket, return the reply
+fn admin_command(
+    command: admin::Command,
+    backend: &mut dyn Backend,
+    cli: &Cli,
+    config: &mut Config,
+    topic: &mut Topic,
+    drain: &mut bool,
+) -> String {
+    match command {
+        admin::Command::Help => String::from(admin::HELP),
+        admin::Command::Sessions => backend.dump().join("\n"),
+        admin::Command::Metrics => metrics::render(&config.topic, backend.clients()),
+        admin::Command::Throughput => metrics::rates().describe(),
+        admin::Command::Drain => {
+            *drain = true;
+            String::from("Draining")
+        }
+        admin::Command::Reload => match config.reload(cli) {
+            Ok(()) => String::from("Configuration reloaded"),
+            Err(error) => {
+                let class = error::report(&*error, Class::Config);
+                ERROR!("Unable to reload configuration: {}: {}", class, error);
+                format!("Unable to reload: {}", error)
+            }
+        },
+        admin::Command::LogLevel(Some(level)) => {
+            log::set_level(level);
+            format!("Log level set to {:?}", level).to_lowercase()
+        }
+        admin::Command::LogLevel(None) => String::from("Usage: log-level error|warn|info|debug"),
+        admin::Command::Kill(Some(client_id), reason) => {
+            let reason = reason_or(reason, "Terminated by an administrator");
+            match backend.kill_session(client_id, &reason, false, config, topic) {
+                Ok(()) => format!("Killed the process of client {}", client_id),
+                Err(error) => format!("Unable to kill: {}", error),
+            }
+        }
+        admin::Command::Restart(Some(client_id), reason) => {
+            let reason = reason_or(reason, "Restarted by an administrator");
+            match backend.kill_session(client_id, &reason, true, config, topic) {
+                Ok(()) => format!("Restarted the process of client {}", client_id),
+                Err(error) => format!("Unable to restart: {}", error),
+            }
+        }
+        admin::Command::Kill(None, _) => String::from("Usage: kill <client_id> [reason]"),
+        admin::Command::Restart(None, _) => String::from("Usage: restart <client_id> [reason]"),
+        admin::Command::Unknown(name) => format!("Unknown command {}, see help", name),
+    }
+}
+
+fn reason_or(reason: String, default: &str) -> String {
+    if reason.is_empty() {
+        String::from(default)
+    } else {
+        reason
+    }
+}
+
+/// Replace the backend with a new instance of its executable,
+/// e.g. after an upgrade. The pipes of the processes are kept
+/// open and the new instance takes over the saved sessions.
+/// Only return on failure
+fn upgrade(backend: &dyn Backend, config: &Config) -> Box<dyn std::error::Error> {
+    if config.state_file.is_none() && config.runtime_dir.is_none() {
+        return "neither state_file nor runtime_dir is configured".into();
+    }
+    let exe = match executable() {
+        Ok(exe) => exe,
+        Err(error) => return error,
+    };
+    let fds = backend.inherited_fds();
+    set_cloexec(&fds, false);
+    INFO!("Replacing the backend with {}", exe.display());
+    let error = Command::new(exe).args(env::args_os().skip(1)).exec();
+    set_cloexec(&fds, true);
+    error.into()
+}
+
+/// Executable of a new instance: the `argv[0]` of the backend,
+/// searched in the `PATH` when it has no `/`. Not the running
+/// executable, which is gone once the package is upgraded
+fn executable() -> Result<PathBuf, Box<dyn std::error::Error>> {
+    let arg0 = PathBuf::from(env::args_os().next().ok_or("argv[0] is not set")?);
+    let exe = if arg0.as_os_str().as_bytes().contains(&b'/') {
+        Some(arg0.clone())
+    } else {
+        spawn::search(&arg0)?
+    };
+    match exe {
+        Some(exe) if spawn::is_executable(&exe) => Ok(exe),
+        _ => Err(format!("The executable {} is missing", arg0.display()).into()),
+    }
+}
+
+fn set_cloexec(fds: &[RawFd], on: bool) {
+    for fd in fds {
+        unsafe {
+            let flags = libc::fcntl(*fd, libc::F_GETFD);
+            let flags = if on {
+                flags | libc::FD_CLOEXEC
+            } else {
+                flags & !libc::FD_CLOEXEC
+            };
+            libc::fcntl(*fd, libc::F_SETFD, flags);
+        }
+    }
+}
+
+/// Register the file descriptors of the backend then open
+/// the topic
+fn open(topic: &mut Topic, fds: &[RawFd]) -> Result<(), Box<dyn std::error::Error>> {
+    for fd in fds {
+        topic.register_io(*fd, IOInterest::READABLE)?;
+    }
+    topic.open()?;
+    Ok(())
+}
@@ -0,0 +1,178 @@
+//! # Security audit log
+//!
+//! With `audit_log`, the lifecycle of the sessions is recorded
+//! apart from the debug log, one JSON object per event: the
+//! subscriptions and their refusals, the commands run with
+//! their arguments, the signals sent and the exit statuses.
+//! The records go either to an append-only file or to syslog,
+//! in the `authpriv` facility. The same events run the
+//! configured [`crate::hooks`] and are posted to the
+//! [`crate::webhook`]
+//!
+//! **Author**: "Dany LE"
+//!
+use crate::config::Config;
+use crate::hooks;
+use crate::webhook;
+use crate::WARN;
+use latpr::utils::{LogLevel, LOG};
+use serde_json::{json, Value};
+use std::ffi::CString;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::process::ExitStatusExt;
+use std::path::Path;
+use std::process::ExitStatus;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// value of `audit_log` sending the records to syslog
+pub const SYSLOG: &str = "syslog";
+
+/// names of the events
+pub const EVENTS: [&str; 8] = [
+    "subscribe",
+    "refuse",
+    "unsubscribe",
+    "spawn",
+    "signal",
+    "exit",
+    "quota",
+    "transfer",
+];
+
+/// An event of the lifecycle of a session
+pub enum Event<'a> {
+    Subscribe,
+    /// subscription refused, with the reason
+    Refuse(&'a str),
+    /// with the reason, e.g. `client` when it unsubscribed itself
+    Unsubscribe(&'a str),
+    Spawn {
+        command: &'a Path,
+        args: &'a [String],
+        pid: u32,
+    },
+    Signal {
+        pid: u32,
+        signal: i32,
+        reason: &'a str,
+    },
+    Exit {
+        pid: u32,
+        status: ExitStatus,
+    },
+    /// a limit reached by the session, e.g. `max_memory`
+    Quota(&'a str),
+    /// a file read or written by a client, see [`crate::transfer`]
+    Transfer {
+        op: &'a str,
+        path: &'a Path,
+        offset: u64,
+        size: u64,
+    },
+}
+
+impl Event<'_> {
+    /// The event as a JSON object, the name of the event
+    /// in `event`
+    pub fn to_json(&self) -> Value {
+        match self {
+            Event::Subscribe => json!({ "event": "subscribe" }),
+            Event::Refuse(reason) => json!({ "event": "refuse", "reason": reason }),
+            Event::Unsubscribe(reason) => json!({ "event": "unsubscribe", "reason": reason }),
+            Event::Spawn { command, args, pid } => json!({
+                "event": "spawn",
+                "command": command.display().to_string(),
+                "args": args,
+                "pid": pid,
+            }),
+            Event::Signal {
+                pid,
+                signal,
+                reason,
+            } => json!({
+                "event": "signal",
+                "pid": pid,
+                "signal": signal,
+                "reason": reason,
+            }),
+            Event::Exit { pid, status } => json!({
+                "event": "exit",
+                "pid": pid,
+                "exit_code": status.code(),
+                "signal": status.signal(),
+            }),
+            Event::Quota(limit) => json!({ "event": "quota", "limit": limit }),
+            Event::Transfer {
+                op,
+                path,
+                offset,
+                size,
+            } => json!({
+                "event": "transfer",
+                "op": op,
+                "path": path.display().to_string(),
+                "offset": offset,
+                "size": size,
+            }),
+        }
+    }
+}
+
+/// Record an event of a client, run its hook and post it to
+/// the webhook, a failure is logged only
+pub fn log(config: &Config, client_id: u16, user: &str, session: &str, event: Event) {
+    hooks::fire(config, client_id, user, session, &event);
+    if config.audit_log.is_none() && config.webhook_url.is_none() {
+        return;
+    }
+    let time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    let mut record = json!({
+        "time": time,
+        "topic": config.topic,
+        "client_id": client_id,
+        "user": user,
+    });
+    if !session.is_empty() {
+        record["session_id"] = json!(session);
+    }
+    if let (Some(record), Value::Object(fields)) = (record.as_object_mut(), event.to_json()) {
+        record.extend(fields);
+    }
+    webhook::notify(config, &record);
+    let target = match config.audit_log.as_ref() {
+        None => return,
+        Some(target) => target,
+    };
+    let line = record.to_string();
+    if target == SYSLOG {
+        syslog(&line);
+        return;
+    }
+    let mut data = line.into_bytes();
+    data.push(b'\n');
+    let written = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .mode(0o600)
+        .open(target)
+        .and_then(|mut file| file.write_all(&data));
+    if let Err(error) = written {
+        WARN!("Unable to write the audit log to {}: {}", target, error);
+    }
+}
+
+fn syslog(line: &str) {
+    if let Ok(message) = CString::new(line) {
+        unsafe {
+            libc::syslog(
+                libc::LOG_AUTHPRIV | libc::LOG_INFO,
+                c"%s".as_ptr(),
+                message.as_ptr(),
+            );
+        }
+    }
+}
@@ -0,0 +1,95 @@
+//! # Interface of the backend modes
+//!
+//! **Author**: "Dany LE"
+//!
+use crate::bridge::Peer;
+use crate::config::Config;
+use latpr::tunnel::{CallbackEvent, Topic};
+use std::os::unix::io::RawFd;
+use std::rc::Rc;
+
+/// A backend mode, driven by the events of its topic
+pub trait Backend {
+    /// File descriptors to watch for readability each time
+    /// the topic is opened, including after a reconnection
+    fn fds(&self) -> Vec<RawFd> {
+        Vec::new()
+    }
+
+    /// File descriptors kept open when the backend replaces
+    /// itself with a new instance, see [`crate::state`]
+    fn inherited_fds(&self) -> Vec<RawFd> {
+        Vec::new()
+    }
+
+    /// Topic relayed by the backend, stepped along with its
+    /// own, see [`crate::bridge`]
+    fn peer(&self) -> Option<Rc<Peer>> {
+        None
+    }
+
+    /// Number of subscribed clients
+    fn clients(&self) -> usize;
+
+    /// Whether the process of the backend runs, none for the
+    /// modes with a process per client, see [`crate::health`]
+    fn process_alive(&self) -> Option<bool> {
+        None
+    }
+
+    /// Human-readable description of the state of the backend,
+    /// one entry per line, written to the log on SIGUSR1
+    fn dump(&self) -> Vec<String>;
+
+    /// Handle an event of the topic, this is also called
+    /// periodically when there is no event. An error ends the
+    /// connection to the hub, so a failure that concerns a
+    /// single client should drop that client instead
+    fn step_handle(
+        &mut self,
+        evt: &CallbackEvent,
+        config: &Config,
+        topic: &mut Topic,
+    ) -> Result<(), Box<dyn std::error::Error>>;
+
+    /// Called when a child process has exited (SIGCHLD), the
+    /// processes are only checked for exit then
+    fn children_exited(
+        &mut self,
+        config: &Config,
+        topic: &mut Topic,
+    ) -> Result<(), Box<dyn std::error::Error>>;
+
+    /// Kill the process of a client, on an admin command, and
+    /// spawn a new one when `restart` is set. The reason is sent
+    /// to the client
+    fn kill_session(
+        &mut self,
+        _client_id: u16,
+        _reason: &str,
+        _restart: bool,
+        _config: &Config,
+        _topic: &mut Topic,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        Err("not supported in this mode".into())
+    }
+
+    /// Called when the connection to the hub is lost, the
+    /// clients are gone with it but the processes are kept
+    fn disconnected(&mut self, _config: &Config) {}
+
+    /// Called periodically while the hub is unreachable, e.g.
+    /// to buffer the output of the processes
+    fn step_offline(&mut self, _config: &Config) {}
+    /// Unsubscribe all the clients and terminate the processes
+    /// before the backend exits
+    fn shutdown(
+        &mut self,
+        config: &Config,
+        topic: &mut Topic,
+    ) -> Result<(), Box<dyn std::error::Error>>;
+
+    /// Terminate the processes kept while the hub is unreachable
+    /// when the backend exits before reconnecting
+    fn terminate(&mut self, _config: &Config) {}
+}
@@ -0,0 +1,456 @@
+//! # Bridge between two topics
+//!
+//! `shbridge` owns no process: it relays the data between its
+//! topic and a peer topic, possibly on another hub socket. The
+//! data of a client of one side is sent to all the clients of
+//! the other side, e.g. to expose a topic on another hub.
+//!
+//! The bridge publishes the peer topic rather than subscribing
+//! to it: the socket of the hub only takes publishers, the
+//! subscriptions come from the WebSocket clients of the hub,
+//! and latpr only implements the publisher side ([`Topic`]).
+//! So the peer topic must not be served by another backend, the
+//! hub would refuse the second publisher; a backend is chained
+//! by having its clients, e.g. the frontend, subscribe to the
+//! topic of the bridge.
+//!
+//! The events of the peer topic are queued here and handled
+//! by the event loop of the topic of the backend, as those of
+//! the admin topic, see [`crate::admin`]
+//!
+//! **Author**: "Dany LE"
+//!
+use crate::audit::{self, Event};
+use crate::backend::Backend;
+use crate::clients::{self, Roster, Subscription};
+use crate::config::Config;
+use crate::frame;
+use crate::log::{self, Level};
+use crate::metrics::{self, Counter};
+use crate::stats::{self, Stats};
+use crate::telemetry::{self, Span};
+use crate::{DEBUG, INFO, WARN};
+use latpr::tunnel::{CallbackEvent, Msg, MsgKind, Topic};
+use latpr::utils::{LogLevel, LOG};
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+/// Settings of the bridge mode, e.g.
+///
+/// ```toml
+/// [bridge]
+/// topic = "shell"
+/// socket = "/var/antd/other_hotline.sock"
+/// ```
+#[derive(Deserialize, Serialize, Default, Debug, Clone, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct BridgeSettings {
+    /// name of the peer topic
+    pub topic: Option<String>,
+    /// socket of the hub of the peer topic, the one of the
+    /// backend when not set
+    pub socket: Option<String>,
+}
+
+/// An event of the peer topic
+enum PeerEvent {
+    Subscribe(u16, String),
+    Unsubscribe(u16),
+    UnsubscribeAll,
+    Data(u16, Vec<u8>),
+}
+
+/// The peer topic, stepped by the event loop along with the
+/// topic of the backend
+pub struct Peer {
+    name: String,
+    socket: String,
+    /// events received on the peer topic
+    inbox: RefCell<Vec<PeerEvent>>,
+    /// messages to write to the peer topic
+    outbox: RefCell<Vec<Msg>>,
+}
+
+impl Peer {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn socket(&self) -> &str {
+        &self.socket
+    }
+
+    /// Handle an event of the peer topic: queue the messages of
+    /// the clients and write the pending ones
+    pub fn step_handle(
+        &self,
+        evt: &CallbackEvent,
+        topic: &mut Topic,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if let Some(msg) = evt.msg {
+            frame::trace("<-", msg);
+            let event = match msg.kind {
+                MsgKind::ChannelSubscribe => Some(PeerEvent::Subscribe(
+                    msg.client_id,
+                    clients::subscriber(msg)?,
+                )),
+                MsgKind::ChannelUnsubscribe => Some(PeerEvent::Unsubscribe(msg.client_id)),
+                MsgKind::ChannelUnsubscribeAll => Some(PeerEvent::UnsubscribeAll),
+                MsgKind::ChannelData => Some(PeerEvent::Data(msg.client_id, msg.data.clone())),
+                _ => None,
+            };
+            self.inbox.borrow_mut().extend(event);
+        }
+        for msg in self.outbox.take() {
+            frame::send(topic, &msg)?;
+        }
+        Ok(())
+    }
+
+    fn send(&self, msg: Msg) {
+        self.outbox.borrow_mut().push(msg);
+    }
+}
+
+pub struct Client {
+    user: String,
+    /// identifier of the subscription, see [`telemetry::session_id`]
+    session: String,
+    /// whether the data of the client is relayed
+    writer: bool,
+    /// counters of the client
+    stats: Stats,
+    /// span of the subscription, see [`crate::telemetry`]
+    span: Option<Span>,
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        clients::end_span(&mut self.span, &self.stats);
+    }
+}
+
+crate::member!(Client);
+
+fn session_span(topic: &str, client_id: u16, user: &str, session: &str, writer: bool) -> Span {
+    let mut span = clients::span(topic, client_id, user, session);
+    span.set("antd.writer", writer);
+    span
+}
+
+/// Backend relaying its topic and the peer topic
+pub struct Bridge {
+    /// clients of the topic of the backend
+    clients: HashMap<u16, Client>,
+    /// clients of the peer topic
+    peers: HashMap<u16, Client>,
+    peer: Rc<Peer>,
+}
+
+impl Bridge {
+    pub fn new(config: &Config) -> Result<Bridge, Box<dyn std::error::Error>> {
+        let name = config
+            .bridge
+            .topic
+            .clone()
+            .ok_or("bridge.topic: missing value")?;
+        let socket = config
+            .bridge
+            .socket
+            .clone()
+            .unwrap_or_else(|| config.socket.clone());
+        INFO!("Relay the topic {} on {}", name, socket);
+        Ok(Bridge {
+            clients: HashMap::new(),
+            peers: HashMap::new(),
+            peer: Rc::new(Peer {
+                name,
+                socket,
+                inbox: RefCell::new(Vec::new()),
+                outbox: RefCell::new(Vec::new()),
+            }),
+        })
+    }
+
+    fn handle_message(
+        &mut self,
+        msg: &Msg,
+        config: &Config,
+        topic: &mut Topic,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if !matches!(msg.kind, MsgKind::ChannelData) {
+            DEBUG!(
+                "Receive message kind {} from client {}: {} bytes",
+                msg.kind,
+                msg.client_id,
+                msg.size
+            );
+        } else if let Some((frames, bytes)) =
+            log::throttle(Level::Debug, "receive", msg.client_id, msg.size as usize)
+        {
+            DEBUG!(
+                "Receive {} data frame(s) from client {}: {} bytes",
+                frames,
+                msg.client_id,
+                bytes
+            );
+        }
+        if let Some(client) = self.clients.get_mut(&msg.client_id) {
+            client.stats.received();
+        }
+        match msg.kind {
+            MsgKind::ChannelSubscribe => {
+                let Some(Subscription {
+                    user,
+                    session,
+                    context: _context,
+                }) = clients::subscribe(msg, config, topic, |_| {
+                    Ok(self
+                        .clients
+                        .remove(&msg.client_id)
+                        .map(|old| old.user.clone()))
+                })?
+                else {
+                    return Ok(());
+                };
+                if !self.make_room(msg.client_id, config, topic)? {
+                    return Ok(());
+                }
+                let writer = config.can_write(&user);
+                let span = session_span(&config.topic, msg.client_id, &user, &session, writer);
+                audit::log(config, msg.client_id, &user, &session, Event::Subscribe);
+                self.clients.insert(
+                    msg.client_id,
+                    Client {
+                        user,
+                        session,
+                        writer,
+                        stats: Stats::default(),
+                        span: Some(span),
+                    },
+                );
+                INFO!(
+                    "Client {} subscribe to channel {}",
+                    msg.client_id,
+                    &config.topic
+                );
+                clients::welcome(msg.client_id, config, topic)?;
+            }
+            MsgKind::ChannelUnsubscribe => {
+                self.unsubscribe(msg.client_id, config, topic)?;
+            }
+            MsgKind::ChannelUnsubscribeAll => self.unsubscribe_all(config, topic)?,
+            MsgKind::ChannelData => {
+                let Some(client) = self.clients.get_mut(&msg.client_id) else {
+                    WARN!("Client {} is not in the list", msg.client_id);
+                    return Ok(());
+                };
+                if !client.writer {
+                    WARN!("Refuse data of client {}: read-only", msg.client_id);
+                    frame::send(
+                        topic,
+                        &frame::error(msg.client_id, "Read-only subscription"),
+                    )?;
+                    return Ok(());
+                }
+                let n = msg.data.len() as u64;
+                client.stats.bytes_in += n;
+                metrics::add(Counter::BytesIn, n);
+                for (key, peer) in self.peers.iter_mut() {
+                    let data = msg.data.clone();
+                    self.peer
+                        .send(Msg::create(MsgKind::ChannelData, 0, *key, data));
+                    peer.stats.sent(msg.data.len());
+                }
+            }
+            MsgKind::ChannelCtrl if stats::is_query(&msg.data) => {
+                self.status(msg.client_id, topic)?;
+            }
+            _ => {
+                WARN!(
+                    "Receive message kind {} from client {}",
+                    msg.kind,
+                    msg.client_id
+                );
+            }
+        };
+        Ok(())
+    }
+
+    /// Handle the queued events of the peer topic. Its clients
+    /// are let in as those of the backend, but for the limits
+    /// of the channel
+    fn handle_peer(
+        &mut self,
+        config: &Config,
+        topic: &mut Topic,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let name = self.peer.name.clone();
+        for event in self.peer.inbox.take() {
+            match event {
+                PeerEvent::Subscribe(client_id, user) => {
+                    let session = telemetry::session_id();
+                    let _context = log::context(&name, client_id, &user, &session);
+                    if config.is_denied(&user) || !config.is_authorized(&user) {
+                        WARN!(
+                            "Refuse client ({}) {} on channel {}: not allowed",
+                            user,
+                            client_id,
+                            name
+                        );
+                        audit::log(
+                            config,
+                            client_id,
+                            &user,
+                            &session,
+                            Event::Refuse("not allowed"),
+                        );
+                        self.peer.send(frame::error(client_id, "Access denied"));
+                        continue;
+                    }
+                    let writer = config.can_write(&user);
+                    let span = session_span(&name, client_id, &user, &session, writer);
+                    audit::log(config, client_id, &user, &session, Event::Subscribe);
+                    INFO!("Client {} subscribe to channel {}", client_id, name);
+                    self.peers.insert(
+                        client_id,
+                        Client {
+                            user,
+                            session,
+                            writer,
+                            stats: Stats::default(),
+                            span: Some(span),
+                        },
+                    );
+                }
+                PeerEvent::Unsubscribe(client_id) => {
+                    WARN!("Client {} unsubscribe to channel {}", client_id, name);
+                    if let Some(c) = self.peers.remove(&client_id) {
+                        let reason = Event::Unsubscribe("client");
+                        audit::log(config, client_id, &c.user, &c.session, reason);
+                    }
+                }
+                PeerEvent::UnsubscribeAll => {
+                    INFO!("Unsubscribed all clients from channel {}", name);
+                    self.peers.clear();
+                }
+                PeerEvent::Data(client_id, data) => {
+                    let Some(peer) = self.peers.get_mut(&client_id) else {
+                        WARN!("Client {} is not in the list of {}", client_id, name);
+                        continue;
+                    };
+                    peer.stats.received();
+                    if !peer.writer {
+                        WARN!("Refuse data of client {}: read-only", client_id);
+                        self.peer
+                            .send(frame::error(client_id, "Read-only subscription"));
+                        continue;
+                    }
+                    let n = data.len() as u64;
+                    peer.stats.bytes_in += n;
+                    metrics::add(Counter::BytesIn, n);
+                    for (key, client) in self.clients.iter_mut() {
+                        let msg = Msg::create(MsgKind::ChannelData, 0, *key, data.clone());
+                        frame::send(topic, &msg)?;
+                        client.stats.sent(data.len());
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Roster for Bridge {
+    type Client = Client;
+
+    fn table(&self) -> &HashMap<u16, Client> {
+        &self.clients
+    }
+
+    fn table_mut(&mut self) -> &mut HashMap<u16, Client> {
+        &mut self.clients
+    }
+}
+
+impl Backend for Bridge {
+    fn clients(&self) -> usize {
+        self.clients.len()
+    }
+
+    fn peer(&self) -> Option<Rc<Peer>> {
+        Some(Rc::clone(&self.peer))
+    }
+
+    fn dump(&self) -> Vec<String> {
+        let mut lines = vec![format!(
+            "{} client(s), {} client(s) on {}",
+            self.clients.len(),
+            self.peers.len(),
+            self.peer.name
+        )];
+        for (side, clients) in [("client", &self.clients), ("peer", &self.peers)] {
+            let mut keys: Vec<&u16> = clients.keys().collect();
+            keys.sort();
+            for key in keys {
+                let c = &clients[key];
+                lines.push(format!(
+                    "{} {}: user {}{}, {}",
+                    side,
+                    key,
+                    c.user,
+                    if c.writer { "" } else { " (read-only)" },
+                    c.stats.describe()
+                ));
+            }
+        }
+        lines
+    }
+
+    fn step_handle(
+        &mut self,
+        evt: &CallbackEvent,
+        config: &Config,
+        topic: &mut Topic,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if let Some(msg) = evt.msg {
+            self.scoped(msg, config, topic, |bridge, topic| {
+                bridge.handle_message(msg, config, topic)
+            })?;
+        }
+        self.handle_peer(config, topic)?;
+        self.enforce_windows(config, topic)?;
+        self.reap_idle(config, topic)?;
+        Ok(())
+    }
+
+    fn children_exited(
+        &mut self,
+        _config: &Config,
+        _topic: &mut Topic,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        Ok(())
+    }
+
+    fn disconnected(&mut self, _config: &Config) {
+        // the peer topic is opened again with the topic of the
+        // backend
+        self.clients.clear();
+        self.peers.clear();
+        self.peer.inbox.take();
+        self.peer.outbox.take();
+    }
+
+    fn shutdown(
+        &mut self,
+        _config: &Config,
+        topic: &mut Topic,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.notify_shutdown(topic)?;
+        self.clients.clear();
+        self.peers.clear();
+        Ok(())
+    }
+}
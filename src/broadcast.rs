@@ -0,0 +1,724 @@
+//! # Single broadcast channel for all subscribed clients
+//!
+//! One process is spawned at startup, its output is sent to
+//! every subscriber and the data of any subscriber is written
+//! to its input, unless it is a read-only subscriber. The
+//! recent output can be kept and replayed to the new
+//! subscribers. The process is restarted when it exits
+//!
+//! **Author**: "Dany LE"
+//!
+use crate::account;
+use crate::audit::{self, Event};
+use crate::backend::Backend;
+use crate::clients::{self, Roster, Subscription};
+use crate::config::{Config, Mode};
+use crate::error::{Class, Classify};
+use crate::frame;
+use crate::history::{self, Run};
+use crate::journal;
+use crate::log::{self, Level};
+use crate::metrics::{self, Counter};
+use crate::process::{self, Process};
+use crate::profile::{Phase, Timer};
+use crate::recorder::{self, Recorder};
+use crate::replay::Replay;
+use crate::spawn;
+use crate::spool::Spool;
+use crate::state::{self, Record};
+use crate::stats::{self, Stats};
+use crate::telemetry::Span;
+use crate::{DEBUG, ERROR, INFO, WARN};
+use latpr::tunnel::{CallbackEvent, IOInterest, Msg, MsgKind, Topic};
+use latpr::utils::{LogLevel, LOG};
+use std::collections::HashMap;
+use std::io::Write;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::process::ExitStatusExt;
+use std::process::{Command, ExitStatus, Stdio};
+use std::time::{Duration, Instant};
+
+/// time given to the process to exit on shutdown
+const TERMINATE_GRACE: Duration = Duration::from_secs(2);
+/// delay before the first restart of the process, doubled on
+/// each consecutive restart
+const RESTART_DELAY: Duration = Duration::from_secs(1);
+const MAX_RESTART_DELAY: Duration = Duration::from_secs(60);
+/// a process running longer than this resets the restart count
+const STABLE_RUN: Duration = Duration::from_secs(60);
+/// time spent at most sending the spooled output on each step,
+/// for the loop to keep reading the process
+const DRAIN_BUDGET: Duration = Duration::from_millis(50);
+
+pub struct Subscriber {
+    user: String,
+    /// identifier of the subscription, see [`telemetry::session_id`]
+    session: String,
+    /// whether the subscriber may write to the process
+    writer: bool,
+    /// counters of the subscriber
+    stats: Stats,
+    /// span of the subscription, see [`crate::telemetry`]
+    span: Option<Span>,
+}
+
+impl Drop for Subscriber {
+    fn drop(&mut self) {
+        clients::end_span(&mut self.span, &self.stats);
+    }
+}
+
+crate::member!(Subscriber);
+
+pub struct Broadcast {
+    clients: HashMap<u16, Subscriber>,
+    /// none while the process is restarting or given up
+    process: Option<Process>,
+    fd: RawFd,
+    replay: Replay,
+    started_at: Instant,
+    /// consecutive restarts of the process
+    restarts: u32,
+    restart_at: Option<Instant>,
+    /// bytes of output of the process, sent to each client
+    bytes_out: u64,
+    /// current process, for the history
+    run: Option<Run>,
+    /// recording of the current process, see `record_dir`
+    recorder: Option<Recorder>,
+    /// span of the current process
+    span: Option<Span>,
+    /// queue of the output not sent yet, see `spool_dir`
+    spool: Option<Spool>,
+    /// whether the process may have exited since the last check
+    reap: bool,
+    /// last report of the resource usage, see `usage_interval`
+    usage_sent: Option<Instant>,
+}
+
+impl Broadcast {
+    /// Spawn the broadcast process, once the process left by a
+    /// crashed instance is killed
+    pub fn new(config: &Config) -> Result<Broadcast, Box<dyn std::error::Error>> {
+        if let Some(dir) = config.runtime_dir.as_ref() {
+            for record in state::take_pidfiles(dir, &config.topic)? {
+                if state::kill_orphan(&record) {
+                    WARN!("Kill orphan broadcast process {}", record.pid);
+                }
+            }
+        }
+        let spool = match config.spool_dir.as_ref() {
+            None => None,
+            Some(dir) => {
+                let name: String = config
+                    .topic
+                    .chars()
+                    .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+                    .collect();
+                Some(Spool::create(dir, &name, config.spool_size)?)
+            }
+        };
+        let (process, fd, run) = Broadcast::spawn(config)?;
+        let recorder = recorder::start(config, &process.id().to_string(), None);
+        Ok(Broadcast {
+            span: Some(process_span(&run, config)),
+            recorder,
+            clients: HashMap::new(),
+            process: Some(process),
+            run: Some(run),
+            fd,
+            replay: Replay::default(),
+            started_at: Instant::now(),
+            restarts: 0,
+            restart_at: None,
+            bytes_out: 0,
+            spool,
+            reap: true,
+            usage_sent: None,
+        })
+    }
+
+    fn spawn(config: &Config) -> Result<(Process, RawFd, Run), Box<dyn std::error::Error>> {
+        let _timer = Timer::start(Phase::Spawn);
+        // in the journal mode, the process is the journalctl of
+        // the settings
+        let (path, args) = match config.mode {
+            Mode::Journal => journal::command_line(config)?,
+            _ => (config.allowed_command(&config.command)?, Vec::new()),
+        };
+        let mut command = Command::new(&path);
+        command
+            .args(&args)
+            .envs(&config.env)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped());
+        config.rlimits.apply(&mut command);
+        spawn::die_with_parent(&mut command);
+        let process = Process::from(
+            command
+                .spawn()
+                .inspect_err(|_| metrics::add(Counter::SpawnFailures, 1))
+                .class(Class::Spawn)?,
+        );
+        let fd = process
+            .stdout
+            .as_ref()
+            .ok_or("Unable to get child process STDOUT")?
+            .as_raw_fd();
+        if let Some(script) = config.init_script()? {
+            if let Some(mut stdin) = process.stdin.as_ref() {
+                stdin.write_all(&script)?;
+            }
+        }
+        if let Some(dir) = config.runtime_dir.as_ref() {
+            let record = Record {
+                client_id: 0,
+                user: account::current_user(),
+                session: String::new(),
+                pid: process.id(),
+                start_time: process.start_time(),
+                stdin: process.stdin.as_ref().map_or(-1, |f| f.as_raw_fd()),
+                stdout: fd,
+            };
+            if let Err(error) = state::write_pidfile(dir, &config.topic, &record) {
+                WARN!("Unable to write the pidfile of {}: {}", record.pid, error);
+            }
+        }
+        // the process is shared, it belongs to no session
+        let run = Run::start(0, "", &path, process.id());
+        let spawned = Event::Spawn {
+            command: &path,
+            args: &args,
+            pid: process.id(),
+        };
+        audit::log(config, 0, &account::current_user(), "", spawned);
+        metrics::add(Counter::Spawned, 1);
+        Ok((process, fd, run))
+    }
+
+    /// Record the exit of the process to the history
+    fn record_exit(&mut self, status: ExitStatus, config: &Config) {
+        self.recorder = None;
+        if let Some(mut span) = self.span.take() {
+            if let Some(code) = status.code() {
+                span.set("process.exit.code", code as i64);
+            }
+            if let Some(signal) = status.signal() {
+                span.set("process.exit.signal", signal as i64);
+            }
+            span.end();
+        }
+        if let Some(run) = self.run.take() {
+            let pid = run.pid;
+            audit::log(
+                config,
+                0,
+                &account::current_user(),
+                "",
+                Event::Exit { pid, status },
+            );
+            metrics::add(Counter::Exited, 1);
+            history::record(config, &account::current_user(), &run, status);
+        }
+    }
+
+    /// Send some output to all the subscribers
+    fn send(&mut self, data: &[u8], topic: &mut Topic) -> Result<(), Box<dyn std::error::Error>> {
+        for (key, subscriber) in self.clients.iter_mut() {
+            let msg = Msg::create(MsgKind::ChannelData, 0, *key, data.to_vec());
+            frame::send(topic, &msg)?;
+            subscriber.stats.sent(data.len());
+        }
+        Ok(())
+    }
+
+    /// Send the spooled output for a while, the rest is sent on
+    /// the next steps. The clients are notified of the output
+    /// dropped when the spool overflowed
+    fn drain(
+        &mut self,
+        config: &Config,
+        topic: &mut Topic,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let dropped = match self.spool.as_mut() {
+            None => return Ok(()),
+            Some(spool) => spool.take_dropped(),
+        };
+        if dropped > 0 {
+            WARN!(
+                "Spool of channel {} is full, {} bytes dropped",
+                config.topic,
+                dropped
+            );
+            metrics::add(Counter::BytesDropped, dropped);
+            for (key, subscriber) in self.clients.iter_mut() {
+                subscriber.stats.dropped_out += dropped;
+                frame::send(topic, &frame::dropped(*key, "Output truncated", dropped))?;
+            }
+        }
+        let mut buf = vec![0; config.buffer_size];
+        let begin = Instant::now();
+        while begin.elapsed() < DRAIN_BUDGET {
+            let n = match self.spool.as_mut() {
+                Some(spool) if !spool.is_empty() => spool.pop(&mut buf)?,
+                _ => break,
+            };
+            self.send(&buf[0..n], topic)?;
+        }
+        Ok(())
+    }
+
+    /// Send a notice to all the subscribers
+    fn notify(&self, reason: &str, topic: &mut Topic) -> Result<(), Box<dyn std::error::Error>> {
+        for key in self.clients.keys() {
+            frame::send(topic, &frame::error(*key, reason))?;
+        }
+        Ok(())
+    }
+
+    /// Send each subscriber the resource usage of the tree of
+    /// the process every `usage_interval`
+    fn report_usage(
+        &mut self,
+        config: &Config,
+        topic: &mut Topic,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let interval = match config.usage_interval {
+            None => return Ok(()),
+            Some(secs) => Duration::from_secs(secs),
+        };
+        if self.usage_sent.is_some_and(|t| t.elapsed() < interval) {
+            return Ok(());
+        }
+        self.usage_sent = Some(Instant::now());
+        let pid = match self.process.as_ref() {
+            None => return Ok(()),
+            Some(process) => process.id(),
+        };
+        let tree = process::trees(&[pid]).concat();
+        let (cpu, rss) = process::usage(&tree);
+        for (key, subscriber) in self.clients.iter() {
+            frame::send(topic, &frame::usage(*key, cpu, rss, &subscriber.stats))?;
+        }
+        Ok(())
+    }
+
+    /// Restart the process when it has exited, with an
+    /// exponential backoff and up to `max_restarts` times in a row
+    fn supervise(
+        &mut self,
+        config: &Config,
+        topic: &mut Topic,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if let Some(process) = self.process.as_mut() {
+            if !std::mem::take(&mut self.reap) {
+                return Ok(());
+            }
+            let status = match process.try_wait()? {
+                None => return Ok(()),
+                Some(status) => status,
+            };
+            WARN!("Broadcast process has exited with status {}", status);
+            if let Some(dir) = config.runtime_dir.as_ref() {
+                if let Err(error) = state::remove_pidfile(dir, &config.topic, process.id()) {
+                    WARN!(
+                        "Unable to remove the pidfile of {}: {}",
+                        process.id(),
+                        error
+                    );
+                }
+            }
+            topic.unregister_io(self.fd)?;
+            self.record_exit(status, config);
+            self.process = None;
+            self.fd = -1;
+            if self.started_at.elapsed() >= STABLE_RUN {
+                self.restarts = 0;
+            }
+            self.schedule_restart(config, topic)?;
+            return Ok(());
+        }
+        if self.restart_at.is_none_or(|t| Instant::now() < t) {
+            return Ok(());
+        }
+        self.restart_at = None;
+        match Broadcast::spawn(config) {
+            Ok((process, fd, run)) => {
+                topic.register_io(fd, IOInterest::READABLE)?;
+                INFO!("Broadcast process restarted");
+                self.recorder = recorder::start(config, &process.id().to_string(), None);
+                self.span = Some(process_span(&run, config));
+                self.process = Some(process);
+                self.run = Some(run);
+                self.fd = fd;
+                self.started_at = Instant::now();
+                self.notify("Process restarted", topic)?;
+            }
+            Err(error) => {
+                WARN!("Unable to restart the broadcast process: {}", error);
+                self.schedule_restart(config, topic)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn schedule_restart(
+        &mut self,
+        config: &Config,
+        topic: &mut Topic,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if self.restarts >= config.max_restarts {
+            ERROR!("Broadcast process keeps failing, giving up");
+            return self.notify("Process has exited, no more restarts", topic);
+        }
+        let delay = RESTART_DELAY
+            .saturating_mul(1 << self.restarts.min(16))
+            .min(MAX_RESTART_DELAY);
+        self.restarts += 1;
+        self.restart_at = Some(Instant::now() + delay);
+        self.notify(
+            &format!("Process has exited, restarting in {}s", delay.as_secs()),
+            topic,
+        )
+    }
+
+    /// Handle a message of a client
+    fn handle_message(
+        &mut self,
+        msg: &Msg,
+        config: &Config,
+        topic: &mut Topic,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        // the data frames are too frequent to be logged one by one
+        if !matches!(msg.kind, MsgKind::ChannelData) {
+            DEBUG!(
+                "Receive message kind {} from client {}: {} bytes",
+                msg.kind,
+                msg.client_id,
+                msg.size
+            );
+        } else if let Some((frames, bytes)) =
+            log::throttle(Level::Debug, "receive", msg.client_id, msg.size as usize)
+        {
+            DEBUG!(
+                "Receive {} data frame(s) from client {}: {} bytes",
+                frames,
+                msg.client_id,
+                bytes
+            );
+        }
+        if let Some(client) = self.clients.get_mut(&msg.client_id) {
+            client.stats.received();
+        }
+        match msg.kind {
+            MsgKind::ChannelSubscribe => {
+                let Some(Subscription {
+                    user,
+                    session,
+                    context: _context,
+                }) = clients::subscribe(msg, config, topic, |_| {
+                    Ok(self
+                        .clients
+                        .remove(&msg.client_id)
+                        .map(|old| old.user.clone()))
+                })?
+                else {
+                    return Ok(());
+                };
+                if !self.make_room(msg.client_id, config, topic)? {
+                    return Ok(());
+                }
+                // the writers are resolved once, the group lookup
+                // is too costly for each data frame
+                // the journal is read-only
+                let writer = config.mode != Mode::Journal && config.can_write(&user);
+                let mut span = clients::span(&config.topic, msg.client_id, &user, &session);
+                span.set("antd.writer", writer);
+                audit::log(config, msg.client_id, &user, &session, Event::Subscribe);
+                self.clients.insert(
+                    msg.client_id,
+                    Subscriber {
+                        user,
+                        session,
+                        writer,
+                        stats: Stats::default(),
+                        span: Some(span),
+                    },
+                );
+                INFO!(
+                    "Client {} subscribe to channel {}",
+                    msg.client_id,
+                    &config.topic
+                );
+                clients::welcome(msg.client_id, config, topic)?;
+                // catch up with the recent output
+                if let Some(data) = self.replay.content() {
+                    let msg = Msg::create(MsgKind::ChannelData, 0, msg.client_id, data);
+                    frame::send(topic, &msg)?;
+                }
+            }
+            MsgKind::ChannelUnsubscribe => {
+                self.unsubscribe(msg.client_id, config, topic)?;
+            }
+            MsgKind::ChannelUnsubscribeAll => self.unsubscribe_all(config, topic)?,
+            MsgKind::ChannelData => {
+                let subscriber = match self.clients.get_mut(&msg.client_id) {
+                    None => {
+                        WARN!("Client {} is not in the list", msg.client_id);
+                        return Ok(());
+                    }
+                    Some(subscriber) => subscriber,
+                };
+                // read-only subscribers only get the output
+                if !subscriber.writer {
+                    WARN!(
+                        "Refuse data of client ({}) {}: read-only",
+                        subscriber.user,
+                        msg.client_id
+                    );
+                    frame::send(
+                        topic,
+                        &frame::error(msg.client_id, "Read-only subscription"),
+                    )?;
+                    return Ok(());
+                }
+                // write data to child
+                match self.process.as_ref() {
+                    None => frame::send(
+                        topic,
+                        &frame::error(msg.client_id, "Process is not running"),
+                    )?,
+                    Some(process) => {
+                        if process.write_input(&msg.data).class(Class::ChildIo)? {
+                            subscriber.stats.bytes_in += msg.data.len() as u64;
+                            if let Some(recorder) = self.recorder.as_mut() {
+                                recorder.input(&msg.data);
+                            }
+                            metrics::add(Counter::BytesIn, msg.data.len() as u64);
+                        } else {
+                            let n = msg.data.len() as u64;
+                            subscriber.stats.dropped_in += n;
+                            metrics::add(Counter::BytesDropped, n);
+                            WARN!(
+                                "Drop {} bytes of client {}: input pipe full, {} bytes dropped in total",
+                                n,
+                                msg.client_id,
+                                subscriber.stats.dropped_in
+                            );
+                            frame::send(
+                                topic,
+                                &frame::dropped(
+                                    msg.client_id,
+                                    "Process is not reading its input",
+                                    n,
+                                ),
+                            )?;
+                        }
+                    }
+                }
+            }
+            MsgKind::ChannelCtrl if stats::is_query(&msg.data) => {
+                self.status(msg.client_id, topic)?;
+            }
+            _ => {
+                WARN!(
+                    "Receive message kind {} from client {}",
+                    msg.kind,
+                    msg.client_id
+                );
+            }
+        };
+        Ok(())
+    }
+}
+
+/// Root span of a broadcast process
+fn process_span(run: &Run, config: &Config) -> Span {
+    let mut span = Span::root("process");
+    span.set("antd.topic", config.topic.as_str());
+    span.set("process.command", run.command.as_str());
+    span.set("process.pid", run.pid as i64);
+    span
+}
+
+impl Roster for Broadcast {
+    type Client = Subscriber;
+
+    fn table(&self) -> &HashMap<u16, Subscriber> {
+        &self.clients
+    }
+
+    fn table_mut(&mut self) -> &mut HashMap<u16, Subscriber> {
+        &mut self.clients
+    }
+}
+
+impl Backend for Broadcast {
+    fn fds(&self) -> Vec<RawFd> {
+        match self.process {
+            Some(_) => vec![self.fd],
+            None => Vec::new(),
+        }
+    }
+
+    fn clients(&self) -> usize {
+        self.clients.len()
+    }
+
+    fn process_alive(&self) -> Option<bool> {
+        Some(self.process.is_some())
+    }
+
+    fn dump(&self) -> Vec<String> {
+        let pid = match self.process.as_ref() {
+            Some(p) => p.id().to_string(),
+            None => "none".to_string(),
+        };
+        let mut lines = vec![format!(
+            "{} client(s), pid {}, fd {}, {} bytes out, {} restart(s), {} bytes of replay",
+            self.clients.len(),
+            pid,
+            self.fd,
+            self.bytes_out,
+            self.restarts,
+            self.replay.size()
+        )];
+        if let Some(spool) = self.spool.as_ref() {
+            lines.push(format!("{} bytes queued in the spool", spool.size()));
+        }
+        let mut keys: Vec<&u16> = self.clients.keys().collect();
+        keys.sort();
+        for key in keys {
+            let s = &self.clients[key];
+            lines.push(format!(
+                "client {}: user {}, {}, {}",
+                key,
+                s.user,
+                if s.writer { "writer" } else { "read-only" },
+                s.stats.describe()
+            ));
+        }
+        lines
+    }
+
+    fn step_handle(
+        &mut self,
+        evt: &CallbackEvent,
+        config: &Config,
+        topic: &mut Topic,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if let Some(msg) = evt.msg {
+            self.scoped(msg, config, topic, |broadcast, topic| {
+                broadcast.handle_message(msg, config, topic)
+            })?;
+        }
+        self.supervise(config, topic)?;
+        self.drain(config, topic)?;
+        self.report_usage(config, topic)?;
+        // the topic windows apply to all the subscribers
+        self.enforce_windows(config, topic)?;
+        self.reap_idle(config, topic)?;
+        let event = match evt.event {
+            None => return Ok(()),
+            Some(e) => e,
+        };
+        let fd = match evt.fd {
+            None => return Ok(()),
+            Some(d) => d,
+        };
+        if event.is_readable() {
+            // got data send it to client
+            let mut buf = vec![0; config.buffer_size];
+            let process = match self.process.as_mut() {
+                // stale event of the descriptor of a previous process
+                Some(p) if p.stdout_fd() == Some(fd) => p,
+                _ => return Ok(()),
+            };
+            if let Some(n) = process.read_output(&mut buf[..]).class(Class::ChildIo)? {
+                if n == 0 {
+                    // end of output, the exit is handled by supervise
+                    return Ok(());
+                }
+                self.bytes_out += n as u64;
+                metrics::add(Counter::BytesOut, n as u64);
+                self.replay.push(&buf[0..n], config);
+                if let Some(recorder) = self.recorder.as_mut() {
+                    recorder.output(&buf[0..n]);
+                }
+                match self.spool.as_mut() {
+                    Some(spool) => {
+                        if let Some((reads, bytes)) = log::throttle(Level::Debug, "spool", 0, n) {
+                            DEBUG!("Spooling {} bytes of raw data in {} read(s)", bytes, reads);
+                        }
+                        spool.push(&buf[0..n])?;
+                    }
+                    None => {
+                        if let Some((reads, bytes)) = log::throttle(Level::Debug, "send", 0, n) {
+                            DEBUG!(
+                                "Sending {} bytes of raw data to all clients in {} read(s)",
+                                bytes,
+                                reads
+                            );
+                        }
+                        self.send(&buf[0..n], topic)?;
+                    }
+                }
+            }
+        }
+        self.drain(config, topic)
+    }
+
+    fn children_exited(
+        &mut self,
+        config: &Config,
+        topic: &mut Topic,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.reap = true;
+        self.supervise(config, topic)
+    }
+
+    fn disconnected(&mut self, _config: &Config) {
+        self.clients.clear();
+    }
+
+    fn step_offline(&mut self, config: &Config) {
+        // the output goes to the replay buffer and the spool, for
+        // the clients subscribing once the hub is back
+        let mut buf = vec![0; config.buffer_size];
+        while let Some(process) = self.process.as_mut() {
+            let n = match process.read_output(&mut buf[..]) {
+                Ok(Some(n)) if n > 0 => n,
+                _ => break,
+            };
+            self.bytes_out += n as u64;
+            metrics::add(Counter::BytesOut, n as u64);
+            self.replay.push(&buf[0..n], config);
+            if let Some(recorder) = self.recorder.as_mut() {
+                recorder.output(&buf[0..n]);
+            }
+            if let Some(spool) = self.spool.as_mut() {
+                if let Err(error) = spool.push(&buf[0..n]) {
+                    WARN!("Unable to spool the output: {}", error);
+                }
+            }
+        }
+    }
+
+    fn shutdown(
+        &mut self,
+        config: &Config,
+        topic: &mut Topic,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.notify_shutdown(topic)?;
+        self.clients.clear();
+        self.terminate(config);
+        Ok(())
+    }
+
+    fn terminate(&mut self, config: &Config) {
+        spawn::terminate(self.process.iter_mut().collect(), TERMINATE_GRACE);
+        if let Some(Ok(Some(status))) = self.process.as_mut().map(|p| p.try_wait()) {
+            self.record_exit(status, config);
+        }
+    }
+}
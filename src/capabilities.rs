@@ -0,0 +1,47 @@
+//! # Description of the backend for the orchestration tools
+//!
+//! **Author**: "Dany LE"
+//!
+use crate::config::Mode;
+use clap::ValueEnum;
+use serde_json::{json, Value};
+
+/// Modes, protocol features and build features supported
+/// by this build, `mode` is the default mode of the executable
+pub fn describe(mode: Option<Mode>) -> Value {
+    let modes: Vec<String> = Mode::value_variants()
+        .iter()
+        .filter_map(|m| m.to_possible_value())
+        .map(|v| String::from(v.get_name()))
+        .collect();
+    let default_mode = mode
+        .unwrap_or_default()
+        .to_possible_value()
+        .map(|v| String::from(v.get_name()));
+    json!({
+        "name": env!("CARGO_PKG_NAME"),
+        "version": env!("CARGO_PKG_VERSION"),
+        "modes": modes,
+        "default_mode": default_mode,
+        "protocol": {
+            // terminal size, command, arguments and environment
+            // given in a ChannelCtrl frame before the spawn
+            "session_setup": true,
+            "command_menu": true,
+            "client_args": true,
+            "client_env": true,
+            "error_frames": true,
+            "banner": true,
+            "replay": true,
+            "read_only": true,
+            "reconnect": true,
+            // not supported yet: resizing a running session,
+            // forwarding signals and compressing the data
+            "resize": false,
+            "signals": false,
+            "compression": false,
+        },
+        // cargo features enabled at build time
+        "features": Vec::<String>::new(),
+    })
+}
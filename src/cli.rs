@@ -0,0 +1,193 @@
+//! # Command line interface shared by all backends
+//!
+//! **Author**: "Dany LE"
+//!
+use crate::config::{Mode, SpawnPolicy};
+use crate::log::Level;
+use clap::{Parser, Subcommand};
+use std::path::PathBuf;
+use toml::{Table, Value};
+
+/// Antd tunnel backend for interacting with shell scripts
+///
+/// For compatibility, the socket, topic and command can also
+/// be given as positional arguments: `SOCKET TOPIC COMMAND`
+#[derive(Parser, Debug, Default)]
+#[command(version, subcommand_precedence_over_arg = true)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub action: Option<Action>,
+    /// TOML configuration file, command line options
+    /// take precedence over its content
+    #[arg(long, env = "ANTD_SH_CONFIG")]
+    pub config: Option<PathBuf>,
+    /// mode of operation
+    #[arg(long, value_enum)]
+    pub mode: Option<Mode>,
+    /// path to the Antd tunnel hub socket
+    #[arg(long, short)]
+    pub socket: Option<String>,
+    /// name of the topic (channel) to publish
+    #[arg(long, short)]
+    pub topic: Option<String>,
+    /// command to run
+    #[arg(long, short)]
+    pub cmd: Option<String>,
+    /// `SOCKET TOPIC COMMAND`, legacy positional form
+    #[arg(num_args = 0..=3, value_name = "ARGS")]
+    pub positional: Vec<String>,
+    /// inline banner sent to each client on subscription
+    #[arg(long)]
+    pub banner: Option<String>,
+    /// file sent to each client on subscription
+    #[arg(long)]
+    pub banner_file: Option<PathBuf>,
+    /// named commands the client can select, `name=command`
+    #[arg(long = "command-menu", value_delimiter = ',')]
+    pub commands: Vec<String>,
+    /// pattern that each client supplied argument must match
+    #[arg(long)]
+    pub args_pattern: Option<String>,
+    /// maximum number of client supplied arguments
+    #[arg(long)]
+    pub max_args: Option<usize>,
+    /// environment variables a client may set
+    #[arg(long, value_delimiter = ',')]
+    pub env_allow: Vec<String>,
+    /// spawn the process of a client on its first data (lazy)
+    /// or right after its subscription (eager)
+    #[arg(long, value_enum)]
+    pub spawn: Option<SpawnPolicy>,
+    /// unsubscribe a client when its process exits
+    #[arg(long)]
+    pub unsubscribe_on_exit: bool,
+    /// log every frame sent and received, with a hex dump of
+    /// the start of its payload
+    #[arg(long)]
+    pub trace_frames: bool,
+    /// file written to the stdin of each child right after spawn
+    #[arg(long)]
+    pub init_file: Option<PathBuf>,
+    /// size of the buffer used to read the child output
+    #[arg(long)]
+    pub buffer_size: Option<usize>,
+    /// log verbosity
+    #[arg(long, value_enum)]
+    pub log_level: Option<Level>,
+    /// where the messages are logged: syslog, stderr or a file
+    #[arg(long)]
+    pub log_target: Option<String>,
+    /// Unix socket answering health checks
+    #[arg(long)]
+    pub health_socket: Option<PathBuf>,
+    /// Unix socket accepting the admin commands
+    #[arg(long)]
+    pub admin_socket: Option<PathBuf>,
+}
+
+/// Actions other than running the backend
+#[derive(Subcommand, Debug, Clone, PartialEq, Eq)]
+pub enum Action {
+    /// check the configuration and exit without
+    /// connecting to the hub
+    Validate,
+    /// print a systemd service unit running the backend
+    /// with the current configuration
+    GenerateUnit {
+        /// account running the service
+        #[arg(long)]
+        user: Option<String>,
+    },
+    /// print the supported modes and features as JSON
+    Capabilities,
+    /// print the most recent processes of the history file
+    History {
+        /// only the processes of this user
+        #[arg(long)]
+        user: Option<String>,
+        /// number of entries to print
+        #[arg(long, default_value_t = 20)]
+        limit: usize,
+        /// print the entries as JSON lines
+        #[arg(long)]
+        json: bool,
+    },
+}
+
+impl Cli {
+    /// Settings given on the command line, as a table of
+    /// configuration keys
+    pub fn overrides(&self) -> Table {
+        let mut table = Table::new();
+        let mut set = |key: &str, value: Option<Value>| {
+            if let Some(value) = value {
+                table.insert(String::from(key), value);
+            }
+        };
+        if let Some(mode) = self.mode {
+            set("mode", Value::try_from(mode).ok());
+        }
+        // named arguments take precedence over positional ones
+        let positional = |i: usize| self.positional.get(i).cloned();
+        set(
+            "socket",
+            self.socket
+                .clone()
+                .or_else(|| positional(0))
+                .map(Value::from),
+        );
+        set(
+            "topic",
+            self.topic
+                .clone()
+                .or_else(|| positional(1))
+                .map(Value::from),
+        );
+        set(
+            "command",
+            self.cmd.clone().or_else(|| positional(2)).map(Value::from),
+        );
+        set("banner", self.banner.clone().map(Value::from));
+        set("banner_file", path_value(&self.banner_file));
+        if !self.commands.is_empty() {
+            let menu: Table = self
+                .commands
+                .iter()
+                .filter_map(|entry| entry.split_once('='))
+                .map(|(name, cmd)| (String::from(name.trim()), Value::from(cmd.trim())))
+                .collect();
+            set("commands", Some(Value::Table(menu)));
+        }
+        set("args_pattern", self.args_pattern.clone().map(Value::from));
+        set("max_args", self.max_args.map(|v| Value::from(v as i64)));
+        if !self.env_allow.is_empty() {
+            set("env_allow", Some(Value::from(self.env_allow.clone())));
+        }
+        if let Some(spawn) = self.spawn {
+            set("spawn", Value::try_from(spawn).ok());
+        }
+        if self.unsubscribe_on_exit {
+            set("unsubscribe_on_exit", Some(Value::from(true)));
+        }
+        if self.trace_frames {
+            set("trace_frames", Some(Value::from(true)));
+        }
+        set("init_file", path_value(&self.init_file));
+        set(
+            "buffer_size",
+            self.buffer_size.map(|v| Value::from(v as i64)),
+        );
+        if let Some(level) = self.log_level {
+            set("log_level", Value::try_from(level).ok());
+        }
+        set("log_target", self.log_target.clone().map(Value::from));
+        set("health_socket", path_value(&self.health_socket));
+        set("admin_socket", path_value(&self.admin_socket));
+        table
+    }
+}
+
+fn path_value(path: &Option<PathBuf>) -> Option<Value> {
+    path.as_ref()
+        .map(|p| Value::from(p.to_string_lossy().into_owned()))
+}
@@ -0,0 +1,493 @@
+//! # Client management shared by the backend modes
+//!
+//! The backends keep their clients by id, see [`Roster`], whose
+//! provided methods handle what is common to all the modes: the
+//! unsubscriptions, the evictions, the idle clients, the access
+//! windows of the channel, the status queries and the errors of
+//! the messages
+//!
+//! **Author**: "Dany LE"
+//!
+use crate::audit::{self, Event};
+use crate::config::{Config, Eviction};
+use crate::error::{self, Class, Classify};
+use crate::frame;
+use crate::log;
+use crate::schedule;
+use crate::stats::Stats;
+use crate::telemetry::{self, Span};
+use crate::{ERROR, INFO, WARN};
+use latpr::tunnel::{Msg, MsgKind, Topic};
+use latpr::utils::{LogLevel, LOG};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// A client of a backend, see [`Roster`]
+pub trait Member {
+    fn user(&self) -> &str;
+    /// identifier of the subscription, see [`telemetry::session_id`]
+    fn session(&self) -> &str;
+    fn stats(&self) -> &Stats;
+}
+
+/// Implement [`Member`] for a client with the `user`, `session`
+/// and `stats` fields of all the modes
+#[macro_export]
+macro_rules! member {
+    ($client:ty) => {
+        impl $crate::clients::Member for $client {
+            fn user(&self) -> &str {
+                &self.user
+            }
+            fn session(&self) -> &str {
+                &self.session
+            }
+            fn stats(&self) -> &$crate::stats::Stats {
+                &self.stats
+            }
+        }
+    };
+}
+
+/// A backend keeping its clients by id
+pub trait Roster {
+    type Client: Member;
+
+    fn table(&self) -> &HashMap<u16, Self::Client>;
+
+    fn table_mut(&mut self) -> &mut HashMap<u16, Self::Client>;
+
+    /// Remove a client with what it holds, e.g. its processes or
+    /// the descriptors registered on the topic
+    fn release(
+        &mut self,
+        client_id: u16,
+        _config: &Config,
+        _topic: &mut Topic,
+    ) -> Result<Option<Self::Client>, Box<dyn std::error::Error>> {
+        Ok(self.table_mut().remove(&client_id))
+    }
+
+    /// Whether a client waits for the backend, e.g. for its
+    /// request, it is not idle then
+    fn busy(&self, _client_id: u16) -> bool {
+        false
+    }
+
+    /// Handle a message of a client in its log context. An error
+    /// is scoped to the client, which is dropped, the other
+    /// sessions go on
+    fn scoped(
+        &mut self,
+        msg: &Msg,
+        config: &Config,
+        topic: &mut Topic,
+        handle: impl FnOnce(&mut Self, &mut Topic) -> Result<(), Box<dyn std::error::Error>>,
+    ) -> Result<(), Box<dyn std::error::Error>>
+    where
+        Self: Sized,
+    {
+        let (user, session) = self
+            .table()
+            .get(&msg.client_id)
+            .map(|c| (c.user().to_string(), c.session().to_string()))
+            .unwrap_or_default();
+        let _context = log::context(&config.topic, msg.client_id, &user, &session);
+        if let Err(error) = handle(self, topic) {
+            ERROR!(
+                "Error on message kind {} of client {}: {}: {}",
+                msg.kind,
+                msg.client_id,
+                error::report(&*error, Class::Internal),
+                error
+            );
+            self.drop_client(msg.client_id, config, topic)?;
+        }
+        Ok(())
+    }
+
+    /// Drop a client whose message could not be handled
+    fn drop_client(
+        &mut self,
+        client_id: u16,
+        config: &Config,
+        topic: &mut Topic,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.release(client_id, config, topic)?;
+        expel(client_id, "Internal error, session closed", topic)
+    }
+
+    /// Make room for a new subscriber, see [`room`], return
+    /// whether it may subscribe
+    fn make_room(
+        &mut self,
+        client_id: u16,
+        config: &Config,
+        topic: &mut Topic,
+    ) -> Result<bool, Box<dyn std::error::Error>> {
+        match room(
+            client_id,
+            self.table(),
+            |c| c.stats().last_active,
+            config,
+            topic,
+        )? {
+            Room::Free => Ok(true),
+            Room::Full => Ok(false),
+            Room::Evict(key) => {
+                if let Some(c) = self.release(key, config, topic)? {
+                    let reason = Event::Unsubscribe("evicted");
+                    audit::log(config, key, c.user(), c.session(), reason);
+                }
+                expel(key, "Evicted: channel full", topic)?;
+                Ok(true)
+            }
+        }
+    }
+
+    /// Remove a client on its request, return it
+    fn unsubscribe(
+        &mut self,
+        client_id: u16,
+        config: &Config,
+        topic: &mut Topic,
+    ) -> Result<Option<Self::Client>, Box<dyn std::error::Error>> {
+        WARN!(
+            "Client {} unsubscribe to channel {}",
+            client_id,
+            &config.topic
+        );
+        let removed = self.release(client_id, config, topic)?;
+        match removed.as_ref() {
+            None => WARN!("Client {} is not in the client list", client_id),
+            Some(c) => {
+                let reason = Event::Unsubscribe("client");
+                audit::log(config, client_id, c.user(), c.session(), reason)
+            }
+        }
+        Ok(removed)
+    }
+
+    /// Remove all the clients, on the request of the hub
+    fn unsubscribe_all(
+        &mut self,
+        config: &Config,
+        topic: &mut Topic,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        INFO!("Unsubscribed all clients from channel {}", config.topic);
+        let keys: Vec<u16> = self.table().keys().copied().collect();
+        for key in keys {
+            self.release(key, config, topic)?;
+            let msg = Msg::create(MsgKind::ChannelUnsubscribe, 0, key, Vec::new());
+            frame::send(topic, &msg)?;
+        }
+        Ok(())
+    }
+
+    /// Answer the query of the counters of a client
+    fn status(&self, client_id: u16, topic: &mut Topic) -> Result<(), Box<dyn std::error::Error>> {
+        if let Some(c) = self.table().get(&client_id) {
+            frame::send(topic, &frame::status(client_id, c.stats()))?;
+        }
+        Ok(())
+    }
+
+    /// Unsubscribe all the clients once the access windows of the
+    /// channel have closed
+    fn enforce_windows(
+        &mut self,
+        config: &Config,
+        topic: &mut Topic,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if self.table().is_empty()
+            || schedule::is_open(&schedule::parse_all(&config.access_windows)?)
+        {
+            return Ok(());
+        }
+        WARN!("Access window of channel {} has closed", config.topic);
+        let keys: Vec<u16> = self.table().keys().copied().collect();
+        for key in keys {
+            if let Some(c) = self.release(key, config, topic)? {
+                let reason = Event::Unsubscribe("access window closed");
+                audit::log(config, key, c.user(), c.session(), reason);
+            }
+            expel(key, "Access window closed", topic)?;
+        }
+        Ok(())
+    }
+
+    /// Unsubscribe the clients idle for longer than
+    /// `idle_timeout`, unless [`busy`](Roster::busy)
+    fn reap_idle(
+        &mut self,
+        config: &Config,
+        topic: &mut Topic,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let keys: Vec<u16> = idle(self.table(), |c| c.stats().last_active, config)
+            .into_iter()
+            .filter(|key| !self.busy(*key))
+            .collect();
+        for key in keys {
+            WARN!("Client {} is idle, unsubscribe it", key);
+            if let Some(c) = self.release(key, config, topic)? {
+                audit::log(
+                    config,
+                    key,
+                    c.user(),
+                    c.session(),
+                    Event::Unsubscribe("idle"),
+                );
+            }
+            expel(key, "Idle timeout", topic)?;
+        }
+        Ok(())
+    }
+
+    /// Send an unsubscribe frame to all the clients as the
+    /// backend shuts down, they are removed by the caller with
+    /// their processes
+    fn notify_shutdown(&self, topic: &mut Topic) -> Result<(), Box<dyn std::error::Error>> {
+        INFO!(
+            "Shutting down, unsubscribe {} client(s)",
+            self.table().len()
+        );
+        for key in self.table().keys() {
+            let msg = Msg::create(MsgKind::ChannelUnsubscribe, 0, *key, Vec::new());
+            frame::send(topic, &msg)?;
+        }
+        Ok(())
+    }
+}
+
+/// Span of a subscription, see [`crate::telemetry`]
+pub fn span(topic: &str, client_id: u16, user: &str, session: &str) -> Span {
+    let mut span = Span::root("session");
+    span.set("antd.topic", topic);
+    span.set("antd.client_id", client_id as i64);
+    span.set("enduser.id", user);
+    span.set("antd.session_id", session);
+    span
+}
+
+/// End the span of a subscription, if any, with the counters of
+/// its client
+pub fn end_span(span: &mut Option<Span>, stats: &Stats) {
+    if let Some(mut span) = span.take() {
+        span.set("antd.bytes_in", stats.bytes_in as i64);
+        span.set("antd.bytes_out", stats.bytes_out as i64);
+        span.set("antd.frames_in", stats.frames_in as i64);
+        span.set("antd.frames_out", stats.frames_out as i64);
+        span.end();
+    }
+}
+
+/// Name of the user in the payload of a subscribe message
+pub fn subscriber(msg: &Msg) -> Result<String, Box<dyn std::error::Error>> {
+    let size = (msg.size as usize).min(msg.data.len());
+    let data = match msg.data[0..size].last() {
+        Some(0) => &msg.data[0..size - 1],
+        _ => &msg.data[0..size],
+    };
+    Ok(String::from(
+        std::str::from_utf8(data).class(Class::Protocol)?,
+    ))
+}
+
+/// A subscription accepted by [`subscribe`]
+pub struct Subscription {
+    pub user: String,
+    pub session: String,
+    /// log context of the client, for the rest of the
+    /// subscription
+    pub context: log::Context,
+}
+
+/// Prologue of a subscription common to all modes: the user and
+/// the session of the client, its log context and the checks of
+/// [`admit`]. A previous subscription of the same client is
+/// ended first by `replace`, returning its user. `None` when the
+/// client is refused
+pub fn subscribe(
+    msg: &Msg,
+    config: &Config,
+    topic: &mut Topic,
+    replace: impl FnOnce(&mut Topic) -> Result<Option<String>, Box<dyn std::error::Error>>,
+) -> Result<Option<Subscription>, Box<dyn std::error::Error>> {
+    let user = subscriber(msg)?;
+    let session = telemetry::session_id();
+    let context = log::context(&config.topic, msg.client_id, &user, &session);
+    if let Some(old) = replace(topic)? {
+        WARN!(
+            "Client {} subscribes again, replace its previous subscription (user {})",
+            msg.client_id,
+            old
+        );
+    }
+    if !admit(msg.client_id, &user, &session, config, topic)? {
+        return Ok(None);
+    }
+    Ok(Some(Subscription {
+        user,
+        session,
+        context,
+    }))
+}
+
+/// Checks common to all modes before accepting a subscription,
+/// the client is notified with an error frame when refused
+pub fn admit(
+    client_id: u16,
+    user: &str,
+    session: &str,
+    config: &Config,
+    topic: &mut Topic,
+) -> Result<bool, Box<dyn std::error::Error>> {
+    if config.is_denied(user) {
+        WARN!(
+            "Refuse client ({}) {} on channel {}: user is denied",
+            user,
+            client_id,
+            &config.topic
+        );
+        audit::log(
+            config,
+            client_id,
+            user,
+            session,
+            Event::Refuse("user is denied"),
+        );
+        frame::send(topic, &frame::error(client_id, "Access denied"))?;
+        return Ok(false);
+    }
+    if !config.is_authorized(user) {
+        WARN!(
+            "Refuse client ({}) {} on channel {}: not in the allowed groups",
+            user,
+            client_id,
+            &config.topic
+        );
+        audit::log(
+            config,
+            client_id,
+            user,
+            session,
+            Event::Refuse("not in the allowed groups"),
+        );
+        frame::send(topic, &frame::error(client_id, "Access denied"))?;
+        return Ok(false);
+    }
+    if !schedule::is_open(&schedule::parse_all(&config.profile(user).access_windows)?) {
+        WARN!(
+            "Refuse client ({}) {} on channel {}: outside of the access windows",
+            user,
+            client_id,
+            &config.topic
+        );
+        audit::log(
+            config,
+            client_id,
+            user,
+            session,
+            Event::Refuse("outside of the access windows"),
+        );
+        frame::send(
+            topic,
+            &frame::error(client_id, "Outside of the access windows"),
+        )?;
+        return Ok(false);
+    }
+    Ok(true)
+}
+
+/// Room for a new subscriber
+pub enum Room {
+    Free,
+    /// the channel is full, this client is to be evicted
+    Evict(u16),
+    /// the channel is full, the subscriber has been refused
+    Full,
+}
+
+/// Check that the channel has room for a new subscriber. When
+/// it is full, depending on `eviction`, either the subscriber is
+/// refused with an error frame or the client idle for the
+/// longest time is to be evicted
+pub fn room<T>(
+    client_id: u16,
+    clients: &HashMap<u16, T>,
+    last_active: impl Fn(&T) -> Instant,
+    config: &Config,
+    topic: &mut Topic,
+) -> Result<Room, Box<dyn std::error::Error>> {
+    if config.has_room(clients.len()) {
+        return Ok(Room::Free);
+    }
+    let idlest = clients
+        .iter()
+        .min_by_key(|(_, c)| last_active(c))
+        .map(|(k, _)| *k);
+    match idlest {
+        Some(key) if config.eviction == Eviction::OldestIdle => {
+            WARN!(
+                "Channel {} is full, evict client {} for client {}",
+                &config.topic,
+                key,
+                client_id
+            );
+            Ok(Room::Evict(key))
+        }
+        _ => {
+            WARN!(
+                "Refuse client {} on channel {}: channel full",
+                client_id,
+                &config.topic
+            );
+            frame::send(topic, &frame::error(client_id, "Channel full"))?;
+            Ok(Room::Full)
+        }
+    }
+}
+
+/// Send the banner, if any, to a newly subscribed client
+pub fn welcome(
+    client_id: u16,
+    config: &Config,
+    topic: &mut Topic,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if let Some(banner) = config.banner()? {
+        let msg = Msg::create(MsgKind::ChannelData, 0, client_id, banner);
+        frame::send(topic, &msg)?;
+    }
+    Ok(())
+}
+
+/// Notify a client of the reason of its eviction then
+/// unsubscribe it
+pub fn expel(
+    client_id: u16,
+    reason: &str,
+    topic: &mut Topic,
+) -> Result<(), Box<dyn std::error::Error>> {
+    frame::send(topic, &frame::error(client_id, reason))?;
+    let msg = Msg::create(MsgKind::ChannelUnsubscribe, 0, client_id, Vec::new());
+    frame::send(topic, &msg)?;
+    Ok(())
+}
+
+/// Clients without any message for longer than `idle_timeout`,
+/// `last_active` gives the time of the last message of a client
+pub fn idle<T>(
+    clients: &HashMap<u16, T>,
+    last_active: impl Fn(&T) -> Instant,
+    config: &Config,
+) -> Vec<u16> {
+    let ttl = match config.idle_timeout {
+        None => return Vec::new(),
+        Some(secs) => Duration::from_secs(secs),
+    };
+    clients
+        .iter()
+        .filter(|(_, c)| last_active(c).elapsed() >= ttl)
+        .map(|(k, _)| *k)
+        .collect()
+}
@@ -0,0 +1,1584 @@
+//! # Backend configuration
+//!
+//! The configuration is built from three layers, each one
+//! overriding the previous: the TOML file given by `--config`,
+//! the command line options, and the `ANTD_SH_*` environment
+//! variables
+//!
+//! **Author**: "Dany LE"
+//!
+use crate::account;
+use crate::audit;
+use crate::bridge::BridgeSettings;
+use crate::cli::Cli;
+use crate::cron::CronSettings;
+use crate::exec::ExecSettings;
+use crate::fifo::FifoSettings;
+use crate::frame;
+use crate::git::GitSettings;
+use crate::hooks::Hooks;
+use crate::http::Endpoint;
+use crate::journal::{self, JournalSettings};
+use crate::kube::KubeSettings;
+use crate::log::{self, Level};
+use crate::mux::MuxSettings;
+use crate::playback::PlaybackSettings;
+use crate::proxy::{SockSettings, TcpSettings};
+use crate::queue::QueueSettings;
+use crate::redact;
+use crate::rotate::Rotation;
+use crate::rpc::RpcSettings;
+use crate::schedule;
+use crate::session::SessionSettings;
+use crate::spawn::{self, Rlimits};
+use crate::svc::SvcSettings;
+use crate::sysstats::SysStatsSettings;
+use crate::tail::TailSettings;
+use crate::telemetry;
+use crate::transfer::FileSettings;
+use crate::wasm::WasmSettings;
+use crate::watch::WatchSettings;
+use crate::{INFO, WARN};
+use latpr::utils::{LogLevel, LOG};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use toml::{Table, Value};
+
+/// prefix of the environment variables overriding the configuration
+const ENV_PREFIX: &str = "ANTD_SH_";
+/// maximum nesting of the included configuration files
+const MAX_INCLUDE_DEPTH: usize = 8;
+
+/// Kind of the value of a setting given by the environment
+#[derive(Clone, Copy)]
+enum Kind {
+    /// a TOML scalar, e.g. a number or a boolean
+    Scalar,
+    /// a string, taken as is
+    Text,
+    /// a comma separated list
+    List,
+    /// a table of comma separated `name=value` pairs
+    Pairs,
+}
+
+/// Settings that the `ANTD_SH_<KEY>` variables may set, with the
+/// kind of their value. The profiles of the users and of the
+/// groups are only set in the configuration file
+const ENV_KEYS: &[(&str, Kind)] = &[
+    ("mode", Kind::Text),
+    ("socket", Kind::Text),
+    ("topic", Kind::Text),
+    ("command", Kind::Text),
+    ("banner", Kind::Text),
+    ("banner_file", Kind::Text),
+    ("commands", Kind::Pairs),
+    ("args_pattern", Kind::Text),
+    ("redact", Kind::List),
+    ("max_args", Kind::Scalar),
+    ("env_allow", Kind::List),
+    ("spawn", Kind::Text),
+    ("max_spawn_failures", Kind::Scalar),
+    ("max_restarts", Kind::Scalar),
+    ("unsubscribe_on_exit", Kind::Scalar),
+    ("probe", Kind::Text),
+    ("probe_interval", Kind::Scalar),
+    ("probe_failures", Kind::Scalar),
+    ("probe_restart", Kind::Scalar),
+    ("session_tmpdir", Kind::Text),
+    ("session_tmpdir_size", Kind::Scalar),
+    ("max_memory", Kind::Scalar),
+    ("usage_interval", Kind::Scalar),
+    ("stall_timeout", Kind::Scalar),
+    ("stall_restart", Kind::Scalar),
+    ("init_file", Kind::Text),
+    ("buffer_size", Kind::Scalar),
+    ("allowed_commands", Kind::List),
+    ("log_level", Kind::Text),
+    ("log_target", Kind::Text),
+    ("trace_frames", Kind::Scalar),
+    ("profile_interval", Kind::Scalar),
+    ("log_rotation", Kind::Pairs),
+    ("denied_users", Kind::List),
+    ("allowed_groups", Kind::List),
+    ("access_windows", Kind::List),
+    ("max_clients", Kind::Scalar),
+    ("eviction", Kind::Text),
+    ("max_sessions_per_user", Kind::Scalar),
+    ("replay_bytes", Kind::Scalar),
+    ("replay_lines", Kind::Scalar),
+    ("spool_dir", Kind::Text),
+    ("spool_size", Kind::Scalar),
+    ("writers", Kind::List),
+    ("env", Kind::Pairs),
+    ("rlimits", Kind::Pairs),
+    ("session_timeout", Kind::Scalar),
+    ("reconnect_grace", Kind::Scalar),
+    ("reconnect_buffer", Kind::Scalar),
+    ("state_file", Kind::Text),
+    ("idle_timeout", Kind::Scalar),
+    ("history_file", Kind::Text),
+    ("hooks", Kind::Pairs),
+    ("statsd_addr", Kind::Text),
+    ("statsd_format", Kind::Text),
+    ("statsd_interval", Kind::Scalar),
+    ("otlp_endpoint", Kind::Text),
+    ("webhook_url", Kind::Text),
+    ("webhook_events", Kind::List),
+    ("health_socket", Kind::Text),
+    ("admin_socket", Kind::Text),
+    ("audit_log", Kind::Text),
+    ("record_dir", Kind::Text),
+    ("record_rotation", Kind::Pairs),
+    ("admin_users", Kind::List),
+    ("metrics_listen", Kind::Text),
+    ("runtime_dir", Kind::Text),
+    ("idle_exit", Kind::Scalar),
+    ("file", Kind::Pairs),
+    ("tail", Kind::Pairs),
+    ("sock", Kind::Pairs),
+    ("tcp", Kind::Pairs),
+    ("fifo", Kind::Pairs),
+    ("cron", Kind::Pairs),
+    ("exec", Kind::Pairs),
+    ("rpc", Kind::Pairs),
+    ("playback", Kind::Pairs),
+    ("kube", Kind::Pairs),
+    ("wasm", Kind::Pairs),
+    ("journal", Kind::Pairs),
+    ("sysstats", Kind::Pairs),
+    ("watch", Kind::Pairs),
+    ("mux", Kind::Pairs),
+    ("session", Kind::Pairs),
+    ("queue", Kind::Pairs),
+    ("git", Kind::Pairs),
+    ("svc", Kind::Pairs),
+    ("bridge", Kind::Pairs),
+];
+
+/// Mode of operation of the backend
+#[derive(Deserialize, Serialize, clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum Mode {
+    /// one process per client
+    #[default]
+    P2p,
+    /// one process per client, run as the subscribed user
+    P2pUser,
+    /// one process shared by all clients
+    Broadcast,
+    /// transfer of the files below a root directory, no process
+    File,
+    /// new lines of a file sent to all clients, no process
+    Tail,
+    /// each client connected to a Unix domain socket, no process
+    Sock,
+    /// each client connected to a TCP destination, no process
+    Tcp,
+    /// a named pipe sent to all clients, their data written to
+    /// another one, no process
+    Fifo,
+    /// one process run on a schedule, its output sent to all
+    /// clients
+    Cron,
+    /// one process per request of a client, fed with the request
+    Exec,
+    /// one process per JSON-RPC call of a client, the command of
+    /// its method
+    Rpc,
+    /// the session recordings played back to each client, no
+    /// process
+    Playback,
+    /// one `kubectl exec` per client, the command being run in a
+    /// container
+    Kube,
+    /// one WebAssembly module run by wasmtime per client, the
+    /// command being the module
+    Wasm,
+    /// the systemd journal followed by one journalctl shared by
+    /// all clients
+    Journal,
+    /// the statistics of the host sampled and sent to all
+    /// clients, no process
+    Sysstats,
+    /// the changes below some paths sent to all clients, no
+    /// process
+    Watch,
+    /// many streams per client, each run by its own process or
+    /// by a process shared by all clients
+    Mux,
+    /// named sessions of the users, whose processes outlive the
+    /// subscriptions attached to them
+    Session,
+    /// jobs of the clients run by a bounded pool of processes
+    Queue,
+    /// one process per git operation of a client, on the
+    /// configured repositories
+    Git,
+    /// one systemctl process per service control of a client,
+    /// on the configured units
+    Svc,
+    /// the data relayed between the topic and a peer topic, no
+    /// process
+    Bridge,
+}
+
+impl Mode {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Mode::P2p => "p2p",
+            Mode::P2pUser => "p2p-user",
+            Mode::Broadcast => "broadcast",
+            Mode::File => "file",
+            Mode::Tail => "tail",
+            Mode::Sock => "sock",
+            Mode::Tcp => "tcp",
+            Mode::Fifo => "fifo",
+            Mode::Cron => "cron",
+            Mode::Exec => "exec",
+            Mode::Rpc => "rpc",
+            Mode::Playback => "playback",
+            Mode::Kube => "kube",
+            Mode::Wasm => "wasm",
+            Mode::Journal => "journal",
+            Mode::Sysstats => "sysstats",
+            Mode::Watch => "watch",
+            Mode::Mux => "mux",
+            Mode::Session => "session",
+            Mode::Queue => "queue",
+            Mode::Git => "git",
+            Mode::Svc => "svc",
+            Mode::Bridge => "bridge",
+        }
+    }
+
+    /// Whether the mode runs the configured command
+    pub fn runs_command(&self) -> bool {
+        matches!(
+            self,
+            Mode::P2p
+                | Mode::P2pUser
+                | Mode::Broadcast
+                | Mode::Cron
+                | Mode::Exec
+                | Mode::Kube
+                | Mode::Wasm
+                | Mode::Session
+                | Mode::Queue
+        )
+    }
+}
+
+/// When the process of a client is spawned (shp2p)
+#[derive(Deserialize, Serialize, clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum SpawnPolicy {
+    /// on the first data of the client
+    #[default]
+    Lazy,
+    /// right after the subscription
+    Eager,
+}
+
+/// What happens to a new subscriber when the channel is full
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum Eviction {
+    /// the subscription is refused
+    #[default]
+    Reject,
+    /// the client idle for the longest time is unsubscribed
+    OldestIdle,
+}
+
+/// Format of the metrics pushed to `statsd_addr`
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum StatsdFormat {
+    /// the topic is part of the metric names
+    #[default]
+    Statsd,
+    /// the topic is a tag of the metrics
+    Dogstatsd,
+}
+
+/// Settings of a session that can be overridden per user
+/// or per group
+#[derive(Deserialize, Serialize, Default, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Profile {
+    /// default command to run
+    pub command: Option<String>,
+    /// environment variables set for the child
+    pub env: HashMap<String, String>,
+    /// resource limits of the child
+    pub rlimits: Rlimits,
+    /// maximum lifetime of the child in seconds
+    pub session_timeout: Option<u64>,
+    /// time windows during which the user can subscribe,
+    /// see [`crate::schedule`]
+    pub access_windows: Vec<String>,
+}
+
+impl Profile {
+    /// Override the settings with the ones set in `other`
+    fn merge(&mut self, other: &Profile) {
+        if other.command.is_some() {
+            self.command = other.command.clone();
+        }
+        self.env.extend(other.env.clone());
+        self.rlimits.merge(&other.rlimits);
+        self.session_timeout = other.session_timeout.or(self.session_timeout);
+        if !other.access_windows.is_empty() {
+            self.access_windows = other.access_windows.clone();
+        }
+    }
+}
+
+/// Settings of the backend, e.g. in TOML:
+///
+/// ```toml
+/// socket = "/var/antd/antd_hotline.sock"
+/// topic = "shell"
+/// command = "/bin/bash"
+/// env_allow = ["LANG"]
+///
+/// [commands]
+/// top = "/usr/bin/top"
+///
+/// [users.admin]
+/// command = "/bin/bash"
+///
+/// [groups.staff.rlimits]
+/// nproc = 64
+/// ```
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// mode of operation
+    pub mode: Mode,
+    /// path to the hub socket
+    pub socket: String,
+    /// name of the topic
+    pub topic: String,
+    /// default command to run
+    pub command: String,
+    /// inline banner sent to each client on subscription
+    pub banner: Option<String>,
+    /// file whose content is sent to each client on subscription,
+    /// can not be used with `banner`
+    pub banner_file: Option<PathBuf>,
+    /// named commands the client can select from, `command`
+    /// is used when the client does not select any
+    pub commands: HashMap<String, String>,
+    /// pattern that each client supplied argument must fully
+    /// match, client arguments are refused when not set
+    pub args_pattern: Option<String>,
+    #[serde(skip)]
+    args_regex: Option<Regex>,
+    /// patterns of the secrets masked in the traced frames and
+    /// the session recordings
+    pub redact: Vec<String>,
+    #[serde(skip)]
+    redact_regexes: Vec<Regex>,
+    /// maximum number of client supplied arguments
+    pub max_args: usize,
+    /// names of the environment variables a client may set
+    pub env_allow: Vec<String>,
+    /// when the process of a client is spawned (shp2p)
+    pub spawn: SpawnPolicy,
+    /// consecutive failures to spawn the process of a client,
+    /// or instant exits of the process, after which the client
+    /// is unsubscribed. The retries are delayed exponentially
+    pub max_spawn_failures: u32,
+    /// consecutive restarts of the shbcast process after which
+    /// it is no longer restarted
+    pub max_restarts: u32,
+    /// unsubscribe the client of shp2p when its process exits,
+    /// instead of spawning a new one on its next data
+    pub unsubscribe_on_exit: bool,
+    /// liveness probe of the processes of shp2p, run with `sh -c`
+    /// and `CPID` set to the pid of the process
+    pub probe: Option<String>,
+    /// seconds between two probes of a process, a probe running
+    /// longer than that has failed
+    pub probe_interval: u64,
+    /// consecutive failed probes after which the process is
+    /// killed
+    pub probe_failures: u32,
+    /// spawn a new process once a hung one is killed
+    pub probe_restart: bool,
+    /// directory under which each shp2p session gets its own
+    /// `TMPDIR`, removed when the session ends
+    pub session_tmpdir: Option<PathBuf>,
+    /// size in bytes of the tmpfs mounted on the `TMPDIR` of
+    /// each session, requires `CAP_SYS_ADMIN`
+    pub session_tmpdir_size: Option<u64>,
+    /// resident memory in bytes of the process of a shp2p session
+    /// and its descendants, beyond which the session is killed
+    pub max_memory: Option<u64>,
+    /// seconds between two reports of the resource usage of its
+    /// process sent to each client, none when not set
+    pub usage_interval: Option<u64>,
+    /// seconds after which a process of shp2p that neither reads
+    /// the pending input of its client nor writes output is
+    /// reported as stalled, never when not set
+    pub stall_timeout: Option<u64>,
+    /// kill a stalled process and spawn a new one
+    pub stall_restart: bool,
+    /// file written to the stdin of each child right after spawn
+    pub init_file: Option<PathBuf>,
+    /// size of the buffer used to read the child output
+    pub buffer_size: usize,
+    /// absolute paths of the executables that can be run,
+    /// any command is allowed when empty
+    pub allowed_commands: Vec<PathBuf>,
+    /// log verbosity: error, warn, info or debug
+    pub log_level: Level,
+    /// where the messages are logged: `syslog`, `stderr` or the
+    /// absolute path of a file
+    pub log_target: String,
+    /// log every frame sent and received, implies the debug
+    /// level
+    pub trace_frames: bool,
+    /// seconds between two reports of the profiler, which is
+    /// off when not set
+    pub profile_interval: Option<u64>,
+    /// rotation of the log file, never rotated by default
+    pub log_rotation: Rotation,
+    /// users whose subscriptions are refused, user names or
+    /// `@group`
+    pub denied_users: Vec<String>,
+    /// Unix groups of which a subscriber must be a member of
+    /// at least one, anyone can subscribe when empty
+    pub allowed_groups: Vec<String>,
+    /// time windows during which the clients can subscribe,
+    /// e.g. `mon-fri 08:00-18:00`, always when empty. The
+    /// sessions are terminated when the windows close
+    pub access_windows: Vec<String>,
+    /// maximum number of simultaneous subscribers, unlimited
+    /// when not set
+    pub max_clients: Option<usize>,
+    /// what happens to a new subscriber once `max_clients` is
+    /// reached
+    pub eviction: Eviction,
+    /// maximum number of simultaneous sessions of a same user
+    /// (shp2p), unlimited when not set
+    pub max_sessions_per_user: Option<usize>,
+    /// size in bytes of the output kept by shbcast for the
+    /// new subscribers, nothing is kept when not set
+    pub replay_bytes: Option<usize>,
+    /// same as `replay_bytes`, in lines
+    pub replay_lines: Option<usize>,
+    /// directory of the spool of shbcast, where its output is
+    /// queued when the tunnel is slower than the process, no
+    /// spool when not set
+    pub spool_dir: Option<PathBuf>,
+    /// maximum size of the spool in bytes, the oldest output
+    /// is dropped beyond
+    pub spool_size: usize,
+    /// subscribers allowed to write to the input of the shbcast
+    /// process, user names or `@group`, everyone when empty
+    pub writers: Vec<String>,
+    /// environment variables set for the children
+    pub env: HashMap<String, String>,
+    /// resource limits of the children
+    pub rlimits: Rlimits,
+    /// maximum lifetime of the children in seconds
+    pub session_timeout: Option<u64>,
+    /// seconds during which the process of an unsubscribed
+    /// client is kept for its user to subscribe again (shp2p)
+    pub reconnect_grace: Option<u64>,
+    /// maximum size of the output kept during the reconnect
+    /// grace period, the oldest output is dropped
+    pub reconnect_buffer: usize,
+    /// file where shp2p saves its sessions, for a new instance
+    /// of the backend to take them over
+    pub state_file: Option<PathBuf>,
+    /// seconds without any message after which a client is
+    /// unsubscribed, never when not set
+    pub idle_timeout: Option<u64>,
+    /// append-only file where the processes are recorded once
+    /// exited, see `shbackend history`
+    pub history_file: Option<PathBuf>,
+    /// programs run on the events of the sessions
+    pub hooks: Hooks,
+    /// StatsD server receiving the metrics, e.g. `127.0.0.1:8125`,
+    /// none when not set
+    pub statsd_addr: Option<String>,
+    pub statsd_format: StatsdFormat,
+    /// seconds between two pushes of the metrics to StatsD
+    pub statsd_interval: u64,
+    /// OTLP/HTTP collector receiving the spans of the sessions,
+    /// e.g. `http://localhost:4318`, none when not set
+    pub otlp_endpoint: Option<String>,
+    /// HTTP endpoint receiving the events of the sessions, none
+    /// when not set
+    pub webhook_url: Option<String>,
+    /// events posted to `webhook_url`, all when empty
+    pub webhook_events: Vec<String>,
+    /// Unix socket answering health checks, none when not set
+    pub health_socket: Option<PathBuf>,
+    /// Unix socket accepting the admin commands, e.g. from
+    /// `shctl`, none when not set
+    pub admin_socket: Option<PathBuf>,
+    /// file of the audit log of the sessions, or `syslog` for
+    /// the authpriv facility, none when not set
+    pub audit_log: Option<String>,
+    /// directory of the recordings of the processes, in the
+    /// asciicast v2 format, none when not set
+    pub record_dir: Option<PathBuf>,
+    /// rotation of the recordings, which are never rotated nor
+    /// removed by default
+    pub record_rotation: Rotation,
+    /// users allowed on the admin topic `<topic>.admin`, which
+    /// is only opened when set
+    pub admin_users: Vec<String>,
+    /// address of the HTTP listener of the Prometheus metrics,
+    /// e.g. `127.0.0.1:9464`, none when not set
+    pub metrics_listen: Option<String>,
+    /// directory of the pidfiles of the processes, those left by
+    /// a crashed instance are killed on startup
+    pub runtime_dir: Option<PathBuf>,
+    /// seconds without any client after which the backend exits,
+    /// e.g. when it is started on demand by socket activation
+    pub idle_exit: Option<u64>,
+    /// settings of the file transfer mode, see
+    /// [`crate::transfer`]
+    pub file: FileSettings,
+    /// settings of the file following mode, see [`crate::tail`]
+    pub tail: TailSettings,
+    /// settings of the socket proxy mode, see [`crate::proxy`]
+    pub sock: SockSettings,
+    /// settings of the TCP proxy mode, see [`crate::proxy`]
+    pub tcp: TcpSettings,
+    /// settings of the named pipe mode, see [`crate::fifo`]
+    pub fifo: FifoSettings,
+    /// settings of the scheduled command mode, see [`crate::cron`]
+    pub cron: CronSettings,
+    /// settings of the request/response mode, see [`crate::exec`]
+    pub exec: ExecSettings,
+    /// methods of the JSON-RPC mode, see [`crate::rpc`]
+    pub rpc: RpcSettings,
+    /// settings of the playback mode, see [`crate::playback`]
+    pub playback: PlaybackSettings,
+    /// settings of the Kubernetes exec mode, see [`crate::kube`]
+    pub kube: KubeSettings,
+    /// settings of the WASI sandbox mode, see [`crate::wasm`]
+    pub wasm: WasmSettings,
+    /// settings of the journal follow mode, see [`crate::journal`]
+    pub journal: JournalSettings,
+    /// settings of the system statistics mode, see
+    /// [`crate::sysstats`]
+    pub sysstats: SysStatsSettings,
+    /// settings of the filesystem watch mode, see [`crate::watch`]
+    pub watch: WatchSettings,
+    /// streams of the multiplexing mode, see [`crate::mux`]
+    pub mux: MuxSettings,
+    /// settings of the persistent session mode, see
+    /// [`crate::session`]
+    pub session: SessionSettings,
+    /// settings of the job queue mode, see [`crate::queue`]
+    pub queue: QueueSettings,
+    /// repositories of the git mode, see [`crate::git`]
+    pub git: GitSettings,
+    /// units of the service control mode, see [`crate::svc`]
+    pub svc: SvcSettings,
+    /// peer topic of the bridge mode, see [`crate::bridge`]
+    pub bridge: BridgeSettings,
+    /// per user overrides of the session settings
+    pub users: HashMap<String, Profile>,
+    /// per group overrides of the session settings, the user
+    /// overrides take precedence over the group ones
+    pub groups: HashMap<String, Profile>,
+    /// patterns of the files merged into a configuration file,
+    /// only declared here so that the key is known when checking
+    /// a file, see [`read_file`]
+    #[serde(skip_serializing)]
+    include: Option<Value>,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            mode: Mode::P2p,
+            socket: String::new(),
+            topic: String::new(),
+            command: String::new(),
+            banner: None,
+            banner_file: None,
+            commands: HashMap::new(),
+            args_pattern: None,
+            args_regex: None,
+            redact: Vec::new(),
+            redact_regexes: Vec::new(),
+            max_args: 8,
+            env_allow: Vec::new(),
+            spawn: SpawnPolicy::Lazy,
+            max_spawn_failures: 5,
+            max_restarts: 5,
+            unsubscribe_on_exit: false,
+            probe: None,
+            probe_interval: 30,
+            probe_failures: 3,
+            probe_restart: false,
+            session_tmpdir: None,
+            session_tmpdir_size: None,
+            max_memory: None,
+            usage_interval: None,
+            stall_timeout: None,
+            stall_restart: false,
+            init_file: None,
+            buffer_size: 2048,
+            allowed_commands: Vec::new(),
+            log_level: Level::Warn,
+            log_target: String::from(log::SYSLOG),
+            trace_frames: false,
+            profile_interval: None,
+            log_rotation: Rotation::default(),
+            denied_users: Vec::new(),
+            allowed_groups: Vec::new(),
+            access_windows: Vec::new(),
+            max_clients: None,
+            eviction: Eviction::Reject,
+            max_sessions_per_user: None,
+            replay_bytes: None,
+            replay_lines: None,
+            spool_dir: None,
+            spool_size: 16 * 1024 * 1024,
+            writers: Vec::new(),
+            env: HashMap::new(),
+            rlimits: Rlimits::default(),
+            session_timeout: None,
+            reconnect_grace: None,
+            reconnect_buffer: 65536,
+            state_file: None,
+            runtime_dir: None,
+            history_file: None,
+            metrics_listen: None,
+            admin_users: Vec::new(),
+            record_dir: None,
+            record_rotation: Rotation::default(),
+            audit_log: None,
+            health_socket: None,
+            admin_socket: None,
+            otlp_endpoint: None,
+            webhook_url: None,
+            webhook_events: Vec::new(),
+            statsd_addr: None,
+            hooks: Hooks::default(),
+            statsd_format: StatsdFormat::Statsd,
+            statsd_interval: 10,
+            idle_timeout: None,
+            idle_exit: None,
+            file: FileSettings::default(),
+            tail: TailSettings::default(),
+            sock: SockSettings::default(),
+            tcp: TcpSettings::default(),
+            fifo: FifoSettings::default(),
+            cron: CronSettings::default(),
+            exec: ExecSettings::default(),
+            rpc: RpcSettings::default(),
+            playback: PlaybackSettings::default(),
+            kube: KubeSettings::default(),
+            wasm: WasmSettings::default(),
+            journal: JournalSettings::default(),
+            sysstats: SysStatsSettings::default(),
+            watch: WatchSettings::default(),
+            mux: MuxSettings::default(),
+            session: SessionSettings::default(),
+            queue: QueueSettings::default(),
+            git: GitSettings::default(),
+            svc: SvcSettings::default(),
+            bridge: BridgeSettings::default(),
+            users: HashMap::new(),
+            groups: HashMap::new(),
+            include: None,
+        }
+    }
+}
+
+impl Config {
+    /// Build the configuration from the configuration file,
+    /// the command line and the environment
+    pub fn load(cli: &Cli) -> Result<Config, Box<dyn std::error::Error>> {
+        let mut table = match cli.config.as_ref() {
+            Some(path) => read_file(path, 0)?,
+            None => Table::new(),
+        };
+        table.extend(cli.overrides());
+        table.extend(env_overrides()?);
+        let mut config: Config = Value::Table(table).try_into().map_err(|e| {
+            format!(
+                "Invalid setting on the command line or in the {}* environment: {}",
+                ENV_PREFIX,
+                e.to_string().trim_end()
+            )
+        })?;
+        config.validate()?;
+        if config.trace_frames {
+            log::set_level(Level::Debug);
+        } else if config.profile_interval.is_some() {
+            log::set_level(config.log_level.max(Level::Info));
+        } else {
+            log::set_level(config.log_level);
+        }
+        frame::set_trace(config.trace_frames);
+        redact::set_patterns(config.redact_regexes.clone());
+        log::set_target(&config.log_target, &config.log_rotation)
+            .map_err(|e| format!("Unable to open the log file {}: {}", config.log_target, e))?;
+        Ok(config)
+    }
+
+    /// Check the mandatory settings and the conflicting ones, and
+    /// prepare the derived ones. All the problems are reported at
+    /// once, one per line
+    fn validate(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let mut errors = Vec::new();
+        for (key, value) in [
+            ("socket", &self.socket),
+            ("topic", &self.topic),
+            ("command", &self.command),
+            ("file.root", &self.file.root),
+        ] {
+            let required = match key {
+                "command" => self.mode.runs_command(),
+                "file.root" => self.mode == Mode::File,
+                _ => true,
+            };
+            if required && value.is_empty() {
+                errors.push(format!("{}: missing value", key));
+            }
+        }
+        if self.mode != Mode::File && self.file != FileSettings::default() {
+            errors.push(String::from("file: only supported in file mode"));
+        }
+        match self.tail.path.as_ref() {
+            None if self.mode == Mode::Tail => {
+                errors.push(String::from("tail.path: missing value"))
+            }
+            Some(_) if self.mode != Mode::Tail => {
+                errors.push(String::from("tail: only supported in tail mode"))
+            }
+            Some(path) if !path.is_absolute() || path.file_name().is_none() => errors.push(
+                format!("tail.path: {} is not an absolute file path", path.display()),
+            ),
+            _ => {}
+        }
+        match self.sock.path.as_ref() {
+            None if self.mode == Mode::Sock => {
+                errors.push(String::from("sock.path: missing value"))
+            }
+            Some(_) if self.mode != Mode::Sock => {
+                errors.push(String::from("sock: only supported in sock mode"))
+            }
+            Some(path) if !path.is_absolute() => errors.push(format!(
+                "sock.path: {} is not an absolute path",
+                path.display()
+            )),
+            _ => {}
+        }
+        if !matches!(self.mode, Mode::Exec | Mode::Rpc | Mode::Git | Mode::Svc)
+            && self.exec != ExecSettings::default()
+        {
+            errors.push(String::from(
+                "exec: only supported in exec, rpc, git and svc modes",
+            ));
+        }
+        if self.mode != Mode::Svc && self.svc != SvcSettings::default() {
+            errors.push(String::from("svc: only supported in svc mode"));
+        }
+        if self.mode == Mode::Svc && self.svc.units.is_empty() {
+            errors.push(String::from("svc.units: missing value"));
+        }
+        if !Path::new(self.svc.command()).is_absolute() {
+            errors.push(format!(
+                "svc.command: {:?} is not an absolute path",
+                self.svc.command()
+            ));
+        }
+        // the units are given to systemctl by their full name
+        for unit in self
+            .svc
+            .units
+            .iter()
+            .filter(|u| !u.contains('.') || u.contains('/'))
+        {
+            errors.push(format!("svc.units: {:?} is not the name of a unit", unit));
+        }
+        if self.mode != Mode::Git && self.git != GitSettings::default() {
+            errors.push(String::from("git: only supported in git mode"));
+        }
+        if self.mode == Mode::Git && self.git.repos.is_empty() {
+            errors.push(String::from("git.repos: missing value"));
+        }
+        if !Path::new(self.git.command()).is_absolute() {
+            errors.push(format!(
+                "git.command: {:?} is not an absolute path",
+                self.git.command()
+            ));
+        }
+        for (name, repo) in self.git.repos.iter().filter(|(_, r)| !r.path.is_absolute()) {
+            errors.push(format!(
+                "git.repos.{}.path: {} is not an absolute path",
+                name,
+                repo.path.display()
+            ));
+        }
+        if self.mode != Mode::Rpc && self.rpc != RpcSettings::default() {
+            errors.push(String::from("rpc: only supported in rpc mode"));
+        }
+        if self.mode == Mode::Rpc && self.rpc.methods.is_empty() {
+            errors.push(String::from("rpc.methods: missing value"));
+        }
+        if self.mode != Mode::Playback && self.playback != PlaybackSettings::default() {
+            errors.push(String::from("playback: only supported in playback mode"));
+        }
+        if self.mode == Mode::Playback && self.playback.dir(self).is_none() {
+            errors.push(String::from(
+                "playback.dir, record_dir: one of them must be set",
+            ));
+        }
+        if let Some(dir) = self.playback.dir.as_ref().filter(|d| !d.is_absolute()) {
+            errors.push(format!(
+                "playback.dir: {} is not an absolute path",
+                dir.display()
+            ));
+        }
+        for (user, _) in self.file.upload_limits.iter().filter(|(_, s)| **s == 0) {
+            errors.push(format!("file.upload_limits.{}: must be positive", user));
+        }
+        if self.playback.max_idle.is_some_and(|s| s <= 0.0) {
+            errors.push(String::from("playback.max_idle: must be positive"));
+        }
+        if self.mode != Mode::Kube && self.kube != KubeSettings::default() {
+            errors.push(String::from("kube: only supported in kube mode"));
+        }
+        match (self.kube.pod.as_ref(), self.kube.selector.as_ref()) {
+            (Some(_), Some(_)) => errors.push(String::from(
+                "kube.pod, kube.selector: only one of them can be set",
+            )),
+            (None, None) if self.mode == Mode::Kube => errors.push(String::from(
+                "kube.pod, kube.selector: one of them must be set",
+            )),
+            _ => {}
+        }
+        if let Some(path) = self.kube.kubeconfig.as_ref().filter(|p| !p.is_absolute()) {
+            errors.push(format!(
+                "kube.kubeconfig: {} is not an absolute path",
+                path.display()
+            ));
+        }
+        if self.mode != Mode::Wasm && self.wasm != WasmSettings::default() {
+            errors.push(String::from("wasm: only supported in wasm mode"));
+        }
+        for dir in self.wasm.dirs.iter() {
+            let host = dir.split_once("::").map_or(dir.as_str(), |(host, _)| host);
+            if !Path::new(host).is_absolute() {
+                errors.push(format!("wasm.dirs: {} is not an absolute path", host));
+            }
+        }
+        if self.mode != Mode::Journal && self.journal != JournalSettings::default() {
+            errors.push(String::from("journal: only supported in journal mode"));
+        }
+        if let Err(e) = self
+            .journal
+            .priority
+            .as_deref()
+            .map_or(Ok(()), journal::check_priority)
+        {
+            errors.push(format!("journal.priority: {}", e));
+        }
+        if let Err(e) = self
+            .journal
+            .output
+            .as_deref()
+            .map_or(Ok(()), journal::check_output)
+        {
+            errors.push(format!("journal.output: {}", e));
+        }
+        if self.mode != Mode::Sysstats && self.sysstats != SysStatsSettings::default() {
+            errors.push(String::from("sysstats: only supported in sysstats mode"));
+        }
+        for path in self.sysstats.disks.iter().filter(|p| !p.is_absolute()) {
+            errors.push(format!(
+                "sysstats.disks: {} is not an absolute path",
+                path.display()
+            ));
+        }
+        if self.mode == Mode::Watch && self.watch.paths.is_empty() {
+            errors.push(String::from("watch.paths: missing value"));
+        }
+        if self.mode != Mode::Watch && self.watch != WatchSettings::default() {
+            errors.push(String::from("watch: only supported in watch mode"));
+        }
+        for path in self.watch.paths.iter().filter(|p| !p.is_absolute()) {
+            errors.push(format!(
+                "watch.paths: {} is not an absolute path",
+                path.display()
+            ));
+        }
+        if self.mode != Mode::Session && self.session != SessionSettings::default() {
+            errors.push(String::from("session: only supported in session mode"));
+        }
+        if self.mode != Mode::Queue && self.queue != QueueSettings::default() {
+            errors.push(String::from("queue: only supported in queue mode"));
+        }
+        if self.mode != Mode::Mux && self.mux != MuxSettings::default() {
+            errors.push(String::from("mux: only supported in mux mode"));
+        }
+        if self.mode == Mode::Mux && self.mux.streams.is_empty() {
+            errors.push(String::from("mux.streams: missing value"));
+        }
+        for (name, stream) in self.mux.streams.iter() {
+            if !Path::new(&stream.command).is_absolute() {
+                errors.push(format!(
+                    "mux.streams.{}.command: {:?} is not an absolute path",
+                    name, stream.command
+                ));
+            }
+        }
+        for (name, method) in self.rpc.methods.iter() {
+            if !Path::new(&method.command).is_absolute() {
+                errors.push(format!(
+                    "rpc.methods.{}.command: {:?} is not an absolute path",
+                    name, method.command
+                ));
+            }
+        }
+        if self.mode != Mode::Bridge && self.bridge != BridgeSettings::default() {
+            errors.push(String::from("bridge: only supported in bridge mode"));
+        }
+        if self.mode == Mode::Bridge {
+            match self.bridge.topic.as_ref() {
+                None => errors.push(String::from("bridge.topic: missing value")),
+                Some(topic)
+                    if *topic == self.topic
+                        && self
+                            .bridge
+                            .socket
+                            .as_ref()
+                            .is_none_or(|s| *s == self.socket) =>
+                {
+                    errors.push(String::from("bridge.topic: the topic of the backend"))
+                }
+                Some(_) => {}
+            }
+        }
+        if self.mode != Mode::Cron && self.cron != CronSettings::default() {
+            errors.push(String::from("cron: only supported in cron mode"));
+        }
+        match (self.cron.schedule.as_ref(), self.cron.interval) {
+            (Some(_), Some(_)) => errors.push(String::from(
+                "cron.schedule, cron.interval: only one of them can be set",
+            )),
+            (None, None) if self.mode == Mode::Cron => errors.push(String::from(
+                "cron.schedule, cron.interval: one of them must be set",
+            )),
+            (Some(text), None) => {
+                if let Err(e) = schedule::Cron::parse(text) {
+                    errors.push(format!("cron.schedule: {}", e));
+                }
+            }
+            _ => {}
+        }
+        if self.mode != Mode::Fifo && self.fifo != FifoSettings::default() {
+            errors.push(String::from("fifo: only supported in fifo mode"));
+        }
+        if self.mode == Mode::Fifo && self.fifo.output.is_none() {
+            errors.push(String::from("fifo.output: missing value"));
+        }
+        for (key, path) in [
+            ("fifo.output", self.fifo.output.as_ref()),
+            ("fifo.input", self.fifo.input.as_ref()),
+        ] {
+            if let Some(path) = path.filter(|p| !p.is_absolute()) {
+                errors.push(format!(
+                    "{}: {} is not an absolute path",
+                    key,
+                    path.display()
+                ));
+            }
+        }
+        if self.mode != Mode::Tcp && self.tcp != TcpSettings::default() {
+            errors.push(String::from("tcp: only supported in tcp mode"));
+        }
+        if self.mode == Mode::Tcp && self.tcp.address.is_none() && self.tcp.allow.is_empty() {
+            errors.push(String::from(
+                "tcp.address, tcp.allow: one of them must be set",
+            ));
+        }
+        for (key, destination) in self
+            .tcp
+            .address
+            .iter()
+            .map(|a| ("tcp.address", a))
+            .chain(self.tcp.allow.iter().map(|a| ("tcp.allow", a)))
+        {
+            let port = destination.rsplit_once(':').map(|(_, port)| port);
+            let valid = match port {
+                Some("*") => key == "tcp.allow",
+                Some(port) => port.parse::<u16>().is_ok(),
+                None => false,
+            };
+            if !valid {
+                errors.push(format!("{}: {} is not a host:port", key, destination));
+            }
+        }
+        for (event, path) in self.hooks.all() {
+            if !path.is_absolute() {
+                errors.push(format!(
+                    "hooks.{}: {} is not an absolute path",
+                    event,
+                    path.display()
+                ));
+            }
+        }
+        for path in self.allowed_commands.iter() {
+            if !path.is_absolute() {
+                errors.push(format!(
+                    "allowed_commands: {} is not an absolute path",
+                    path.display()
+                ));
+            }
+        }
+        if self.banner.is_some() && self.banner_file.is_some() {
+            errors.push(String::from(
+                "banner, banner_file: only one of them can be set",
+            ));
+        }
+        if self.session_tmpdir_size.is_some() && self.session_tmpdir.is_none() {
+            errors.push(String::from("session_tmpdir_size: requires session_tmpdir"));
+        }
+        if let Some(target) = self.audit_log.as_ref() {
+            if target != audit::SYSLOG && !Path::new(target).is_absolute() {
+                errors.push(format!(
+                    "audit_log: {} is neither an absolute path nor {}",
+                    target,
+                    audit::SYSLOG
+                ));
+            }
+        }
+        if let Some(endpoint) = self.otlp_endpoint.as_ref() {
+            if let Err(e) = Endpoint::parse(endpoint, telemetry::TRACES_PATH) {
+                errors.push(format!("otlp_endpoint: {}: {}", endpoint, e));
+            }
+        }
+        if self.log_target != log::SYSLOG
+            && self.log_target != log::STDERR
+            && !Path::new(&self.log_target).is_absolute()
+        {
+            errors.push(format!(
+                "log_target: {} is neither syslog, stderr nor an absolute path",
+                self.log_target
+            ));
+        }
+        if let Some(url) = self.webhook_url.as_ref() {
+            if let Err(e) = Endpoint::parse(url, "/") {
+                errors.push(format!("webhook_url: {}: {}", url, e));
+            }
+        }
+        for event in self.webhook_events.iter() {
+            if !audit::EVENTS.contains(&event.as_str()) {
+                errors.push(format!("webhook_events: unknown event {}", event));
+            }
+        }
+        if let Some(addr) = self.metrics_listen.as_ref() {
+            if let Err(e) = addr.parse::<SocketAddr>() {
+                errors.push(format!("metrics_listen: {}: {}", addr, e));
+            }
+        }
+        if self.replay_bytes.is_some() && self.replay_lines.is_some() {
+            errors.push(String::from(
+                "replay_bytes, replay_lines: only one of them can be set",
+            ));
+        }
+        if !matches!(
+            self.mode,
+            Mode::P2p | Mode::P2pUser | Mode::Kube | Mode::Wasm
+        ) {
+            // the sessions of the session mode and the jobs of the
+            // queue mode are run as those of the p2p mode, from the
+            // menu and the profiles
+            let menu = !matches!(self.mode, Mode::Session | Mode::Queue);
+            for (key, set) in [
+                ("commands", menu && !self.commands.is_empty()),
+                ("users", menu && !self.users.is_empty()),
+                ("groups", menu && !self.groups.is_empty()),
+                ("session_timeout", self.session_timeout.is_some()),
+                ("reconnect_grace", self.reconnect_grace.is_some()),
+                ("state_file", self.state_file.is_some()),
+                ("unsubscribe_on_exit", self.unsubscribe_on_exit),
+                ("probe", self.probe.is_some()),
+                ("stall_timeout", self.stall_timeout.is_some()),
+                ("max_memory", self.max_memory.is_some()),
+                ("session_tmpdir", self.session_tmpdir.is_some()),
+                (
+                    "max_sessions_per_user",
+                    self.max_sessions_per_user.is_some(),
+                ),
+            ] {
+                if set {
+                    errors.push(format!(
+                        "{}: not supported in {} mode",
+                        key,
+                        self.mode.name()
+                    ));
+                }
+            }
+        }
+        if !matches!(
+            self.mode,
+            Mode::Broadcast | Mode::Tail | Mode::Fifo | Mode::Journal
+        ) {
+            for (key, set) in [
+                ("replay_bytes", self.replay_bytes.is_some()),
+                ("replay_lines", self.replay_lines.is_some()),
+            ] {
+                if set {
+                    errors.push(format!(
+                        "{}: not supported in {} mode",
+                        key,
+                        self.mode.name()
+                    ));
+                }
+            }
+        }
+        if !matches!(self.mode, Mode::Broadcast | Mode::Fifo) && !self.writers.is_empty() {
+            errors.push(format!(
+                "writers: not supported in {} mode",
+                self.mode.name()
+            ));
+        }
+        if !matches!(self.mode, Mode::Broadcast | Mode::Journal) {
+            for (key, set) in [("spool_dir", self.spool_dir.is_some())] {
+                if set {
+                    errors.push(format!(
+                        "{}: only supported in broadcast and journal modes",
+                        key
+                    ));
+                }
+            }
+        }
+        for (key, value) in [
+            ("buffer_size", Some(self.buffer_size)),
+            ("probe_interval", Some(self.probe_interval as usize)),
+            ("stall_timeout", self.stall_timeout.map(|s| s as usize)),
+            ("idle_timeout", self.idle_timeout.map(|s| s as usize)),
+            ("max_memory", self.max_memory.map(|s| s as usize)),
+            ("usage_interval", self.usage_interval.map(|s| s as usize)),
+            (
+                "profile_interval",
+                self.profile_interval.map(|s| s as usize),
+            ),
+            (
+                "session_tmpdir_size",
+                self.session_tmpdir_size.map(|s| s as usize),
+            ),
+            ("max_clients", self.max_clients),
+            ("max_sessions_per_user", self.max_sessions_per_user),
+            ("replay_bytes", self.replay_bytes),
+            ("replay_lines", self.replay_lines),
+            ("spool_size", Some(self.spool_size)),
+            ("file.chunk_size", self.file.chunk_size),
+            ("cron.interval", self.cron.interval.map(|s| s as usize)),
+            ("cron.timeout", self.cron.timeout.map(|s| s as usize)),
+            ("exec.timeout", self.exec.timeout.map(|s| s as usize)),
+            ("exec.max_output", self.exec.max_output),
+            ("mux.max_streams", self.mux.max_streams),
+            ("session.max_sessions", self.session.max_sessions),
+            ("session.buffer", self.session.buffer),
+            ("queue.workers", self.queue.workers),
+            ("queue.max_jobs", self.queue.max_jobs),
+            ("queue.timeout", self.queue.timeout.map(|s| s as usize)),
+            ("git.max_log", self.git.max_log),
+            (
+                "wasm.max_memory_size",
+                self.wasm.max_memory_size.map(|s| s as usize),
+            ),
+            ("wasm.fuel", self.wasm.fuel.map(|s| s as usize)),
+            (
+                "sysstats.interval",
+                self.sysstats.interval.map(|s| s as usize),
+            ),
+            (
+                "file.max_file_size",
+                self.file.max_file_size.map(|s| s as usize),
+            ),
+            (
+                "file.max_upload_size",
+                self.file.max_upload_size.map(|s| s as usize),
+            ),
+            ("statsd_interval", Some(self.statsd_interval as usize)),
+            (
+                "log_rotation.max_size",
+                self.log_rotation.max_size.map(|s| s as usize),
+            ),
+            (
+                "log_rotation.interval",
+                self.log_rotation.interval.map(|s| s as usize),
+            ),
+            (
+                "record_rotation.max_size",
+                self.record_rotation.max_size.map(|s| s as usize),
+            ),
+            (
+                "record_rotation.interval",
+                self.record_rotation.interval.map(|s| s as usize),
+            ),
+            // the current recording is one of them
+            ("record_rotation.keep", self.record_rotation.keep),
+        ] {
+            if value == Some(0) {
+                errors.push(format!("{}: must be positive", key));
+            }
+        }
+        let sections = self
+            .users
+            .iter()
+            .map(|(name, p)| (format!("users.{}.access_windows", name), p))
+            .chain(
+                self.groups
+                    .iter()
+                    .map(|(name, p)| (format!("groups.{}.access_windows", name), p)),
+            );
+        for (key, windows) in
+            std::iter::once((String::from("access_windows"), &self.access_windows))
+                .chain(sections.map(|(key, p)| (key, &p.access_windows)))
+        {
+            if let Err(e) = schedule::parse_all(windows) {
+                errors.push(format!("{}: {}", key, e));
+            }
+        }
+        self.args_regex = match self.args_pattern.as_ref() {
+            Some(pattern) => match Regex::new(&format!("^(?:{})$", pattern)) {
+                Ok(regex) => Some(regex),
+                Err(e) => {
+                    errors.push(format!("args_pattern: {}", e));
+                    None
+                }
+            },
+            None => None,
+        };
+        self.redact_regexes = Vec::new();
+        for pattern in self.redact.iter() {
+            match Regex::new(pattern) {
+                Ok(regex) => self.redact_regexes.push(regex),
+                Err(e) => errors.push(format!("redact: {}", e)),
+            }
+        }
+        if !errors.is_empty() {
+            return Err(format!("Invalid configuration:\n{}", errors.join("\n")).into());
+        }
+        Ok(())
+    }
+
+    /// Re-read the configuration, the changes apply to the
+    /// sessions created afterward. The socket and the topic
+    /// can not be changed while running
+    pub fn reload(&mut self, cli: &Cli) -> Result<(), Box<dyn std::error::Error>> {
+        let mut config = Config::load(cli)?;
+        if config.socket != self.socket || config.topic != self.topic {
+            WARN!("Socket and topic changes are ignored until restart");
+        }
+        config.socket = std::mem::take(&mut self.socket);
+        config.topic = std::mem::take(&mut self.topic);
+        *self = config;
+        INFO!("Configuration of topic {} reloaded", self.topic);
+        Ok(())
+    }
+
+    /// Whether the subscriptions of a user are refused
+    pub fn is_denied(&self, user: &str) -> bool {
+        !self.denied_users.is_empty() && account::matches(user, &self.denied_users)
+    }
+
+    /// Whether a user belongs to one of the allowed groups
+    pub fn is_authorized(&self, user: &str) -> bool {
+        self.allowed_groups.is_empty()
+            || account::groups_of(user)
+                .iter()
+                .any(|group| self.allowed_groups.contains(group))
+    }
+
+    /// Whether a new subscriber can be accepted given the
+    /// number of current ones
+    pub fn has_room(&self, clients: usize) -> bool {
+        self.max_clients.is_none_or(|max| clients < max)
+    }
+
+    /// Whether a subscriber may write to the input of the
+    /// broadcast process, to the input pipe of shfifo, or to the
+    /// other topic of shbridge
+    pub fn can_write(&self, user: &str) -> bool {
+        self.writers.is_empty() || account::matches(user, &self.writers)
+    }
+
+    /// Check the arguments supplied by a client against the policy
+    pub fn check_args(&self, args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+        if args.is_empty() {
+            return Ok(());
+        }
+        let pattern = self
+            .args_regex
+            .as_ref()
+            .ok_or("Client arguments are not allowed")?;
+        if args.len() > self.max_args {
+            return Err(format!("Too many arguments, maximum is {}", self.max_args).into());
+        }
+        for arg in args {
+            if !pattern.is_match(arg) {
+                return Err(format!("Argument refused by policy: {}", arg).into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Content of the init script, if any
+    pub fn init_script(&self) -> Result<Option<Vec<u8>>, Box<dyn std::error::Error>> {
+        match self.init_file.as_ref() {
+            Some(path) => Ok(Some(fs::read(path)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Keep only the client supplied environment variables
+    /// that are in the allowlist
+    pub fn allowed_env(&self, envs: &HashMap<String, String>) -> Vec<(String, String)> {
+        envs.iter()
+            .filter(|(name, _)| self.env_allow.contains(name))
+            .map(|(name, value)| (name.clone(), value.clone()))
+            .collect()
+    }
+
+    /// Session settings of a user: the topic defaults, overridden
+    /// by the sections of its groups then by its own section
+    pub fn profile(&self, user: &str) -> Profile {
+        let mut profile = Profile {
+            command: Some(self.command.clone()),
+            env: self.env.clone(),
+            rlimits: self.rlimits.clone(),
+            session_timeout: self.session_timeout,
+            access_windows: self.access_windows.clone(),
+        };
+        if !self.groups.is_empty() {
+            let mut groups = account::groups_of(user);
+            groups.sort();
+            for group in groups {
+                if let Some(section) = self.groups.get(&group) {
+                    profile.merge(section);
+                }
+            }
+        }
+        if let Some(section) = self.users.get(user) {
+            profile.merge(section);
+        }
+        profile
+    }
+
+    /// Find the command to run, `name` is the command selected
+    /// by the client from the menu
+    pub fn command_for<'a>(
+        &'a self,
+        profile: &'a Profile,
+        name: Option<&str>,
+    ) -> Result<&'a str, Box<dyn std::error::Error>> {
+        match name {
+            None => Ok(profile.command.as_deref().unwrap_or(&self.command)),
+            Some(name) => Ok(self
+                .commands
+                .get(name)
+                .ok_or(format!("Unknown command: {}", name))?),
+        }
+    }
+
+    /// Check a command against the allowlist, through the path of
+    /// its executable as found, the links not being followed: a
+    /// busybox applet is allowed by its own name only. The command
+    /// is returned as given
+    pub fn allowed_command(&self, cmd: &str) -> Result<PathBuf, Box<dyn std::error::Error>> {
+        let path = spawn::locate(cmd)?;
+        if self.allowed_commands.is_empty()
+            || self.allowed_commands.iter().any(|p| same_file(p, &path))
+        {
+            return Ok(PathBuf::from(cmd));
+        }
+        Err(format!("Command is not allowed: {}", path.display()).into())
+    }
+
+    /// Content of the banner, if any. The banner file is read
+    /// on each call so that it can be edited while running
+    pub fn banner(&self) -> Result<Option<Vec<u8>>, Box<dyn std::error::Error>> {
+        if let Some(path) = self.banner_file.as_ref() {
+            return Ok(Some(fs::read(path)?));
+        }
+        Ok(self.banner.as_ref().map(|s| s.as_bytes().to_vec()))
+    }
+}
+
+/// Whether an allowlist entry designates the resolved executable
+fn same_file(entry: &Path, path: &Path) -> bool {
+    entry == path || spawn::real_dir(entry).is_ok_and(|p| p == path)
+}
+
+/// Read a configuration file, with its `${VAR}` references
+/// expanded and its `include` patterns (relative to its
+/// directory) merged. The file takes precedence over the
+/// files it includes
+fn read_file(path: &Path, depth: usize) -> Result<Table, Box<dyn std::error::Error>> {
+    if depth > MAX_INCLUDE_DEPTH {
+        return Err(format!("Too many nested includes at {}", path.display()).into());
+    }
+    let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    // deserialize the text itself first: unlike the merged table,
+    // it keeps the position of the unknown keys and of the values
+    // of the wrong type in the errors
+    toml::from_str::<Config>(&text).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let mut table = text
+        .parse::<Table>()
+        .map_err(|e| format!("{}: {}", path.display(), e))?;
+    for (_, value) in table.iter_mut() {
+        interpolate(value).map_err(|e| format!("{}: {}", path.display(), e))?;
+    }
+    let patterns = match table.remove("include") {
+        None => Vec::new(),
+        Some(Value::String(pattern)) => vec![pattern],
+        Some(Value::Array(patterns)) => patterns
+            .into_iter()
+            .map(|v| match v {
+                Value::String(pattern) => Ok(pattern),
+                _ => Err(format!(
+                    "{}: include must be a list of strings",
+                    path.display()
+                )),
+            })
+            .collect::<Result<Vec<String>, String>>()?,
+        Some(_) => {
+            return Err(format!("{}: include must be a list of strings", path.display()).into())
+        }
+    };
+    let dir = path.parent().unwrap_or_else(|| Path::new("."));
+    let mut merged = Table::new();
+    for pattern in patterns {
+        let pattern = dir.join(pattern);
+        let mut paths = glob::glob(&pattern.to_string_lossy())?.collect::<Result<Vec<_>, _>>()?;
+        paths.sort();
+        for included in paths {
+            merge(&mut merged, read_file(&included, depth + 1)?);
+        }
+    }
+    merge(&mut merged, table);
+    Ok(merged)
+}
+
+/// Deep merge of two tables, the values of `other` take precedence
+fn merge(base: &mut Table, other: Table) {
+    for (key, value) in other {
+        match (base.get_mut(&key), value) {
+            (Some(Value::Table(current)), Value::Table(value)) => merge(current, value),
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+/// Expand the `${VAR}` environment variable references in the
+/// strings of a value, `$${` is a literal `${`
+fn interpolate(value: &mut Value) -> Result<(), Box<dyn std::error::Error>> {
+    match value {
+        Value::String(s) => *s = expand(s)?,
+        Value::Array(values) => {
+            for value in values.iter_mut() {
+                interpolate(value)?;
+            }
+        }
+        Value::Table(table) => {
+            for (_, value) in table.iter_mut() {
+                interpolate(value)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+fn expand(s: &str) -> Result<String, Box<dyn std::error::Error>> {
+    let pattern = Regex::new(r"\$\$\{|\$\{([A-Za-z_][A-Za-z0-9_]*)\}")?;
+    let mut result = String::new();
+    let mut last = 0;
+    for caps in pattern.captures_iter(s) {
+        let all = caps.get(0).ok_or("Invalid match")?;
+        result.push_str(&s[last..all.start()]);
+        match caps.get(1) {
+            None => result.push_str("${"),
+            Some(name) => result.push_str(
+                &env::var(name.as_str())
+                    .map_err(|_| format!("Undefined variable {}", name.as_str()))?,
+            ),
+        }
+        last = all.end();
+    }
+    result.push_str(&s[last..]);
+    Ok(result)
+}
+
+/// Settings given by the `ANTD_SH_<KEY>` environment variables.
+/// The values are converted to the kind of the key, see
+/// [`ENV_KEYS`]. The variables naming no setting are ignored
+fn env_overrides() -> Result<Table, Box<dyn std::error::Error>> {
+    let mut table = Table::new();
+    for (name, raw) in env::vars() {
+        let key = match name.strip_prefix(ENV_PREFIX) {
+            Some("CONFIG") | None => continue,
+            Some("CMD") => String::from("command"),
+            Some(key) => key.to_lowercase(),
+        };
+        let Some((_, kind)) = ENV_KEYS.iter().find(|(k, _)| *k == key) else {
+            WARN!("Ignore {}: no such setting", name);
+            continue;
+        };
+        let value = match kind {
+            Kind::Scalar => scalar(&raw),
+            Kind::Text => Value::from(raw),
+            Kind::List => list(&raw),
+            Kind::Pairs => pairs(&raw),
+        };
+        table.insert(key, value);
+    }
+    Ok(table)
+}
+
+/// Parse a comma separated list
+fn list(raw: &str) -> Value {
+    Value::from(
+        raw.split(',')
+            .map(|v| v.trim())
+            .filter(|v| !v.is_empty())
+            .collect::<Vec<&str>>(),
+    )
+}
+
+/// Parse comma separated `name=value` pairs
+fn pairs(raw: &str) -> Value {
+    Value::Table(
+        raw.split(',')
+            .filter_map(|entry| entry.split_once('='))
+            .map(|(k, v)| (String::from(k.trim()), Value::from(v.trim())))
+            .collect(),
+    )
+}
+
+/// Parse a raw value as a TOML scalar, falling back to a string
+fn scalar(raw: &str) -> Value {
+    format!("v = {}", raw)
+        .parse::<Table>()
+        .ok()
+        .and_then(|mut t| t.remove("v"))
+        .unwrap_or_else(|| Value::from(raw))
+}
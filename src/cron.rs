@@ -0,0 +1,556 @@
+//! # Scheduled command backend
+//!
+//! `shcron` runs the command on a cron schedule or at a fixed
+//! interval, e.g. for a status dashboard. The output of each
+//! run is sent to all the subscribers as it comes, followed by
+//! the exit status of the run. The output of the last complete
+//! run can be sent to the new subscribers
+//!
+//! **Author**: "Dany LE"
+//!
+use crate::account;
+use crate::audit::{self, Event};
+use crate::backend::Backend;
+use crate::clients::{self, Roster, Subscription};
+use crate::config::Config;
+use crate::error::{self, Class, Classify};
+use crate::frame;
+use crate::history::{self, Run};
+use crate::log::{self, Level};
+use crate::metrics::{self, Counter};
+use crate::process::Process;
+use crate::profile::{Phase, Timer};
+use crate::schedule::Cron;
+use crate::spawn;
+use crate::stats::{self, Stats};
+use crate::telemetry::Span;
+use crate::{DEBUG, ERROR, INFO, WARN};
+use latpr::tunnel::{CallbackEvent, IOInterest, Msg, MsgKind, Topic};
+use latpr::utils::{LogLevel, LOG};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::os::unix::io::RawFd;
+use std::os::unix::process::ExitStatusExt;
+use std::process::{Command, ExitStatus, Stdio};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// time given to the process to exit on shutdown
+const TERMINATE_GRACE: Duration = Duration::from_secs(2);
+/// output of a run kept for the new subscribers, the rest of
+/// the output is only sent to the current ones
+const MAX_RESULT: usize = 1024 * 1024;
+
+/// Settings of the scheduled command mode, e.g.
+///
+/// ```toml
+/// [cron]
+/// schedule = "*/5 * * * *"
+/// last_result = true
+/// ```
+#[derive(Deserialize, Serialize, Default, Debug, Clone, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct CronSettings {
+    /// cron expression of the runs, in local time, see
+    /// [`Cron`]
+    pub schedule: Option<String>,
+    /// seconds between the starts of two runs, the first run
+    /// starts with the backend
+    pub interval: Option<u64>,
+    /// seconds after which a run is killed, no limit when not
+    /// set
+    pub timeout: Option<u64>,
+    /// whether the new subscribers get the output of the last
+    /// complete run
+    pub last_result: bool,
+}
+
+pub struct Subscriber {
+    user: String,
+    /// identifier of the subscription, see [`telemetry::session_id`]
+    session: String,
+    /// counters of the subscriber
+    stats: Stats,
+    /// span of the subscription, see [`crate::telemetry`]
+    span: Option<Span>,
+}
+
+impl Drop for Subscriber {
+    fn drop(&mut self) {
+        clients::end_span(&mut self.span, &self.stats);
+    }
+}
+
+crate::member!(Subscriber);
+
+/// A run of the command
+struct Running {
+    process: Process,
+    run: Run,
+    started_at: Instant,
+    /// output so far, up to [`MAX_RESULT`]
+    output: Vec<u8>,
+    span: Option<Span>,
+    /// whether the run has been killed on timeout
+    killed: bool,
+}
+
+/// Output and exit status of the last complete run
+struct LastRun {
+    output: Vec<u8>,
+    status: ExitStatus,
+    /// seconds since the epoch
+    end: u64,
+}
+
+/// Backend running the command on a schedule
+pub struct Scheduled {
+    clients: HashMap<u16, Subscriber>,
+    cron: Option<Cron>,
+    /// minute of the last schedule check, since the epoch
+    checked: u64,
+    /// start of the last run, for the interval
+    started_at: Option<Instant>,
+    running: Option<Running>,
+    last: Option<LastRun>,
+    runs: u64,
+    /// runs skipped because the previous one was still running
+    skipped: u64,
+    /// whether the process may have exited since the last check
+    reap: bool,
+}
+
+impl Scheduled {
+    pub fn new(config: &Config) -> Result<Scheduled, Box<dyn std::error::Error>> {
+        let cron = match config.cron.schedule.as_ref() {
+            Some(text) => Some(Cron::parse(text)?),
+            None => None,
+        };
+        Ok(Scheduled {
+            clients: HashMap::new(),
+            cron,
+            checked: now() / 60,
+            started_at: None,
+            running: None,
+            last: None,
+            runs: 0,
+            skipped: 0,
+            reap: false,
+        })
+    }
+
+    /// Whether a run is due, once per matching minute of the
+    /// schedule or once per interval
+    fn is_due(&mut self, config: &Config) -> bool {
+        if let Some(cron) = self.cron.as_ref() {
+            let minute = now() / 60;
+            if minute == self.checked {
+                return false;
+            }
+            self.checked = minute;
+            return cron.is_due();
+        }
+        match config.cron.interval {
+            Some(secs) => self
+                .started_at
+                .is_none_or(|t| t.elapsed() >= Duration::from_secs(secs)),
+            None => false,
+        }
+    }
+
+    /// Start a run when it is due, the fd of its output is
+    /// returned to be watched
+    fn schedule(&mut self, config: &Config) -> Option<RawFd> {
+        if !self.is_due(config) {
+            return None;
+        }
+        self.started_at = Some(Instant::now());
+        if self.running.is_some() {
+            self.skipped += 1;
+            WARN!("Previous run is still running, skip this one");
+            return None;
+        }
+        match spawn_run(config) {
+            Ok(running) => {
+                DEBUG!("Run {} started, pid {}", self.runs, running.run.pid);
+                let fd = running.process.stdout_fd();
+                self.runs += 1;
+                self.running = Some(running);
+                fd
+            }
+            Err(error) => {
+                ERROR!(
+                    "Unable to run {}: {}: {}",
+                    config.command,
+                    error::report(&*error, Class::Spawn),
+                    error
+                );
+                None
+            }
+        }
+    }
+
+    /// Read the output of the run, none when there is nothing
+    /// to read
+    fn read_output(&mut self, config: &Config) -> Option<Vec<u8>> {
+        let running = self.running.as_mut()?;
+        let mut buf = vec![0; config.buffer_size];
+        match running.process.read_output(&mut buf[..]) {
+            Ok(Some(n)) if n > 0 => {
+                buf.truncate(n);
+                metrics::add(Counter::BytesOut, n as u64);
+                let room = MAX_RESULT.saturating_sub(running.output.len());
+                running.output.extend_from_slice(&buf[..n.min(room)]);
+                Some(buf)
+            }
+            Ok(_) => None,
+            Err(e) => {
+                WARN!(
+                    "Unable to read the output of the run: {}: {}",
+                    error::report(&e, Class::ChildIo),
+                    e
+                );
+                None
+            }
+        }
+    }
+
+    /// Kill the run once it has exceeded the timeout, collect
+    /// it once it has exited. The status is returned with the
+    /// end of the output
+    fn supervise(&mut self, config: &Config) -> Option<(ExitStatus, Vec<u8>)> {
+        let running = self.running.as_mut()?;
+        if !running.killed
+            && config
+                .cron
+                .timeout
+                .is_some_and(|secs| running.started_at.elapsed() >= Duration::from_secs(secs))
+        {
+            running.killed = true;
+            WARN!("Run of pid {} has timed out, kill it", running.run.pid);
+            if let Err(error) = running.process.kill() {
+                WARN!("Unable to kill the run: {}", error);
+            }
+            self.reap = true;
+        }
+        if !std::mem::take(&mut self.reap) {
+            return None;
+        }
+        let status = match running.process.try_wait() {
+            Ok(Some(status)) => status,
+            Ok(None) => return None,
+            Err(error) => {
+                WARN!("Unable to wait for the run: {}", error);
+                return None;
+            }
+        };
+        let mut rest = Vec::new();
+        while let Some(data) = self.read_output(config) {
+            rest.extend(data);
+        }
+        let mut running = self.running.take()?;
+        if let Some(mut span) = running.span.take() {
+            if let Some(code) = status.code() {
+                span.set("process.exit.code", code as i64);
+            }
+            if let Some(signal) = status.signal() {
+                span.set("process.exit.signal", signal as i64);
+            }
+            span.end();
+        }
+        let pid = running.run.pid;
+        let user = account::current_user();
+        audit::log(config, 0, &user, "", Event::Exit { pid, status });
+        metrics::add(Counter::Exited, 1);
+        history::record(config, &user, &running.run, status);
+        INFO!("Run of pid {} has exited with status {}", pid, status);
+        self.last = Some(LastRun {
+            output: std::mem::take(&mut running.output),
+            status,
+            end: now(),
+        });
+        Some((status, rest))
+    }
+
+    /// Send some output to all the subscribers
+    fn send(&mut self, data: &[u8], topic: &mut Topic) -> Result<(), Box<dyn std::error::Error>> {
+        if let Some((reads, bytes)) = log::throttle(Level::Debug, "send", 0, data.len()) {
+            DEBUG!(
+                "Sending {} bytes of raw data to all clients in {} read(s)",
+                bytes,
+                reads
+            );
+        }
+        for (key, subscriber) in self.clients.iter_mut() {
+            let msg = Msg::create(MsgKind::ChannelData, 0, *key, data.to_vec());
+            frame::send(topic, &msg)?;
+            subscriber.stats.sent(data.len());
+        }
+        Ok(())
+    }
+
+    fn handle_message(
+        &mut self,
+        msg: &Msg,
+        config: &Config,
+        topic: &mut Topic,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        DEBUG!(
+            "Receive message kind {} from client {}: {} bytes",
+            msg.kind,
+            msg.client_id,
+            msg.size
+        );
+        if let Some(client) = self.clients.get_mut(&msg.client_id) {
+            client.stats.received();
+        }
+        match msg.kind {
+            MsgKind::ChannelSubscribe => {
+                let Some(Subscription {
+                    user,
+                    session,
+                    context: _context,
+                }) = clients::subscribe(msg, config, topic, |_| {
+                    Ok(self
+                        .clients
+                        .remove(&msg.client_id)
+                        .map(|old| old.user.clone()))
+                })?
+                else {
+                    return Ok(());
+                };
+                if !self.make_room(msg.client_id, config, topic)? {
+                    return Ok(());
+                }
+                let span = clients::span(&config.topic, msg.client_id, &user, &session);
+                audit::log(config, msg.client_id, &user, &session, Event::Subscribe);
+                self.clients.insert(
+                    msg.client_id,
+                    Subscriber {
+                        user,
+                        session,
+                        stats: Stats::default(),
+                        span: Some(span),
+                    },
+                );
+                INFO!(
+                    "Client {} subscribe to channel {}",
+                    msg.client_id,
+                    &config.topic
+                );
+                clients::welcome(msg.client_id, config, topic)?;
+                // the result of the last run, the current run is
+                // sent as it comes
+                if let Some(last) = self.last.as_ref().filter(|_| config.cron.last_result) {
+                    if !last.output.is_empty() {
+                        let data = last.output.clone();
+                        let msg = Msg::create(MsgKind::ChannelData, 0, msg.client_id, data);
+                        frame::send(topic, &msg)?;
+                    }
+                    frame::send(topic, &frame::exit_status(msg.client_id, last.status))?;
+                }
+            }
+            MsgKind::ChannelUnsubscribe => {
+                self.unsubscribe(msg.client_id, config, topic)?;
+            }
+            MsgKind::ChannelUnsubscribeAll => self.unsubscribe_all(config, topic)?,
+            MsgKind::ChannelData => {
+                frame::send(topic, &frame::error(msg.client_id, "Read-only channel"))?;
+            }
+            MsgKind::ChannelCtrl if stats::is_query(&msg.data) => {
+                self.status(msg.client_id, topic)?;
+            }
+            _ => {
+                WARN!(
+                    "Receive message kind {} from client {}",
+                    msg.kind,
+                    msg.client_id
+                );
+            }
+        };
+        Ok(())
+    }
+}
+
+/// Spawn a run of the command, its input is empty
+fn spawn_run(config: &Config) -> Result<Running, Box<dyn std::error::Error>> {
+    let _timer = Timer::start(Phase::Spawn);
+    let path = config.allowed_command(&config.command)?;
+    let mut command = Command::new(&path);
+    command
+        .envs(&config.env)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped());
+    config.rlimits.apply(&mut command);
+    spawn::die_with_parent(&mut command);
+    let process = Process::from(
+        command
+            .spawn()
+            .inspect_err(|_| metrics::add(Counter::SpawnFailures, 1))
+            .class(Class::Spawn)?,
+    );
+    // the runs are shared, they belong to no session
+    let run = Run::start(0, "", &path, process.id());
+    let spawned = Event::Spawn {
+        command: &path,
+        args: &[],
+        pid: process.id(),
+    };
+    audit::log(config, 0, &account::current_user(), "", spawned);
+    metrics::add(Counter::Spawned, 1);
+    let mut span = Span::root("process");
+    span.set("antd.topic", config.topic.as_str());
+    span.set("process.command", run.command.as_str());
+    span.set("process.pid", run.pid as i64);
+    Ok(Running {
+        process,
+        run,
+        started_at: Instant::now(),
+        output: Vec::new(),
+        span: Some(span),
+        killed: false,
+    })
+}
+
+/// Seconds since the epoch
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+impl Roster for Scheduled {
+    type Client = Subscriber;
+
+    fn table(&self) -> &HashMap<u16, Subscriber> {
+        &self.clients
+    }
+
+    fn table_mut(&mut self) -> &mut HashMap<u16, Subscriber> {
+        &mut self.clients
+    }
+}
+
+impl Backend for Scheduled {
+    fn fds(&self) -> Vec<RawFd> {
+        self.running
+            .iter()
+            .filter_map(|r| r.process.stdout_fd())
+            .collect()
+    }
+
+    fn clients(&self) -> usize {
+        self.clients.len()
+    }
+
+    fn process_alive(&self) -> Option<bool> {
+        // idle between the runs, the backend is alive
+        Some(true)
+    }
+
+    fn dump(&self) -> Vec<String> {
+        let mut lines = vec![format!(
+            "{} client(s), {} run(s), {} skipped, {}",
+            self.clients.len(),
+            self.runs,
+            self.skipped,
+            match (self.running.as_ref(), self.last.as_ref()) {
+                (Some(r), _) => format!("running pid {}", r.run.pid),
+                (None, Some(last)) => format!(
+                    "last run ended at {} with status {}",
+                    history::local_time(last.end),
+                    last.status
+                ),
+                (None, None) => String::from("no run yet"),
+            }
+        )];
+        let mut keys: Vec<&u16> = self.clients.keys().collect();
+        keys.sort();
+        for key in keys {
+            let s = &self.clients[key];
+            lines.push(format!(
+                "client {}: user {}, {}",
+                key,
+                s.user,
+                s.stats.describe()
+            ));
+        }
+        lines
+    }
+
+    fn step_handle(
+        &mut self,
+        evt: &CallbackEvent,
+        config: &Config,
+        topic: &mut Topic,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if let Some(msg) = evt.msg {
+            self.scoped(msg, config, topic, |scheduled, topic| {
+                scheduled.handle_message(msg, config, topic)
+            })?;
+        }
+        if let Some(fd) = self.schedule(config) {
+            topic.register_io(fd, IOInterest::READABLE)?;
+        }
+        // the output is read on each step, the run is short
+        // enough for its readiness not to matter
+        while let Some(data) = self.read_output(config) {
+            self.send(&data, topic)?;
+        }
+        let fd = self.running.as_ref().and_then(|r| r.process.stdout_fd());
+        if let Some((status, rest)) = self.supervise(config) {
+            if !rest.is_empty() {
+                self.send(&rest, topic)?;
+            }
+            if let Some(fd) = fd {
+                topic.unregister_io(fd)?;
+            }
+            for key in self.clients.keys() {
+                frame::send(topic, &frame::exit_status(*key, status))?;
+            }
+        }
+        self.enforce_windows(config, topic)?;
+        self.reap_idle(config, topic)?;
+        Ok(())
+    }
+
+    fn children_exited(
+        &mut self,
+        _config: &Config,
+        _topic: &mut Topic,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        // collected on the next step, once its output is read
+        self.reap = true;
+        Ok(())
+    }
+
+    fn disconnected(&mut self, _config: &Config) {
+        self.clients.clear();
+    }
+
+    fn step_offline(&mut self, config: &Config) {
+        // the runs go on, for their last result
+        self.schedule(config);
+        while self.read_output(config).is_some() {}
+        self.reap = true;
+        self.supervise(config);
+    }
+
+    fn shutdown(
+        &mut self,
+        config: &Config,
+        topic: &mut Topic,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.notify_shutdown(topic)?;
+        self.clients.clear();
+        self.terminate(config);
+        Ok(())
+    }
+
+    fn terminate(&mut self, config: &Config) {
+        if let Some(running) = self.running.as_mut() {
+            spawn::terminate(vec![&mut running.process], TERMINATE_GRACE);
+        }
+        self.reap = true;
+        self.supervise(config);
+    }
+}
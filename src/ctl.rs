@@ -0,0 +1,73 @@
+//! # Admin command line tool
+//!
+//! `shctl` sends a command to the admin socket of a running
+//! backend, see `admin_socket`, and prints its reply
+//!
+//! **Author**: "Dany LE"
+//!
+use crate::admin;
+use crate::log::Level;
+use clap::{Parser, Subcommand};
+use std::path::PathBuf;
+
+/// Manage a running shell backend through its admin socket
+#[derive(Parser, Debug)]
+#[command(version)]
+pub struct Ctl {
+    /// admin socket of the backend
+    #[arg(long, short, env = "SHCTL_SOCKET")]
+    pub socket: PathBuf,
+    #[command(subcommand)]
+    pub command: CtlCommand,
+}
+
+#[derive(Subcommand, Debug, Clone, PartialEq, Eq)]
+pub enum CtlCommand {
+    /// State of the backend and counters of its sessions
+    Sessions,
+    /// Metrics in the Prometheus text format
+    Metrics,
+    /// Bytes per second in and out and write latency of the topic
+    Throughput,
+    /// Refuse new clients and exit after the last one
+    Drain,
+    /// Reload the configuration
+    Reload,
+    /// Change the log level until the next reload
+    LogLevel {
+        #[arg(value_enum)]
+        level: Level,
+    },
+    /// Kill the process of a client, the reason is sent to it
+    Kill { client_id: u16, reason: Vec<String> },
+    /// Kill the process of a client and spawn a new one
+    Restart { client_id: u16, reason: Vec<String> },
+}
+
+impl CtlCommand {
+    /// The command line of the admin socket
+    fn line(&self) -> String {
+        match self {
+            CtlCommand::Sessions => String::from("sessions"),
+            CtlCommand::Metrics => String::from("metrics"),
+            CtlCommand::Throughput => String::from("throughput"),
+            CtlCommand::Drain => String::from("drain"),
+            CtlCommand::Reload => String::from("reload"),
+            CtlCommand::LogLevel { level } => format!("log-level {:?}", level).to_lowercase(),
+            CtlCommand::Kill { client_id, reason } => {
+                format!("kill {} {}", client_id, reason.join(" "))
+            }
+            CtlCommand::Restart { client_id, reason } => {
+                format!("restart {} {}", client_id, reason.join(" "))
+            }
+        }
+    }
+}
+
+/// Run `shctl`
+pub fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let ctl = Ctl::parse();
+    let reply = admin::request(&ctl.socket, &ctl.command.line())?;
+    print!("{}", reply);
+    Ok(())
+}
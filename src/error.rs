@@ -0,0 +1,110 @@
+//! # Error classes
+//!
+//! The errors reaching the logs are sorted in a few classes,
+//! counted by the [`crate::metrics`], so that e.g. a missing
+//! command of a user is told apart from a broken socket of the
+//! hub. The functions keep returning `Box<dyn Error>`: an error
+//! is classified where it arises with [`Classify::class`], and
+//! the class is read back with [`report`] where it is logged
+//!
+//! **Author**: "Dany LE"
+//!
+use std::fmt;
+
+/// Class of an error
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Class {
+    /// the process of a session can not be spawned, e.g. its
+    /// command is missing
+    Spawn,
+    /// read or write on the pipes of a process
+    ChildIo,
+    /// read or write on the topic, i.e. the socket of the hub
+    Tunnel,
+    /// malformed frame of a client
+    Protocol,
+    /// invalid configuration
+    Config,
+    /// anything else
+    Internal,
+}
+
+impl Class {
+    pub const ALL: [Class; 6] = [
+        Class::Spawn,
+        Class::ChildIo,
+        Class::Tunnel,
+        Class::Protocol,
+        Class::Config,
+        Class::Internal,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Class::Spawn => "spawn",
+            Class::ChildIo => "child_io",
+            Class::Tunnel => "tunnel",
+            Class::Protocol => "protocol",
+            Class::Config => "config",
+            Class::Internal => "internal",
+        }
+    }
+}
+
+impl fmt::Display for Class {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// An error and its class, displayed as the error
+#[derive(Debug)]
+pub struct Error {
+    pub class: Class,
+    source: Box<dyn std::error::Error>,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.source.fmt(f)
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&*self.source)
+    }
+}
+
+/// Classify the error of a result
+pub trait Classify<T> {
+    /// Set the class of the error, unless it already has one,
+    /// which is more accurate
+    fn class(self, class: Class) -> Result<T, Box<dyn std::error::Error>>;
+}
+
+impl<T, E: Into<Box<dyn std::error::Error>>> Classify<T> for Result<T, E> {
+    fn class(self, class: Class) -> Result<T, Box<dyn std::error::Error>> {
+        self.map_err(|error| {
+            let source = error.into();
+            if source.is::<Error>() {
+                source
+            } else {
+                Box::new(Error { class, source })
+            }
+        })
+    }
+}
+
+/// Class of an error, `default` when it has none
+pub fn class_of(error: &(dyn std::error::Error + 'static), default: Class) -> Class {
+    error.downcast_ref::<Error>().map_or(default, |e| e.class)
+}
+
+/// Count an error about to be logged, return its class for
+/// the message
+pub fn report(error: &(dyn std::error::Error + 'static), default: Class) -> Class {
+    let class = class_of(error, default);
+    crate::metrics::error(class);
+    class
+}
@@ -0,0 +1,725 @@
+//! # Request/response backend
+//!
+//! Each data frame of a `shexec` client is a complete request:
+//! the command is spawned with the frame as its input, and its
+//! output is sent back once it has exited, followed by its exit
+//! status, e.g. for the non-interactive tools
+//!
+//! ```text
+//! -> data <request>
+//! <- data <output>
+//! <- ctrl {"exit_code": 0}
+//! ```
+//!
+//! The requests of a client are run one at a time, in order.
+//! shrpc runs its JSON-RPC calls the same way, see [`crate::rpc`],
+//! shgit its git operations, see [`crate::git`], and shsvc its
+//! service controls, see [`crate::svc`]
+//!
+//! **Author**: "Dany LE"
+//!
+use crate::audit::{self, Event};
+use crate::backend::Backend;
+use crate::clients::{self, Roster, Subscription};
+use crate::config::{Config, Mode};
+use crate::error::{self, Class, Classify};
+use crate::frame;
+use crate::git;
+use crate::history::{self, Run};
+use crate::log::{self, Level};
+use crate::metrics::{self, Counter};
+use crate::process::Process;
+use crate::profile::{Phase, Timer};
+use crate::rpc::{self, Call};
+use crate::spawn;
+use crate::stats::{self, Stats};
+use crate::svc;
+use crate::telemetry::Span;
+use crate::{DEBUG, ERROR, INFO, WARN};
+use latpr::tunnel::{CallbackEvent, IOInterest, Msg, MsgKind, Topic};
+use latpr::utils::{LogLevel, LOG};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, Write};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::process::{Command, ExitStatus, Stdio};
+use std::time::{Duration, Instant};
+
+/// time given to the processes to exit on shutdown
+const TERMINATE_GRACE: Duration = Duration::from_secs(2);
+/// default of `exec.max_output`
+const MAX_OUTPUT: usize = 1024 * 1024;
+/// requests of a client waiting for the running one, the next
+/// ones are refused
+const MAX_QUEUED: usize = 16;
+
+/// Settings of the request/response mode, e.g.
+///
+/// ```toml
+/// [exec]
+/// timeout = 30
+/// max_output = 1048576
+/// ```
+#[derive(Deserialize, Serialize, Default, Debug, Clone, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ExecSettings {
+    /// seconds after which a request is killed, no limit when
+    /// not set
+    pub timeout: Option<u64>,
+    /// largest output sent back, in bytes, the rest is dropped
+    /// (1 MiB by default)
+    pub max_output: Option<usize>,
+}
+
+impl ExecSettings {
+    pub fn max_output(&self) -> usize {
+        self.max_output.unwrap_or(MAX_OUTPUT)
+    }
+}
+
+/// A request to run
+pub struct Job {
+    /// command of the method of a call, the command of the user
+    /// when not set
+    pub command: Option<String>,
+    pub args: Vec<String>,
+    pub input: Vec<u8>,
+    /// JSON-RPC call of the request, answered with a reply
+    /// instead of the raw output
+    pub call: Option<Call>,
+    /// git operation of the request, answered with a reply
+    /// instead of the raw output
+    pub git: Option<git::Call>,
+    /// service control of the request, answered with a reply
+    /// instead of the raw output
+    pub svc: Option<svc::Call>,
+}
+
+/// A request being run
+struct Request {
+    process: Process,
+    run: Run,
+    started_at: Instant,
+    /// input not written yet, the input of the process is
+    /// closed once it is all written
+    input: Vec<u8>,
+    output: Vec<u8>,
+    /// bytes of output dropped beyond `max_output`
+    dropped: u64,
+    /// whether the request has been killed on timeout
+    killed: bool,
+    call: Option<Call>,
+    git: Option<git::Call>,
+    svc: Option<svc::Call>,
+}
+
+impl Request {
+    /// Write as much input as the process takes, then close it
+    fn write_input(&mut self) -> io::Result<()> {
+        let stdin = match self.process.stdin.as_mut() {
+            None => return Ok(()),
+            Some(stdin) => stdin,
+        };
+        while !self.input.is_empty() {
+            match stdin.write(&self.input) {
+                Ok(n) => {
+                    self.input.drain(..n);
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                // the process does not read its input
+                Err(e) if e.kind() == io::ErrorKind::BrokenPipe => self.input.clear(),
+                Err(e) => return Err(e),
+            }
+        }
+        self.process.stdin = None;
+        Ok(())
+    }
+
+    /// Read the available output, up to `max_output`
+    fn read_output(&mut self, config: &Config) -> io::Result<()> {
+        let mut buf = vec![0; config.buffer_size];
+        while let Some(n) = self.process.read_output(&mut buf[..])? {
+            if n == 0 {
+                break;
+            }
+            metrics::add(Counter::BytesOut, n as u64);
+            let room = config.exec.max_output().saturating_sub(self.output.len());
+            self.output.extend_from_slice(&buf[..n.min(room)]);
+            self.dropped += n.saturating_sub(room) as u64;
+        }
+        Ok(())
+    }
+}
+
+pub struct Client {
+    user: String,
+    /// identifier of the session, see [`telemetry::session_id`]
+    session: String,
+    request: Option<Request>,
+    /// requests waiting for the running one
+    queue: VecDeque<Job>,
+    /// counters of the client
+    stats: Stats,
+    /// span of the subscription, see [`crate::telemetry`]
+    span: Option<Span>,
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        if let Some(request) = self.request.as_mut() {
+            if let Err(error) = request.process.kill() {
+                WARN!("Unable to kill the request: {}", error);
+            }
+        }
+        clients::end_span(&mut self.span, &self.stats);
+    }
+}
+
+crate::member!(Client);
+
+/// Backend running a process per request
+pub struct Exec {
+    clients: HashMap<u16, Client>,
+    /// whether a process may have exited since the last check
+    reap: bool,
+}
+
+impl Default for Exec {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Exec {
+    pub fn new() -> Exec {
+        Exec {
+            clients: HashMap::new(),
+            reap: false,
+        }
+    }
+
+    /// Remove a client, its request is killed
+    fn remove(&mut self, client_id: u16, topic: &mut Topic) -> Option<Client> {
+        let client = self.clients.remove(&client_id)?;
+        if let Some(fd) = client.request.as_ref().and_then(|r| r.process.stdout_fd()) {
+            if let Err(error) = topic.unregister_io(fd) {
+                WARN!(
+                    "Unable to unregister the request of client {}: {}",
+                    client_id,
+                    error
+                );
+            }
+        }
+        Some(client)
+    }
+
+    /// Start the next request of a client, if none is running
+    fn start_next(
+        client_id: u16,
+        client: &mut Client,
+        config: &Config,
+        topic: &mut Topic,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if client.request.is_some() {
+            return Ok(());
+        }
+        let job = match client.queue.pop_front() {
+            None => return Ok(()),
+            Some(job) => job,
+        };
+        let call = job.call.clone();
+        let git = job.git.clone();
+        let svc = job.svc.clone();
+        match spawn_request(client_id, client, job, config) {
+            Ok(mut request) => {
+                if let Some(fd) = request.process.stdout_fd() {
+                    topic.register_io(fd, IOInterest::READABLE)?;
+                }
+                request.write_input().class(Class::ChildIo)?;
+                client.request = Some(request);
+            }
+            Err(error) => {
+                ERROR!(
+                    "Unable to run the request of client {}: {}: {}",
+                    client_id,
+                    error::report(&*error, Class::Spawn),
+                    error
+                );
+                match (call, git, svc) {
+                    (Some(call), _, _) => {
+                        let reply =
+                            rpc::error(call.id, rpc::COMMAND_FAILED, "Unable to run the command");
+                        send_reply(client_id, client, &reply, topic)?;
+                    }
+                    (None, Some(git), _) => {
+                        let reply = git::error(&git.id, "Unable to run git");
+                        send_reply(client_id, client, &reply, topic)?;
+                    }
+                    (None, None, Some(svc)) => {
+                        let reply = svc::error(&svc.id, "Unable to run systemctl");
+                        send_reply(client_id, client, &reply, topic)?;
+                    }
+                    (None, None, None) => {
+                        frame::send(topic, &frame::error(client_id, "Unable to run the request"))?
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Move the requests along: write their input, read their
+    /// output, kill them on timeout and answer the finished ones
+    fn supervise(
+        &mut self,
+        config: &Config,
+        topic: &mut Topic,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let reap = std::mem::take(&mut self.reap);
+        let mut failed = Vec::new();
+        for (key, client) in self.clients.iter_mut() {
+            // e.g. after a request that could not be spawned
+            Exec::start_next(*key, client, config, topic)?;
+            let request = match client.request.as_mut() {
+                None => continue,
+                Some(request) => request,
+            };
+            let _context = log::context(&config.topic, *key, &client.user, &client.session);
+            if let Err(e) = request
+                .write_input()
+                .and_then(|_| request.read_output(config))
+            {
+                ERROR!(
+                    "Unable to run the request of client {}: {}: {}",
+                    key,
+                    error::report(&e, Class::ChildIo),
+                    e
+                );
+                failed.push(*key);
+                continue;
+            }
+            if !request.killed
+                && config
+                    .exec
+                    .timeout
+                    .is_some_and(|secs| request.started_at.elapsed() >= Duration::from_secs(secs))
+            {
+                WARN!("Request of client {} has timed out, kill it", key);
+                request.killed = true;
+                if let Err(error) = request.process.kill() {
+                    WARN!("Unable to kill the request: {}", error);
+                }
+            }
+            if !reap && !request.killed {
+                continue;
+            }
+            let status = match request.process.try_wait()? {
+                None => continue,
+                Some(status) => status,
+            };
+            request.read_output(config).class(Class::ChildIo)?;
+            if let Some(mut request) = client.request.take() {
+                if let Some(fd) = request.process.stdout_fd() {
+                    topic.unregister_io(fd)?;
+                }
+                answer(*key, client, &mut request, status, config, topic)?;
+            }
+            Exec::start_next(*key, client, config, topic)?;
+        }
+        for key in failed {
+            self.drop_client(key, config, topic)?;
+        }
+        Ok(())
+    }
+
+    fn handle_message(
+        &mut self,
+        msg: &Msg,
+        config: &Config,
+        topic: &mut Topic,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if !matches!(msg.kind, MsgKind::ChannelData) {
+            DEBUG!(
+                "Receive message kind {} from client {}: {} bytes",
+                msg.kind,
+                msg.client_id,
+                msg.size
+            );
+        } else if let Some((frames, bytes)) =
+            log::throttle(Level::Debug, "receive", msg.client_id, msg.size as usize)
+        {
+            DEBUG!(
+                "Receive {} data frame(s) from client {}: {} bytes",
+                frames,
+                msg.client_id,
+                bytes
+            );
+        }
+        if let Some(client) = self.clients.get_mut(&msg.client_id) {
+            client.stats.received();
+        }
+        match msg.kind {
+            MsgKind::ChannelSubscribe => {
+                let Some(Subscription {
+                    user,
+                    session,
+                    context: _context,
+                }) = clients::subscribe(msg, config, topic, |topic| {
+                    Ok(self
+                        .remove(msg.client_id, topic)
+                        .map(|old| old.user.clone()))
+                })?
+                else {
+                    return Ok(());
+                };
+                if !self.make_room(msg.client_id, config, topic)? {
+                    return Ok(());
+                }
+                let span = clients::span(&config.topic, msg.client_id, &user, &session);
+                audit::log(config, msg.client_id, &user, &session, Event::Subscribe);
+                INFO!(
+                    "Client {} subscribe to channel {}",
+                    msg.client_id,
+                    &config.topic
+                );
+                self.clients.insert(
+                    msg.client_id,
+                    Client {
+                        user,
+                        session,
+                        request: None,
+                        queue: VecDeque::new(),
+                        stats: Stats::default(),
+                        span: Some(span),
+                    },
+                );
+                clients::welcome(msg.client_id, config, topic)?;
+            }
+            MsgKind::ChannelUnsubscribe => {
+                self.unsubscribe(msg.client_id, config, topic)?;
+            }
+            MsgKind::ChannelUnsubscribeAll => self.unsubscribe_all(config, topic)?,
+            MsgKind::ChannelData => {
+                let client = match self.clients.get_mut(&msg.client_id) {
+                    None => {
+                        WARN!("Client {} is not in the list", msg.client_id);
+                        return Ok(());
+                    }
+                    Some(client) => client,
+                };
+                if client.queue.len() >= MAX_QUEUED {
+                    WARN!(
+                        "Refuse request of client {}: {} requests queued",
+                        msg.client_id,
+                        client.queue.len()
+                    );
+                    let reason = "Too many requests, try again later";
+                    return frame::send(topic, &frame::error(msg.client_id, reason));
+                }
+                client.stats.bytes_in += msg.data.len() as u64;
+                metrics::add(Counter::BytesIn, msg.data.len() as u64);
+                let job = match config.mode {
+                    Mode::Rpc => match rpc::parse(&msg.data, config) {
+                        Ok(job) => job,
+                        Err(reply) => {
+                            WARN!("Invalid call of client {}: {}", msg.client_id, reply);
+                            return send_reply(msg.client_id, client, &reply, topic);
+                        }
+                    },
+                    Mode::Git => match git::parse(&msg.data, &config.git) {
+                        Ok(job) => job,
+                        Err(reply) => {
+                            WARN!("Invalid git request of client {}: {}", msg.client_id, reply);
+                            return send_reply(msg.client_id, client, &reply, topic);
+                        }
+                    },
+                    Mode::Svc => match svc::parse(&msg.data, &client.user, &config.svc) {
+                        Ok(job) => job,
+                        Err(reply) => {
+                            WARN!(
+                                "Refused service control of client {}: {}",
+                                msg.client_id,
+                                reply
+                            );
+                            return send_reply(msg.client_id, client, &reply, topic);
+                        }
+                    },
+                    _ => Job {
+                        command: None,
+                        args: Vec::new(),
+                        input: msg.data.clone(),
+                        call: None,
+                        git: None,
+                        svc: None,
+                    },
+                };
+                client.queue.push_back(job);
+                Exec::start_next(msg.client_id, client, config, topic)?;
+            }
+            MsgKind::ChannelCtrl if stats::is_query(&msg.data) => {
+                self.status(msg.client_id, topic)?;
+            }
+            _ => {
+                WARN!(
+                    "Receive message kind {} from client {}",
+                    msg.kind,
+                    msg.client_id
+                );
+            }
+        };
+        Ok(())
+    }
+}
+
+/// Spawn the process of a request, its input is written as it
+/// takes it
+fn spawn_request(
+    client_id: u16,
+    client: &mut Client,
+    job: Job,
+    config: &Config,
+) -> Result<Request, Box<dyn std::error::Error>> {
+    let _timer = Timer::start(Phase::Spawn);
+    let profile = config.profile(&client.user);
+    let path = match job.command.as_deref() {
+        Some(cmd) => config.allowed_command(cmd)?,
+        None => config.allowed_command(config.command_for(&profile, None)?)?,
+    };
+    let mut command = Command::new(&path);
+    command
+        .args(&job.args)
+        .envs(&profile.env)
+        .env("CUSER", &client.user)
+        .env("CID", format!("{}", client_id))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped());
+    if job.git.is_some() {
+        // no one to answer a prompt for credentials
+        command.env("GIT_TERMINAL_PROMPT", "0");
+    }
+    profile.rlimits.apply(&mut command);
+    spawn::die_with_parent(&mut command);
+    let process = Process::from(
+        command
+            .spawn()
+            .inspect_err(|_| metrics::add(Counter::SpawnFailures, 1))
+            .class(Class::Spawn)?,
+    );
+    if let Some(stdin) = process.stdin.as_ref() {
+        unsafe {
+            let fd = stdin.as_raw_fd();
+            let flags = libc::fcntl(fd, libc::F_GETFL);
+            libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK);
+        }
+    }
+    metrics::add(Counter::Spawned, 1);
+    client.stats.spawns += 1;
+    let spawned = Event::Spawn {
+        command: &path,
+        args: &job.args,
+        pid: process.id(),
+    };
+    audit::log(config, client_id, &client.user, &client.session, spawned);
+    DEBUG!(
+        "Run request of client {}{}: {} bytes, pid {}",
+        client_id,
+        match (job.call.as_ref(), job.git.as_ref(), job.svc.as_ref()) {
+            (Some(call), _, _) => format!(", method {}", call.method),
+            (None, Some(git), _) => format!(", git {} of {}", git.op.name(), git.repo),
+            (None, None, Some(svc)) => format!(", {} of {}", svc.op.name(), svc.unit),
+            (None, None, None) => String::new(),
+        },
+        job.input.len(),
+        process.id()
+    );
+    Ok(Request {
+        run: Run::start(client_id, &client.session, &path, process.id()),
+        process,
+        started_at: Instant::now(),
+        input: job.input,
+        output: Vec::new(),
+        dropped: 0,
+        killed: false,
+        call: job.call,
+        git: job.git,
+        svc: job.svc,
+    })
+}
+
+/// Send the output and the exit status of a finished request
+fn answer(
+    client_id: u16,
+    client: &mut Client,
+    request: &mut Request,
+    status: ExitStatus,
+    config: &Config,
+    topic: &mut Topic,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let pid = request.run.pid;
+    let (user, session) = (&client.user, &client.session);
+    audit::log(
+        config,
+        client_id,
+        user,
+        session,
+        Event::Exit { pid, status },
+    );
+    metrics::add(Counter::Exited, 1);
+    history::record(config, user, &request.run, status);
+    DEBUG!(
+        "Request of client {} has exited with status {}: {} bytes of output",
+        client_id,
+        status,
+        request.output.len()
+    );
+    if request.dropped > 0 {
+        client.stats.dropped_out += request.dropped;
+        metrics::add(Counter::BytesDropped, request.dropped);
+    }
+    if let Some(svc) = request.svc.as_ref() {
+        let reply = svc::reply(svc, status, &request.output);
+        return send_reply(client_id, client, &reply, topic);
+    }
+    if let Some(git) = request.git.as_ref() {
+        let reply = git::reply(git, status, &request.output, request.dropped);
+        return send_reply(client_id, client, &reply, topic);
+    }
+    if let Some(call) = request.call.as_ref() {
+        return match rpc::reply(call, status, &request.output, request.dropped) {
+            Some(reply) => send_reply(client_id, client, &reply, topic),
+            None => Ok(()),
+        };
+    }
+    for chunk in request.output.chunks(config.buffer_size) {
+        let msg = Msg::create(MsgKind::ChannelData, 0, client_id, chunk.to_vec());
+        frame::send(topic, &msg)?;
+        client.stats.sent(chunk.len());
+    }
+    if request.dropped > 0 {
+        let reason = "Output truncated";
+        frame::send(topic, &frame::dropped(client_id, reason, request.dropped))?;
+    }
+    frame::send(topic, &frame::exit_status(client_id, status))
+}
+
+/// Send a JSON-RPC reply in a data frame
+fn send_reply(
+    client_id: u16,
+    client: &mut Client,
+    reply: &Value,
+    topic: &mut Topic,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let data = reply.to_string().into_bytes();
+    client.stats.sent(data.len());
+    frame::send(
+        topic,
+        &Msg::create(MsgKind::ChannelData, 0, client_id, data),
+    )
+}
+
+impl Roster for Exec {
+    type Client = Client;
+
+    fn table(&self) -> &HashMap<u16, Client> {
+        &self.clients
+    }
+
+    fn table_mut(&mut self) -> &mut HashMap<u16, Client> {
+        &mut self.clients
+    }
+
+    fn release(
+        &mut self,
+        client_id: u16,
+        _config: &Config,
+        topic: &mut Topic,
+    ) -> Result<Option<Client>, Box<dyn std::error::Error>> {
+        Ok(self.remove(client_id, topic))
+    }
+
+    // a client waiting for its request is not idle
+    fn busy(&self, client_id: u16) -> bool {
+        self.clients[&client_id].request.is_some()
+    }
+}
+
+impl Backend for Exec {
+    fn fds(&self) -> Vec<RawFd> {
+        self.clients
+            .values()
+            .filter_map(|c| c.request.as_ref().and_then(|r| r.process.stdout_fd()))
+            .collect()
+    }
+
+    fn clients(&self) -> usize {
+        self.clients.len()
+    }
+
+    fn dump(&self) -> Vec<String> {
+        let mut lines = vec![format!("{} client(s)", self.clients.len())];
+        let mut keys: Vec<&u16> = self.clients.keys().collect();
+        keys.sort();
+        for key in keys {
+            let c = &self.clients[key];
+            lines.push(format!(
+                "client {}: user {}, {}, {} queued, {}",
+                key,
+                c.user,
+                match c.request.as_ref() {
+                    Some(r) => format!("running pid {}", r.run.pid),
+                    None => String::from("idle"),
+                },
+                c.queue.len(),
+                c.stats.describe()
+            ));
+        }
+        lines
+    }
+
+    fn step_handle(
+        &mut self,
+        evt: &CallbackEvent,
+        config: &Config,
+        topic: &mut Topic,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if let Some(msg) = evt.msg {
+            self.scoped(msg, config, topic, |exec, topic| {
+                exec.handle_message(msg, config, topic)
+            })?;
+        }
+        self.supervise(config, topic)?;
+        self.enforce_windows(config, topic)?;
+        self.reap_idle(config, topic)
+    }
+
+    fn children_exited(
+        &mut self,
+        config: &Config,
+        topic: &mut Topic,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.reap = true;
+        self.supervise(config, topic)
+    }
+
+    fn disconnected(&mut self, _config: &Config) {
+        // the requests are killed with their clients
+        self.clients.clear();
+    }
+
+    fn shutdown(
+        &mut self,
+        _config: &Config,
+        topic: &mut Topic,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.notify_shutdown(topic)?;
+        let processes = self
+            .clients
+            .values_mut()
+            .filter_map(|c| c.request.as_mut().map(|r| &mut r.process))
+            .collect();
+        spawn::terminate(processes, TERMINATE_GRACE);
+        self.clients.clear();
+        Ok(())
+    }
+}
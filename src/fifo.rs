@@ -0,0 +1,418 @@
+//! # Named pipe backend
+//!
+//! `shfifo` owns no process: a long-lived application of the
+//! host writes to a named pipe whose content is sent to all the
+//! subscribers, and reads the data of the clients from another
+//! one. The application may start, stop or restart on its own,
+//! the pipes outlive it
+//!
+//! **Author**: "Dany LE"
+//!
+use crate::audit::{self, Event};
+use crate::backend::Backend;
+use crate::clients::{self, Roster, Subscription};
+use crate::config::Config;
+use crate::error::{Class, Classify};
+use crate::frame;
+use crate::log::{self, Level};
+use crate::metrics::{self, Counter};
+use crate::replay::Replay;
+use crate::stats::{self, Stats};
+use crate::telemetry::Span;
+use crate::{DEBUG, INFO, WARN};
+use latpr::tunnel::{CallbackEvent, Msg, MsgKind, Topic};
+use latpr::utils::{LogLevel, LOG};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::{Path, PathBuf};
+
+/// Settings of the named pipe mode, e.g.
+///
+/// ```toml
+/// [fifo]
+/// output = "/run/app/out.fifo"
+/// input = "/run/app/in.fifo"
+/// ```
+#[derive(Deserialize, Serialize, Default, Debug, Clone, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct FifoSettings {
+    /// absolute path of the pipe read by the backend, its
+    /// content is sent to all the subscribers
+    pub output: Option<PathBuf>,
+    /// absolute path of the pipe the data of the clients is
+    /// written to, the channel is read-only when not set
+    pub input: Option<PathBuf>,
+}
+
+pub struct Subscriber {
+    user: String,
+    /// identifier of the subscription, see [`telemetry::session_id`]
+    session: String,
+    /// whether the subscriber may write to the input pipe
+    writer: bool,
+    /// counters of the subscriber
+    stats: Stats,
+    /// span of the subscription, see [`crate::telemetry`]
+    span: Option<Span>,
+}
+
+impl Drop for Subscriber {
+    fn drop(&mut self) {
+        clients::end_span(&mut self.span, &self.stats);
+    }
+}
+
+crate::member!(Subscriber);
+
+/// Open a pipe without blocking. The output pipe is also opened
+/// for writing, so that it never reaches its end when the
+/// application closes it, and the input pipe fails with ENXIO
+/// while no application reads it
+fn open(path: &Path, output: bool) -> io::Result<File> {
+    OpenOptions::new()
+        .read(output)
+        .write(true)
+        .custom_flags(libc::O_NONBLOCK)
+        .open(path)
+}
+
+/// Backend relaying two named pipes
+pub struct Fifo {
+    clients: HashMap<u16, Subscriber>,
+    output: File,
+    /// opened on the first data of the clients, and again once
+    /// the application has closed it
+    input: Option<File>,
+    /// recent output for the new subscribers, see `replay_bytes`
+    replay: Replay,
+    bytes_out: u64,
+}
+
+impl Fifo {
+    pub fn new(config: &Config) -> Result<Fifo, Box<dyn std::error::Error>> {
+        let path = config
+            .fifo
+            .output
+            .as_ref()
+            .ok_or("fifo.output: missing value")?;
+        let output =
+            open(path, true).map_err(|e| format!("Unable to open {}: {}", path.display(), e))?;
+        INFO!("Read the output of {}", path.display());
+        Ok(Fifo {
+            clients: HashMap::new(),
+            output,
+            input: None,
+            replay: Replay::default(),
+            bytes_out: 0,
+        })
+    }
+
+    /// Read the output pipe, none when it is empty
+    fn read_output(&mut self, config: &Config) -> io::Result<Option<Vec<u8>>> {
+        let mut buf = vec![0; config.buffer_size];
+        match self.output.read(&mut buf) {
+            Ok(n) if n > 0 => {
+                buf.truncate(n);
+                self.bytes_out += n as u64;
+                metrics::add(Counter::BytesOut, n as u64);
+                self.replay.push(&buf, config);
+                Ok(Some(buf))
+            }
+            Ok(_) => Ok(None),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Write some data of a client unless the input pipe is too
+    /// full to take it without blocking, e.g. when the
+    /// application does not read it. Return whether the data
+    /// was written, an error when no application reads the pipe
+    fn write_input(&mut self, data: &[u8], config: &Config) -> io::Result<bool> {
+        if self.input.is_none() {
+            let path =
+                config.fifo.input.as_ref().ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::Unsupported, "Read-only channel")
+                })?;
+            self.input = Some(open(path, false)?);
+        }
+        let input = match self.input.as_mut() {
+            None => return Ok(false),
+            Some(input) => input,
+        };
+        let fd = input.as_raw_fd();
+        let size = unsafe { libc::fcntl(fd, libc::F_GETPIPE_SZ) };
+        let mut pending: libc::c_int = 0;
+        if unsafe { libc::ioctl(fd, libc::FIONREAD, &mut pending) } < 0 {
+            pending = 0;
+        }
+        if size >= 0 && pending as usize + data.len() > size as usize {
+            return Ok(false);
+        }
+        if let Err(e) = input.write_all(data) {
+            // the application has closed the pipe, it is opened
+            // again on the next data
+            self.input = None;
+            return Err(e);
+        }
+        Ok(true)
+    }
+
+    fn handle_message(
+        &mut self,
+        msg: &Msg,
+        config: &Config,
+        topic: &mut Topic,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if !matches!(msg.kind, MsgKind::ChannelData) {
+            DEBUG!(
+                "Receive message kind {} from client {}: {} bytes",
+                msg.kind,
+                msg.client_id,
+                msg.size
+            );
+        } else if let Some((frames, bytes)) =
+            log::throttle(Level::Debug, "receive", msg.client_id, msg.size as usize)
+        {
+            DEBUG!(
+                "Receive {} data frame(s) from client {}: {} bytes",
+                frames,
+                msg.client_id,
+                bytes
+            );
+        }
+        if let Some(client) = self.clients.get_mut(&msg.client_id) {
+            client.stats.received();
+        }
+        match msg.kind {
+            MsgKind::ChannelSubscribe => {
+                let Some(Subscription {
+                    user,
+                    session,
+                    context: _context,
+                }) = clients::subscribe(msg, config, topic, |_| {
+                    Ok(self
+                        .clients
+                        .remove(&msg.client_id)
+                        .map(|old| old.user.clone()))
+                })?
+                else {
+                    return Ok(());
+                };
+                if !self.make_room(msg.client_id, config, topic)? {
+                    return Ok(());
+                }
+                let writer = config.fifo.input.is_some() && config.can_write(&user);
+                let mut span = clients::span(&config.topic, msg.client_id, &user, &session);
+                span.set("antd.writer", writer);
+                audit::log(config, msg.client_id, &user, &session, Event::Subscribe);
+                self.clients.insert(
+                    msg.client_id,
+                    Subscriber {
+                        user,
+                        session,
+                        writer,
+                        stats: Stats::default(),
+                        span: Some(span),
+                    },
+                );
+                INFO!(
+                    "Client {} subscribe to channel {}",
+                    msg.client_id,
+                    &config.topic
+                );
+                clients::welcome(msg.client_id, config, topic)?;
+                // catch up with the recent output
+                if let Some(data) = self.replay.content() {
+                    let msg = Msg::create(MsgKind::ChannelData, 0, msg.client_id, data);
+                    frame::send(topic, &msg)?;
+                }
+            }
+            MsgKind::ChannelUnsubscribe => {
+                self.unsubscribe(msg.client_id, config, topic)?;
+            }
+            MsgKind::ChannelUnsubscribeAll => self.unsubscribe_all(config, topic)?,
+            MsgKind::ChannelData => {
+                let writer = match self.clients.get(&msg.client_id) {
+                    None => {
+                        WARN!("Client {} is not in the list", msg.client_id);
+                        return Ok(());
+                    }
+                    Some(subscriber) => subscriber.writer,
+                };
+                if !writer {
+                    WARN!("Refuse data of client {}: read-only", msg.client_id);
+                    frame::send(
+                        topic,
+                        &frame::error(msg.client_id, "Read-only subscription"),
+                    )?;
+                    return Ok(());
+                }
+                let written = match self.write_input(&msg.data, config) {
+                    Ok(written) => written,
+                    Err(e) if e.raw_os_error() == Some(libc::ENXIO) => {
+                        let reason = "Application is not running";
+                        return frame::send(topic, &frame::error(msg.client_id, reason));
+                    }
+                    Err(e) => return Err(e).class(Class::ChildIo),
+                };
+                let n = msg.data.len() as u64;
+                if let Some(subscriber) = self.clients.get_mut(&msg.client_id) {
+                    if written {
+                        subscriber.stats.bytes_in += n;
+                        metrics::add(Counter::BytesIn, n);
+                    } else {
+                        subscriber.stats.dropped_in += n;
+                        metrics::add(Counter::BytesDropped, n);
+                        WARN!(
+                            "Drop {} bytes of client {}: input pipe full, {} bytes dropped in total",
+                            n,
+                            msg.client_id,
+                            subscriber.stats.dropped_in
+                        );
+                        frame::send(
+                            topic,
+                            &frame::dropped(
+                                msg.client_id,
+                                "Application is not reading its input",
+                                n,
+                            ),
+                        )?;
+                    }
+                }
+            }
+            MsgKind::ChannelCtrl if stats::is_query(&msg.data) => {
+                self.status(msg.client_id, topic)?;
+            }
+            _ => {
+                WARN!(
+                    "Receive message kind {} from client {}",
+                    msg.kind,
+                    msg.client_id
+                );
+            }
+        };
+        Ok(())
+    }
+}
+
+impl Roster for Fifo {
+    type Client = Subscriber;
+
+    fn table(&self) -> &HashMap<u16, Subscriber> {
+        &self.clients
+    }
+
+    fn table_mut(&mut self) -> &mut HashMap<u16, Subscriber> {
+        &mut self.clients
+    }
+}
+
+impl Backend for Fifo {
+    fn fds(&self) -> Vec<RawFd> {
+        vec![self.output.as_raw_fd()]
+    }
+
+    fn clients(&self) -> usize {
+        self.clients.len()
+    }
+
+    fn dump(&self) -> Vec<String> {
+        let mut lines = vec![format!(
+            "{} client(s), input pipe {}, {} bytes out, {} bytes of replay",
+            self.clients.len(),
+            match self.input {
+                Some(_) => "open",
+                None => "closed",
+            },
+            self.bytes_out,
+            self.replay.size()
+        )];
+        let mut keys: Vec<&u16> = self.clients.keys().collect();
+        keys.sort();
+        for key in keys {
+            let s = &self.clients[key];
+            lines.push(format!(
+                "client {}: user {}{}, {}",
+                key,
+                s.user,
+                if s.writer { "" } else { " (read-only)" },
+                s.stats.describe()
+            ));
+        }
+        lines
+    }
+
+    fn step_handle(
+        &mut self,
+        evt: &CallbackEvent,
+        config: &Config,
+        topic: &mut Topic,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if let Some(msg) = evt.msg {
+            self.scoped(msg, config, topic, |fifo, topic| {
+                fifo.handle_message(msg, config, topic)
+            })?;
+        }
+        if evt.fd == Some(self.output.as_raw_fd()) && evt.event.is_some_and(|e| e.is_readable()) {
+            if let Some(data) = self.read_output(config).class(Class::ChildIo)? {
+                if let Some((reads, bytes)) = log::throttle(Level::Debug, "send", 0, data.len()) {
+                    DEBUG!(
+                        "Sending {} bytes of raw data to all clients in {} read(s)",
+                        bytes,
+                        reads
+                    );
+                }
+                for (key, subscriber) in self.clients.iter_mut() {
+                    let msg = Msg::create(MsgKind::ChannelData, 0, *key, data.clone());
+                    frame::send(topic, &msg)?;
+                    subscriber.stats.sent(data.len());
+                }
+            }
+        }
+        self.enforce_windows(config, topic)?;
+        self.reap_idle(config, topic)?;
+        Ok(())
+    }
+
+    fn children_exited(
+        &mut self,
+        _config: &Config,
+        _topic: &mut Topic,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        Ok(())
+    }
+
+    fn disconnected(&mut self, _config: &Config) {
+        self.clients.clear();
+    }
+
+    fn step_offline(&mut self, config: &Config) {
+        // keep the pipe flowing, so that the application does
+        // not block on it, the output goes to the replay buffer
+        loop {
+            match self.read_output(config) {
+                Ok(Some(_)) => continue,
+                Ok(None) => break,
+                Err(error) => {
+                    WARN!("Unable to read the output pipe: {}", error);
+                    break;
+                }
+            }
+        }
+    }
+
+    fn shutdown(
+        &mut self,
+        _config: &Config,
+        topic: &mut Topic,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.notify_shutdown(topic)?;
+        self.clients.clear();
+        Ok(())
+    }
+}
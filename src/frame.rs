@@ -0,0 +1,145 @@
+//! # Helpers to build the frames sent to clients
+//!
+//! The frames are sent with [`send`], which traces them along
+//! with the received ones when `trace_frames` is set
+//!
+//! **Author**: "Dany LE"
+//!
+use crate::error::{Class, Classify};
+use crate::metrics;
+use crate::profile::{self, Phase};
+use crate::redact;
+use crate::stats::Stats;
+use crate::DEBUG;
+use latpr::tunnel::{Msg, MsgKind, Topic};
+use latpr::utils::{LogLevel, LOG};
+use serde_json::json;
+use std::os::unix::process::ExitStatusExt;
+use std::process::ExitStatus;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+/// bytes of the payload dumped when tracing a frame
+const TRACE_BYTES: usize = 32;
+
+static TRACE: AtomicBool = AtomicBool::new(false);
+
+/// Trace the frames sent and received, see `trace_frames`
+pub fn set_trace(enabled: bool) {
+    TRACE.store(enabled, Ordering::Relaxed);
+}
+
+/// Log a frame with its kind, client id, size and the hex dump
+/// of the start of its payload when tracing, `direction` is
+/// `<-` for a received frame and `->` for a sent one
+pub fn trace(direction: &str, msg: &Msg) {
+    if !TRACE.load(Ordering::Relaxed) {
+        return;
+    }
+    let data = if redact::enabled() {
+        redact::bytes(&msg.data)
+    } else {
+        msg.data.clone()
+    };
+    let head = &data[..data.len().min(TRACE_BYTES)];
+    let hex: Vec<String> = head.iter().map(|b| format!("{:02x}", b)).collect();
+    let text: String = head
+        .iter()
+        .map(|b| {
+            if b.is_ascii_graphic() || *b == b' ' {
+                *b as char
+            } else {
+                '.'
+            }
+        })
+        .collect();
+    let more = if data.len() > head.len() {
+        format!(" (+{} bytes)", data.len() - head.len())
+    } else {
+        String::new()
+    };
+    DEBUG!(
+        "{} {} client {} channel {}, {} bytes: {} |{}|{}",
+        direction,
+        msg.kind,
+        msg.client_id,
+        msg.channel_id,
+        msg.data.len(),
+        hex.join(" "),
+        text,
+        more
+    );
+}
+
+/// Send a frame on a topic
+pub fn send(topic: &mut Topic, msg: &Msg) -> Result<(), Box<dyn std::error::Error>> {
+    trace("->", msg);
+    let started = Instant::now();
+    let result = topic.write(msg);
+    metrics::observe_write(started.elapsed());
+    profile::record(Phase::Topic, started.elapsed());
+    result.class(Class::Tunnel)
+}
+
+/// Build an error frame carrying a human readable reason
+pub fn error(client_id: u16, reason: &str) -> Msg {
+    Msg::create(
+        MsgKind::ChannelError,
+        0,
+        client_id,
+        reason.as_bytes().to_vec(),
+    )
+}
+
+/// Build an error frame telling a client that some of its
+/// data was dropped, e.g. `Input pipe full: 512 bytes dropped`
+pub fn dropped(client_id: u16, reason: &str, bytes: u64) -> Msg {
+    error(client_id, &format!("{}: {} bytes dropped", reason, bytes))
+}
+
+/// Build a control frame answering a status query, e.g.
+/// `{"status": {"bytes_in": 12, "bytes_out": 2048, ...}}`
+pub fn status(client_id: u16, stats: &Stats) -> Msg {
+    let body = json!({ "status": stats.to_json() });
+    Msg::create(
+        MsgKind::ChannelCtrl,
+        0,
+        client_id,
+        body.to_string().into_bytes(),
+    )
+}
+
+/// Build a control frame reporting the resource usage of the
+/// process of a client, e.g.
+/// `{"usage": {"cpu_ms": 1520, "rss": 10485760, "bytes_in": 12, "bytes_out": 2048}}`
+pub fn usage(client_id: u16, cpu: Duration, rss: u64, stats: &Stats) -> Msg {
+    let body = json!({
+        "usage": {
+            "cpu_ms": cpu.as_millis() as u64,
+            "rss": rss,
+            "bytes_in": stats.bytes_in,
+            "bytes_out": stats.bytes_out,
+        }
+    });
+    Msg::create(
+        MsgKind::ChannelCtrl,
+        0,
+        client_id,
+        body.to_string().into_bytes(),
+    )
+}
+
+/// Build a control frame carrying the exit status of the process
+/// of a client, e.g. `{"exit_code": 0}` or `{"signal": 9}`
+pub fn exit_status(client_id: u16, status: ExitStatus) -> Msg {
+    let body = match (status.code(), status.signal()) {
+        (Some(code), _) => json!({ "exit_code": code }),
+        (None, signal) => json!({ "signal": signal }),
+    };
+    Msg::create(
+        MsgKind::ChannelCtrl,
+        0,
+        client_id,
+        body.to_string().into_bytes(),
+    )
+}
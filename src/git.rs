@@ -0,0 +1,348 @@
+//! # Git operations over a channel
+//!
+//! The clients of `shgit` send requests in data frames to run
+//! a few git operations on the configured repositories, e.g.
+//! for the code editor of the frontend, without a shell:
+//!
+//! ```text
+//! -> data {"id": 1, "op": "status", "repo": "site"}
+//! <- data {"id": 1, "result": {"branch": "main", "upstream": "origin/main", "ahead": 1, "behind": 0, "files": [{"path": "index.html", "index": "M", "worktree": " "}]}}
+//! -> data {"id": 2, "op": "log", "repo": "site", "max": 1}
+//! <- data {"id": 2, "result": [{"hash": "4bd4c2a...", "author": "Dany LE", "email": "...", "time": 1700000000, "subject": "..."}]}
+//! ```
+//!
+//! The requests are run as those of shexec, see [`crate::exec`],
+//! this module parses them and builds the replies
+//!
+//! **Author**: "Dany LE"
+//!
+use crate::exec::Job;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::process::ExitStatus;
+
+/// default of `git.command`
+const GIT: &str = "/usr/bin/git";
+/// default of `git.max_log`
+const MAX_LOG: usize = 100;
+/// commits of a log when the request sets none
+const LOG: usize = 20;
+/// separator of the fields of a commit in the log
+const FIELD: char = '\x1f';
+
+/// A repository, e.g.
+///
+/// ```toml
+/// [git.repos.site]
+/// path = "/srv/git/site"
+/// url = "https://git.example.com/site.git"
+/// ```
+#[derive(Deserialize, Serialize, Default, Debug, Clone, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Repo {
+    /// absolute path of the working tree
+    pub path: PathBuf,
+    /// remote cloned into `path`, the repository cannot be
+    /// cloned when not set
+    pub url: Option<String>,
+}
+
+/// Settings of the git mode
+#[derive(Deserialize, Serialize, Default, Debug, Clone, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct GitSettings {
+    /// absolute path of git, `/usr/bin/git` when not set
+    pub command: Option<String>,
+    /// repositories by name, the only ones the clients can use
+    pub repos: BTreeMap<String, Repo>,
+    /// most commits of a log, 100 when not set
+    pub max_log: Option<usize>,
+}
+
+impl GitSettings {
+    pub fn command(&self) -> &str {
+        self.command.as_deref().unwrap_or(GIT)
+    }
+
+    pub fn max_log(&self) -> usize {
+        self.max_log.unwrap_or(MAX_LOG)
+    }
+}
+
+/// An operation of a request
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Op {
+    Clone,
+    Pull,
+    Status,
+    Log,
+}
+
+impl Op {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Op::Clone => "clone",
+            Op::Pull => "pull",
+            Op::Status => "status",
+            Op::Log => "log",
+        }
+    }
+}
+
+/// A request being run, for its reply
+#[derive(Debug, Clone)]
+pub struct Call {
+    pub id: Value,
+    pub op: Op,
+    pub repo: String,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Request {
+    #[serde(default)]
+    id: Value,
+    op: Op,
+    repo: String,
+    /// commits of a log
+    max: Option<usize>,
+}
+
+/// Parse a request into the job running git, or the error
+/// reply
+pub fn parse(data: &[u8], settings: &GitSettings) -> Result<Job, Value> {
+    let request: Request = match serde_json::from_slice(data) {
+        Ok(request) => request,
+        Err(e) => return Err(error(&Value::Null, &format!("Invalid request: {}", e))),
+    };
+    let id = request.id;
+    let repo = match settings.repos.get(&request.repo) {
+        None => return Err(error(&id, &format!("Unknown repository {}", request.repo))),
+        Some(repo) => repo,
+    };
+    let path = repo.path.to_string_lossy();
+    let args: Vec<String> = match request.op {
+        Op::Clone => match repo.url.as_ref() {
+            None => return Err(error(&id, "The repository cannot be cloned")),
+            Some(url) => vec![
+                "clone".into(),
+                "--quiet".into(),
+                "--".into(),
+                url.clone(),
+                path.into(),
+            ],
+        },
+        Op::Pull => vec![
+            "-C".into(),
+            path.into(),
+            "pull".into(),
+            "--ff-only".into(),
+            "--quiet".into(),
+        ],
+        Op::Status => vec![
+            "-C".into(),
+            path.into(),
+            "status".into(),
+            "--porcelain=v1".into(),
+            "--branch".into(),
+            "-z".into(),
+        ],
+        Op::Log => vec![
+            "-C".into(),
+            path.into(),
+            "log".into(),
+            format!(
+                "--max-count={}",
+                request.max.unwrap_or(LOG).min(settings.max_log())
+            ),
+            "-z".into(),
+            format!("--format=%H{0}%an{0}%ae{0}%at{0}%s", FIELD),
+        ],
+    };
+    Ok(Job {
+        command: Some(String::from(settings.command())),
+        args,
+        input: Vec::new(),
+        call: None,
+        git: Some(Call {
+            id,
+            op: request.op,
+            repo: request.repo,
+        }),
+        svc: None,
+    })
+}
+
+/// Reply of a request once git has exited
+pub fn reply(call: &Call, status: ExitStatus, output: &[u8], dropped: u64) -> Value {
+    if !status.success() {
+        let message = format!("git {} failed: {}", call.op.name(), status);
+        return error(&call.id, &message);
+    }
+    if dropped > 0 {
+        return error(&call.id, "Output truncated");
+    }
+    let text = String::from_utf8_lossy(output);
+    let result = match call.op {
+        Op::Clone | Op::Pull => json!({ "repo": call.repo }),
+        Op::Status => status_of(&text),
+        Op::Log => log_of(&text),
+    };
+    json!({ "id": call.id, "result": result })
+}
+
+/// An error reply, e.g. `{"id": 1, "error": {"message": "..."}}`
+pub fn error(id: &Value, message: &str) -> Value {
+    json!({ "id": id, "error": { "message": message } })
+}
+
+/// Parse the output of `git status --porcelain=v1 --branch -z`
+fn status_of(text: &str) -> Value {
+    let mut result = json!({ "branch": Value::Null, "files": [] });
+    let mut files = Vec::new();
+    let mut entries = text.split('\0').filter(|e| !e.is_empty());
+    while let Some(entry) = entries.next() {
+        // e.g. `## main...origin/main [ahead 1, behind 2]`, or
+        // `## No commits yet on main` in an empty repository
+        if let Some(header) = entry.strip_prefix("## ") {
+            let header = ["No commits yet on ", "Initial commit on "]
+                .iter()
+                .find_map(|p| header.strip_prefix(p))
+                .unwrap_or(header);
+            let (refs, counts) = match header.split_once(" [") {
+                Some((refs, counts)) => (refs, counts.trim_end_matches(']')),
+                None => (header, ""),
+            };
+            match refs.split_once("...") {
+                Some((branch, upstream)) => {
+                    result["branch"] = json!(branch);
+                    result["upstream"] = json!(upstream);
+                    result["ahead"] = json!(0);
+                    result["behind"] = json!(0);
+                }
+                None => result["branch"] = json!(refs),
+            }
+            for count in counts.split(", ") {
+                if let Some((key, n)) = count.split_once(' ') {
+                    if let Ok(n) = n.parse::<u64>() {
+                        result[key] = json!(n);
+                    }
+                }
+            }
+            continue;
+        }
+        if entry.len() < 4 || !entry.is_char_boundary(3) {
+            continue;
+        }
+        let (codes, path) = entry.split_at(3);
+        let (index, worktree) = codes.split_at(1);
+        let mut file = json!({
+            "path": path,
+            "index": index,
+            "worktree": &worktree[..1],
+        });
+        // the original path of a rename or a copy follows
+        if matches!(index, "R" | "C") {
+            if let Some(from) = entries.next() {
+                file["from"] = json!(from);
+            }
+        }
+        files.push(file);
+    }
+    result["files"] = Value::Array(files);
+    result
+}
+
+/// Parse the output of `git log -z` with the fields separated
+/// by [`FIELD`]
+fn log_of(text: &str) -> Value {
+    let commits: Vec<Value> = text
+        .split('\0')
+        .filter(|c| !c.is_empty())
+        .filter_map(|commit| {
+            let mut fields = commit.trim_start_matches('\n').splitn(5, FIELD);
+            let hash = fields.next()?;
+            let author = fields.next()?;
+            let email = fields.next()?;
+            let time = fields.next()?.parse::<u64>().unwrap_or(0);
+            let subject = fields.next().unwrap_or("");
+            Some(json!({
+                "hash": hash,
+                "author": author,
+                "email": email,
+                "time": time,
+                "subject": subject,
+            }))
+        })
+        .collect();
+    Value::Array(commits)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn status_of_branch() {
+        let status = status_of("## main...origin/main [ahead 1, behind 2]\0");
+        assert_eq!(status["branch"], "main");
+        assert_eq!(status["upstream"], "origin/main");
+        assert_eq!(status["ahead"], 1);
+        assert_eq!(status["behind"], 2);
+        assert_eq!(status["files"], json!([]));
+    }
+
+    #[test]
+    fn status_of_no_commits() {
+        let status = status_of("## No commits yet on main\0?? a\0");
+        assert_eq!(status["branch"], "main");
+        assert!(status.get("upstream").is_none());
+        assert_eq!(
+            status["files"],
+            json!([{ "path": "a", "index": "?", "worktree": "?" }])
+        );
+    }
+
+    #[test]
+    fn status_of_rename() {
+        let status = status_of("## main\0R  b\0a\0 M c\0");
+        assert_eq!(
+            status["files"],
+            json!([
+                { "path": "b", "index": "R", "worktree": " ", "from": "a" },
+                { "path": "c", "index": " ", "worktree": "M" },
+            ])
+        );
+    }
+
+    #[test]
+    fn log_of_commits() {
+        let text = format!(
+            "h1{0}Ann{0}ann@example.org{0}1700000000{0}First{0}line\0\nh2{0}Bob{0}bob@example.org{0}x{0}\0",
+            FIELD
+        );
+        assert_eq!(
+            log_of(&text),
+            json!([
+                {
+                    "hash": "h1",
+                    "author": "Ann",
+                    "email": "ann@example.org",
+                    "time": 1700000000,
+                    "subject": format!("First{}line", FIELD),
+                },
+                {
+                    "hash": "h2",
+                    "author": "Bob",
+                    "email": "bob@example.org",
+                    "time": 0,
+                    "subject": "",
+                },
+            ])
+        );
+        assert_eq!(log_of(""), json!([]));
+    }
+}
@@ -0,0 +1,109 @@
+//! # Health check socket
+//!
+//! With `health_socket`, the backend listens on a Unix socket
+//! and answers each connection with a JSON line describing its
+//! health, then closes it, e.g. for `socat - UNIX:<path>`:
+//!
+//! ```json
+//! {"healthy": true, "hub": true, "process": true, "last_step_ms": 12, "clients": 2, "draining": false}
+//! ```
+//!
+//! **Author**: "Dany LE"
+//!
+use crate::WARN;
+use latpr::utils::{LogLevel, LOG};
+use serde_json::{json, Value};
+use std::fs;
+use std::io::{self, Write};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixListener;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// the event loop is considered stuck beyond this time
+/// without a step
+const STALE_STEP: Duration = Duration::from_secs(5);
+/// time given to a client to read the report
+const WRITE_TIMEOUT: Duration = Duration::from_millis(200);
+
+/// State of the backend reported on the socket
+pub struct Report {
+    /// whether the topic is open on the hub
+    pub hub: bool,
+    /// whether the process of the backend runs, none for the
+    /// modes with a process per client
+    pub process: Option<bool>,
+    /// time since the last step of the event loop
+    pub last_step: Duration,
+    pub clients: usize,
+    pub draining: bool,
+}
+
+impl Report {
+    pub fn to_json(&self) -> Value {
+        let healthy = self.hub && self.process != Some(false) && self.last_step < STALE_STEP;
+        json!({
+            "healthy": healthy,
+            "hub": self.hub,
+            "process": self.process,
+            "last_step_ms": self.last_step.as_millis() as u64,
+            "clients": self.clients,
+            "draining": self.draining,
+        })
+    }
+}
+
+pub struct HealthSocket {
+    listener: UnixListener,
+    path: PathBuf,
+}
+
+impl HealthSocket {
+    /// Listen on a socket, replacing the one left by a previous
+    /// instance of the backend
+    pub fn bind(path: &Path) -> Result<HealthSocket, Box<dyn std::error::Error>> {
+        if path.exists() {
+            fs::remove_file(path)?;
+        }
+        let listener = UnixListener::bind(path)
+            .map_err(|e| format!("Unable to listen on {}: {}", path.display(), e))?;
+        listener.set_nonblocking(true)?;
+        Ok(HealthSocket {
+            listener,
+            path: path.to_path_buf(),
+        })
+    }
+
+    pub fn fd(&self) -> RawFd {
+        self.listener.as_raw_fd()
+    }
+
+    /// Answer the pending connections with a report
+    pub fn serve(&self, report: &Report) {
+        let mut line = report.to_json().to_string();
+        line.push('\n');
+        loop {
+            let mut stream = match self.listener.accept() {
+                Ok((stream, _)) => stream,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return,
+                Err(error) => {
+                    WARN!("Unable to accept a health check: {}", error);
+                    return;
+                }
+            };
+            let written = stream
+                .set_nonblocking(false)
+                .and_then(|_| stream.set_write_timeout(Some(WRITE_TIMEOUT)))
+                .and_then(|_| stream.write_all(line.as_bytes()));
+            if let Err(error) = written {
+                WARN!("Unable to answer a health check: {}", error);
+            }
+        }
+    }
+}
+
+impl Drop for HealthSocket {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
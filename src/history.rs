@@ -0,0 +1,155 @@
+//! # History of the processes
+//!
+//! Each process run by the backend is recorded once it has
+//! exited to the append-only `history_file`, one JSON object
+//! per line, for the operators to audit what ran through each
+//! topic with `shbackend history`
+//!
+//! **Author**: "Dany LE"
+//!
+use crate::config::Config;
+use crate::WARN;
+use latpr::utils::{LogLevel, LOG};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::os::unix::process::ExitStatusExt;
+use std::path::Path;
+use std::process::ExitStatus;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// A process being run
+#[derive(Debug, Clone)]
+pub struct Run {
+    pub client_id: u16,
+    /// session of the process, see [`crate::telemetry::session_id`]
+    pub session: String,
+    pub command: String,
+    pub pid: u32,
+    /// seconds since the epoch
+    pub start: u64,
+}
+
+impl Run {
+    pub fn start(client_id: u16, session: &str, command: &Path, pid: u32) -> Run {
+        Run {
+            client_id,
+            session: String::from(session),
+            command: command.display().to_string(),
+            pid,
+            start: now(),
+        }
+    }
+}
+
+/// An entry of the history file
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Entry {
+    pub topic: String,
+    pub client_id: u16,
+    pub user: String,
+    /// empty in the entries written before the sessions had one
+    #[serde(default)]
+    pub session_id: String,
+    pub command: String,
+    pub pid: u32,
+    /// seconds since the epoch
+    pub start: u64,
+    pub end: u64,
+    /// exit code, none when killed by a signal
+    pub exit_code: Option<i32>,
+    pub signal: Option<i32>,
+}
+
+/// Record a process that has exited, a failure is logged only
+pub fn record(config: &Config, user: &str, run: &Run, status: ExitStatus) {
+    let path = match config.history_file.as_ref() {
+        None => return,
+        Some(path) => path,
+    };
+    let entry = Entry {
+        topic: config.topic.clone(),
+        client_id: run.client_id,
+        user: String::from(user),
+        session_id: run.session.clone(),
+        command: run.command.clone(),
+        pid: run.pid,
+        start: run.start,
+        end: now(),
+        exit_code: status.code(),
+        signal: status.signal(),
+    };
+    if let Err(error) = append(path, &entry) {
+        WARN!(
+            "Unable to write the history to {}: {}",
+            path.display(),
+            error
+        );
+    }
+}
+
+fn append(path: &Path, entry: &Entry) -> Result<(), Box<dyn std::error::Error>> {
+    let mut line = serde_json::to_vec(entry)?;
+    line.push(b'\n');
+    // a single write per entry, the lines of concurrent
+    // backends sharing the file are not interleaved
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?
+        .write_all(&line)?;
+    Ok(())
+}
+
+/// Read the history, the malformed lines are skipped
+pub fn read(path: &Path) -> Result<Vec<Entry>, Box<dyn std::error::Error>> {
+    let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    Ok(text
+        .lines()
+        .filter_map(|line| serde_json::from_str(line).ok())
+        .collect())
+}
+
+/// One line description of an entry, in local time
+pub fn describe(entry: &Entry) -> String {
+    let status = match (entry.exit_code, entry.signal) {
+        (Some(code), _) => format!("exit {}", code),
+        (None, Some(signal)) => format!("signal {}", signal),
+        _ => String::from("unknown"),
+    };
+    format!(
+        "{}  {:>6}s  {:<12} {:<10} client {:<5} pid {:<7} {:<10} {}",
+        local_time(entry.start),
+        entry.end.saturating_sub(entry.start),
+        entry.topic,
+        entry.user,
+        entry.client_id,
+        entry.pid,
+        status,
+        entry.command
+    )
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+/// Format a unix time in the local time zone
+pub fn local_time(secs: u64) -> String {
+    unsafe {
+        let t = secs as libc::time_t;
+        let mut tm: libc::tm = std::mem::zeroed();
+        libc::localtime_r(&t, &mut tm);
+        format!(
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            tm.tm_year + 1900,
+            tm.tm_mon + 1,
+            tm.tm_mday,
+            tm.tm_hour,
+            tm.tm_min,
+            tm.tm_sec
+        )
+    }
+}
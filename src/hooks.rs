@@ -0,0 +1,119 @@
+//! # Lifecycle hooks
+//!
+//! External programs run on the events of the sessions, e.g.
+//! to send a notification, with the context of the event in
+//! environment variables. The hooks are not waited for: they
+//! are reaped on SIGCHLD like the dropped processes
+//!
+//! **Author**: "Dany LE"
+//!
+use crate::audit::Event;
+use crate::config::Config;
+use crate::process::Process;
+use crate::WARN;
+use latpr::utils::{LogLevel, LOG};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+
+/// Program run on each event, by name of the event, e.g.
+///
+/// ```toml
+/// [hooks]
+/// spawn = "/usr/local/bin/notify-spawn"
+/// quota = "/usr/local/bin/page-oncall"
+/// ```
+#[derive(Deserialize, Serialize, Default, Debug, Clone, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Hooks {
+    pub subscribe: Option<PathBuf>,
+    /// subscription refused
+    pub refuse: Option<PathBuf>,
+    pub unsubscribe: Option<PathBuf>,
+    pub spawn: Option<PathBuf>,
+    /// signal sent to a process by the backend
+    pub signal: Option<PathBuf>,
+    pub exit: Option<PathBuf>,
+    /// limit reached by a session
+    pub quota: Option<PathBuf>,
+    /// file read or written by a client
+    pub transfer: Option<PathBuf>,
+}
+
+impl Hooks {
+    fn get(&self, event: &str) -> Option<&PathBuf> {
+        match event {
+            "subscribe" => self.subscribe.as_ref(),
+            "refuse" => self.refuse.as_ref(),
+            "unsubscribe" => self.unsubscribe.as_ref(),
+            "spawn" => self.spawn.as_ref(),
+            "signal" => self.signal.as_ref(),
+            "exit" => self.exit.as_ref(),
+            "quota" => self.quota.as_ref(),
+            "transfer" => self.transfer.as_ref(),
+            _ => None,
+        }
+    }
+
+    /// The configured hooks, by name of the event
+    pub fn all(&self) -> Vec<(&'static str, &PathBuf)> {
+        [
+            ("subscribe", self.subscribe.as_ref()),
+            ("refuse", self.refuse.as_ref()),
+            ("unsubscribe", self.unsubscribe.as_ref()),
+            ("spawn", self.spawn.as_ref()),
+            ("signal", self.signal.as_ref()),
+            ("exit", self.exit.as_ref()),
+            ("quota", self.quota.as_ref()),
+            ("transfer", self.transfer.as_ref()),
+        ]
+        .into_iter()
+        .filter_map(|(name, path)| path.map(|p| (name, p)))
+        .collect()
+    }
+}
+
+/// Run the hook of an event, if any. The program gets
+/// `ANTD_EVENT`, `ANTD_TOPIC`, `ANTD_CLIENT_ID`, `ANTD_USER`,
+/// `ANTD_SESSION_ID` and the fields of the event, e.g. `ANTD_PID`
+pub fn fire(config: &Config, client_id: u16, user: &str, session: &str, event: &Event) {
+    let fields = match event.to_json() {
+        Value::Object(fields) => fields,
+        _ => return,
+    };
+    let name = fields
+        .get("event")
+        .and_then(|v| v.as_str())
+        .unwrap_or_default();
+    let path = match config.hooks.get(name) {
+        None => return,
+        Some(path) => path,
+    };
+    let mut command = Command::new(path);
+    command
+        .env("ANTD_TOPIC", &config.topic)
+        .env("ANTD_CLIENT_ID", client_id.to_string())
+        .env("ANTD_USER", user)
+        .env("ANTD_SESSION_ID", session)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null());
+    for (key, value) in fields.iter() {
+        let value = match value {
+            Value::Null => continue,
+            Value::String(s) => s.clone(),
+            other => other.to_string(),
+        };
+        command.env(format!("ANTD_{}", key.to_uppercase()), value);
+    }
+    match command.spawn() {
+        // reaped once exited, see [`crate::process::reap_dropped`]
+        Ok(child) => drop(Process::from(child)),
+        Err(error) => WARN!(
+            "Unable to run the {} hook {}: {}",
+            name,
+            path.display(),
+            error
+        ),
+    }
+}
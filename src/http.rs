@@ -0,0 +1,148 @@
+//! # Minimal HTTP client
+//!
+//! JSON documents posted to plain `http://` endpoints, e.g. the
+//! OTLP collector or the webhooks, in batches by a thread so
+//! that a slow endpoint never blocks the event loop
+//!
+//! **Author**: "Dany LE"
+//!
+use crate::WARN;
+use latpr::utils::{LogLevel, LOG};
+use serde_json::Value;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::sync::mpsc::{self, RecvTimeoutError, SyncSender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+/// delay before the first retry of a failed post, doubled on
+/// each retry
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+/// documents waiting for the thread, beyond that they are dropped
+const QUEUE_SIZE: usize = 4096;
+
+/// An `http://host:port/path` endpoint
+#[derive(Debug, Clone)]
+pub struct Endpoint {
+    /// host and port
+    host: String,
+    path: String,
+}
+
+impl Endpoint {
+    /// Parse an URL, the port defaults to 80 and the path to
+    /// `default_path`
+    pub fn parse(url: &str, default_path: &str) -> Result<Endpoint, Box<dyn std::error::Error>> {
+        let rest = url
+            .strip_prefix("http://")
+            .ok_or("only http:// endpoints are supported")?;
+        let (host, path) = match rest.find('/') {
+            Some(i) => (&rest[..i], &rest[i..]),
+            None => (rest, default_path),
+        };
+        if host.is_empty() {
+            return Err("missing host".into());
+        }
+        let host = if host.contains(':') {
+            String::from(host)
+        } else {
+            format!("{}:80", host)
+        };
+        Ok(Endpoint {
+            host,
+            path: String::from(path),
+        })
+    }
+
+    /// Post a JSON document, any status but 2xx is an error
+    pub fn post(&self, body: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let mut stream = TcpStream::connect(&self.host)?;
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        stream.set_write_timeout(Some(TIMEOUT))?;
+        write!(
+            stream,
+            "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            self.path,
+            self.host,
+            body.len(),
+            body
+        )?;
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response)?;
+        let response = String::from_utf8_lossy(&response);
+        let status = response.lines().next().unwrap_or_default();
+        match status.split_whitespace().nth(1) {
+            Some(code) if code.starts_with('2') => Ok(()),
+            _ => Err(format!("unexpected response: {}", status).into()),
+        }
+    }
+}
+
+/// Batching of the documents posted by [`start`]
+pub struct Batching {
+    /// interval between two posts
+    pub interval: Duration,
+    /// documents posted at most at once
+    pub max_batch: usize,
+    /// attempts after a failed post, the batch is dropped then
+    pub retries: u32,
+}
+
+/// Start a thread posting the documents sent to the returned
+/// queue, in batches wrapped in a single document by `wrap`.
+/// The documents are dropped when the queue is full
+pub fn start(
+    name: &str,
+    endpoint: Endpoint,
+    batching: Batching,
+    wrap: impl Fn(Vec<Value>) -> Value + Send + 'static,
+) -> io::Result<SyncSender<Value>> {
+    let (sender, receiver) = mpsc::sync_channel(QUEUE_SIZE);
+    thread::Builder::new()
+        .name(String::from(name))
+        .spawn(move || {
+            let mut batch = Vec::new();
+            let mut last_post = Instant::now();
+            loop {
+                let timeout = batching.interval.saturating_sub(last_post.elapsed());
+                let closed = match receiver.recv_timeout(timeout) {
+                    Ok(value) => {
+                        batch.push(value);
+                        false
+                    }
+                    Err(RecvTimeoutError::Timeout) => false,
+                    Err(RecvTimeoutError::Disconnected) => true,
+                };
+                let due =
+                    last_post.elapsed() >= batching.interval || batch.len() >= batching.max_batch;
+                if (due || closed) && !batch.is_empty() {
+                    let body = wrap(std::mem::take(&mut batch)).to_string();
+                    post_with_retries(&endpoint, &body, batching.retries);
+                }
+                if due || closed {
+                    last_post = Instant::now();
+                }
+                if closed {
+                    return;
+                }
+            }
+        })?;
+    Ok(sender)
+}
+
+fn post_with_retries(endpoint: &Endpoint, body: &str, retries: u32) {
+    let mut delay = RETRY_DELAY;
+    for attempt in 0..=retries {
+        match endpoint.post(body) {
+            Ok(()) => return,
+            Err(error) if attempt == retries => {
+                WARN!("Unable to post to {}: {}", endpoint.host, error);
+            }
+            Err(_) => {
+                thread::sleep(delay);
+                delay *= 2;
+            }
+        }
+    }
+}
@@ -0,0 +1,121 @@
+//! # systemd journal follow backend
+//!
+//! `shjournal` follows the systemd journal with a single
+//! `journalctl --follow` shared by all the subscribers, as the
+//! process of `shbcast`, so that the live system logs can be
+//! watched from the admin UI without a process per viewer:
+//!
+//! ```text
+//! journalctl --follow --no-pager --lines=0 --output=short-iso [--unit=...] [--priority=...] [--identifier=...]
+//! ```
+//!
+//! **Author**: "Dany LE"
+//!
+use crate::config::Config;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+
+/// journalctl used when none is configured, looked up in `PATH`
+const JOURNALCTL: &str = "journalctl";
+/// format of the entries when none is configured
+const OUTPUT: &str = "short-iso";
+/// output formats of journalctl
+const OUTPUTS: [&str; 16] = [
+    "short",
+    "short-full",
+    "short-iso",
+    "short-iso-precise",
+    "short-precise",
+    "short-monotonic",
+    "short-delta",
+    "short-unix",
+    "verbose",
+    "export",
+    "json",
+    "json-pretty",
+    "json-sse",
+    "json-seq",
+    "cat",
+    "with-unit",
+];
+/// priorities of the journal, from the most to the least urgent
+const PRIORITIES: [&str; 8] = [
+    "emerg", "alert", "crit", "err", "warning", "notice", "info", "debug",
+];
+
+/// Settings of the journal follow mode, e.g.
+///
+/// ```toml
+/// [journal]
+/// units = ["nginx.service", "antd.service"]
+/// priority = "warning"
+/// ```
+#[derive(Deserialize, Serialize, Default, Debug, Clone, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct JournalSettings {
+    /// journalctl executable, `journalctl` in `PATH` by default
+    pub journalctl: Option<String>,
+    /// units whose entries are followed, all when empty
+    pub units: Vec<String>,
+    /// syslog identifiers whose entries are followed, all when
+    /// empty
+    pub identifiers: Vec<String>,
+    /// least urgent priority followed, or a range of them, e.g.
+    /// `err` or `warning..emerg`
+    pub priority: Option<String>,
+    /// entries of the past printed when journalctl starts
+    pub lines: Option<usize>,
+    /// output format of journalctl, `short-iso` by default
+    pub output: Option<String>,
+}
+
+impl JournalSettings {
+    /// journalctl executable
+    pub fn journalctl(&self) -> &str {
+        self.journalctl.as_deref().unwrap_or(JOURNALCTL)
+    }
+}
+
+/// Check a priority: a name or a level from 0 to 7, or a range
+/// of them
+pub fn check_priority(priority: &str) -> Result<(), String> {
+    let valid = |p: &str| PRIORITIES.contains(&p) || p.parse::<u8>().is_ok_and(|n| n < 8);
+    let (from, to) = priority.split_once("..").unwrap_or((priority, priority));
+    if !valid(from) || !valid(to) {
+        return Err(format!("{} is not a priority or a range of them", priority));
+    }
+    Ok(())
+}
+
+/// Check an output format of journalctl
+pub fn check_output(output: &str) -> Result<(), String> {
+    if !OUTPUTS.contains(&output) {
+        return Err(format!("{} is not an output format of journalctl", output));
+    }
+    Ok(())
+}
+
+/// Executable and arguments of the `journalctl` following the
+/// configured entries. journalctl is checked against the
+/// allowlist
+pub fn command_line(config: &Config) -> Result<(PathBuf, Vec<String>), Box<dyn std::error::Error>> {
+    let settings = &config.journal;
+    let journalctl = config.allowed_command(settings.journalctl())?;
+    let mut args = vec![
+        String::from("--follow"),
+        String::from("--no-pager"),
+        format!("--lines={}", settings.lines.unwrap_or(0)),
+        format!("--output={}", settings.output.as_deref().unwrap_or(OUTPUT)),
+    ];
+    args.extend(settings.units.iter().map(|unit| format!("--unit={}", unit)));
+    args.extend(
+        settings
+            .identifiers
+            .iter()
+            .map(|id| format!("--identifier={}", id)),
+    );
+    if let Some(priority) = settings.priority.as_ref() {
+        args.push(format!("--priority={}", priority));
+    }
+    Ok((journalctl, args))
+}
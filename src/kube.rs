@@ -0,0 +1,131 @@
+//! # Kubernetes exec backend
+//!
+//! `shkube` attaches each client to `kubectl exec -i` in a
+//! configured pod, as `shp2p` does with a local command: the
+//! command (or the one chosen from the menu) is run in the
+//! container and its stdio is bridged through the tunnel
+//!
+//! ```text
+//! kubectl [--kubeconfig=...] [--context=...] [--namespace=...] exec -i <pod> [--container=...] -- <command> <args>
+//! ```
+//!
+//! **Author**: "Dany LE"
+//!
+use crate::config::Config;
+use crate::DEBUG;
+use latpr::utils::{LogLevel, LOG};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// kubectl used when none is configured, looked up in `PATH`
+const KUBECTL: &str = "kubectl";
+/// time given to the API server to list the pods of a selector
+const REQUEST_TIMEOUT: &str = "5s";
+
+/// Settings of the Kubernetes exec mode, e.g.
+///
+/// ```toml
+/// command = "/bin/sh"
+///
+/// [kube]
+/// namespace = "shop"
+/// selector = "app=web"
+/// container = "php"
+/// ```
+#[derive(Deserialize, Serialize, Default, Debug, Clone, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct KubeSettings {
+    /// kubectl executable, `kubectl` in `PATH` by default
+    pub kubectl: Option<String>,
+    /// absolute path of the kubeconfig file, the default one of
+    /// kubectl when not set
+    pub kubeconfig: Option<PathBuf>,
+    /// context of the kubeconfig file
+    pub context: Option<String>,
+    pub namespace: Option<String>,
+    /// pod the clients are attached to, or any target of
+    /// `kubectl exec`, e.g. `deploy/web`
+    pub pod: Option<String>,
+    /// label selector of the pods, the clients are attached to
+    /// the first running one
+    pub selector: Option<String>,
+    /// container of the pod, the default one when not set
+    pub container: Option<String>,
+}
+
+impl KubeSettings {
+    /// kubectl executable
+    pub fn kubectl(&self) -> &str {
+        self.kubectl.as_deref().unwrap_or(KUBECTL)
+    }
+
+    /// Options selecting the cluster and the namespace
+    fn global_args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        if let Some(path) = self.kubeconfig.as_ref() {
+            args.push(format!("--kubeconfig={}", path.display()));
+        }
+        if let Some(context) = self.context.as_ref() {
+            args.push(format!("--context={}", context));
+        }
+        if let Some(namespace) = self.namespace.as_ref() {
+            args.push(format!("--namespace={}", namespace));
+        }
+        args
+    }
+
+    /// Pod a client is attached to. With a selector, the pods
+    /// are listed each time, so that a new session follows the
+    /// pods being replaced
+    fn target(&self, kubectl: &Path) -> Result<String, Box<dyn std::error::Error>> {
+        let selector = match (self.pod.as_ref(), self.selector.as_ref()) {
+            (Some(pod), _) => return Ok(pod.clone()),
+            (None, Some(selector)) => selector,
+            (None, None) => return Err("No pod configured".into()),
+        };
+        let output = Command::new(kubectl)
+            .args(self.global_args())
+            .args(["get", "pods", "--selector", selector])
+            .arg("--field-selector=status.phase=Running")
+            .arg("--output=jsonpath={.items[0].metadata.name}")
+            .arg(format!("--request-timeout={}", REQUEST_TIMEOUT))
+            .output()?;
+        if !output.status.success() {
+            let error = String::from_utf8_lossy(&output.stderr);
+            return Err(
+                format!("Unable to list the pods of {}: {}", selector, error.trim()).into(),
+            );
+        }
+        let pod = String::from_utf8_lossy(&output.stdout).trim().to_string();
+        if pod.is_empty() {
+            return Err(format!("No running pod matches {}", selector).into());
+        }
+        DEBUG!("Pod {} selected by {}", pod, selector);
+        Ok(pod)
+    }
+}
+
+/// Executable and arguments of the `kubectl exec` running a
+/// command in the container. kubectl is checked against the
+/// allowlist, the command is not: it is run in the container
+pub fn command_line(
+    config: &Config,
+    cmd: &str,
+    args: &[String],
+) -> Result<(PathBuf, Vec<String>), Box<dyn std::error::Error>> {
+    let kubectl = config.allowed_command(config.kube.kubectl())?;
+    let mut line = config.kube.global_args();
+    line.extend([
+        String::from("exec"),
+        String::from("-i"),
+        config.kube.target(&kubectl)?,
+    ]);
+    if let Some(container) = config.kube.container.as_ref() {
+        line.push(format!("--container={}", container));
+    }
+    line.push(String::from("--"));
+    line.push(cmd.to_string());
+    line.extend(args.iter().cloned());
+    Ok((kubectl, line))
+}
@@ -0,0 +1,63 @@
+//! # Shared components of the shell script backends
+//!
+//! **Author**: "Dany LE"
+//!
+pub mod account;
+pub mod admin;
+pub mod app;
+pub mod audit;
+pub mod backend;
+pub mod bridge;
+pub mod broadcast;
+pub mod capabilities;
+pub mod cli;
+pub mod clients;
+pub mod config;
+pub mod cron;
+pub mod ctl;
+pub mod error;
+pub mod exec;
+pub mod fifo;
+pub mod frame;
+pub mod git;
+pub mod health;
+pub mod history;
+pub mod hooks;
+pub mod http;
+pub mod journal;
+pub mod kube;
+pub mod log;
+pub mod metrics;
+pub mod mux;
+pub mod notify;
+pub mod p2p;
+pub mod playback;
+pub mod probe;
+pub mod process;
+pub mod profile;
+pub mod proxy;
+pub mod queue;
+pub mod recorder;
+pub mod redact;
+pub mod replay;
+pub mod rotate;
+pub mod rpc;
+pub mod schedule;
+pub mod session;
+pub mod setup;
+pub mod signals;
+pub mod spawn;
+pub mod spool;
+pub mod state;
+pub mod stats;
+pub mod svc;
+pub mod sysstats;
+pub mod tail;
+pub mod telemetry;
+pub mod tmpdir;
+pub mod transfer;
+pub mod unit;
+pub mod validate;
+pub mod wasm;
+pub mod watch;
+pub mod webhook;
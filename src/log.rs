@@ -0,0 +1,285 @@
+//! # Log verbosity and target
+//!
+//! The `DEBUG!`, `INFO!`, `WARN!`, `ERROR!` and `EXIT!` macros
+//! of this crate drop the messages below the configured level,
+//! and send the others to the configured target: syslog through
+//! the macros of `latpr`, stderr or a file, see
+//! [`crate::rotate`] for the rotation of the file. While a
+//! client is handled, its messages are prefixed with the topic,
+//! client id and user, see [`context`]. The messages of the
+//! data path, logged on each read, are aggregated by
+//! [`throttle`]
+//!
+//! **Author**: "Dany LE"
+//!
+use crate::history;
+use crate::rotate::{self, Rotation};
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fmt::Arguments;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// value of `log_target` sending the messages to syslog
+pub const SYSLOG: &str = "syslog";
+/// value of `log_target` sending the messages to stderr
+pub const STDERR: &str = "stderr";
+
+static LEVEL: AtomicU8 = AtomicU8::new(Level::Warn as u8);
+/// target of the messages, syslog when none
+static TARGET: Mutex<Option<Target>> = Mutex::new(None);
+
+/// interval between two messages of a throttled site of a
+/// client, see [`throttle`]
+const THROTTLE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Occurrences of a throttled message not logged yet
+#[derive(Default)]
+struct Throttled {
+    logged: Option<Instant>,
+    count: u64,
+    bytes: u64,
+}
+
+/// throttled messages by site and client
+static THROTTLED: Mutex<BTreeMap<(&'static str, u16), Throttled>> = Mutex::new(BTreeMap::new());
+
+thread_local! {
+    /// prefix of the messages, see [`context`]
+    static CONTEXT: RefCell<String> = const { RefCell::new(String::new()) };
+}
+
+/// Restores the previous context of the messages once dropped
+pub struct Context {
+    previous: String,
+}
+
+impl Drop for Context {
+    fn drop(&mut self) {
+        let previous = std::mem::take(&mut self.previous);
+        CONTEXT.with(|c| *c.borrow_mut() = previous);
+    }
+}
+
+/// Prefix the messages logged until the returned guard is
+/// dropped with a client and its session, e.g.
+/// `[shell#3 alice 4f2c...] `, the user and the session are left
+/// out when not known yet
+pub fn context(topic: &str, client_id: u16, user: &str, session: &str) -> Context {
+    let mut prefix = format!("[{}#{}", topic, client_id);
+    for field in [user, session] {
+        if !field.is_empty() {
+            prefix.push(' ');
+            prefix.push_str(field);
+        }
+    }
+    prefix.push_str("] ");
+    let previous = CONTEXT.with(|c| c.replace(prefix));
+    Context { previous }
+}
+
+/// Prefix of the messages in the current context
+pub fn prefix() -> String {
+    CONTEXT.with(|c| c.borrow().clone())
+}
+
+enum Target {
+    Stderr,
+    File(LogFile),
+}
+
+struct LogFile {
+    file: File,
+    path: PathBuf,
+    /// size of the file
+    size: u64,
+    opened: Instant,
+    rotation: Rotation,
+}
+
+impl LogFile {
+    fn open(path: &Path, rotation: &Rotation) -> io::Result<LogFile> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+        Ok(LogFile {
+            file,
+            path: path.to_path_buf(),
+            size,
+            opened: Instant::now(),
+            rotation: rotation.clone(),
+        })
+    }
+
+    fn write(&mut self, message: &str) -> io::Result<()> {
+        if self.rotation.due(self.size, self.opened.elapsed()) {
+            rotate::shift(&self.path, self.rotation.keep)?;
+            *self = LogFile::open(&self.path, &self.rotation)?;
+        }
+        self.file.write_all(message.as_bytes())?;
+        self.size += message.len() as u64;
+        Ok(())
+    }
+}
+
+/// Log verbosity, from the least to the most verbose
+#[derive(
+    Deserialize, Serialize, clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum Level {
+    Error = 0,
+    Warn = 1,
+    Info = 2,
+    Debug = 3,
+}
+
+/// Set the verbosity of the process
+pub fn set_level(level: Level) {
+    LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+/// Whether messages of the given level are emitted
+pub fn enabled(level: Level) -> bool {
+    level as u8 <= LEVEL.load(Ordering::Relaxed)
+}
+
+/// Count a message of the data path, e.g. on each read of an
+/// output, about `bytes` bytes of a client. The message is due
+/// at most once per [`THROTTLE_INTERVAL`] for a site and a
+/// client: then the number of occurrences and the bytes since
+/// the last one are returned, for the message to aggregate
+/// them. None when the level is not enabled. The warnings and
+/// errors are never throttled
+pub fn throttle(
+    level: Level,
+    site: &'static str,
+    client_id: u16,
+    bytes: usize,
+) -> Option<(u64, u64)> {
+    if !enabled(level) {
+        return None;
+    }
+    let mut throttled = THROTTLED.lock().ok()?;
+    let now = Instant::now();
+    let entry = throttled.entry((site, client_id)).or_default();
+    entry.count += 1;
+    entry.bytes += bytes as u64;
+    if entry
+        .logged
+        .is_some_and(|t| now.duration_since(t) < THROTTLE_INTERVAL)
+    {
+        return None;
+    }
+    entry.logged = Some(now);
+    let due = (entry.count, entry.bytes);
+    entry.count = 0;
+    entry.bytes = 0;
+    Some(due)
+}
+
+/// Set the target of the messages: `syslog`, `stderr` or the
+/// path of a file, opened in append mode and rotated as given
+pub fn set_target(target: &str, rotation: &Rotation) -> io::Result<()> {
+    let target = match target {
+        SYSLOG => None,
+        STDERR => Some(Target::Stderr),
+        path => Some(Target::File(LogFile::open(Path::new(path), rotation)?)),
+    };
+    *TARGET.lock().unwrap_or_else(|e| e.into_inner()) = target;
+    Ok(())
+}
+
+/// Whether the messages go to syslog
+pub fn to_syslog() -> bool {
+    TARGET.lock().map_or(true, |target| target.is_none())
+}
+
+/// Write a message to stderr or to the log file
+pub fn write(level: &str, file: &str, line: u32, args: Arguments) {
+    let mut target = match TARGET.lock() {
+        Ok(target) => target,
+        Err(_) => return,
+    };
+    let time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    let message = format!(
+        "{} [{}] {}@[{}:{}]: {}{}\n",
+        history::local_time(time),
+        std::process::id(),
+        level,
+        file,
+        line,
+        prefix(),
+        args
+    );
+    // a failure to log cannot be logged
+    let _ = match target.as_mut() {
+        None => return,
+        Some(Target::Stderr) => io::stderr().write_all(message.as_bytes()),
+        Some(Target::File(file)) => file.write(&message),
+    };
+}
+
+#[macro_export]
+macro_rules! DEBUG {
+    ($($args:tt)*) => ({
+        if $crate::log::enabled($crate::log::Level::Debug) {
+            if $crate::log::to_syslog() {
+                ::latpr::INFO!("{}{}", $crate::log::prefix(), format_args!($($args)*));
+            } else {
+                $crate::log::write("debug", file!(), line!(), format_args!($($args)*));
+            }
+        }
+    })
+}
+
+#[macro_export]
+macro_rules! INFO {
+    ($($args:tt)*) => ({
+        if $crate::log::enabled($crate::log::Level::Info) {
+            if $crate::log::to_syslog() {
+                ::latpr::INFO!("{}{}", $crate::log::prefix(), format_args!($($args)*));
+            } else {
+                $crate::log::write("info", file!(), line!(), format_args!($($args)*));
+            }
+        }
+    })
+}
+
+#[macro_export]
+macro_rules! WARN {
+    ($($args:tt)*) => ({
+        if $crate::log::enabled($crate::log::Level::Warn) {
+            if $crate::log::to_syslog() {
+                ::latpr::WARN!("{}{}", $crate::log::prefix(), format_args!($($args)*));
+            } else {
+                $crate::log::write("warn", file!(), line!(), format_args!($($args)*));
+            }
+        }
+    })
+}
+
+#[macro_export]
+macro_rules! ERROR {
+    ($($args:tt)*) => ({
+        if $crate::log::to_syslog() {
+            ::latpr::ERROR!("{}{}", $crate::log::prefix(), format_args!($($args)*));
+        } else {
+            $crate::log::write("error", file!(), line!(), format_args!($($args)*));
+        }
+    })
+}
+
+#[macro_export]
+macro_rules! EXIT {
+    ($($args:tt)*) => ({
+        $crate::ERROR!($($args)*);
+        std::process::exit(1);
+    })
+}
@@ -0,0 +1,9 @@
+//! # Antd tunnel shell script backend, all modes
+//!
+//! The mode is selected with `--mode p2p|p2p-user|broadcast|file|tail|sock|tcp|fifo|cron|exec|rpc|playback|kube|wasm|journal|sysstats|watch|mux|session|queue|git|svc|bridge`
+//!
+//! **Author**: "Dany LE"
+//!
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    shellbackend::app::main(None)
+}
@@ -0,0 +1,503 @@
+//! # Prometheus and StatsD metrics
+//!
+//! Counters and gauges of the backend, exposed in the text
+//! exposition format by an optional HTTP listener on
+//! `/metrics`, served from the event loop of the topic, and
+//! optionally pushed to a StatsD server. The throughput and the
+//! latency of the writes to the topic are rolling gauges over
+//! the last [`RATE_WINDOW`]
+//!
+//! **Author**: "Dany LE"
+//!
+use crate::config::StatsdFormat;
+use crate::error::Class;
+use crate::WARN;
+use latpr::utils::{LogLevel, LOG};
+use std::collections::VecDeque;
+use std::fmt::Write as _;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream, UdpSocket};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// A counter of the backend
+#[derive(Debug, Clone, Copy)]
+pub enum Counter {
+    Spawned,
+    Exited,
+    SpawnFailures,
+    BytesIn,
+    BytesOut,
+    BytesDropped,
+}
+
+impl Counter {
+    const ALL: [Counter; 6] = [
+        Counter::Spawned,
+        Counter::Exited,
+        Counter::SpawnFailures,
+        Counter::BytesIn,
+        Counter::BytesOut,
+        Counter::BytesDropped,
+    ];
+
+    fn name(&self) -> &'static str {
+        match self {
+            Counter::Spawned => "processes_spawned_total",
+            Counter::Exited => "processes_exited_total",
+            Counter::SpawnFailures => "spawn_failures_total",
+            Counter::BytesIn => "bytes_in_total",
+            Counter::BytesOut => "bytes_out_total",
+            Counter::BytesDropped => "bytes_dropped_total",
+        }
+    }
+
+    /// Name of the StatsD metric
+    fn short_name(&self) -> &'static str {
+        match self {
+            Counter::Spawned => "processes_spawned",
+            Counter::Exited => "processes_exited",
+            Counter::SpawnFailures => "spawn_failures",
+            Counter::BytesIn => "bytes_in",
+            Counter::BytesOut => "bytes_out",
+            Counter::BytesDropped => "bytes_dropped",
+        }
+    }
+
+    fn help(&self) -> &'static str {
+        match self {
+            Counter::Spawned => "Processes spawned",
+            Counter::Exited => "Processes exited",
+            Counter::SpawnFailures => "Processes that could not be spawned",
+            Counter::BytesIn => "Bytes received from the clients",
+            Counter::BytesOut => "Bytes of output sent by the processes",
+            Counter::BytesDropped => "Bytes of input or output dropped",
+        }
+    }
+}
+
+static COUNTERS: [AtomicU64; Counter::ALL.len()] = [
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+];
+
+/// errors logged per class, see [`crate::error`]
+static ERRORS: [AtomicU64; Class::ALL.len()] = [
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+];
+
+/// upper bounds of the buckets of the step latency, in seconds
+const BUCKETS: [f64; 8] = [0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0];
+
+/// Latency of the steps of the event loop
+struct Histogram {
+    /// observations per bucket, not cumulated, the last one
+    /// beyond the largest bound
+    counts: [u64; BUCKETS.len() + 1],
+    sum: f64,
+}
+
+static STEPS: Mutex<Histogram> = Mutex::new(Histogram {
+    counts: [0; BUCKETS.len() + 1],
+    sum: 0.0,
+});
+
+/// window of the rolling gauges
+pub const RATE_WINDOW: Duration = Duration::from_secs(10);
+/// interval between two samples of the rolling gauges
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// writes of frames to the topic and their total duration
+static WRITES: AtomicU64 = AtomicU64::new(0);
+static WRITE_NANOS: AtomicU64 = AtomicU64::new(0);
+
+/// Cumulated values at an instant, the rolling gauges are
+/// computed from the oldest sample of the window
+#[derive(Clone, Copy)]
+struct Sample {
+    at: Instant,
+    bytes_in: u64,
+    bytes_out: u64,
+    writes: u64,
+    write_nanos: u64,
+}
+
+impl Sample {
+    fn now() -> Sample {
+        Sample {
+            at: Instant::now(),
+            bytes_in: COUNTERS[Counter::BytesIn as usize].load(Ordering::Relaxed),
+            bytes_out: COUNTERS[Counter::BytesOut as usize].load(Ordering::Relaxed),
+            writes: WRITES.load(Ordering::Relaxed),
+            write_nanos: WRITE_NANOS.load(Ordering::Relaxed),
+        }
+    }
+}
+
+static SAMPLES: Mutex<VecDeque<Sample>> = Mutex::new(VecDeque::new());
+
+/// Rolling gauges of the topic
+pub struct Rates {
+    /// bytes per second received from the clients
+    pub bytes_in: f64,
+    /// bytes per second of output sent to the clients
+    pub bytes_out: f64,
+    /// mean duration of a write to the topic
+    pub write_latency: Duration,
+}
+
+impl Rates {
+    /// One line summary, for the admin interface
+    pub fn describe(&self) -> String {
+        format!(
+            "in {:.0} B/s, out {:.0} B/s, write latency {:?} over the last {}s",
+            self.bytes_in,
+            self.bytes_out,
+            self.write_latency,
+            RATE_WINDOW.as_secs()
+        )
+    }
+}
+
+/// Increase a counter
+pub fn add(counter: Counter, n: u64) {
+    COUNTERS[counter as usize].fetch_add(n, Ordering::Relaxed);
+}
+
+/// Count an error of a class
+pub fn error(class: Class) {
+    ERRORS[class as usize].fetch_add(1, Ordering::Relaxed);
+}
+
+/// Record the duration of a write of a frame to the topic
+pub fn observe_write(duration: Duration) {
+    WRITES.fetch_add(1, Ordering::Relaxed);
+    WRITE_NANOS.fetch_add(duration.as_nanos() as u64, Ordering::Relaxed);
+}
+
+/// Sample the values of the rolling gauges, called on each
+/// step of the event loop
+pub fn sample() {
+    if let Ok(mut samples) = SAMPLES.lock() {
+        if samples
+            .back()
+            .is_some_and(|s| s.at.elapsed() < SAMPLE_INTERVAL)
+        {
+            return;
+        }
+        samples.push_back(Sample::now());
+        while samples
+            .front()
+            .is_some_and(|s| s.at.elapsed() > RATE_WINDOW)
+        {
+            samples.pop_front();
+        }
+    }
+}
+
+/// The rolling gauges, from the oldest sample of the window
+pub fn rates() -> Rates {
+    let now = Sample::now();
+    let oldest = SAMPLES
+        .lock()
+        .ok()
+        .and_then(|samples| samples.front().copied())
+        .unwrap_or(now);
+    let secs = now.at.duration_since(oldest.at).as_secs_f64();
+    let per_sec = |n: u64| if secs > 0.0 { n as f64 / secs } else { 0.0 };
+    let write_nanos = (now.write_nanos - oldest.write_nanos)
+        .checked_div(now.writes - oldest.writes)
+        .unwrap_or(0);
+    Rates {
+        bytes_in: per_sec(now.bytes_in - oldest.bytes_in),
+        bytes_out: per_sec(now.bytes_out - oldest.bytes_out),
+        write_latency: Duration::from_nanos(write_nanos),
+    }
+}
+
+/// Record the duration of a step of the event loop
+pub fn observe_step(duration: Duration) {
+    let secs = duration.as_secs_f64();
+    if let Ok(mut steps) = STEPS.lock() {
+        let i = BUCKETS
+            .iter()
+            .position(|b| secs <= *b)
+            .unwrap_or(BUCKETS.len());
+        steps.counts[i] += 1;
+        steps.sum += secs;
+    }
+}
+
+/// Metrics in the text exposition format, labelled with the
+/// topic
+pub fn render(topic: &str, clients: usize) -> String {
+    let label = format!(
+        "topic=\"{}\"",
+        topic.replace('\\', "\\\\").replace('"', "\\\"")
+    );
+    let mut text = String::new();
+    let _ = writeln!(text, "# HELP shbackend_clients Subscribed clients");
+    let _ = writeln!(text, "# TYPE shbackend_clients gauge");
+    let _ = writeln!(text, "shbackend_clients{{{}}} {}", label, clients);
+    for counter in Counter::ALL {
+        let name = counter.name();
+        let _ = writeln!(text, "# HELP shbackend_{} {}", name, counter.help());
+        let _ = writeln!(text, "# TYPE shbackend_{} counter", name);
+        let value = COUNTERS[counter as usize].load(Ordering::Relaxed);
+        let _ = writeln!(text, "shbackend_{}{{{}}} {}", name, label, value);
+    }
+    let name = "shbackend_errors_total";
+    let _ = writeln!(text, "# HELP {} Errors logged, by class", name);
+    let _ = writeln!(text, "# TYPE {} counter", name);
+    for class in Class::ALL {
+        let value = ERRORS[class as usize].load(Ordering::Relaxed);
+        let _ = writeln!(text, "{}{{{},class=\"{}\"}} {}", name, label, class, value);
+    }
+    let rates = rates();
+    for (name, help, value) in [
+        (
+            "bytes_in_per_second",
+            "Bytes per second received from the clients",
+            rates.bytes_in,
+        ),
+        (
+            "bytes_out_per_second",
+            "Bytes per second of output sent to the clients",
+            rates.bytes_out,
+        ),
+        (
+            "topic_write_latency_seconds",
+            "Mean duration of the writes to the topic",
+            rates.write_latency.as_secs_f64(),
+        ),
+    ] {
+        let _ = writeln!(text, "# HELP shbackend_{} {}", name, help);
+        let _ = writeln!(text, "# TYPE shbackend_{} gauge", name);
+        let _ = writeln!(text, "shbackend_{}{{{}}} {}", name, label, value);
+    }
+    let name = "shbackend_step_duration_seconds";
+    let _ = writeln!(
+        text,
+        "# HELP {} Duration of the steps of the event loop",
+        name
+    );
+    let _ = writeln!(text, "# TYPE {} histogram", name);
+    if let Ok(steps) = STEPS.lock() {
+        let mut count = 0;
+        for (i, bound) in BUCKETS.iter().enumerate() {
+            count += steps.counts[i];
+            let _ = writeln!(
+                text,
+                "{}_bucket{{{},le=\"{}\"}} {}",
+                name, label, bound, count
+            );
+        }
+        count += steps.counts[BUCKETS.len()];
+        let _ = writeln!(text, "{}_bucket{{{},le=\"+Inf\"}} {}", name, label, count);
+        let _ = writeln!(text, "{}_sum{{{}}} {}", name, label, steps.sum);
+        let _ = writeln!(text, "{}_count{{{}}} {}", name, label, count);
+    }
+    text
+}
+
+/// time given to a scraper to send its request and read the
+/// response, in total, the event loop is blocked meanwhile
+const SCRAPE_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// HTTP listener of the metrics
+pub struct Exporter {
+    listener: TcpListener,
+}
+
+impl Exporter {
+    pub fn bind(addr: &str) -> Result<Exporter, Box<dyn std::error::Error>> {
+        let listener = TcpListener::bind(addr)
+            .map_err(|e| format!("Unable to listen for the metrics on {}: {}", addr, e))?;
+        listener.set_nonblocking(true)?;
+        Ok(Exporter { listener })
+    }
+
+    pub fn fd(&self) -> RawFd {
+        self.listener.as_raw_fd()
+    }
+
+    /// Answer the pending scrapes
+    pub fn serve(&self, topic: &str, clients: usize) {
+        loop {
+            let stream = match self.listener.accept() {
+                Ok((stream, _)) => stream,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return,
+                Err(error) => {
+                    WARN!("Unable to accept a metrics connection: {}", error);
+                    return;
+                }
+            };
+            if let Err(error) = answer(stream, topic, clients) {
+                WARN!("Unable to answer a metrics request: {}", error);
+            }
+        }
+    }
+}
+
+/// Time left to serve a scrape, an error once it is over
+fn remaining(deadline: Instant) -> io::Result<Duration> {
+    deadline
+        .checked_duration_since(Instant::now())
+        .filter(|left| !left.is_zero())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::TimedOut, "scrape timed out"))
+}
+
+fn answer(mut stream: TcpStream, topic: &str, clients: usize) -> io::Result<()> {
+    // the timeout bounds the whole scrape, not each read, as it
+    // is served by the event loop
+    let deadline = Instant::now() + SCRAPE_TIMEOUT;
+    stream.set_nonblocking(false)?;
+    let mut request = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") && request.len() < 8192 {
+        stream.set_read_timeout(Some(remaining(deadline)?))?;
+        let n = stream.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        request.extend_from_slice(&buf[..n]);
+    }
+    let line = String::from_utf8_lossy(&request);
+    let mut parts = line.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", render(topic, clients)),
+        (Some("GET"), Some(_)) => ("404 Not Found", String::from("Not found\n")),
+        _ => (
+            "405 Method Not Allowed",
+            String::from("Method not allowed\n"),
+        ),
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    let mut data = response.as_bytes();
+    while !data.is_empty() {
+        stream.set_write_timeout(Some(remaining(deadline)?))?;
+        match stream.write(data)? {
+            0 => return Err(io::ErrorKind::WriteZero.into()),
+            n => data = &data[n..],
+        }
+    }
+    Ok(())
+}
+
+/// Pushes the metrics to a StatsD server over UDP: the
+/// increase of the counters since the last push, and the
+/// number of clients and the rolling gauges as gauges
+pub struct Statsd {
+    socket: UdpSocket,
+    format: StatsdFormat,
+    interval: Duration,
+    last_push: Instant,
+    /// values of the counters at the last push
+    pushed: [u64; Counter::ALL.len()],
+    pushed_errors: [u64; Class::ALL.len()],
+}
+
+impl Statsd {
+    pub fn connect(
+        addr: &str,
+        format: StatsdFormat,
+        interval: Duration,
+    ) -> Result<Statsd, Box<dyn std::error::Error>> {
+        let local = if addr.starts_with('[') {
+            "[::]:0"
+        } else {
+            "0.0.0.0:0"
+        };
+        let socket = UdpSocket::bind(local)?;
+        socket
+            .connect(addr)
+            .map_err(|e| format!("Unable to reach the StatsD server {}: {}", addr, e))?;
+        socket.set_nonblocking(true)?;
+        Ok(Statsd {
+            socket,
+            format,
+            interval,
+            last_push: Instant::now(),
+            pushed: [0; Counter::ALL.len()],
+            pushed_errors: [0; Class::ALL.len()],
+        })
+    }
+
+    /// Push the metrics when due, a failure is logged only
+    pub fn step(&mut self, topic: &str, clients: usize) {
+        if self.last_push.elapsed() < self.interval {
+            return;
+        }
+        self.last_push = Instant::now();
+        let rates = rates();
+        let mut lines = vec![
+            self.line(topic, "clients", &format!("{}|g", clients)),
+            self.line(topic, "bytes_in_rate", &format!("{:.0}|g", rates.bytes_in)),
+            self.line(
+                topic,
+                "bytes_out_rate",
+                &format!("{:.0}|g", rates.bytes_out),
+            ),
+            self.line(
+                topic,
+                "write_latency_us",
+                &format!("{}|g", rates.write_latency.as_micros()),
+            ),
+        ];
+        for counter in Counter::ALL {
+            let value = COUNTERS[counter as usize].load(Ordering::Relaxed);
+            let delta = value - self.pushed[counter as usize];
+            self.pushed[counter as usize] = value;
+            if delta > 0 {
+                lines.push(self.line(topic, counter.short_name(), &format!("{}|c", delta)));
+            }
+        }
+        for class in Class::ALL {
+            let value = ERRORS[class as usize].load(Ordering::Relaxed);
+            let delta = value - self.pushed_errors[class as usize];
+            self.pushed_errors[class as usize] = value;
+            if delta > 0 {
+                let name = format!("errors_{}", class);
+                lines.push(self.line(topic, &name, &format!("{}|c", delta)));
+            }
+        }
+        if let Err(error) = self.socket.send(lines.join("\n").as_bytes()) {
+            WARN!("Unable to push the metrics to StatsD: {}", error);
+        }
+    }
+
+    /// A metric, e.g. `shbackend.shell.clients:2|g` or with
+    /// DogStatsD, `shbackend.clients:2|g|#topic:shell`
+    fn line(&self, topic: &str, name: &str, value: &str) -> String {
+        let topic: String = topic
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() || c == '-' {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect();
+        match self.format {
+            StatsdFormat::Statsd => format!("shbackend.{}.{}:{}", topic, name, value),
+            StatsdFormat::Dogstatsd => format!("shbackend.{}:{}|#topic:{}", name, value, topic),
+        }
+    }
+}
@@ -0,0 +1,846 @@
+//! # Multiplexing backend
+//!
+//! A `shmux` subscription hosts many independent streams, so
+//! that a frontend with several views, e.g. a shell, a log and
+//! a monitor, needs a single subscription. Each stream has a
+//! sub-id chosen by the client and is mapped to a configured
+//! stream: its own process, or a process shared by all the
+//! streams opened on it, as the one of `shbcast`
+//!
+//! ```text
+//! -> ctrl {"open": 1, "stream": "shell"}
+//! <- ctrl {"opened": 1}
+//! -> data <sub-id> <input>
+//! <- data <sub-id> <output>
+//! -> ctrl {"close": 1}
+//! <- ctrl {"closed": 1}
+//! ```
+//!
+//! The data frames start with the sub-id, as a big-endian u16.
+//! A stream whose process exits is closed with its exit status,
+//! e.g. `{"closed": 1, "exit_code": 0}`. The shared streams are
+//! read-only
+//!
+//! **Author**: "Dany LE"
+//!
+use crate::audit::{self, Event};
+use crate::backend::Backend;
+use crate::clients::{self, Roster, Subscription};
+use crate::config::Config;
+use crate::error::{self, Class, Classify};
+use crate::frame;
+use crate::log::{self, Level};
+use crate::metrics::{self, Counter};
+use crate::process::Process;
+use crate::profile::{Phase, Timer};
+use crate::spawn;
+use crate::stats::{self, Stats};
+use crate::telemetry::Span;
+use crate::{DEBUG, ERROR, INFO, WARN};
+use latpr::tunnel::{CallbackEvent, IOInterest, Msg, MsgKind, Topic};
+use latpr::utils::{LogLevel, LOG};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::os::unix::io::RawFd;
+use std::os::unix::process::ExitStatusExt;
+use std::process::{Command, ExitStatus, Stdio};
+use std::time::Duration;
+
+/// time given to the processes to exit on shutdown
+const TERMINATE_GRACE: Duration = Duration::from_secs(2);
+/// default of `mux.max_streams`
+const MAX_STREAMS: usize = 16;
+/// size of the sub-id at the start of the data frames
+const HEADER: usize = 2;
+
+/// A stream that can be opened, e.g.
+///
+/// ```toml
+/// [mux.streams.log]
+/// command = "/usr/bin/tail"
+/// args = ["-F", "/var/log/syslog"]
+/// shared = true
+/// ```
+#[derive(Deserialize, Serialize, Default, Debug, Clone, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct StreamSettings {
+    /// absolute path of the command
+    pub command: String,
+    pub args: Vec<String>,
+    /// whether a single process is shared by all the streams
+    /// opened on it, instead of one process per stream
+    pub shared: bool,
+}
+
+/// Settings of the multiplexing mode
+#[derive(Deserialize, Serialize, Default, Debug, Clone, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct MuxSettings {
+    /// streams by name
+    pub streams: BTreeMap<String, StreamSettings>,
+    /// streams open at once per client, 16 by default
+    pub max_streams: Option<usize>,
+}
+
+impl MuxSettings {
+    pub fn max_streams(&self) -> usize {
+        self.max_streams.unwrap_or(MAX_STREAMS)
+    }
+}
+
+/// A control frame of a client
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Request {
+    open: Option<u16>,
+    stream: Option<String>,
+    close: Option<u16>,
+}
+
+/// An open stream of a client
+enum Target {
+    /// its own process
+    Private(Process),
+    /// attached to the shared process of a stream
+    Shared(String),
+}
+
+pub struct Client {
+    user: String,
+    /// identifier of the session, see [`telemetry::session_id`]
+    session: String,
+    /// open streams by sub-id
+    streams: HashMap<u16, Target>,
+    /// counters of the client
+    stats: Stats,
+    /// span of the subscription, see [`crate::telemetry`]
+    span: Option<Span>,
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        for target in self.streams.values_mut() {
+            if let Target::Private(process) = target {
+                if let Err(error) = kill(process) {
+                    WARN!("Unable to kill the process of a stream: {}", error);
+                }
+            }
+        }
+        clients::end_span(&mut self.span, &self.stats);
+    }
+}
+
+crate::member!(Client);
+
+/// The process of a shared stream
+struct Shared {
+    process: Process,
+    /// client and sub-id of the streams attached to it
+    attached: Vec<(u16, u16)>,
+}
+
+impl Drop for Shared {
+    fn drop(&mut self) {
+        if let Err(error) = kill(&mut self.process) {
+            WARN!("Unable to kill the process of a shared stream: {}", error);
+        }
+    }
+}
+
+/// Backend hosting many streams per client
+pub struct Mux {
+    clients: HashMap<u16, Client>,
+    /// processes of the shared streams by name
+    shared: HashMap<String, Shared>,
+    /// whether a process may have exited since the last check
+    reap: bool,
+}
+
+impl Default for Mux {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Build a control frame about a stream
+fn ctrl(client_id: u16, body: Value) -> Msg {
+    Msg::create(
+        MsgKind::ChannelCtrl,
+        0,
+        client_id,
+        body.to_string().into_bytes(),
+    )
+}
+
+/// Build the control frame closing a stream, with the exit
+/// status of its process or the reason, if any
+fn closed(client_id: u16, id: u16, status: Option<ExitStatus>, reason: Option<&str>) -> Msg {
+    let mut body = json!({ "closed": id });
+    if let Some(status) = status {
+        match (status.code(), status.signal()) {
+            (Some(code), _) => body["exit_code"] = json!(code),
+            (None, signal) => body["signal"] = json!(signal),
+        }
+    }
+    if let Some(reason) = reason {
+        body["error"] = json!(reason);
+    }
+    ctrl(client_id, body)
+}
+
+/// Build a data frame of a stream
+fn data(client_id: u16, id: u16, payload: &[u8]) -> Msg {
+    let mut data = Vec::with_capacity(HEADER + payload.len());
+    data.extend_from_slice(&id.to_be_bytes());
+    data.extend_from_slice(payload);
+    Msg::create(MsgKind::ChannelData, 0, client_id, data)
+}
+
+/// Spawn the process of a stream
+fn spawn_stream(
+    name: &str,
+    client_id: u16,
+    user: &str,
+    session: &str,
+    config: &Config,
+    topic: &mut Topic,
+) -> Result<Process, Box<dyn std::error::Error>> {
+    let _timer = Timer::start(Phase::Spawn);
+    let stream = config
+        .mux
+        .streams
+        .get(name)
+        .ok_or_else(|| format!("Unknown stream: {}", name))?;
+    let profile = config.profile(user);
+    let path = config.allowed_command(&stream.command)?;
+    let mut command = Command::new(&path);
+    command
+        .args(&stream.args)
+        .envs(&profile.env)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped());
+    // a shared process belongs to no client
+    if !stream.shared {
+        command
+            .env("CUSER", user)
+            .env("CID", format!("{}", client_id));
+    }
+    profile.rlimits.apply(&mut command);
+    spawn::die_with_parent(&mut command);
+    let process = Process::from(
+        command
+            .spawn()
+            .inspect_err(|_| metrics::add(Counter::SpawnFailures, 1))
+            .class(Class::Spawn)?,
+    );
+    if let Some(fd) = process.stdout_fd() {
+        topic.register_io(fd, IOInterest::READABLE)?;
+    }
+    metrics::add(Counter::Spawned, 1);
+    let spawned = Event::Spawn {
+        command: &path,
+        args: &stream.args,
+        pid: process.id(),
+    };
+    audit::log(config, client_id, user, session, spawned);
+    DEBUG!(
+        "Run stream {} for client {}: pid {}",
+        name,
+        client_id,
+        process.id()
+    );
+    Ok(process)
+}
+
+/// Kill a process unless it has already exited
+fn kill(process: &mut Process) -> std::io::Result<()> {
+    match process.try_wait()? {
+        Some(_) => Ok(()),
+        None => process.kill(),
+    }
+}
+
+/// Read the output left by an exited process
+fn drain(process: &mut Process, config: &Config) -> std::io::Result<Vec<u8>> {
+    let mut output = Vec::new();
+    let mut buf = vec![0; config.buffer_size];
+    while let Some(n) = process.read_output(&mut buf[..])? {
+        if n == 0 {
+            break;
+        }
+        output.extend_from_slice(&buf[..n]);
+    }
+    Ok(output)
+}
+
+/// Stop watching the output of a process
+fn unregister(process: &Process, topic: &mut Topic) {
+    if let Some(fd) = process.stdout_fd() {
+        if let Err(error) = topic.unregister_io(fd) {
+            WARN!("Unable to unregister the process of a stream: {}", error);
+        }
+    }
+}
+
+impl Mux {
+    pub fn new() -> Mux {
+        Mux {
+            clients: HashMap::new(),
+            shared: HashMap::new(),
+            reap: false,
+        }
+    }
+
+    /// Detach a stream from its shared process, which is killed
+    /// once no stream is attached to it
+    fn detach(&mut self, name: &str, client_id: u16, id: u16, topic: &mut Topic) {
+        let Some(shared) = self.shared.get_mut(name) else {
+            return;
+        };
+        shared.attached.retain(|a| *a != (client_id, id));
+        if shared.attached.is_empty() {
+            DEBUG!("No stream attached to {}, stop its process", name);
+            if let Some(shared) = self.shared.remove(name) {
+                unregister(&shared.process, topic);
+            }
+        }
+    }
+
+    /// Close a stream, its process is killed
+    fn close(&mut self, client_id: u16, id: u16, topic: &mut Topic) -> bool {
+        let target = match self.clients.get_mut(&client_id) {
+            Some(client) => client.streams.remove(&id),
+            None => None,
+        };
+        match target {
+            None => false,
+            Some(Target::Private(mut process)) => {
+                unregister(&process, topic);
+                if let Err(error) = kill(&mut process) {
+                    WARN!("Unable to kill the process of a stream: {}", error);
+                }
+                true
+            }
+            Some(Target::Shared(name)) => {
+                self.detach(&name, client_id, id, topic);
+                true
+            }
+        }
+    }
+
+    /// Remove a client, its streams are closed
+    fn remove(&mut self, client_id: u16, topic: &mut Topic) -> Option<Client> {
+        let ids: Vec<u16> = self
+            .clients
+            .get(&client_id)?
+            .streams
+            .keys()
+            .copied()
+            .collect();
+        for id in ids {
+            self.close(client_id, id, topic);
+        }
+        self.clients.remove(&client_id)
+    }
+
+    /// Open a stream on a control request of a client
+    fn open(
+        &mut self,
+        client_id: u16,
+        id: u16,
+        name: &str,
+        config: &Config,
+        topic: &mut Topic,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let Some(client) = self.clients.get(&client_id) else {
+            WARN!("Client {} is not in the list", client_id);
+            return Ok(());
+        };
+        let refuse = if client.streams.contains_key(&id) {
+            Some(String::from("Stream already open"))
+        } else if client.streams.len() >= config.mux.max_streams() {
+            Some(format!(
+                "Too many streams, maximum is {}",
+                config.mux.max_streams()
+            ))
+        } else if !config.mux.streams.contains_key(name) {
+            Some(format!("Unknown stream: {}", name))
+        } else {
+            None
+        };
+        if let Some(reason) = refuse {
+            WARN!(
+                "Refuse to open stream {} of client {}: {}",
+                id,
+                client_id,
+                reason
+            );
+            return frame::send(topic, &closed(client_id, id, None, Some(&reason)));
+        }
+        let (user, session) = (client.user.clone(), client.session.clone());
+        let target = if config.mux.streams[name].shared {
+            if !self.shared.contains_key(name) {
+                match spawn_stream(name, client_id, &user, &session, config, topic) {
+                    Ok(process) => {
+                        let attached = Vec::new();
+                        self.shared
+                            .insert(name.to_string(), Shared { process, attached });
+                    }
+                    Err(error) => {
+                        ERROR!(
+                            "Unable to run stream {}: {}: {}",
+                            name,
+                            error::report(&*error, Class::Spawn),
+                            error
+                        );
+                        let reason = "Unable to run the stream";
+                        return frame::send(topic, &closed(client_id, id, None, Some(reason)));
+                    }
+                }
+            }
+            if let Some(shared) = self.shared.get_mut(name) {
+                shared.attached.push((client_id, id));
+            }
+            Target::Shared(name.to_string())
+        } else {
+            match spawn_stream(name, client_id, &user, &session, config, topic) {
+                Ok(process) => Target::Private(process),
+                Err(error) => {
+                    ERROR!(
+                        "Unable to run stream {} of client {}: {}: {}",
+                        name,
+                        client_id,
+                        error::report(&*error, Class::Spawn),
+                        error
+                    );
+                    let reason = "Unable to run the stream";
+                    return frame::send(topic, &closed(client_id, id, None, Some(reason)));
+                }
+            }
+        };
+        if let Some(client) = self.clients.get_mut(&client_id) {
+            client.stats.spawns += 1;
+            client.streams.insert(id, target);
+        }
+        INFO!("Client {} opened stream {} on {}", client_id, id, name);
+        frame::send(topic, &ctrl(client_id, json!({ "opened": id })))
+    }
+
+    /// Write the data of a stream to its process
+    fn input(&mut self, msg: &Msg, topic: &mut Topic) -> Result<(), Box<dyn std::error::Error>> {
+        let client = match self.clients.get_mut(&msg.client_id) {
+            None => {
+                WARN!("Client {} is not in the list", msg.client_id);
+                return Ok(());
+            }
+            Some(client) => client,
+        };
+        if msg.data.len() < HEADER {
+            return frame::send(topic, &frame::error(msg.client_id, "Missing stream id"));
+        }
+        let id = u16::from_be_bytes([msg.data[0], msg.data[1]]);
+        let payload = &msg.data[HEADER..];
+        client.stats.bytes_in += payload.len() as u64;
+        metrics::add(Counter::BytesIn, payload.len() as u64);
+        let process = match client.streams.get(&id) {
+            None => {
+                let reason = format!("Stream {} is not open", id);
+                return frame::send(topic, &frame::error(msg.client_id, &reason));
+            }
+            Some(Target::Shared(_)) => {
+                let reason = format!("Stream {} is read-only", id);
+                return frame::send(topic, &frame::error(msg.client_id, &reason));
+            }
+            Some(Target::Private(process)) => process,
+        };
+        if !process.write_input(payload).class(Class::ChildIo)? {
+            client.stats.dropped_in += payload.len() as u64;
+            metrics::add(Counter::BytesDropped, payload.len() as u64);
+            let reason = format!("Input pipe of stream {} full", id);
+            frame::send(
+                topic,
+                &frame::dropped(msg.client_id, &reason, payload.len() as u64),
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Send the output of the process reading from a descriptor
+    /// to its streams
+    fn output(
+        &mut self,
+        fd: RawFd,
+        config: &Config,
+        topic: &mut Topic,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut buf = vec![0; config.buffer_size];
+        let owns = |p: &Process| p.stdout_fd() == Some(fd);
+        for (key, client) in self.clients.iter_mut() {
+            let found = client
+                .streams
+                .iter_mut()
+                .find_map(|(id, target)| match target {
+                    Target::Private(process) if owns(process) => Some((*id, process)),
+                    _ => None,
+                });
+            let Some((id, process)) = found else {
+                continue;
+            };
+            let n = match process.read_output(&mut buf[..]).class(Class::ChildIo)? {
+                Some(n) if n > 0 => n,
+                _ => return Ok(()),
+            };
+            if let Some((reads, bytes)) = log::throttle(Level::Debug, "send", *key, n) {
+                DEBUG!(
+                    "Sending {} bytes of stream {} to client {} in {} read(s)",
+                    bytes,
+                    id,
+                    key,
+                    reads
+                );
+            }
+            client.stats.sent(n);
+            metrics::add(Counter::BytesOut, n as u64);
+            return frame::send(topic, &data(*key, id, &buf[..n]));
+        }
+        let Some((name, shared)) = self.shared.iter_mut().find(|(_, s)| owns(&s.process)) else {
+            DEBUG!("Ignore event of descriptor {}: no process owns it", fd);
+            return Ok(());
+        };
+        let n = match shared
+            .process
+            .read_output(&mut buf[..])
+            .class(Class::ChildIo)?
+        {
+            Some(n) if n > 0 => n,
+            _ => return Ok(()),
+        };
+        if let Some((reads, bytes)) = log::throttle(Level::Debug, "send", 0, n) {
+            DEBUG!(
+                "Sending {} bytes of stream {} to {} stream(s) in {} read(s)",
+                bytes,
+                name,
+                shared.attached.len(),
+                reads
+            );
+        }
+        metrics::add(Counter::BytesOut, n as u64);
+        for (key, id) in shared.attached.iter() {
+            frame::send(topic, &data(*key, *id, &buf[..n]))?;
+            if let Some(client) = self.clients.get_mut(key) {
+                client.stats.sent(n);
+            }
+        }
+        Ok(())
+    }
+
+    /// Close the streams whose process has exited
+    fn reap_streams(
+        &mut self,
+        config: &Config,
+        topic: &mut Topic,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if !std::mem::take(&mut self.reap) {
+            return Ok(());
+        }
+        for (key, client) in self.clients.iter_mut() {
+            let mut exited = Vec::new();
+            for (id, target) in client.streams.iter_mut() {
+                if let Target::Private(process) = target {
+                    if let Some(status) = process.try_wait()? {
+                        let output = drain(process, config).class(Class::ChildIo)?;
+                        exited.push((*id, process.id(), status, output));
+                    }
+                }
+            }
+            for (id, pid, status, output) in exited {
+                if let Some(Target::Private(process)) = client.streams.remove(&id) {
+                    unregister(&process, topic);
+                }
+                for chunk in output.chunks(config.buffer_size) {
+                    client.stats.sent(chunk.len());
+                    metrics::add(Counter::BytesOut, chunk.len() as u64);
+                    frame::send(topic, &data(*key, id, chunk))?;
+                }
+                DEBUG!(
+                    "Stream {} of client {} has exited with status {}",
+                    id,
+                    key,
+                    status
+                );
+                let exit = Event::Exit { pid, status };
+                audit::log(config, *key, &client.user, &client.session, exit);
+                metrics::add(Counter::Exited, 1);
+                frame::send(topic, &closed(*key, id, Some(status), None))?;
+            }
+        }
+        let mut exited = Vec::new();
+        for (name, shared) in self.shared.iter_mut() {
+            if let Some(status) = shared.process.try_wait()? {
+                let output = drain(&mut shared.process, config).class(Class::ChildIo)?;
+                exited.push((name.clone(), status, output));
+            }
+        }
+        for (name, status, output) in exited {
+            let Some(shared) = self.shared.remove(&name) else {
+                continue;
+            };
+            unregister(&shared.process, topic);
+            WARN!("Shared stream {} has exited with status {}", name, status);
+            metrics::add(Counter::Exited, 1);
+            for (key, id) in shared.attached.iter() {
+                if let Some(client) = self.clients.get_mut(key) {
+                    client.streams.remove(id);
+                    client.stats.sent(output.len());
+                }
+                for chunk in output.chunks(config.buffer_size) {
+                    frame::send(topic, &data(*key, *id, chunk))?;
+                }
+                frame::send(topic, &closed(*key, *id, Some(status), None))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn handle_message(
+        &mut self,
+        msg: &Msg,
+        config: &Config,
+        topic: &mut Topic,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if !matches!(msg.kind, MsgKind::ChannelData) {
+            DEBUG!(
+                "Receive message kind {} from client {}: {} bytes",
+                msg.kind,
+                msg.client_id,
+                msg.size
+            );
+        } else if let Some((frames, bytes)) =
+            log::throttle(Level::Debug, "receive", msg.client_id, msg.size as usize)
+        {
+            DEBUG!(
+                "Receive {} data frame(s) from client {}: {} bytes",
+                frames,
+                msg.client_id,
+                bytes
+            );
+        }
+        if let Some(client) = self.clients.get_mut(&msg.client_id) {
+            client.stats.received();
+        }
+        match msg.kind {
+            MsgKind::ChannelSubscribe => {
+                let Some(Subscription {
+                    user,
+                    session,
+                    context: _context,
+                }) = clients::subscribe(msg, config, topic, |topic| {
+                    Ok(self
+                        .remove(msg.client_id, topic)
+                        .map(|old| old.user.clone()))
+                })?
+                else {
+                    return Ok(());
+                };
+                if !self.make_room(msg.client_id, config, topic)? {
+                    return Ok(());
+                }
+                let span = clients::span(&config.topic, msg.client_id, &user, &session);
+                audit::log(config, msg.client_id, &user, &session, Event::Subscribe);
+                INFO!(
+                    "Client {} subscribe to channel {}",
+                    msg.client_id,
+                    &config.topic
+                );
+                self.clients.insert(
+                    msg.client_id,
+                    Client {
+                        user,
+                        session,
+                        streams: HashMap::new(),
+                        stats: Stats::default(),
+                        span: Some(span),
+                    },
+                );
+                clients::welcome(msg.client_id, config, topic)?;
+            }
+            MsgKind::ChannelUnsubscribe => {
+                self.unsubscribe(msg.client_id, config, topic)?;
+            }
+            MsgKind::ChannelUnsubscribeAll => self.unsubscribe_all(config, topic)?,
+            MsgKind::ChannelData => self.input(msg, topic)?,
+            MsgKind::ChannelCtrl if stats::is_query(&msg.data) => {
+                self.status(msg.client_id, topic)?;
+            }
+            MsgKind::ChannelCtrl => {
+                let request: Request = match serde_json::from_slice(&msg.data) {
+                    Ok(request) => request,
+                    Err(error) => {
+                        WARN!("Invalid request of client {}: {}", msg.client_id, error);
+                        let reason = format!("Invalid request: {}", error);
+                        return frame::send(topic, &frame::error(msg.client_id, &reason));
+                    }
+                };
+                match request {
+                    Request {
+                        open: Some(id),
+                        stream: Some(name),
+                        close: None,
+                    } => self.open(msg.client_id, id, &name, config, topic)?,
+                    Request {
+                        open: None,
+                        stream: None,
+                        close: Some(id),
+                    } => {
+                        if self.close(msg.client_id, id, topic) {
+                            INFO!("Client {} closed stream {}", msg.client_id, id);
+                            frame::send(topic, &closed(msg.client_id, id, None, None))?;
+                        } else {
+                            let reason = format!("Stream {} is not open", id);
+                            frame::send(topic, &frame::error(msg.client_id, &reason))?;
+                        }
+                    }
+                    _ => {
+                        let reason = "Invalid request: expected open and stream, or close";
+                        frame::send(topic, &frame::error(msg.client_id, reason))?;
+                    }
+                }
+            }
+            _ => {
+                WARN!(
+                    "Receive message kind {} from client {}",
+                    msg.kind,
+                    msg.client_id
+                );
+            }
+        };
+        Ok(())
+    }
+}
+
+impl Roster for Mux {
+    type Client = Client;
+
+    fn table(&self) -> &HashMap<u16, Client> {
+        &self.clients
+    }
+
+    fn table_mut(&mut self) -> &mut HashMap<u16, Client> {
+        &mut self.clients
+    }
+
+    fn release(
+        &mut self,
+        client_id: u16,
+        _config: &Config,
+        topic: &mut Topic,
+    ) -> Result<Option<Client>, Box<dyn std::error::Error>> {
+        Ok(self.remove(client_id, topic))
+    }
+}
+
+impl Backend for Mux {
+    fn fds(&self) -> Vec<RawFd> {
+        let private = self.clients.values().flat_map(|c| {
+            c.streams.values().filter_map(|t| match t {
+                Target::Private(process) => process.stdout_fd(),
+                Target::Shared(_) => None,
+            })
+        });
+        let shared = self.shared.values().filter_map(|s| s.process.stdout_fd());
+        private.chain(shared).collect()
+    }
+
+    fn clients(&self) -> usize {
+        self.clients.len()
+    }
+
+    fn dump(&self) -> Vec<String> {
+        let mut lines = vec![format!(
+            "{} client(s), {} shared stream(s)",
+            self.clients.len(),
+            self.shared.len()
+        )];
+        let mut names: Vec<&String> = self.shared.keys().collect();
+        names.sort();
+        for name in names {
+            let s = &self.shared[name];
+            lines.push(format!(
+                "shared stream {}: pid {}, {} attached",
+                name,
+                s.process.id(),
+                s.attached.len()
+            ));
+        }
+        let mut keys: Vec<&u16> = self.clients.keys().collect();
+        keys.sort();
+        for key in keys {
+            let c = &self.clients[key];
+            lines.push(format!(
+                "client {}: user {}, {} stream(s), {}",
+                key,
+                c.user,
+                c.streams.len(),
+                c.stats.describe()
+            ));
+        }
+        lines
+    }
+
+    fn step_handle(
+        &mut self,
+        evt: &CallbackEvent,
+        config: &Config,
+        topic: &mut Topic,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if let Some(msg) = evt.msg {
+            self.scoped(msg, config, topic, |mux, topic| {
+                mux.handle_message(msg, config, topic)
+            })?;
+        }
+        if let (Some(event), Some(fd)) = (evt.event, evt.fd) {
+            if event.is_readable() {
+                self.output(fd, config, topic)?;
+            }
+        }
+        self.reap_streams(config, topic)?;
+        self.enforce_windows(config, topic)?;
+        self.reap_idle(config, topic)?;
+        Ok(())
+    }
+
+    fn children_exited(
+        &mut self,
+        config: &Config,
+        topic: &mut Topic,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.reap = true;
+        self.reap_streams(config, topic)
+    }
+
+    fn disconnected(&mut self, _config: &Config) {
+        // the processes are killed with their streams
+        self.clients.clear();
+        self.shared.clear();
+    }
+
+    fn shutdown(
+        &mut self,
+        _config: &Config,
+        topic: &mut Topic,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.notify_shutdown(topic)?;
+        let private = self.clients.values_mut().flat_map(|c| {
+            c.streams.values_mut().filter_map(|t| match t {
+                Target::Private(process) => Some(process),
+                Target::Shared(_) => None,
+            })
+        });
+        let shared = self.shared.values_mut().map(|s| &mut s.process);
+        spawn::terminate(private.chain(shared).collect(), TERMINATE_GRACE);
+        self.clients.clear();
+        self.shared.clear();
+        Ok(())
+    }
+}
@@ -0,0 +1,126 @@
+//! # systemd service integration
+//!
+//! Readiness, status and watchdog notifications sent to the
+//! socket given by systemd in `NOTIFY_SOCKET`, see sd_notify(3),
+//! and sockets passed by socket activation, see sd_listen_fds(3)
+//!
+//! **Author**: "Dany LE"
+//!
+use std::cell::{Cell, RefCell};
+use std::env;
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::io::RawFd;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::time::{Duration, Instant};
+
+pub struct Notifier {
+    /// none when not run by systemd
+    socket: Option<(UnixDatagram, SocketAddr)>,
+    /// interval of the watchdog pings, half of `WATCHDOG_USEC`
+    watchdog: Option<Duration>,
+    last_ping: Cell<Instant>,
+    status: RefCell<String>,
+}
+
+impl Notifier {
+    pub fn from_env() -> Notifier {
+        let socket = env::var("NOTIFY_SOCKET").ok().and_then(|path| {
+            let addr = match path.strip_prefix('@') {
+                Some(name) => SocketAddr::from_abstract_name(name),
+                None => SocketAddr::from_pathname(&path),
+            };
+            Some((UnixDatagram::unbound().ok()?, addr.ok()?))
+        });
+        // the pings are only expected by the process systemd started
+        let watchdog = env::var("WATCHDOG_USEC")
+            .ok()
+            .filter(|_| {
+                env::var("WATCHDOG_PID").map_or(true, |pid| pid == std::process::id().to_string())
+            })
+            .and_then(|usec| usec.parse::<u64>().ok())
+            .map(|usec| Duration::from_micros(usec / 2));
+        Notifier {
+            socket,
+            watchdog,
+            last_ping: Cell::new(Instant::now()),
+            status: RefCell::new(String::new()),
+        }
+    }
+
+    /// Send a notification, e.g. `READY=1`, failures are ignored
+    pub fn notify(&self, state: &str) {
+        if let Some((socket, addr)) = self.socket.as_ref() {
+            let _ = socket.send_to_addr(state.as_bytes(), addr);
+        }
+    }
+
+    /// Called from the event loop: ping the watchdog when due
+    /// and update the status when it changed
+    pub fn step(&self, status: String) {
+        if self
+            .watchdog
+            .is_some_and(|d| self.last_ping.get().elapsed() >= d)
+        {
+            self.notify("WATCHDOG=1");
+            self.last_ping.set(Instant::now());
+        }
+        if *self.status.borrow() != status {
+            self.notify(&format!("STATUS={}", status));
+            *self.status.borrow_mut() = status;
+        }
+    }
+}
+
+/// first file descriptor passed by systemd
+const LISTEN_FDS_START: RawFd = 3;
+
+/// Sockets passed by systemd socket activation, the variables
+/// are removed so that the children do not see them
+pub fn listen_fds() -> Vec<RawFd> {
+    let pid = env::var("LISTEN_PID")
+        .ok()
+        .and_then(|p| p.parse::<u32>().ok());
+    let count = env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|n| n.parse::<RawFd>().ok());
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+    match (pid, count) {
+        (Some(pid), Some(count)) if pid == std::process::id() => {
+            let fds: Vec<RawFd> = (LISTEN_FDS_START..LISTEN_FDS_START + count).collect();
+            for fd in fds.iter() {
+                unsafe {
+                    libc::fcntl(*fd, libc::F_SETFD, libc::FD_CLOEXEC);
+                }
+            }
+            fds
+        }
+        _ => Vec::new(),
+    }
+}
+
+/// Accept and close the pending connection of an activation
+/// socket, or drain the datagram, the connection only serves
+/// to start the backend
+pub fn discard(fd: RawFd) {
+    unsafe {
+        let conn = libc::accept4(
+            fd,
+            std::ptr::null_mut(),
+            std::ptr::null_mut(),
+            libc::SOCK_CLOEXEC,
+        );
+        if conn >= 0 {
+            libc::close(conn);
+            return;
+        }
+        let mut buf = [0u8; 512];
+        libc::recv(
+            fd,
+            buf.as_mut_ptr() as *mut libc::c_void,
+            buf.len(),
+            libc::MSG_DONTWAIT,
+        );
+    }
+}
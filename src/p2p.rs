@@ -0,0 +1,1504 @@
+//! # Peer to peer channel for each subscribed client
+//!
+//! Each client gets its own process, spawned on its first
+//! data or, with the eager spawn policy, on its
+//! subscription. In `p2p-user` mode, the process runs under
+//! the Unix account of the subscribed user. With a reconnect
+//! grace period, the process of an unsubscribed client is
+//! kept for a while and rebound to the next subscription of
+//! its user
+//!
+//! **Author**: "Dany LE"
+//!
+use crate::account::Account;
+use crate::audit::{self, Event};
+use crate::backend::Backend;
+use crate::clients::{self, Roster, Subscription};
+use crate::config::{Config, Mode, SpawnPolicy};
+use crate::error::{self, Class, Classify};
+use crate::frame;
+use crate::history::{self, Run};
+use crate::kube;
+use crate::log::{self, Level};
+use crate::metrics::{self, Counter};
+use crate::probe::Probe;
+use crate::process::{self, Process};
+use crate::profile::{Phase, Timer};
+use crate::recorder::{self, Recorder};
+use crate::schedule::{self, Window};
+use crate::setup::SessionSetup;
+use crate::spawn;
+use crate::state::{self, Record};
+use crate::stats::{self, Stats};
+use crate::telemetry::{self, Span};
+use crate::tmpdir::TmpDir;
+use crate::wasm;
+use crate::{DEBUG, ERROR, INFO, WARN};
+use latpr::tunnel::{CallbackEvent, IOInterest, Msg, MsgKind, Topic};
+use latpr::utils::{LogLevel, LOG};
+use std::collections::HashMap;
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::process::ExitStatusExt;
+use std::path::Path;
+use std::process::{Command, ExitStatus, Stdio};
+use std::time::{Duration, Instant};
+
+/// a process exiting sooner than this after its spawn counts
+/// as a spawn failure
+const INSTANT_EXIT: Duration = Duration::from_secs(2);
+/// delay before the first retry after a failure, doubled on
+/// each consecutive failure
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+/// time given to the processes to exit on shutdown
+const TERMINATE_GRACE: Duration = Duration::from_secs(2);
+/// interval between two samples of the memory of the sessions
+const MEMORY_CHECK: Duration = Duration::from_secs(5);
+/// seconds during which an adopted session waits for its user
+/// when there is no reconnect grace period
+const ADOPT_GRACE: u64 = 60;
+
+/// Outcome of an attempt to spawn the process of a client
+enum Spawn {
+    Spawned,
+    /// transient failure, e.g. EAGAIN, worth retrying later
+    Retry(String),
+    /// refused by the policy or permanent failure
+    Refused(String),
+}
+
+pub struct ClientData {
+    fd: RawFd,
+    child: Option<Process>,
+    user: String,
+    /// identifier of the session, see [`telemetry::session_id`]
+    session: String,
+    setup: SessionSetup,
+    deadline: Option<Instant>,
+    /// access windows of the user, the session is terminated
+    /// when they close
+    windows: Vec<Window>,
+    /// consecutive spawn failures
+    failures: u32,
+    /// no spawn is attempted before this instant
+    retry_at: Option<Instant>,
+    /// a spawn is attempted at `retry_at` without waiting for
+    /// data of the client
+    retry_pending: bool,
+    spawned_at: Option<Instant>,
+    /// liveness probe of the process
+    probe: Probe,
+    /// counters of the client
+    stats: Stats,
+    /// `TMPDIR` of the processes of the session, removed with it
+    tmpdir: Option<TmpDir>,
+    /// current process, for the history
+    run: Option<Run>,
+    /// recording of the current process, see `record_dir`
+    recorder: Option<Recorder>,
+    /// spans of the session and of its current process, see
+    /// [`crate::telemetry`]
+    span: Option<Span>,
+    process_span: Option<Span>,
+    /// since when the input of the client is pending without
+    /// output of the process, and the pending bytes then
+    stalled: Option<(Instant, usize)>,
+    /// input written before the data of the client, e.g. the init
+    /// script, as the pipe takes it
+    input: Vec<u8>,
+}
+
+impl ClientData {
+    /// Write the queued input as the pipe takes it, return
+    /// whether it is all written
+    fn flush_input(&mut self) -> io::Result<bool> {
+        if let (Some(child), false) = (self.child.as_ref(), self.input.is_empty()) {
+            let n = child.fill_input(&self.input)?;
+            self.input.drain(..n);
+        }
+        Ok(self.input.is_empty())
+    }
+
+    /// Record the exit of the current process to the history
+    fn record_exit(&mut self, status: ExitStatus, config: &Config) {
+        self.recorder = None;
+        if let Some(mut span) = self.process_span.take() {
+            if let Some(code) = status.code() {
+                span.set("process.exit.code", code as i64);
+            }
+            if let Some(signal) = status.signal() {
+                span.set("process.exit.signal", signal as i64);
+            }
+            span.end();
+        }
+        if let Some(run) = self.run.take() {
+            let pid = run.pid;
+            audit::log(
+                config,
+                run.client_id,
+                &self.user,
+                &self.session,
+                Event::Exit { pid, status },
+            );
+            metrics::add(Counter::Exited, 1);
+            history::record(config, &self.user, &run, status);
+        }
+    }
+
+    /// Summary of the session for the state dump
+    fn describe(&self) -> String {
+        let pid = match self.child.as_ref() {
+            Some(p) => p.id().to_string(),
+            None => "none".to_string(),
+        };
+        format!(
+            "user {}, pid {}, fd {}, {}, {} failure(s)",
+            self.user,
+            pid,
+            self.fd,
+            self.stats.describe(),
+            self.failures
+        )
+    }
+
+    fn new(
+        client_id: u16,
+        user: String,
+        session: String,
+        config: &Config,
+    ) -> Result<ClientData, Box<dyn std::error::Error>> {
+        let windows = schedule::parse_all(&config.profile(&user).access_windows)?;
+        let span = clients::span(&config.topic, client_id, &user, &session);
+        Ok(ClientData {
+            span: Some(span),
+            process_span: None,
+            fd: -1,
+            child: None,
+            user,
+            session,
+            setup: SessionSetup::default(),
+            deadline: None,
+            windows,
+            failures: 0,
+            retry_at: None,
+            retry_pending: false,
+            spawned_at: None,
+            probe: Probe::default(),
+            stats: Stats::default(),
+            stalled: None,
+            input: Vec::new(),
+            tmpdir: None,
+            run: None,
+            recorder: None,
+        })
+    }
+
+    /// Count a spawn failure and delay the next attempt,
+    /// return whether the retries are exhausted
+    fn spawn_failed(&mut self, config: &Config) -> bool {
+        self.failures += 1;
+        let delay = RETRY_DELAY
+            .saturating_mul(1 << (self.failures - 1).min(16))
+            .min(MAX_RETRY_DELAY);
+        self.retry_at = Some(Instant::now() + delay);
+        self.failures >= config.max_spawn_failures
+    }
+}
+
+impl Drop for ClientData {
+    fn drop(&mut self) {
+        if let Some(span) = self.process_span.take() {
+            span.end();
+        }
+        if let Some(span) = self.span.as_mut() {
+            span.set("antd.spawns", self.stats.spawns as i64);
+        }
+        clients::end_span(&mut self.span, &self.stats);
+    }
+}
+
+crate::member!(ClientData);
+
+/// Session of an unsubscribed client kept alive during the
+/// reconnect grace period
+struct Detached {
+    client_data: ClientData,
+    /// the process is killed at this instant
+    until: Instant,
+    /// output of the process while detached
+    buffer: Vec<u8>,
+    /// bytes of output dropped from the buffer
+    dropped: u64,
+}
+
+impl Detached {
+    /// Keep the output of the process, only the most recent
+    /// `reconnect_buffer` bytes
+    fn buffer_output(&mut self, output: &[u8], config: &Config) {
+        if let Some(recorder) = self.client_data.recorder.as_mut() {
+            recorder.output(output);
+        }
+        self.buffer.extend_from_slice(output);
+        let excess = self.buffer.len().saturating_sub(config.reconnect_buffer);
+        self.buffer.drain(..excess);
+        self.dropped += excess as u64;
+        self.client_data.stats.dropped_out += excess as u64;
+        metrics::add(Counter::BytesDropped, excess as u64);
+    }
+}
+
+pub struct P2p {
+    clients: HashMap<u16, ClientData>,
+    /// sessions waiting for their user to subscribe again
+    detached: Vec<Detached>,
+    /// sessions written to the state file
+    saved: Vec<Record>,
+    /// run the processes as the subscribed users
+    as_user: bool,
+    /// whether a child may have exited since the last check
+    reap: bool,
+    /// last sample of the memory of the sessions
+    memory_checked: Option<Instant>,
+    /// last report of the resource usage, see `usage_interval`
+    usage_sent: Option<Instant>,
+}
+
+fn unsubscribe_client(
+    client_data: &mut ClientData,
+    config: &Config,
+    topic: &mut Topic,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if let Some(child) = client_data.child.as_mut() {
+        // un register IO
+        topic.unregister_io(client_data.fd)?;
+        let status = match child.try_wait() {
+            Ok(Some(status)) => status,
+            _ => {
+                INFO!("Killing the process associated to client");
+                let client_id = client_data.run.as_ref().map_or(0, |r| r.client_id);
+                audit_kill(
+                    config,
+                    client_id,
+                    &client_data.user,
+                    &client_data.session,
+                    child.id(),
+                    "unsubscribed",
+                );
+                if let Err(error) = child.kill() {
+                    WARN!(
+                        "Unable to kill child process, probably because of it has exited: {}",
+                        error
+                    );
+                }
+                ExitStatus::from_raw(libc::SIGKILL)
+            }
+        };
+        client_data.record_exit(status, config);
+    }
+    Ok(())
+}
+
+/// Record a SIGKILL sent to the process of a session
+fn audit_kill(config: &Config, client_id: u16, user: &str, session: &str, pid: u32, reason: &str) {
+    let signal = libc::SIGKILL;
+    audit::log(
+        config,
+        client_id,
+        user,
+        session,
+        Event::Signal {
+            pid,
+            signal,
+            reason,
+        },
+    );
+}
+
+impl P2p {
+    pub fn new(as_user: bool) -> P2p {
+        P2p {
+            clients: HashMap::new(),
+            detached: Vec::new(),
+            saved: Vec::new(),
+            as_user,
+            reap: true,
+            memory_checked: None,
+            usage_sent: None,
+        }
+    }
+
+    /// Take over the sessions saved by the previous instance of
+    /// the backend. The processes whose pipes were inherited are
+    /// kept as detached sessions for their users to subscribe
+    /// again, the other ones can not be served and are killed
+    pub fn restore(&mut self, config: &Config) -> Result<(), Box<dyn std::error::Error>> {
+        let mut records = match config.state_file.as_ref() {
+            None => Vec::new(),
+            Some(path) => state::load(path)?,
+        };
+        // the pidfiles also cover the sessions not saved yet
+        // when the previous instance crashed
+        if let Some(dir) = config.runtime_dir.as_ref() {
+            for record in state::take_pidfiles(dir, &config.topic)? {
+                if !records.iter().any(|r| r.pid == record.pid) {
+                    records.push(record);
+                }
+            }
+        }
+        let grace = Duration::from_secs(config.reconnect_grace.unwrap_or(ADOPT_GRACE));
+        for record in records {
+            match Process::adopt(record.pid, record.start_time, record.stdin, record.stdout) {
+                Some(process) => {
+                    INFO!(
+                        "Adopt process {} of user {} (client {})",
+                        record.pid,
+                        record.user,
+                        record.client_id
+                    );
+                    // the pidfiles of an older backend have no session
+                    let session = match record.session.is_empty() {
+                        true => telemetry::session_id(),
+                        false => record.session,
+                    };
+                    let mut client_data =
+                        ClientData::new(record.client_id, record.user, session, config)?;
+                    client_data.fd = record.stdout;
+                    client_data.run = Some(Run::start(
+                        record.client_id,
+                        &client_data.session,
+                        Path::new("(adopted)"),
+                        record.pid,
+                    ));
+                    client_data.child = Some(process);
+                    client_data.spawned_at = Some(Instant::now());
+                    self.detached.push(Detached {
+                        client_data,
+                        until: Instant::now() + grace,
+                        buffer: Vec::new(),
+                        dropped: 0,
+                    });
+                }
+                None => {
+                    if state::kill_orphan(&record) {
+                        WARN!(
+                            "Kill orphan process {} of user {}: its pipes are lost",
+                            record.pid,
+                            record.user
+                        );
+                    }
+                }
+            }
+        }
+        self.save_state(config);
+        Ok(())
+    }
+
+    /// Write the sessions to the state file and to their pidfiles
+    /// when they changed
+    fn save_state(&mut self, config: &Config) {
+        if config.state_file.is_none() && config.runtime_dir.is_none() {
+            return;
+        }
+        let sessions = self
+            .clients
+            .iter()
+            .map(|(k, c)| (*k, c))
+            .chain(self.detached.iter().map(|d| (0, &d.client_data)));
+        let records: Vec<Record> = sessions
+            .filter_map(|(client_id, c)| {
+                let process = c.child.as_ref()?;
+                Some(Record {
+                    client_id,
+                    user: c.user.clone(),
+                    session: c.session.clone(),
+                    pid: process.id(),
+                    start_time: process.start_time(),
+                    stdin: process.stdin.as_ref()?.as_raw_fd(),
+                    stdout: c.fd,
+                })
+            })
+            .collect();
+        if records == self.saved {
+            return;
+        }
+        if let Some(path) = config.state_file.as_ref() {
+            if let Err(error) = state::save(path, &records) {
+                WARN!(
+                    "Unable to save the sessions to {}: {}",
+                    path.display(),
+                    error
+                );
+            }
+        }
+        if let Some(dir) = config.runtime_dir.as_ref() {
+            for record in self.saved.iter().filter(|r| !records.contains(r)) {
+                if let Err(error) = state::remove_pidfile(dir, &config.topic, record.pid) {
+                    WARN!("Unable to remove the pidfile of {}: {}", record.pid, error);
+                }
+            }
+            for record in records.iter().filter(|r| !self.saved.contains(r)) {
+                if let Err(error) = state::write_pidfile(dir, &config.topic, record) {
+                    WARN!("Unable to write the pidfile of {}: {}", record.pid, error);
+                }
+            }
+        }
+        self.saved = records;
+    }
+
+    /// Spawn the process of a client and register its output
+    fn spawn_child(
+        client_id: u16,
+        client_data: &mut ClientData,
+        as_user: bool,
+        config: &Config,
+        topic: &mut Topic,
+    ) -> Result<Spawn, Box<dyn std::error::Error>> {
+        let profile = config.profile(&client_data.user);
+        let cmd = config.command_for(&profile, client_data.setup.command.as_deref());
+        let mut envs = config.allowed_env(&client_data.setup.env);
+        envs.extend(profile.env.iter().map(|(k, v)| (k.clone(), v.clone())));
+        envs.push((String::from("CUSER"), client_data.user.clone()));
+        envs.push((String::from("CID"), format!("{}", client_id)));
+        envs.extend(client_data.setup.envs());
+        // in the kube mode, the command is run in the container
+        // through kubectl, in the wasm mode it is the module run
+        // by wasmtime
+        let resolved = match config.mode {
+            Mode::Kube => {
+                cmd.and_then(|cmd| kube::command_line(config, cmd, &client_data.setup.args))
+            }
+            Mode::Wasm => {
+                let names: Vec<String> = envs.iter().map(|(name, _)| name.clone()).collect();
+                cmd.and_then(|cmd| wasm::command_line(config, cmd, &client_data.setup.args, &names))
+            }
+            _ => cmd
+                .and_then(|cmd| config.allowed_command(cmd))
+                .map(|path| (path, client_data.setup.args.clone())),
+        };
+        let (path, args) = match resolved {
+            Ok(resolved) => resolved,
+            Err(error) => {
+                WARN!("Refuse to spawn for client {}: {}", client_id, error);
+                return Ok(Spawn::Refused(error.to_string()));
+            }
+        };
+        let mut command = Command::new(&path);
+        command
+            .args(&args)
+            .envs(envs)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped());
+        profile.rlimits.apply(&mut command);
+        let account = if as_user {
+            match Account::lookup(&client_data.user) {
+                Ok(account) => Some(account),
+                Err(error) => {
+                    WARN!("Refuse to spawn for client {}: {}", client_id, error);
+                    return Ok(Spawn::Refused(error.to_string()));
+                }
+            }
+        } else {
+            None
+        };
+        if let Some(account) = account.as_ref() {
+            account.apply(&mut command);
+        }
+        // the directory is shared by the successive processes
+        // of the session
+        if let (Some(base), None) = (config.session_tmpdir.as_ref(), client_data.tmpdir.as_ref()) {
+            let name = format!("session-{}", client_id);
+            let owner = account.as_ref().map(|a| a.ids());
+            match TmpDir::create(base, &name, owner, config.session_tmpdir_size) {
+                Ok(dir) => client_data.tmpdir = Some(dir),
+                Err(error) => {
+                    WARN!(
+                        "Unable to create the TMPDIR of client {}: {}",
+                        client_id,
+                        error
+                    );
+                    return Ok(Spawn::Refused(format!(
+                        "Unable to create the temporary directory: {}",
+                        error
+                    )));
+                }
+            }
+        }
+        if let Some(dir) = client_data.tmpdir.as_ref() {
+            command
+                .env("TMPDIR", dir.path())
+                .env("TMP", dir.path())
+                .env("TEMP", dir.path());
+        }
+        spawn::die_with_parent(&mut command);
+        // read before spawning, not to leave a child behind
+        let script = config.init_script()?;
+        let mut process = match command.spawn() {
+            Ok(child) => Process::from(child),
+            Err(error) if spawn::is_transient(&error) => {
+                WARN!("Unable to spawn for client {}: {}", client_id, error);
+                metrics::add(Counter::SpawnFailures, 1);
+                metrics::error(Class::Spawn);
+                return Ok(Spawn::Retry(error.to_string()));
+            }
+            Err(error) => {
+                WARN!("Unable to spawn for client {}: {}", client_id, error);
+                metrics::add(Counter::SpawnFailures, 1);
+                metrics::error(Class::Spawn);
+                return Ok(Spawn::Refused(format!(
+                    "Unable to run the command: {}",
+                    error
+                )));
+            }
+        };
+        let registered = match process.stdout_fd() {
+            Some(fd) => topic.register_io(fd, IOInterest::READABLE).map(|()| fd),
+            None => Err("Unable to get child process STDOUT".into()),
+        };
+        let fd = match registered {
+            Ok(fd) => fd,
+            Err(error) => {
+                // the child is not tracked yet
+                if let Err(error) = process.kill() {
+                    WARN!("Unable to kill child process: {}", error);
+                }
+                return Err(error);
+            }
+        };
+        client_data.run = Some(Run::start(
+            client_id,
+            &client_data.session,
+            &path,
+            process.id(),
+        ));
+        metrics::add(Counter::Spawned, 1);
+        client_data.stats.spawns += 1;
+        let spawned = Event::Spawn {
+            command: &path,
+            args: &args,
+            pid: process.id(),
+        };
+        audit::log(
+            config,
+            client_id,
+            &client_data.user,
+            &client_data.session,
+            spawned,
+        );
+        client_data.process_span = client_data.span.as_ref().map(|s| {
+            let mut span = s.child("process");
+            span.set("process.command", path.display().to_string());
+            span.set("process.pid", process.id() as i64);
+            span
+        });
+        let name = format!("{}-{}-{}", client_data.user, client_id, process.id());
+        client_data.recorder = recorder::start(config, &name, Some(&client_data.setup));
+        client_data.child = Some(process);
+        client_data.fd = fd;
+        client_data.spawned_at = Some(Instant::now());
+        client_data.retry_pending = false;
+        client_data.probe = Probe::default();
+        client_data.stalled = None;
+        client_data.deadline = profile
+            .session_timeout
+            .map(|secs| Instant::now() + Duration::from_secs(secs));
+        // pre-configure the process before any client data, the
+        // rest of the script is written on the next steps
+        client_data.input = script.unwrap_or_default();
+        if let Err(error) = client_data.flush_input() {
+            WARN!(
+                "Unable to write the init script of client {}: {}",
+                client_id,
+                error
+            );
+            client_data.input.clear();
+            if let Some(child) = client_data.child.as_mut() {
+                if let Err(error) = child.kill() {
+                    WARN!("Unable to kill child process: {}", error);
+                }
+            }
+        }
+        Ok(Spawn::Spawned)
+    }
+
+    /// Spawn the process of a client. On a transient failure
+    /// the client stays subscribed and the spawn is retried with
+    /// a delay, on a permanent one the client is unsubscribed.
+    /// Return whether the process is running
+    fn spawn_or_retry(
+        &mut self,
+        client_id: u16,
+        config: &Config,
+        topic: &mut Topic,
+    ) -> Result<bool, Box<dyn std::error::Error>> {
+        let client_data = match self.clients.get_mut(&client_id) {
+            None => return Ok(false),
+            Some(client_data) => client_data,
+        };
+        let span = client_data.span.as_ref().map(|s| s.child("spawn"));
+        let timer = Timer::start(Phase::Spawn);
+        let spawned = P2p::spawn_child(client_id, client_data, self.as_user, config, topic)?;
+        drop(timer);
+        if let Some(mut span) = span {
+            match &spawned {
+                Spawn::Spawned => {}
+                Spawn::Retry(reason) | Spawn::Refused(reason) => span.fail(reason),
+            }
+            span.end();
+        }
+        let reason = match spawned {
+            Spawn::Spawned => return Ok(true),
+            Spawn::Retry(reason) => reason,
+            Spawn::Refused(reason) => {
+                self.clients.remove(&client_id);
+                clients::expel(client_id, &reason, topic)?;
+                return Ok(false);
+            }
+        };
+        if client_data.spawn_failed(config) {
+            self.give_up(client_id, topic)?;
+            return Ok(false);
+        }
+        client_data.retry_pending = true;
+        let delay = client_data
+            .retry_at
+            .map_or(0, |t| t.saturating_duration_since(Instant::now()).as_secs());
+        let notice = format!("Temporary failure, retrying in {}s: {}", delay, reason);
+        frame::send(topic, &frame::error(client_id, &notice))?;
+        Ok(false)
+    }
+
+    /// Kill the sessions whose process and its descendants use
+    /// more than `max_memory`, a softer limit than `address_space`
+    /// that many programs handle poorly
+    fn check_memory(
+        &mut self,
+        config: &Config,
+        topic: &mut Topic,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let max = match config.max_memory {
+            None => return Ok(()),
+            Some(max) => max,
+        };
+        if self
+            .memory_checked
+            .is_some_and(|t| t.elapsed() < MEMORY_CHECK)
+        {
+            return Ok(());
+        }
+        self.memory_checked = Some(Instant::now());
+        let sessions: Vec<(u16, u32)> = self
+            .clients
+            .iter()
+            .filter_map(|(k, c)| Some((*k, c.child.as_ref()?.id())))
+            .collect();
+        let roots: Vec<u32> = sessions.iter().map(|(_, pid)| *pid).collect();
+        for ((key, pid), tree) in sessions.into_iter().zip(process::trees(&roots)) {
+            let used: u64 = tree.iter().filter_map(|p| process::rss(*p)).sum();
+            if used <= max {
+                continue;
+            }
+            WARN!(
+                "Session of client {} uses {} bytes of memory (process {}), killing it",
+                key,
+                used,
+                pid
+            );
+            frame::send(topic, &frame::error(key, "Memory limit exceeded"))?;
+            // the descendants first, the process would not
+            // forward the signal
+            for p in tree.iter().skip(1) {
+                unsafe {
+                    libc::kill(*p as libc::pid_t, libc::SIGKILL);
+                }
+            }
+            if let Some(c) = self.clients.get(&key) {
+                let quota = Event::Quota("max_memory");
+                audit::log(config, key, &c.user, &c.session, quota);
+                audit_kill(
+                    config,
+                    key,
+                    &c.user,
+                    &c.session,
+                    pid,
+                    "memory limit exceeded",
+                );
+            }
+            if let Some(child) = self.clients.get_mut(&key).and_then(|c| c.child.as_mut()) {
+                if let Err(error) = child.kill() {
+                    WARN!("Unable to kill child process: {}", error);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Send each client the resource usage of the tree of its
+    /// process every `usage_interval`
+    fn report_usage(
+        &mut self,
+        config: &Config,
+        topic: &mut Topic,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let interval = match config.usage_interval {
+            None => return Ok(()),
+            Some(secs) => Duration::from_secs(secs),
+        };
+        if self.usage_sent.is_some_and(|t| t.elapsed() < interval) {
+            return Ok(());
+        }
+        self.usage_sent = Some(Instant::now());
+        let sessions: Vec<(u16, u32)> = self
+            .clients
+            .iter()
+            .filter_map(|(k, c)| Some((*k, c.child.as_ref()?.id())))
+            .collect();
+        let roots: Vec<u32> = sessions.iter().map(|(_, pid)| *pid).collect();
+        for ((key, _), tree) in sessions.into_iter().zip(process::trees(&roots)) {
+            if let Some(client_data) = self.clients.get(&key) {
+                let (cpu, rss) = process::usage(&tree);
+                frame::send(topic, &frame::usage(key, cpu, rss, &client_data.stats))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Stop retrying to spawn the process of a client
+    fn give_up(
+        &mut self,
+        client_id: u16,
+        topic: &mut Topic,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        WARN!(
+            "Too many spawn failures for client {}, giving up",
+            client_id
+        );
+        self.clients.remove(&client_id);
+        clients::expel(client_id, "The command keeps failing, giving up", topic)
+    }
+
+    fn monitor_clients(
+        &mut self,
+        config: &Config,
+        topic: &mut Topic,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        // the processes are only checked for exit after a SIGCHLD
+        let reap = std::mem::take(&mut self.reap);
+        let mut failing = Vec::new();
+        let mut finished = Vec::new();
+        let mut hung = Vec::new();
+        let closed: Vec<u16> = self
+            .clients
+            .iter()
+            .filter(|(_, c)| !schedule::is_open(&c.windows))
+            .map(|(k, _)| *k)
+            .collect();
+        for key in closed {
+            if let Some(mut client_data) = self.clients.remove(&key) {
+                WARN!("Access window of client {} has closed", key);
+                clients::expel(key, "Access window closed", topic)?;
+                let reason = Event::Unsubscribe("access window closed");
+                audit::log(config, key, &client_data.user, &client_data.session, reason);
+                unsubscribe_client(&mut client_data, config, topic)?;
+            }
+        }
+        self.reap_idle(config, topic)?;
+        for (key, value) in self.clients.iter_mut() {
+            let _context = log::context(&config.topic, *key, &value.user, &value.session);
+            if let Err(error) = value.flush_input() {
+                WARN!(
+                    "Unable to write the init script of client {}: {}",
+                    key,
+                    error
+                );
+                value.input.clear();
+            }
+            if let Some(child) = value.child.as_mut() {
+                // enforce the maximum lifetime of the session
+                if value.deadline.is_some_and(|d| Instant::now() >= d) {
+                    WARN!("Session of client {} has timed out", key);
+                    value.deadline = None;
+                    frame::send(topic, &frame::error(*key, "Session timeout"))?;
+                    let quota = Event::Quota("session_timeout");
+                    audit::log(config, *key, &value.user, &value.session, quota);
+                    let reason = "session timeout";
+                    audit_kill(
+                        config,
+                        *key,
+                        &value.user,
+                        &value.session,
+                        child.id(),
+                        reason,
+                    );
+                    if let Err(error) = child.kill() {
+                        WARN!("Unable to kill child process: {}", error);
+                    }
+                }
+                // kill the process once it fails its probes
+                if value.probe.check(child.id(), &value.user, *key, config) {
+                    WARN!("Process of client {} is not responding, killing it", key);
+                    value.probe = Probe::default();
+                    frame::send(topic, &frame::error(*key, "Session is not responding"))?;
+                    let reason = "probe failed";
+                    audit_kill(
+                        config,
+                        *key,
+                        &value.user,
+                        &value.session,
+                        child.id(),
+                        reason,
+                    );
+                    if let Err(error) = child.kill() {
+                        WARN!("Unable to kill child process: {}", error);
+                    }
+                    if config.probe_restart {
+                        hung.push(*key);
+                    }
+                }
+                // kill the process once it has neither produced output
+                // nor consumed the input of its client for too long
+                if let Some((since, pending)) = value.stalled {
+                    if child.pending_input() < pending {
+                        value.stalled = None;
+                    } else if config
+                        .stall_timeout
+                        .is_some_and(|secs| since.elapsed() >= Duration::from_secs(secs))
+                    {
+                        WARN!("Session of client {} is stalled", key);
+                        value.stalled = None;
+                        frame::send(topic, &frame::error(*key, "Session is stalled"))?;
+                        if config.stall_restart {
+                            let (user, session) = (&value.user, &value.session);
+                            audit_kill(config, *key, user, session, child.id(), "stalled");
+                            if let Err(error) = child.kill() {
+                                WARN!("Unable to kill child process: {}", error);
+                            }
+                            hung.push(*key);
+                        }
+                    }
+                }
+                // check if the child is exited
+                let exited = if reap { child.try_wait()? } else { None };
+                if let Some(status) = exited {
+                    // unregister IO
+                    WARN!(
+                        "Process attached to client {} has exited with status {}",
+                        key,
+                        status
+                    );
+                    topic.unregister_io(value.fd)?;
+                    value.fd = -1;
+                    value.child = None;
+                    value.input.clear();
+                    value.record_exit(status, config);
+                    if config.unsubscribe_on_exit {
+                        finished.push((*key, status));
+                    } else if value.spawned_at.is_some_and(|t| t.elapsed() < INSTANT_EXIT) {
+                        if value.spawn_failed(config) {
+                            failing.push(*key);
+                        }
+                    } else {
+                        value.failures = 0;
+                    }
+                }
+            }
+        }
+        for key in failing {
+            self.give_up(key, topic)?;
+        }
+        self.check_memory(config, topic)?;
+        self.report_usage(config, topic)?;
+        // replace the hung processes, once reaped
+        for key in hung {
+            if let Some(client_data) = self.clients.get_mut(&key) {
+                if let Some(mut child) = client_data.child.take() {
+                    if let Ok(status) = child.wait() {
+                        client_data.record_exit(status, config);
+                    }
+                    topic.unregister_io(client_data.fd)?;
+                    client_data.fd = -1;
+                }
+                INFO!("Restart the process of client {}", key);
+            }
+            self.spawn_or_retry(key, config, topic)?;
+        }
+        // retry the spawns that have failed transiently
+        let due: Vec<u16> = self
+            .clients
+            .iter()
+            .filter(|(_, c)| c.retry_pending && c.retry_at.is_none_or(|t| Instant::now() >= t))
+            .map(|(k, _)| *k)
+            .collect();
+        for key in due {
+            INFO!("Retry to spawn the process of client {}", key);
+            self.spawn_or_retry(key, config, topic)?;
+        }
+        // one session per process: the client leaves with it
+        for (key, status) in finished {
+            INFO!("Unsubscribe client {}: its process has exited", key);
+            self.clients.remove(&key);
+            frame::send(topic, &frame::exit_status(key, status))?;
+            let msg = Msg::create(MsgKind::ChannelUnsubscribe, 0, key, Vec::new());
+            frame::send(topic, &msg)?;
+        }
+        // end the detached sessions whose grace period is over
+        // or whose process has exited
+        let mut i = 0;
+        while i < self.detached.len() {
+            let detached = &mut self.detached[i];
+            let exited = match detached.client_data.child.as_mut() {
+                Some(child) => reap && child.try_wait()?.is_some(),
+                None => true,
+            };
+            if exited || Instant::now() >= detached.until {
+                INFO!(
+                    "End the detached session of user {}",
+                    detached.client_data.user
+                );
+                let mut detached = self.detached.remove(i);
+                unsubscribe_client(&mut detached.client_data, config, topic)?;
+            } else {
+                i += 1;
+            }
+        }
+        Ok(())
+    }
+
+    /// Handle a message of a client
+    fn handle_message(
+        &mut self,
+        msg: &Msg,
+        config: &Config,
+        topic: &mut Topic,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        // the data frames are too frequent to be logged one by one
+        if !matches!(msg.kind, MsgKind::ChannelData) {
+            DEBUG!(
+                "Receive message kind {} from client {}: {} bytes",
+                msg.kind,
+                msg.client_id,
+                msg.size
+            );
+        } else if let Some((frames, bytes)) =
+            log::throttle(Level::Debug, "receive", msg.client_id, msg.size as usize)
+        {
+            DEBUG!(
+                "Receive {} data frame(s) from client {}: {} bytes",
+                frames,
+                msg.client_id,
+                bytes
+            );
+        }
+        if let Some(client) = self.clients.get_mut(&msg.client_id) {
+            client.stats.received();
+        }
+        match msg.kind {
+            MsgKind::ChannelSubscribe => {
+                // a new subscription replaces the session of the
+                // same client id, whose process would be leaked
+                let Some(Subscription {
+                    user,
+                    session,
+                    context: _context,
+                }) = clients::subscribe(msg, config, topic, |topic| {
+                    let Some(mut old) = self.clients.remove(&msg.client_id) else {
+                        return Ok(None);
+                    };
+                    unsubscribe_client(&mut old, config, topic)?;
+                    Ok(Some(old.user.clone()))
+                })?
+                else {
+                    return Ok(());
+                };
+                INFO!(
+                    "Client ({}) {} subscribe to channel {}",
+                    &user,
+                    msg.client_id,
+                    &config.topic
+                );
+                if !self.make_room(msg.client_id, config, topic)? {
+                    return Ok(());
+                }
+                if let Some(max) = config.max_sessions_per_user {
+                    let sessions = self.clients.values().filter(|c| c.user == user).count();
+                    if sessions >= max {
+                        WARN!(
+                            "Refuse client {}: user {} already has {} session(s)",
+                            msg.client_id,
+                            &user,
+                            sessions
+                        );
+                        let quota = Event::Quota("max_sessions_per_user");
+                        audit::log(config, msg.client_id, &user, &session, quota);
+                        let reason =
+                            format!("Too many sessions for user {}, maximum is {}", user, max);
+                        frame::send(topic, &frame::error(msg.client_id, &reason))?;
+                        return Ok(());
+                    }
+                }
+                // rebind a session kept alive since its user left
+                if let Some(i) = self
+                    .detached
+                    .iter()
+                    .position(|d| d.client_data.user == user)
+                {
+                    let mut detached = self.detached.remove(i);
+                    detached.client_data.stats.last_active = Instant::now();
+                    INFO!(
+                        "Rebind the session of user {} to client {}",
+                        &user,
+                        msg.client_id
+                    );
+                    clients::welcome(msg.client_id, config, topic)?;
+                    if detached.dropped > 0 {
+                        WARN!(
+                            "Output of the session of user {} truncated while detached: {} bytes dropped",
+                            &user,
+                            detached.dropped
+                        );
+                        let reason = "Output truncated while disconnected";
+                        frame::send(
+                            topic,
+                            &frame::dropped(msg.client_id, reason, detached.dropped),
+                        )?;
+                    }
+                    if !detached.buffer.is_empty() {
+                        let data =
+                            Msg::create(MsgKind::ChannelData, 0, msg.client_id, detached.buffer);
+                        frame::send(topic, &data)?;
+                    }
+                    // the session goes on, with its own identifier
+                    let session = &detached.client_data.session;
+                    audit::log(config, msg.client_id, &user, session, Event::Subscribe);
+                    self.clients.insert(msg.client_id, detached.client_data);
+                    return Ok(());
+                }
+                audit::log(config, msg.client_id, &user, &session, Event::Subscribe);
+                let client_data = ClientData::new(msg.client_id, user, session, config)?;
+                clients::welcome(msg.client_id, config, topic)?;
+                self.clients.insert(msg.client_id, client_data);
+                // spawn right away so that the prompt shows up before
+                // any input, the setup then applies to the next process
+                if config.spawn == SpawnPolicy::Eager {
+                    self.spawn_or_retry(msg.client_id, config, topic)?;
+                }
+            }
+            MsgKind::ChannelUnsubscribe => {
+                WARN!(
+                    "Client {} unsubscribe to channel {}",
+                    msg.client_id,
+                    &config.topic
+                );
+                let removed = self.clients.remove(&msg.client_id);
+                if let Some(client_data) = removed.as_ref() {
+                    let reason = Event::Unsubscribe("client");
+                    let (user, session) = (&client_data.user, &client_data.session);
+                    audit::log(config, msg.client_id, user, session, reason);
+                }
+                match removed {
+                    None => WARN!("Client {} is not in the client list", msg.client_id),
+                    Some(mut client_data) if client_data.child.is_some() => {
+                        match config.reconnect_grace {
+                            // keep the process for a while, the user
+                            // may be back soon
+                            Some(secs) => {
+                                INFO!("Keep the session of client {} for {}s", msg.client_id, secs);
+                                self.detached.push(Detached {
+                                    client_data,
+                                    until: Instant::now() + Duration::from_secs(secs),
+                                    buffer: Vec::new(),
+                                    dropped: 0,
+                                });
+                            }
+                            None => unsubscribe_client(&mut client_data, config, topic)?,
+                        }
+                    }
+                    Some(_) => {}
+                }
+            }
+            MsgKind::ChannelUnsubscribeAll => {
+                self.unsubscribe_all(config, topic)?;
+                for detached in self.detached.iter_mut() {
+                    unsubscribe_client(&mut detached.client_data, config, topic)?;
+                }
+                self.detached.clear();
+            }
+            MsgKind::ChannelData => {
+                // create the process if necessary then write data to the handle
+                match self.clients.get_mut(&msg.client_id) {
+                    None => WARN!("Client {} is not in the list", msg.client_id),
+                    Some(client_data) => {
+                        if client_data.child.is_none() {
+                            // back off after a failure instead of
+                            // respawning on each keystroke
+                            if client_data.retry_at.is_some_and(|t| Instant::now() < t) {
+                                frame::send(
+                                    topic,
+                                    &frame::error(
+                                        msg.client_id,
+                                        "The command has failed, retrying later",
+                                    ),
+                                )?;
+                                return Ok(());
+                            }
+                            if !self.spawn_or_retry(msg.client_id, config, topic)? {
+                                return Ok(());
+                            }
+                        }
+                        // write data to child
+                        if let Some(client_data) = self.clients.get_mut(&msg.client_id) {
+                            // the data goes after the init script
+                            let queued = !client_data.flush_input().class(Class::ChildIo)?;
+                            if let Some(child) = client_data.child.as_ref() {
+                                if !queued && child.write_input(&msg.data).class(Class::ChildIo)? {
+                                    client_data.stats.bytes_in += msg.data.len() as u64;
+                                    if let Some(recorder) = client_data.recorder.as_mut() {
+                                        recorder.input(&msg.data);
+                                    }
+                                    metrics::add(Counter::BytesIn, msg.data.len() as u64);
+                                } else {
+                                    let n = msg.data.len() as u64;
+                                    client_data.stats.dropped_in += n;
+                                    metrics::add(Counter::BytesDropped, n);
+                                    WARN!(
+                                        "Drop {} bytes of client {}: input pipe full, {} bytes dropped in total",
+                                        n,
+                                        msg.client_id,
+                                        client_data.stats.dropped_in
+                                    );
+                                    frame::send(
+                                        topic,
+                                        &frame::dropped(
+                                            msg.client_id,
+                                            "Session is not reading its input",
+                                            n,
+                                        ),
+                                    )?;
+                                }
+                                // the input is left unread, the session
+                                // may be wedged
+                                let pending = child.pending_input();
+                                if pending > 0 && client_data.stalled.is_none() {
+                                    client_data.stalled = Some((Instant::now(), pending));
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+            MsgKind::ChannelCtrl => {
+                // session setup, only applied to the next spawned process
+                match self.clients.get_mut(&msg.client_id) {
+                    None => WARN!("Client {} is not in the list", msg.client_id),
+                    Some(_) if stats::is_query(&msg.data) => self.status(msg.client_id, topic)?,
+                    Some(client_data) => {
+                        let setup = SessionSetup::parse(&msg.data)?;
+                        // the selected command must be in the menu and allowed,
+                        // and the arguments must match the policy
+                        let profile = config.profile(&client_data.user);
+                        let checked = config
+                            .command_for(&profile, setup.command.as_deref())
+                            .and_then(|cmd| match config.mode {
+                                Mode::Kube => Ok(()),
+                                _ => config.allowed_command(cmd).map(|_| ()),
+                            })
+                            .and_then(|_| config.check_args(&setup.args));
+                        if let Err(error) = checked {
+                            WARN!("Refuse setup of client {}: {}", msg.client_id, error);
+                            frame::send(topic, &frame::error(msg.client_id, &error.to_string()))?;
+                            return Ok(());
+                        }
+                        for name in setup.env.keys() {
+                            if !config.env_allow.contains(name) {
+                                WARN!(
+                                    "Ignore variable {} from client {}: not allowed",
+                                    name,
+                                    msg.client_id
+                                );
+                            }
+                        }
+                        client_data.setup = setup;
+                        if client_data.child.is_some() {
+                            WARN!(
+                                "Process of client {} is running, setup applies to the next one",
+                                msg.client_id
+                            );
+                        }
+                    }
+                }
+            }
+            _ => {
+                WARN!(
+                    "Receive message kind {} from client {}",
+                    msg.kind,
+                    msg.client_id
+                );
+            }
+        };
+        Ok(())
+    }
+}
+
+impl Roster for P2p {
+    type Client = ClientData;
+
+    fn table(&self) -> &HashMap<u16, ClientData> {
+        &self.clients
+    }
+
+    fn table_mut(&mut self) -> &mut HashMap<u16, ClientData> {
+        &mut self.clients
+    }
+
+    // the process of the client is killed
+    fn release(
+        &mut self,
+        client_id: u16,
+        config: &Config,
+        topic: &mut Topic,
+    ) -> Result<Option<ClientData>, Box<dyn std::error::Error>> {
+        let mut removed = self.clients.remove(&client_id);
+        if let Some(client_data) = removed.as_mut() {
+            unsubscribe_client(client_data, config, topic)?;
+        }
+        Ok(removed)
+    }
+}
+
+impl Backend for P2p {
+    fn fds(&self) -> Vec<RawFd> {
+        self.clients
+            .values()
+            .chain(self.detached.iter().map(|d| &d.client_data))
+            .filter(|c| c.child.is_some())
+            .map(|c| c.fd)
+            .collect()
+    }
+
+    fn children_exited(
+        &mut self,
+        config: &Config,
+        topic: &mut Topic,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.reap = true;
+        self.monitor_clients(config, topic)?;
+        self.save_state(config);
+        Ok(())
+    }
+
+    fn disconnected(&mut self, config: &Config) {
+        // the sessions are rebound when their users subscribe
+        // again to the hub
+        let grace = Duration::from_secs(config.reconnect_grace.unwrap_or(ADOPT_GRACE));
+        for (key, client_data) in self.clients.drain() {
+            if client_data.child.is_none() {
+                continue;
+            }
+            INFO!("Keep the session of client {} during the hub outage", key);
+            self.detached.push(Detached {
+                client_data,
+                until: Instant::now() + grace,
+                buffer: Vec::new(),
+                dropped: 0,
+            });
+        }
+    }
+
+    fn step_offline(&mut self, config: &Config) {
+        // the grace period starts once the hub is back
+        let grace = Duration::from_secs(config.reconnect_grace.unwrap_or(ADOPT_GRACE));
+        let mut buf = vec![0; config.buffer_size];
+        for d in self.detached.iter_mut() {
+            d.until = d.until.max(Instant::now() + grace);
+            while let Some(child) = d.client_data.child.as_mut() {
+                match child.read_output(&mut buf[..]) {
+                    Ok(Some(n)) if n > 0 => d.buffer_output(&buf[0..n], config),
+                    _ => break,
+                }
+            }
+        }
+    }
+
+    fn inherited_fds(&self) -> Vec<RawFd> {
+        self.clients
+            .values()
+            .chain(self.detached.iter().map(|d| &d.client_data))
+            .filter_map(|c| c.child.as_ref())
+            .flat_map(|p| [p.stdin.as_ref(), p.stdout.as_ref()])
+            .flatten()
+            .map(|f| f.as_raw_fd())
+            .collect()
+    }
+
+    fn clients(&self) -> usize {
+        self.clients.len()
+    }
+
+    fn dump(&self) -> Vec<String> {
+        let mut lines = vec![format!(
+            "{} client(s), {} detached session(s)",
+            self.clients.len(),
+            self.detached.len()
+        )];
+        let mut keys: Vec<&u16> = self.clients.keys().collect();
+        keys.sort();
+        for key in keys {
+            lines.push(format!("client {}: {}", key, self.clients[key].describe()));
+        }
+        for d in self.detached.iter() {
+            lines.push(format!(
+                "detached for {}s: {}, {} bytes pending",
+                d.until.saturating_duration_since(Instant::now()).as_secs(),
+                d.client_data.describe(),
+                d.buffer.len()
+            ));
+        }
+        lines
+    }
+
+    fn step_handle(
+        &mut self,
+        evt: &CallbackEvent,
+        config: &Config,
+        topic: &mut Topic,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if let Some(msg) = evt.msg {
+            self.scoped(msg, config, topic, |p2p, topic| {
+                p2p.handle_message(msg, config, topic)
+            })?;
+        }
+        self.monitor_clients(config, topic)?;
+        self.save_state(config);
+        let event = match evt.event {
+            None => return Ok(()),
+            Some(e) => e,
+        };
+        let fd = match evt.fd {
+            None => return Ok(()),
+            Some(d) => d,
+        };
+        if event.is_readable() {
+            // got data send it to client
+            let mut buf = vec![0; config.buffer_size];
+            // the owner is found by the pipe of its current process,
+            // never by a descriptor cached from a previous one
+            let owns = |c: &ClientData| c.child.as_ref().and_then(|p| p.stdout_fd()) == Some(fd);
+            if let Some((k, v)) = self.clients.iter_mut().find(|(_, c)| owns(c)) {
+                let key = *k;
+                let _context = log::context(&config.topic, key, &v.user, &v.session);
+                let output = match v.child.as_mut() {
+                    Some(child) => child.read_output(&mut buf[..]),
+                    None => Ok(None),
+                };
+                match output {
+                    Ok(Some(n)) if n > 0 => {
+                        if let Some((reads, bytes)) = log::throttle(Level::Debug, "send", key, n) {
+                            DEBUG!(
+                                "Sending {} bytes of raw data to client {} in {} read(s)",
+                                bytes,
+                                key,
+                                reads
+                            );
+                        }
+                        v.stats.sent(n);
+                        if let Some(recorder) = v.recorder.as_mut() {
+                            recorder.output(&buf[0..n]);
+                        }
+                        metrics::add(Counter::BytesOut, n as u64);
+                        v.stalled = None;
+                        let msg = Msg::create(MsgKind::ChannelData, 0, key, buf[0..n].to_vec());
+                        frame::send(topic, &msg)?;
+                    }
+                    Ok(_) => {}
+                    Err(error) => {
+                        ERROR!(
+                            "Unable to read the output of client {}: {}: {}",
+                            key,
+                            error::report(&error, Class::ChildIo),
+                            error
+                        );
+                        self.drop_client(key, config, topic)?;
+                    }
+                }
+            } else if let Some(d) = self.detached.iter_mut().find(|d| owns(&d.client_data)) {
+                if let Some(child) = d.client_data.child.as_mut() {
+                    if let Some(n) = child.read_output(&mut buf[..]).class(Class::ChildIo)? {
+                        d.buffer_output(&buf[0..n], config);
+                    }
+                }
+            } else {
+                DEBUG!("Ignore event of descriptor {}: no process owns it", fd);
+            }
+        }
+        Ok(())
+    }
+
+    fn kill_session(
+        &mut self,
+        client_id: u16,
+        reason: &str,
+        restart: bool,
+        config: &Config,
+        topic: &mut Topic,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let client_data = self
+            .clients
+            .get_mut(&client_id)
+            .ok_or_else(|| format!("no session for client {}", client_id))?;
+        let child = client_data
+            .child
+            .as_mut()
+            .ok_or_else(|| format!("client {} has no process", client_id))?;
+        frame::send(topic, &frame::error(client_id, reason))?;
+        let (user, session) = (&client_data.user, &client_data.session);
+        audit_kill(config, client_id, user, session, child.id(), reason);
+        if let Err(error) = child.kill() {
+            WARN!("Unable to kill child process: {}", error);
+        }
+        if !restart {
+            // the exit is handled on SIGCHLD as any other
+            return Ok(());
+        }
+        if let Some(mut child) = client_data.child.take() {
+            if let Ok(status) = child.wait() {
+                client_data.record_exit(status, config);
+            }
+            topic.unregister_io(client_data.fd)?;
+            client_data.fd = -1;
+        }
+        INFO!("Restart the process of client {}", client_id);
+        self.spawn_or_retry(client_id, config, topic)?;
+        Ok(())
+    }
+
+    fn shutdown(
+        &mut self,
+        config: &Config,
+        topic: &mut Topic,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.notify_shutdown(topic)?;
+        self.terminate(config);
+        Ok(())
+    }
+
+    fn terminate(&mut self, config: &Config) {
+        let children = self
+            .clients
+            .values_mut()
+            .chain(self.detached.iter_mut().map(|d| &mut d.client_data))
+            .filter_map(|c| c.child.as_mut())
+            .collect();
+        spawn::terminate(children, TERMINATE_GRACE);
+        for c in self
+            .clients
+            .values_mut()
+            .chain(self.detached.iter_mut().map(|d| &mut d.client_data))
+        {
+            if let Some(Ok(Some(status))) = c.child.as_mut().map(|p| p.try_wait()) {
+                c.record_exit(status, config);
+            }
+        }
+        self.clients.clear();
+        self.detached.clear();
+    }
+}
@@ -0,0 +1,803 @@
+//! # Session playback backend
+//!
+//! Instead of running a command, `shreplay` plays the session
+//! recordings (see [`crate::recorder`]) back to its clients with
+//! their timing. Each client chooses a recording and controls
+//! its playback with control frames:
+//!
+//! ```text
+//! -> ctrl {"list": true}
+//! <- ctrl {"recordings": [{"name": "shell-alice-1700000000.cast", "size": 5120, "modified": 1700000042}]}
+//! -> ctrl {"play": "shell-alice-1700000000.cast"}
+//! <- ctrl {"recording": {"name": ..., "width": 80, "height": 24, "title": "shell", "timestamp": 1700000000, "duration": 42.5}}
+//! <- data ...
+//! -> ctrl {"pause": true}
+//! -> ctrl {"seek": 30.0}
+//! -> ctrl {"speed": 2.0}
+//! <- ctrl {"position": 30.0, "duration": 42.5, "paused": true, "speed": 2.0}
+//! <- ctrl {"ended": true}
+//! ```
+//!
+//! The frames are timed with a timer descriptor per client, so
+//! that they are not delayed to the next step of the topic
+//!
+//! **Author**: "Dany LE"
+//!
+use crate::audit::{self, Event};
+use crate::backend::Backend;
+use crate::clients::{self, Roster, Subscription};
+use crate::config::Config;
+use crate::error::{self, Class, Classify};
+use crate::frame;
+use crate::log::{self, Level};
+use crate::metrics::{self, Counter};
+use crate::stats::{self, Stats};
+use crate::telemetry::Span;
+use crate::{DEBUG, ERROR, INFO, WARN};
+use latpr::tunnel::{CallbackEvent, IOInterest, Msg, MsgKind, Topic};
+use latpr::utils::{LogLevel, LOG};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Read};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+/// largest recording that can be played, the recordings are
+/// loaded in memory
+const MAX_SIZE: u64 = 64 * 1024 * 1024;
+/// fastest playback speed
+const MAX_SPEED: f64 = 16.0;
+/// sent before the output of a seek, so that the terminal of
+/// the client is redrawn from scratch
+const RESET: &[u8] = b"\x1bc";
+
+/// Settings of the playback mode, e.g.
+///
+/// ```toml
+/// [playback]
+/// dir = "/var/lib/antd/recordings"
+/// max_idle = 2.0
+/// ```
+#[derive(Deserialize, Serialize, Default, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct PlaybackSettings {
+    /// directory of the recordings, `record_dir` when not set
+    pub dir: Option<PathBuf>,
+    /// longest pause between two frames in seconds, as the
+    /// `--idle-time-limit` of `asciinema play`
+    pub max_idle: Option<f64>,
+}
+
+impl PlaybackSettings {
+    /// Directory of the recordings
+    pub fn dir<'a>(&'a self, config: &'a Config) -> Option<&'a Path> {
+        self.dir.as_deref().or(config.record_dir.as_deref())
+    }
+}
+
+/// Content of a frame of a recording
+enum Content {
+    Output(Vec<u8>),
+    /// new size of the terminal, columns and rows
+    Resize(u64, u64),
+}
+
+/// A frame of a recording, with its time from the start
+struct Entry {
+    time: f64,
+    content: Content,
+}
+
+/// A recording loaded for its playback
+struct Recording {
+    name: String,
+    path: PathBuf,
+    size: u64,
+    header: Value,
+    entries: Vec<Entry>,
+    duration: f64,
+}
+
+impl Recording {
+    /// Load the output and resize frames of an asciicast v2 file,
+    /// the pauses being shortened to `max_idle`
+    fn load(name: &str, dir: &Path, max_idle: Option<f64>) -> io::Result<Recording> {
+        let invalid = |what: &str| io::Error::new(io::ErrorKind::InvalidData, what.to_string());
+        let path = dir.join(name);
+        let size = fs::metadata(&path)?.len();
+        if size > MAX_SIZE {
+            return Err(invalid("recording too large"));
+        }
+        let mut lines = BufReader::new(File::open(&path)?).lines();
+        let header: Value = match lines.next() {
+            Some(line) => serde_json::from_str(&line?)?,
+            None => return Err(invalid("empty recording")),
+        };
+        if header["version"] != json!(2) {
+            return Err(invalid("not an asciicast v2 recording"));
+        }
+        let mut entries = Vec::new();
+        let (mut last, mut time) = (0.0, 0.0);
+        for line in lines {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let (at, kind, data): (f64, String, String) = serde_json::from_str(&line)?;
+            let delay = (at - last).max(0.0);
+            last = at;
+            time += max_idle.map_or(delay, |max| delay.min(max));
+            let content = match kind.as_str() {
+                "o" => Content::Output(data.into_bytes()),
+                "r" => match data.split_once('x').map(|(c, r)| (c.parse(), r.parse())) {
+                    Some((Ok(cols), Ok(rows))) => Content::Resize(cols, rows),
+                    _ => continue,
+                },
+                // the input is not played back, its effect is in the output
+                _ => continue,
+            };
+            entries.push(Entry { time, content });
+        }
+        Ok(Recording {
+            name: name.to_string(),
+            path,
+            size,
+            header,
+            entries,
+            duration: time,
+        })
+    }
+
+    /// Description of the recording sent when its playback starts
+    fn to_json(&self) -> Value {
+        json!({
+            "name": self.name,
+            "width": self.header["width"],
+            "height": self.header["height"],
+            "title": self.header["title"],
+            "timestamp": self.header["timestamp"],
+            "duration": self.duration,
+        })
+    }
+}
+
+/// Playback of a recording for a client
+struct Player {
+    recording: Recording,
+    /// index of the next entry to send
+    next: usize,
+    /// position in seconds when the playback was last resumed
+    /// or paused
+    position: f64,
+    /// when the playback was resumed, none while paused
+    resumed: Option<Instant>,
+    speed: f64,
+    /// timer of the next entry, watched by the topic
+    timer: File,
+}
+
+impl Player {
+    fn new(recording: Recording) -> io::Result<Player> {
+        let fd = unsafe {
+            libc::timerfd_create(
+                libc::CLOCK_MONOTONIC,
+                libc::TFD_NONBLOCK | libc::TFD_CLOEXEC,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Player {
+            recording,
+            next: 0,
+            position: 0.0,
+            resumed: Some(Instant::now()),
+            speed: 1.0,
+            timer: unsafe { File::from_raw_fd(fd) },
+        })
+    }
+
+    /// Current position in seconds
+    fn position(&self) -> f64 {
+        let played = self
+            .resumed
+            .map_or(0.0, |t| t.elapsed().as_secs_f64() * self.speed);
+        (self.position + played).min(self.recording.duration)
+    }
+
+    /// Pause or resume the playback
+    fn pause(&mut self, paused: bool) {
+        self.position = self.position();
+        self.resumed = if paused { None } else { Some(Instant::now()) };
+    }
+
+    fn set_speed(&mut self, speed: f64) {
+        self.position = self.position();
+        if self.resumed.is_some() {
+            self.resumed = Some(Instant::now());
+        }
+        self.speed = speed;
+    }
+
+    /// Move to a position, the entries up to it are to be sent
+    /// again from the start
+    fn seek(&mut self, position: f64) {
+        self.position = position.clamp(0.0, self.recording.duration);
+        if self.resumed.is_some() {
+            self.resumed = Some(Instant::now());
+        }
+        self.next = 0;
+    }
+
+    /// Take the entries that are due
+    fn due(&mut self) -> &[Entry] {
+        let position = self.position();
+        let start = self.next;
+        while self
+            .recording
+            .entries
+            .get(self.next)
+            .is_some_and(|e| e.time <= position)
+        {
+            self.next += 1;
+        }
+        &self.recording.entries[start..self.next]
+    }
+
+    fn ended(&self) -> bool {
+        self.next >= self.recording.entries.len()
+    }
+
+    /// Arm the timer for the next entry, disarm it while paused
+    /// or once all the entries are sent
+    fn arm(&mut self) -> io::Result<()> {
+        let wait = match (self.resumed, self.recording.entries.get(self.next)) {
+            (Some(_), Some(entry)) => {
+                let secs = ((entry.time - self.position()) / self.speed).max(0.0);
+                // a zero value disarms the timer
+                Duration::from_secs_f64(secs).max(Duration::from_nanos(1))
+            }
+            _ => Duration::ZERO,
+        };
+        let value = libc::itimerspec {
+            it_interval: libc::timespec {
+                tv_sec: 0,
+                tv_nsec: 0,
+            },
+            it_value: libc::timespec {
+                tv_sec: wait.as_secs() as libc::time_t,
+                tv_nsec: wait.subsec_nanos() as libc::c_long,
+            },
+        };
+        let fd = self.timer.as_raw_fd();
+        if unsafe { libc::timerfd_settime(fd, 0, &value, std::ptr::null_mut()) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Clear the expirations of the timer
+    fn acknowledge(&mut self) -> io::Result<()> {
+        let mut buf = [0; 8];
+        match self.timer.read(&mut buf) {
+            Err(e) if e.kind() != io::ErrorKind::WouldBlock => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    fn status(&self, client_id: u16) -> Msg {
+        let status = json!({
+            "position": self.position(),
+            "duration": self.recording.duration,
+            "paused": self.resumed.is_none(),
+            "speed": self.speed,
+        });
+        Msg::create(
+            MsgKind::ChannelCtrl,
+            0,
+            client_id,
+            status.to_string().into_bytes(),
+        )
+    }
+}
+
+pub struct Client {
+    user: String,
+    /// identifier of the session, see [`telemetry::session_id`]
+    session: String,
+    /// recording being played, if any
+    player: Option<Player>,
+    /// counters of the client
+    stats: Stats,
+    /// span of the subscription, see [`crate::telemetry`]
+    span: Option<Span>,
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        clients::end_span(&mut self.span, &self.stats);
+    }
+}
+
+crate::member!(Client);
+
+/// Backend playing the recordings back to its clients
+pub struct Playback {
+    clients: HashMap<u16, Client>,
+}
+
+impl Default for Playback {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Playback {
+    pub fn new() -> Playback {
+        Playback {
+            clients: HashMap::new(),
+        }
+    }
+
+    /// Stop watching the timer of the player of a client
+    fn stop(client_id: u16, client: &mut Client, topic: &mut Topic) {
+        if let Some(player) = client.player.take() {
+            if let Err(error) = topic.unregister_io(player.timer.as_raw_fd()) {
+                WARN!(
+                    "Unable to unregister the timer of client {}: {}",
+                    client_id,
+                    error
+                );
+            }
+        }
+    }
+
+    /// Remove a client and stop its playback
+    fn remove(&mut self, client_id: u16, topic: &mut Topic) -> Option<Client> {
+        let mut client = self.clients.remove(&client_id)?;
+        Playback::stop(client_id, &mut client, topic);
+        Some(client)
+    }
+
+    /// Send the entries of the player of a client that are due,
+    /// the consecutive outputs being merged up to `buffer_size`
+    fn play(
+        client_id: u16,
+        client: &mut Client,
+        config: &Config,
+        topic: &mut Topic,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let player = match client.player.as_mut() {
+            None => return Ok(()),
+            Some(player) => player,
+        };
+        let mut output = Vec::new();
+        let mut messages = Vec::new();
+        for entry in player.due() {
+            match &entry.content {
+                Content::Output(data) => output.extend_from_slice(data),
+                Content::Resize(cols, rows) => {
+                    messages.extend(output_frames(client_id, &mut output, config));
+                    let size = json!({ "resize": { "cols": cols, "rows": rows } });
+                    let data = size.to_string().into_bytes();
+                    messages.push(Msg::create(MsgKind::ChannelCtrl, 0, client_id, data));
+                }
+            }
+        }
+        messages.extend(output_frames(client_id, &mut output, config));
+        let ended = player.ended() && player.resumed.is_some();
+        if ended {
+            player.pause(true);
+        }
+        player.arm().class(Class::Internal)?;
+        let mut sent = 0;
+        for msg in messages {
+            if matches!(msg.kind, MsgKind::ChannelData) {
+                client.stats.sent(msg.data.len());
+                metrics::add(Counter::BytesOut, msg.data.len() as u64);
+                sent += msg.data.len();
+            }
+            frame::send(topic, &msg)?;
+        }
+        if let Some((frames, bytes)) = (sent > 0)
+            .then(|| log::throttle(Level::Debug, "send", client_id, sent))
+            .flatten()
+        {
+            DEBUG!(
+                "Playing {} frame(s) to client {}: {} bytes",
+                frames,
+                client_id,
+                bytes
+            );
+        }
+        if ended {
+            INFO!("Playback of client {} has ended", client_id);
+            let data = json!({ "ended": true }).to_string().into_bytes();
+            frame::send(
+                topic,
+                &Msg::create(MsgKind::ChannelCtrl, 0, client_id, data),
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Start the playback of a recording for a client, the
+    /// client is told when it cannot be read
+    fn start(
+        client_id: u16,
+        client: &mut Client,
+        name: &str,
+        config: &Config,
+        topic: &mut Topic,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let dir = config
+            .playback
+            .dir(config)
+            .ok_or("no recording directory")?;
+        if !is_recording(name) {
+            WARN!(
+                "Client {} asks for an invalid recording {}",
+                client_id,
+                name
+            );
+            return frame::send(topic, &frame::error(client_id, "Invalid recording name"));
+        }
+        let recording = match Recording::load(name, dir, config.playback.max_idle) {
+            Ok(recording) => recording,
+            Err(error) => {
+                WARN!(
+                    "Unable to load recording {} for client {}: {}",
+                    name,
+                    client_id,
+                    error
+                );
+                return frame::send(
+                    topic,
+                    &frame::error(client_id, "Unable to read the recording"),
+                );
+            }
+        };
+        Playback::stop(client_id, client, topic);
+        let played = Event::Transfer {
+            op: "play",
+            path: &recording.path,
+            offset: 0,
+            size: recording.size,
+        };
+        audit::log(config, client_id, &client.user, &client.session, played);
+        INFO!(
+            "Client {} plays recording {}: {} frame(s), {:.1}s",
+            client_id,
+            name,
+            recording.entries.len(),
+            recording.duration
+        );
+        let info = json!({ "recording": recording.to_json() });
+        frame::send(
+            topic,
+            &Msg::create(
+                MsgKind::ChannelCtrl,
+                0,
+                client_id,
+                info.to_string().into_bytes(),
+            ),
+        )?;
+        let player = Player::new(recording).class(Class::Internal)?;
+        topic.register_io(player.timer.as_raw_fd(), IOInterest::READABLE)?;
+        client.player = Some(player);
+        Playback::play(client_id, client, config, topic)
+    }
+
+    fn handle_message(
+        &mut self,
+        msg: &Msg,
+        config: &Config,
+        topic: &mut Topic,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if !matches!(msg.kind, MsgKind::ChannelData) {
+            DEBUG!(
+                "Receive message kind {} from client {}: {} bytes",
+                msg.kind,
+                msg.client_id,
+                msg.size
+            );
+        } else if let Some((frames, bytes)) =
+            log::throttle(Level::Debug, "receive", msg.client_id, msg.size as usize)
+        {
+            DEBUG!(
+                "Ignore {} data frame(s) from client {}: {} bytes",
+                frames,
+                msg.client_id,
+                bytes
+            );
+        }
+        if let Some(client) = self.clients.get_mut(&msg.client_id) {
+            client.stats.received();
+        }
+        match msg.kind {
+            MsgKind::ChannelSubscribe => {
+                let Some(Subscription {
+                    user,
+                    session,
+                    context: _context,
+                }) = clients::subscribe(msg, config, topic, |topic| {
+                    Ok(self
+                        .remove(msg.client_id, topic)
+                        .map(|old| old.user.clone()))
+                })?
+                else {
+                    return Ok(());
+                };
+                if !self.make_room(msg.client_id, config, topic)? {
+                    return Ok(());
+                }
+                let span = clients::span(&config.topic, msg.client_id, &user, &session);
+                audit::log(config, msg.client_id, &user, &session, Event::Subscribe);
+                INFO!(
+                    "Client {} subscribe to channel {}",
+                    msg.client_id,
+                    &config.topic
+                );
+                let client = Client {
+                    user,
+                    session,
+                    player: None,
+                    stats: Stats::default(),
+                    span: Some(span),
+                };
+                self.clients.insert(msg.client_id, client);
+                clients::welcome(msg.client_id, config, topic)?;
+            }
+            MsgKind::ChannelUnsubscribe => {
+                self.unsubscribe(msg.client_id, config, topic)?;
+            }
+            MsgKind::ChannelUnsubscribeAll => self.unsubscribe_all(config, topic)?,
+            MsgKind::ChannelData => {
+                // the playback is read-only, the keystrokes of the
+                // terminal of the client are ignored
+            }
+            MsgKind::ChannelCtrl if stats::is_query(&msg.data) => {
+                self.status(msg.client_id, topic)?;
+            }
+            MsgKind::ChannelCtrl => {
+                let client = match self.clients.get_mut(&msg.client_id) {
+                    None => {
+                        WARN!("Client {} is not in the list", msg.client_id);
+                        return Ok(());
+                    }
+                    Some(client) => client,
+                };
+                let data = match msg.data.last() {
+                    Some(0) => &msg.data[0..msg.data.len() - 1],
+                    _ => &msg.data[..],
+                };
+                let request: Value = serde_json::from_slice(data).class(Class::Protocol)?;
+                if request.get("list").is_some() {
+                    let dir = config
+                        .playback
+                        .dir(config)
+                        .ok_or("no recording directory")?;
+                    let recordings = list(dir).class(Class::Internal)?;
+                    let data = json!({ "recordings": recordings }).to_string().into_bytes();
+                    let msg = Msg::create(MsgKind::ChannelCtrl, 0, msg.client_id, data);
+                    return frame::send(topic, &msg);
+                }
+                if let Some(name) = request.get("play").and_then(Value::as_str) {
+                    return Playback::start(msg.client_id, client, name, config, topic);
+                }
+                let player = match client.player.as_mut() {
+                    Some(player) => player,
+                    None => {
+                        let reason = "No recording is played";
+                        return frame::send(topic, &frame::error(msg.client_id, reason));
+                    }
+                };
+                if let Some(paused) = request.get("pause").and_then(Value::as_bool) {
+                    player.pause(paused);
+                } else if let Some(position) = request.get("seek").and_then(Value::as_f64) {
+                    player.seek(position);
+                    client.stats.sent(RESET.len());
+                    let reset = Msg::create(MsgKind::ChannelData, 0, msg.client_id, RESET.to_vec());
+                    frame::send(topic, &reset)?;
+                } else if let Some(speed) = request
+                    .get("speed")
+                    .and_then(Value::as_f64)
+                    .filter(|s| *s > 0.0 && *s <= MAX_SPEED)
+                {
+                    player.set_speed(speed);
+                } else {
+                    WARN!("Invalid request of client {}", msg.client_id);
+                    return frame::send(topic, &frame::error(msg.client_id, "Invalid request"));
+                }
+                Playback::play(msg.client_id, client, config, topic)?;
+                if let Some(player) = client.player.as_ref() {
+                    frame::send(topic, &player.status(msg.client_id))?;
+                }
+            }
+            _ => {
+                WARN!(
+                    "Receive message kind {} from client {}",
+                    msg.kind,
+                    msg.client_id
+                );
+            }
+        };
+        Ok(())
+    }
+
+    /// Send the entries that are due on the expiration of the
+    /// timer of a client
+    fn tick(
+        &mut self,
+        fd: RawFd,
+        config: &Config,
+        topic: &mut Topic,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let owns = |c: &Client| c.player.as_ref().map(|p| p.timer.as_raw_fd()) == Some(fd);
+        let key = match self.clients.iter().find(|(_, c)| owns(c)) {
+            Some((key, _)) => *key,
+            None => {
+                DEBUG!("Ignore event of descriptor {}: no client owns it", fd);
+                return Ok(());
+            }
+        };
+        let client = self.clients.get_mut(&key).ok_or("no client")?;
+        let _context = log::context(&config.topic, key, &client.user, &client.session);
+        if let Some(player) = client.player.as_mut() {
+            player.acknowledge().class(Class::Internal)?;
+        }
+        if let Err(error) = Playback::play(key, client, config, topic) {
+            ERROR!(
+                "Unable to play the recording of client {}: {}: {}",
+                key,
+                error::report(&*error, Class::Internal),
+                error
+            );
+            self.drop_client(key, config, topic)?;
+        }
+        Ok(())
+    }
+}
+
+/// Data frames of some output, which is taken
+fn output_frames(client_id: u16, output: &mut Vec<u8>, config: &Config) -> Vec<Msg> {
+    let frames = output
+        .chunks(config.buffer_size)
+        .map(|chunk| Msg::create(MsgKind::ChannelData, 0, client_id, chunk.to_vec()))
+        .collect();
+    output.clear();
+    frames
+}
+
+/// Whether a name designates a recording of the directory
+fn is_recording(name: &str) -> bool {
+    name.ends_with(".cast") && !name.starts_with('.') && !name.contains('/')
+}
+
+/// Recordings of a directory, by name
+fn list(dir: &Path) -> io::Result<Vec<Value>> {
+    let mut recordings = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let meta = entry.metadata()?;
+        if !meta.is_file() || !is_recording(&name) {
+            continue;
+        }
+        let modified = meta
+            .modified()
+            .ok()
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |d| d.as_secs());
+        recordings.push(json!({ "name": name, "size": meta.len(), "modified": modified }));
+    }
+    recordings.sort_by(|a, b| a["name"].as_str().cmp(&b["name"].as_str()));
+    Ok(recordings)
+}
+
+impl Roster for Playback {
+    type Client = Client;
+
+    fn table(&self) -> &HashMap<u16, Client> {
+        &self.clients
+    }
+
+    fn table_mut(&mut self) -> &mut HashMap<u16, Client> {
+        &mut self.clients
+    }
+
+    fn release(
+        &mut self,
+        client_id: u16,
+        _config: &Config,
+        topic: &mut Topic,
+    ) -> Result<Option<Client>, Box<dyn std::error::Error>> {
+        Ok(self.remove(client_id, topic))
+    }
+}
+
+impl Backend for Playback {
+    fn fds(&self) -> Vec<RawFd> {
+        self.clients
+            .values()
+            .filter_map(|c| c.player.as_ref().map(|p| p.timer.as_raw_fd()))
+            .collect()
+    }
+
+    fn clients(&self) -> usize {
+        self.clients.len()
+    }
+
+    fn dump(&self) -> Vec<String> {
+        let mut lines = vec![format!("{} client(s)", self.clients.len())];
+        let mut keys: Vec<&u16> = self.clients.keys().collect();
+        keys.sort();
+        for key in keys {
+            let c = &self.clients[key];
+            lines.push(format!(
+                "client {}: user {}, {}, {}",
+                key,
+                c.user,
+                match c.player.as_ref() {
+                    Some(p) => format!(
+                        "playing {} at {:.1}/{:.1}s{}",
+                        p.recording.name,
+                        p.position(),
+                        p.recording.duration,
+                        if p.resumed.is_none() { " (paused)" } else { "" }
+                    ),
+                    None => String::from("idle"),
+                },
+                c.stats.describe()
+            ));
+        }
+        lines
+    }
+
+    fn step_handle(
+        &mut self,
+        evt: &CallbackEvent,
+        config: &Config,
+        topic: &mut Topic,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if let Some(msg) = evt.msg {
+            self.scoped(msg, config, topic, |playback, topic| {
+                playback.handle_message(msg, config, topic)
+            })?;
+        }
+        if let (Some(event), Some(fd)) = (evt.event, evt.fd) {
+            if event.is_readable() {
+                self.tick(fd, config, topic)?;
+            }
+        }
+        self.enforce_windows(config, topic)?;
+        self.reap_idle(config, topic)?;
+        Ok(())
+    }
+
+    fn children_exited(
+        &mut self,
+        _config: &Config,
+        _topic: &mut Topic,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        // the hooks are the only processes, reaped by the caller
+        Ok(())
+    }
+
+    fn disconnected(&mut self, _config: &Config) {
+        // the playbacks end with their clients
+        self.clients.clear();
+    }
+
+    fn shutdown(
+        &mut self,
+        _config: &Config,
+        topic: &mut Topic,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.notify_shutdown(topic)?;
+        Ok(())
+    }
+}
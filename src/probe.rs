@@ -0,0 +1,79 @@
+//! # Liveness probe of the session processes
+//!
+//! The `probe` command is run periodically for each process,
+//! with `CPID` set to its pid. A process whose probe keeps
+//! failing is considered hung
+//!
+//! **Author**: "Dany LE"
+//!
+use crate::config::Config;
+use crate::WARN;
+use latpr::utils::{LogLevel, LOG};
+use std::process::{Child, Command, Stdio};
+use std::time::{Duration, Instant};
+
+#[derive(Default)]
+pub struct Probe {
+    /// probe currently running and its start
+    running: Option<(Child, Instant)>,
+    /// the next probe is started at this instant
+    next: Option<Instant>,
+    /// consecutive failures
+    failures: u32,
+}
+
+impl Probe {
+    /// Start the probe when due and collect its result, return
+    /// whether the process has failed too many probes in a row.
+    /// A probe running longer than the interval has failed
+    pub fn check(&mut self, pid: u32, user: &str, client_id: u16, config: &Config) -> bool {
+        let cmd = match config.probe.as_ref() {
+            None => return false,
+            Some(cmd) => cmd,
+        };
+        let interval = Duration::from_secs(config.probe_interval);
+        let now = Instant::now();
+        if let Some((child, started)) = self.running.as_mut() {
+            let passed = match child.try_wait() {
+                Ok(Some(status)) => Some(status.success()),
+                Ok(None) if now - *started < interval => None,
+                _ => {
+                    let _ = child.kill();
+                    let _ = child.wait();
+                    Some(false)
+                }
+            };
+            match passed {
+                None => return false,
+                Some(true) => self.failures = 0,
+                Some(false) => {
+                    self.failures += 1;
+                    WARN!(
+                        "Probe of process {} (client {}) has failed {} time(s)",
+                        pid,
+                        client_id,
+                        self.failures
+                    );
+                }
+            }
+            self.running = None;
+        }
+        if self.next.is_none_or(|t| now >= t) {
+            self.next = Some(now + interval);
+            match Command::new("/bin/sh")
+                .arg("-c")
+                .arg(cmd)
+                .env("CPID", pid.to_string())
+                .env("CUSER", user)
+                .env("CID", client_id.to_string())
+                .stdin(Stdio::null())
+                .stdout(Stdio::null())
+                .spawn()
+            {
+                Ok(child) => self.running = Some((child, now)),
+                Err(error) => WARN!("Unable to run the probe {}: {}", cmd, error),
+            }
+        }
+        self.failures >= config.probe_failures
+    }
+}
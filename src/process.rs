@@ -0,0 +1,338 @@
+//! # Child processes of the backend
+//!
+//! Same interface as [`std::process::Child`], but a process can
+//! also be adopted by a new instance of the backend that kept
+//! its pipes across `exec`
+//!
+//! **Author**: "Dany LE"
+//!
+use crate::profile::{Phase, Timer};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::process::ExitStatusExt;
+use std::process::{Child, ExitStatus};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// pids of the processes not reaped yet, killed by [`kill_all`]
+static PIDS: Mutex<Vec<libc::pid_t>> = Mutex::new(Vec::new());
+/// pids of the processes dropped before being reaped, see
+/// [`reap_dropped`]
+static DROPPED: Mutex<Vec<libc::pid_t>> = Mutex::new(Vec::new());
+
+pub struct Process {
+    pid: libc::pid_t,
+    pub stdin: Option<File>,
+    pub stdout: Option<File>,
+    /// see [`start_time`]
+    start_time: u64,
+    /// exit status, kept once the process is reaped
+    status: Option<ExitStatus>,
+}
+
+impl From<Child> for Process {
+    fn from(mut child: Child) -> Process {
+        track(child.id() as libc::pid_t);
+        let stdout = child.stdout.take().map(|p| File::from(OwnedFd::from(p)));
+        if let Some(stdout) = stdout.as_ref() {
+            set_nonblocking(stdout.as_raw_fd());
+        }
+        Process {
+            pid: child.id() as libc::pid_t,
+            start_time: start_time(child.id()).unwrap_or_default(),
+            stdin: child.stdin.take().map(|p| File::from(OwnedFd::from(p))),
+            stdout,
+            status: None,
+        }
+    }
+}
+
+impl Process {
+    /// Take over a child process of the previous instance of the
+    /// backend, whose pipes were inherited across `exec`. None
+    /// when the process is gone or the pipes are not open
+    pub fn adopt(pid: u32, start_time: u64, stdin: RawFd, stdout: RawFd) -> Option<Process> {
+        if self::start_time(pid) != Some(start_time) || !is_pipe(stdin) || !is_pipe(stdout) {
+            return None;
+        }
+        track(pid as libc::pid_t);
+        set_nonblocking(stdout);
+        unsafe {
+            Some(Process {
+                pid: pid as libc::pid_t,
+                start_time,
+                stdin: Some(File::from_raw_fd(stdin)),
+                stdout: Some(File::from_raw_fd(stdout)),
+                status: None,
+            })
+        }
+    }
+
+    pub fn id(&self) -> u32 {
+        self.pid as u32
+    }
+
+    /// Descriptor of the output pipe, none once closed
+    pub fn stdout_fd(&self) -> Option<RawFd> {
+        self.stdout.as_ref().map(|f| f.as_raw_fd())
+    }
+
+    /// Read the available output. The pipe is non-blocking: a
+    /// stale readiness event, e.g. for a descriptor that has been
+    /// reused since, gives none instead of blocking the event loop
+    pub fn read_output(&mut self, buf: &mut [u8]) -> io::Result<Option<usize>> {
+        let _timer = Timer::start(Phase::Read);
+        let stdout = match self.stdout.as_mut() {
+            None => return Ok(None),
+            Some(stdout) => stdout,
+        };
+        match stdout.read(buf) {
+            Ok(n) => Ok(Some(n)),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Write some input unless the input pipe is too full to
+    /// take it without blocking, e.g. when the process does not
+    /// read its input. Return whether the data was written
+    pub fn write_input(&self, data: &[u8]) -> io::Result<bool> {
+        let _timer = Timer::start(Phase::Write);
+        let mut stdin = match self.stdin.as_ref() {
+            None => return Ok(false),
+            Some(stdin) => stdin,
+        };
+        let fd = stdin.as_raw_fd();
+        let size = unsafe { libc::fcntl(fd, libc::F_GETPIPE_SZ) };
+        if size >= 0 && self.pending_input() + data.len() > size as usize {
+            return Ok(false);
+        }
+        stdin.write_all(data)?;
+        Ok(true)
+    }
+
+    /// Write as much of the data as the input pipe takes without
+    /// blocking, return the number of bytes written
+    pub fn fill_input(&self, data: &[u8]) -> io::Result<usize> {
+        let _timer = Timer::start(Phase::Write);
+        let mut stdin = match self.stdin.as_ref() {
+            None => return Ok(0),
+            Some(stdin) => stdin,
+        };
+        let fd = stdin.as_raw_fd();
+        let size = unsafe { libc::fcntl(fd, libc::F_GETPIPE_SZ) };
+        let room = match size {
+            size if size >= 0 => (size as usize).saturating_sub(self.pending_input()),
+            _ => data.len(),
+        };
+        let n = room.min(data.len());
+        stdin.write_all(&data[..n])?;
+        Ok(n)
+    }
+
+    /// Bytes written to the input pipe and not read yet
+    pub fn pending_input(&self) -> usize {
+        let fd = match self.stdin.as_ref() {
+            None => return 0,
+            Some(stdin) => stdin.as_raw_fd(),
+        };
+        let mut pending: libc::c_int = 0;
+        if unsafe { libc::ioctl(fd, libc::FIONREAD, &mut pending) } < 0 {
+            return 0;
+        }
+        pending as usize
+    }
+
+    pub fn start_time(&self) -> u64 {
+        self.start_time
+    }
+
+    pub fn kill(&mut self) -> io::Result<()> {
+        self.signal(libc::SIGKILL)
+    }
+
+    pub fn signal(&mut self, signal: libc::c_int) -> io::Result<()> {
+        if self.status.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "process has already exited",
+            ));
+        }
+        if unsafe { libc::kill(self.pid, signal) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    pub fn try_wait(&mut self) -> io::Result<Option<ExitStatus>> {
+        self.wait_pid(libc::WNOHANG)
+    }
+
+    pub fn wait(&mut self) -> io::Result<ExitStatus> {
+        self.wait_pid(0)?
+            .ok_or_else(|| io::Error::other("process is still running"))
+    }
+
+    fn wait_pid(&mut self, options: libc::c_int) -> io::Result<Option<ExitStatus>> {
+        if let Some(status) = self.status {
+            return Ok(Some(status));
+        }
+        let mut status: libc::c_int = 0;
+        match unsafe { libc::waitpid(self.pid, &mut status, options) } {
+            0 => Ok(None),
+            n if n < 0 => Err(io::Error::last_os_error()),
+            _ => {
+                self.status = Some(ExitStatus::from_raw(status));
+                untrack(self.pid);
+                Ok(self.status)
+            }
+        }
+    }
+}
+
+impl Drop for Process {
+    fn drop(&mut self) {
+        // e.g. a killed process, reaped on the next SIGCHLD
+        if self.status.is_none() {
+            if let Ok(mut dropped) = DROPPED.lock() {
+                dropped.push(self.pid);
+            }
+        }
+    }
+}
+
+/// Reap the processes that have exited after being dropped,
+/// so that they do not stay zombies
+pub fn reap_dropped() {
+    let mut dropped = match DROPPED.lock() {
+        Ok(dropped) => dropped,
+        Err(_) => return,
+    };
+    dropped.retain(|pid| {
+        let reaped = unsafe { libc::waitpid(*pid, std::ptr::null_mut(), libc::WNOHANG) } != 0;
+        if reaped {
+            untrack(*pid);
+        }
+        !reaped
+    });
+}
+
+/// Start time of a process, in clock ticks since boot, used
+/// to tell it from a later process with the same pid
+pub fn start_time(pid: u32) -> Option<u64> {
+    let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    // the command name may contain spaces, the fields
+    // are counted from the closing parenthesis
+    let fields = &stat[stat.rfind(')')? + 1..];
+    fields.split_whitespace().nth(19)?.parse().ok()
+}
+
+/// Resident memory of a process in bytes
+pub fn rss(pid: u32) -> Option<u64> {
+    let statm = fs::read_to_string(format!("/proc/{}/statm", pid)).ok()?;
+    let pages: u64 = statm.split_whitespace().nth(1)?.parse().ok()?;
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+    Some(pages * page_size.max(0) as u64)
+}
+
+/// CPU time used by a process, in user and system mode
+pub fn cpu_time(pid: u32) -> Option<Duration> {
+    let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    let mut fields = stat[stat.rfind(')')? + 1..].split_whitespace().skip(11);
+    let utime: u64 = fields.next()?.parse().ok()?;
+    let stime: u64 = fields.next()?.parse().ok()?;
+    let ticks_per_sec = unsafe { libc::sysconf(libc::_SC_CLK_TCK) }.max(1) as u64;
+    Some(Duration::from_millis(
+        (utime + stime) * 1000 / ticks_per_sec,
+    ))
+}
+
+/// CPU time and resident memory in bytes of a process tree,
+/// see [`trees`]
+pub fn usage(tree: &[u32]) -> (Duration, u64) {
+    let cpu = tree.iter().filter_map(|p| cpu_time(*p)).sum();
+    let rss = tree.iter().filter_map(|p| rss(*p)).sum();
+    (cpu, rss)
+}
+
+/// Processes of the trees rooted at some pids, the roots and
+/// all their descendants, from a single scan of `/proc`
+pub fn trees(roots: &[u32]) -> Vec<Vec<u32>> {
+    let mut children: HashMap<u32, Vec<u32>> = HashMap::new();
+    if let Ok(entries) = fs::read_dir("/proc") {
+        for entry in entries.flatten() {
+            let pid = match entry.file_name().to_str().and_then(|n| n.parse().ok()) {
+                Some(pid) => pid,
+                None => continue,
+            };
+            if let Some(ppid) = parent(pid) {
+                children.entry(ppid).or_default().push(pid);
+            }
+        }
+    }
+    roots
+        .iter()
+        .map(|root| {
+            let mut tree = vec![*root];
+            let mut i = 0;
+            while i < tree.len() {
+                if let Some(c) = children.get(&tree[i]) {
+                    tree.extend(c);
+                }
+                i += 1;
+            }
+            tree
+        })
+        .collect()
+}
+
+fn parent(pid: u32) -> Option<u32> {
+    let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    let fields = &stat[stat.rfind(')')? + 1..];
+    fields.split_whitespace().nth(1)?.parse().ok()
+}
+
+fn set_nonblocking(fd: RawFd) {
+    unsafe {
+        let flags = libc::fcntl(fd, libc::F_GETFL);
+        libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK);
+    }
+}
+
+/// Whether the descriptor is open on a pipe, a descriptor
+/// that is not inherited may be open on anything else
+fn is_pipe(fd: RawFd) -> bool {
+    unsafe {
+        let mut stat: libc::stat = std::mem::zeroed();
+        libc::fstat(fd, &mut stat) == 0 && stat.st_mode & libc::S_IFMT == libc::S_IFIFO
+    }
+}
+
+fn track(pid: libc::pid_t) {
+    if let Ok(mut pids) = PIDS.lock() {
+        pids.push(pid);
+    }
+}
+
+fn untrack(pid: libc::pid_t) {
+    if let Ok(mut pids) = PIDS.lock() {
+        pids.retain(|p| *p != pid);
+    }
+}
+
+/// Kill and reap all the processes not reaped yet, used when
+/// the backend dies unexpectedly, e.g. on a panic
+pub fn kill_all() {
+    let pids = match PIDS.lock() {
+        Ok(mut pids) => std::mem::take(&mut *pids),
+        Err(_) => return,
+    };
+    for pid in pids {
+        unsafe {
+            libc::kill(pid, libc::SIGKILL);
+            libc::waitpid(pid, std::ptr::null_mut(), 0);
+        }
+    }
+}
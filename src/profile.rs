@@ -0,0 +1,141 @@
+//! # Event loop profiler
+//!
+//! With `profile_interval`, the durations of the steps of the
+//! event loop and of the spawns, reads and writes of the pipes
+//! of the processes and writes to the topic are sampled, and
+//! their percentiles are logged at each interval, e.g. to find
+//! the source of the latency on a loaded or low-power server
+//!
+//! **Author**: "Dany LE"
+//!
+use crate::INFO;
+use latpr::utils::{LogLevel, LOG};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// samples kept at most per phase between two reports, the
+/// next ones are dropped
+const MAX_SAMPLES: usize = 100_000;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static SAMPLES: Mutex<[Vec<Duration>; 5]> =
+    Mutex::new([Vec::new(), Vec::new(), Vec::new(), Vec::new(), Vec::new()]);
+
+/// A timed part of the work of the backend
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    /// a step of the event loop, including the others
+    Step,
+    Spawn,
+    /// a read of the output of a process
+    Read,
+    /// a write to the input of a process
+    Write,
+    /// a write of a frame to the topic
+    Topic,
+}
+
+impl Phase {
+    pub const ALL: [Phase; 5] = [
+        Phase::Step,
+        Phase::Spawn,
+        Phase::Read,
+        Phase::Write,
+        Phase::Topic,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Phase::Step => "step",
+            Phase::Spawn => "spawn",
+            Phase::Read => "read",
+            Phase::Write => "write",
+            Phase::Topic => "topic",
+        }
+    }
+}
+
+/// Record a duration of a phase when profiling
+pub fn record(phase: Phase, duration: Duration) {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    if let Ok(mut samples) = SAMPLES.lock() {
+        let samples = &mut samples[phase as usize];
+        if samples.len() < MAX_SAMPLES {
+            samples.push(duration);
+        }
+    }
+}
+
+/// Records the time from its start to its drop
+pub struct Timer {
+    phase: Phase,
+    start: Instant,
+}
+
+impl Timer {
+    pub fn start(phase: Phase) -> Timer {
+        Timer {
+            phase,
+            start: Instant::now(),
+        }
+    }
+}
+
+impl Drop for Timer {
+    fn drop(&mut self) {
+        record(self.phase, self.start.elapsed());
+    }
+}
+
+/// Logs the percentiles of the samples at each interval
+pub struct Profiler {
+    interval: Duration,
+    last_report: Instant,
+}
+
+impl Profiler {
+    /// Start profiling
+    pub fn new(interval: Duration) -> Profiler {
+        ENABLED.store(true, Ordering::Relaxed);
+        Profiler {
+            interval,
+            last_report: Instant::now(),
+        }
+    }
+
+    /// Log the report once the interval has elapsed, then start
+    /// a new one
+    pub fn step(&mut self) {
+        if self.last_report.elapsed() < self.interval {
+            return;
+        }
+        let taken = match SAMPLES.lock() {
+            Ok(mut samples) => std::mem::take(&mut *samples),
+            Err(_) => return,
+        };
+        INFO!(
+            "Profile of the last {}s:",
+            self.last_report.elapsed().as_secs()
+        );
+        self.last_report = Instant::now();
+        for (phase, mut samples) in Phase::ALL.into_iter().zip(taken) {
+            if samples.is_empty() {
+                continue;
+            }
+            samples.sort();
+            let at = |p: usize| samples[(samples.len() - 1) * p / 100];
+            INFO!(
+                "  {}: {} samples, p50 {:?}, p90 {:?}, p99 {:?}, max {:?}",
+                phase.name(),
+                samples.len(),
+                at(50),
+                at(90),
+                at(99),
+                at(100)
+            );
+        }
+    }
+}
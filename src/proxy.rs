@@ -0,0 +1,820 @@
+//! # Socket proxy backend
+//!
+//! Instead of running a command, `shsock` connects each client
+//! to a Unix domain socket, e.g. of a REPL server, and `shtcp`
+//! to a TCP destination, and relays the data both ways: the
+//! data frames of the client are written to its connection,
+//! what the daemon writes back is sent to the client. The
+//! client is unsubscribed when the daemon closes the connection
+//!
+//! **Author**: "Dany LE"
+//!
+use crate::audit::{self, Event};
+use crate::backend::Backend;
+use crate::clients::{self, Roster, Subscription};
+use crate::config::{Config, Mode};
+use crate::error::{self, Class, Classify};
+use crate::frame;
+use crate::log::{self, Level};
+use crate::metrics::{self, Counter};
+use crate::stats::{self, Stats};
+use crate::telemetry::Span;
+use crate::{DEBUG, ERROR, INFO, WARN};
+use latpr::tunnel::{CallbackEvent, IOInterest, Msg, MsgKind, Topic};
+use latpr::utils::{LogLevel, LOG};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, Read, Write};
+use std::mem;
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// data of a client not yet taken by the daemon, beyond which
+/// the client is dropped
+const MAX_PENDING: usize = 1024 * 1024;
+/// time given to each address of a TCP destination to accept
+/// the connection
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Settings of the socket proxy mode, e.g.
+///
+/// ```toml
+/// [sock]
+/// path = "/run/repl/repl.sock"
+/// ```
+#[derive(Deserialize, Serialize, Default, Debug, Clone, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct SockSettings {
+    /// absolute path of the Unix domain socket the clients are
+    /// connected to
+    pub path: Option<PathBuf>,
+}
+
+/// Settings of the TCP proxy mode, e.g.
+///
+/// ```toml
+/// [tcp]
+/// address = "127.0.0.1:23"
+/// allow = ["127.0.0.1:23", "10.0.0.5:*"]
+/// ```
+#[derive(Deserialize, Serialize, Default, Debug, Clone, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct TcpSettings {
+    /// `host:port` the clients are connected to on subscription,
+    /// when not set each client chooses its destination
+    pub address: Option<String>,
+    /// destinations a client may choose, `host:port` or
+    /// `host:*` for any port of the host
+    pub allow: Vec<String>,
+}
+
+impl TcpSettings {
+    /// Whether a client may connect to a destination, the
+    /// configured address is always allowed
+    pub fn is_allowed(&self, destination: &str) -> bool {
+        let host = destination.rsplit_once(':').map(|(host, _)| host);
+        self.address.as_deref() == Some(destination)
+            || self.allow.iter().any(|allowed| {
+                allowed == destination
+                    || allowed.strip_suffix(":*").is_some_and(|h| Some(h) == host)
+            })
+    }
+}
+
+/// Connection of a client to the daemon
+enum Stream {
+    Unix(UnixStream),
+    Tcp(TcpStream),
+}
+
+impl Stream {
+    /// Connect to the socket of the sock mode, a local socket
+    /// accepts or refuses the connection at once
+    fn connect(path: &str) -> io::Result<Stream> {
+        let stream = UnixStream::connect(path)?;
+        stream.set_nonblocking(true)?;
+        Ok(Stream::Unix(stream))
+    }
+
+    fn fd(&self) -> RawFd {
+        match self {
+            Stream::Unix(s) => s.as_raw_fd(),
+            Stream::Tcp(s) => s.as_raw_fd(),
+        }
+    }
+
+    /// Read what the daemon has written, none when nothing is
+    /// available yet, zero once the connection is closed
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<Option<usize>> {
+        let result = match self {
+            Stream::Unix(s) => s.read(buf),
+            Stream::Tcp(s) => s.read(buf),
+        };
+        match result {
+            Ok(n) => Ok(Some(n)),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Write as much data as the daemon takes without blocking,
+    /// return the number of bytes written
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let result = match self {
+            Stream::Unix(s) => s.write(data),
+            Stream::Tcp(s) => s.write(data),
+        };
+        match result {
+            Ok(n) => Ok(n),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(0),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// State of a TCP connection being established
+enum Progress {
+    Pending,
+    Connected(Stream),
+    Failed(io::Error),
+}
+
+/// A TCP connection on its way, without blocking the event
+/// loop: the destination is resolved by a thread, then its
+/// addresses are tried in turn until one accepts
+struct Connecting {
+    /// addresses of the destination, until they are resolved
+    resolver: Option<Receiver<io::Result<Vec<SocketAddr>>>>,
+    /// addresses not tried yet
+    addrs: VecDeque<SocketAddr>,
+    /// socket of the current attempt, watched for writability,
+    /// and when it is given up
+    attempt: Option<(TcpStream, Instant)>,
+    /// error of the last attempt, reported when no address is
+    /// left
+    error: Option<io::Error>,
+}
+
+impl Connecting {
+    fn start(destination: &str) -> io::Result<Connecting> {
+        let mut connecting = Connecting {
+            resolver: None,
+            addrs: VecDeque::new(),
+            attempt: None,
+            error: None,
+        };
+        match destination.parse::<SocketAddr>() {
+            Ok(addr) => connecting.addrs.push_back(addr),
+            Err(_) => {
+                let (sender, receiver) = mpsc::sync_channel(1);
+                let destination = destination.to_string();
+                thread::Builder::new()
+                    .name(String::from("resolver"))
+                    .spawn(move || {
+                        let addrs = destination.to_socket_addrs().map(|a| a.collect());
+                        // the client may be gone
+                        let _ = sender.send(addrs);
+                    })?;
+                connecting.resolver = Some(receiver);
+            }
+        }
+        Ok(connecting)
+    }
+
+    /// Descriptor of the current attempt, registered on the topic
+    fn fd(&self) -> Option<RawFd> {
+        self.attempt.as_ref().map(|(stream, _)| stream.as_raw_fd())
+    }
+
+    /// Go on with the connection: check the current attempt, try
+    /// the next address when it fails or times out
+    fn step(&mut self, topic: &mut Topic) -> Result<Progress, Box<dyn std::error::Error>> {
+        if let Some(resolver) = self.resolver.as_ref() {
+            let resolved = match resolver.try_recv() {
+                Err(TryRecvError::Empty) => return Ok(Progress::Pending),
+                Err(TryRecvError::Disconnected) => Err(io::Error::other("resolver has failed")),
+                Ok(resolved) => resolved,
+            };
+            self.resolver = None;
+            match resolved {
+                Ok(addrs) => self.addrs.extend(addrs),
+                Err(error) => self.error = Some(error),
+            }
+        }
+        loop {
+            if let Some((stream, deadline)) = self.attempt.as_ref() {
+                let connected = match stream.take_error() {
+                    Ok(None) => match stream.peer_addr() {
+                        Ok(_) => Ok(true),
+                        Err(e) if e.raw_os_error() == Some(libc::ENOTCONN) => Ok(false),
+                        Err(e) => Err(e),
+                    },
+                    Ok(Some(e)) | Err(e) => Err(e),
+                };
+                let connected = match connected {
+                    Ok(false) if Instant::now() < *deadline => return Ok(Progress::Pending),
+                    Ok(false) => Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        "connection timed out",
+                    )),
+                    result => result,
+                };
+                let (stream, _) = self.attempt.take().ok_or("no attempt")?;
+                topic.unregister_io(stream.as_raw_fd())?;
+                match connected {
+                    Ok(_) => {
+                        stream.set_nodelay(true)?;
+                        return Ok(Progress::Connected(Stream::Tcp(stream)));
+                    }
+                    Err(error) => self.error = Some(error),
+                }
+            }
+            let addr = match self.addrs.pop_front() {
+                Some(addr) => addr,
+                None => {
+                    let error = self.error.take().unwrap_or_else(|| {
+                        io::Error::new(io::ErrorKind::NotFound, "no address for the destination")
+                    });
+                    return Ok(Progress::Failed(error));
+                }
+            };
+            match connect_nonblocking(&addr) {
+                Ok(stream) => {
+                    topic.register_io(stream.as_raw_fd(), IOInterest::WRITABLE)?;
+                    self.attempt = Some((stream, Instant::now() + CONNECT_TIMEOUT));
+                }
+                Err(error) => self.error = Some(error),
+            }
+        }
+    }
+}
+
+/// Start connecting a non-blocking socket to an address, the
+/// connection is established once the socket is writable
+fn connect_nonblocking(addr: &SocketAddr) -> io::Result<TcpStream> {
+    let mut storage: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let (family, len) = match addr {
+        SocketAddr::V4(addr) => {
+            let sin = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in) };
+            sin.sin_family = libc::AF_INET as libc::sa_family_t;
+            sin.sin_port = addr.port().to_be();
+            sin.sin_addr = libc::in_addr {
+                s_addr: u32::from_ne_bytes(addr.ip().octets()),
+            };
+            (libc::AF_INET, mem::size_of::<libc::sockaddr_in>())
+        }
+        SocketAddr::V6(addr) => {
+            let sin6 = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in6) };
+            sin6.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+            sin6.sin6_port = addr.port().to_be();
+            sin6.sin6_flowinfo = addr.flowinfo();
+            sin6.sin6_addr = libc::in6_addr {
+                s6_addr: addr.ip().octets(),
+            };
+            sin6.sin6_scope_id = addr.scope_id();
+            (libc::AF_INET6, mem::size_of::<libc::sockaddr_in6>())
+        }
+    };
+    let kind = libc::SOCK_STREAM | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC;
+    let fd = unsafe { libc::socket(family, kind, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let stream = unsafe { TcpStream::from_raw_fd(fd) };
+    let sockaddr = &storage as *const _ as *const libc::sockaddr;
+    if unsafe { libc::connect(fd, sockaddr, len as libc::socklen_t) } < 0 {
+        let error = io::Error::last_os_error();
+        if error.raw_os_error() != Some(libc::EINPROGRESS) {
+            return Err(error);
+        }
+    }
+    Ok(stream)
+}
+
+pub struct Client {
+    user: String,
+    /// identifier of the session, see [`telemetry::session_id`]
+    session: String,
+    /// connection to the daemon, none until a client of the tcp
+    /// mode without a configured address chooses a destination
+    /// and the connection is established
+    stream: Option<Stream>,
+    /// TCP connection on its way
+    connecting: Option<Connecting>,
+    /// socket or `host:port` of the connection
+    destination: String,
+    /// data of the client not yet written to the daemon
+    pending: Vec<u8>,
+    /// counters of the client
+    stats: Stats,
+    /// span of the subscription, see [`crate::telemetry`]
+    span: Option<Span>,
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        clients::end_span(&mut self.span, &self.stats);
+    }
+}
+
+crate::member!(Client);
+
+impl Client {
+    /// Write the pending data, return whether the daemon has
+    /// taken all of it
+    fn flush(&mut self) -> io::Result<bool> {
+        let stream = match self.stream.as_mut() {
+            None => return Ok(false),
+            Some(stream) => stream,
+        };
+        while !self.pending.is_empty() {
+            match stream.write(&self.pending)? {
+                0 => return Ok(false),
+                n => {
+                    self.pending.drain(..n);
+                }
+            }
+        }
+        Ok(true)
+    }
+}
+
+/// Backend relaying the clients to a socket
+pub struct Proxy {
+    clients: HashMap<u16, Client>,
+}
+
+impl Default for Proxy {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Proxy {
+    pub fn new() -> Proxy {
+        Proxy {
+            clients: HashMap::new(),
+        }
+    }
+
+    /// Remove a client and stop watching its connection
+    fn remove(&mut self, client_id: u16, topic: &mut Topic) -> Option<Client> {
+        let client = self.clients.remove(&client_id)?;
+        let stream = client.stream.as_ref().map(|s| s.fd());
+        let attempt = client.connecting.as_ref().and_then(|c| c.fd());
+        if let Some(fd) = stream.or(attempt) {
+            if let Err(error) = topic.unregister_io(fd) {
+                WARN!(
+                    "Unable to unregister the connection of client {}: {}",
+                    client_id,
+                    error
+                );
+            }
+        }
+        Some(client)
+    }
+
+    /// Connect a client to a destination: the socket of the sock
+    /// mode at once, a TCP destination over the next steps, see
+    /// [`Proxy::progress`]. The client is told when it fails.
+    /// Return whether the connection is established or on its way
+    fn connect(
+        client_id: u16,
+        client: &mut Client,
+        destination: &str,
+        config: &Config,
+        topic: &mut Topic,
+    ) -> Result<bool, Box<dyn std::error::Error>> {
+        client.destination = destination.to_string();
+        let started = match config.mode {
+            Mode::Tcp => Connecting::start(destination).map(|c| client.connecting = Some(c)),
+            _ => Stream::connect(destination).map(|s| client.stream = Some(s)),
+        };
+        match started {
+            Ok(()) => {
+                if let Some(stream) = client.stream.as_ref() {
+                    topic.register_io(stream.fd(), IOInterest::READABLE)?;
+                    INFO!("Client {} connected to {}", client_id, destination);
+                }
+                Ok(true)
+            }
+            Err(error) => {
+                Proxy::unreachable(client_id, destination, &error, topic)?;
+                Ok(false)
+            }
+        }
+    }
+
+    /// Tell a client its destination is unreachable
+    fn unreachable(
+        client_id: u16,
+        destination: &str,
+        error: &io::Error,
+        topic: &mut Topic,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        WARN!(
+            "Unable to connect client {} to {}: {}",
+            client_id,
+            destination,
+            error
+        );
+        metrics::error(Class::Spawn);
+        frame::send(
+            topic,
+            &frame::error(client_id, "Unable to connect to the service"),
+        )
+    }
+
+    /// Go on with the TCP connections on their way. A client that
+    /// cannot reach the configured address is unsubscribed, one
+    /// that chose its destination may choose another
+    fn progress(
+        &mut self,
+        config: &Config,
+        topic: &mut Topic,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut failed = Vec::new();
+        for (key, client) in self.clients.iter_mut() {
+            let Some(connecting) = client.connecting.as_mut() else {
+                continue;
+            };
+            match connecting.step(topic)? {
+                Progress::Pending => {}
+                Progress::Connected(stream) => {
+                    topic.register_io(stream.fd(), IOInterest::READABLE)?;
+                    INFO!("Client {} connected to {}", key, client.destination);
+                    client.connecting = None;
+                    client.stream = Some(stream);
+                }
+                Progress::Failed(error) => {
+                    client.connecting = None;
+                    failed.push((*key, error));
+                }
+            }
+        }
+        for (key, error) in failed {
+            let Some(client) = self.clients.get(&key) else {
+                continue;
+            };
+            Proxy::unreachable(key, &client.destination, &error, topic)?;
+            if config.tcp.address.is_some() {
+                if let Some(c) = self.remove(key, topic) {
+                    let reason = Event::Unsubscribe("unable to connect");
+                    audit::log(config, key, &c.user, &c.session, reason);
+                }
+                clients::expel(key, "Unable to connect to the service", topic)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn handle_message(
+        &mut self,
+        msg: &Msg,
+        config: &Config,
+        topic: &mut Topic,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if !matches!(msg.kind, MsgKind::ChannelData) {
+            DEBUG!(
+                "Receive message kind {} from client {}: {} bytes",
+                msg.kind,
+                msg.client_id,
+                msg.size
+            );
+        } else if let Some((frames, bytes)) =
+            log::throttle(Level::Debug, "receive", msg.client_id, msg.size as usize)
+        {
+            DEBUG!(
+                "Receive {} data frame(s) from client {}: {} bytes",
+                frames,
+                msg.client_id,
+                bytes
+            );
+        }
+        if let Some(client) = self.clients.get_mut(&msg.client_id) {
+            client.stats.received();
+        }
+        match msg.kind {
+            MsgKind::ChannelSubscribe => {
+                let Some(Subscription {
+                    user,
+                    session,
+                    context: _context,
+                }) = clients::subscribe(msg, config, topic, |topic| {
+                    Ok(self
+                        .remove(msg.client_id, topic)
+                        .map(|old| old.user.clone()))
+                })?
+                else {
+                    return Ok(());
+                };
+                if !self.make_room(msg.client_id, config, topic)? {
+                    return Ok(());
+                }
+                let mut client = Client {
+                    user,
+                    session,
+                    stream: None,
+                    connecting: None,
+                    destination: String::new(),
+                    pending: Vec::new(),
+                    stats: Stats::default(),
+                    span: None,
+                };
+                // a client of the tcp mode may have to choose its
+                // destination first
+                let destination = match config.mode {
+                    Mode::Tcp => config.tcp.address.clone(),
+                    _ => config.sock.path.as_ref().map(|p| p.display().to_string()),
+                };
+                if let Some(destination) = destination {
+                    if !Proxy::connect(msg.client_id, &mut client, &destination, config, topic)? {
+                        let reason = Event::Refuse("unable to connect");
+                        audit::log(config, msg.client_id, &client.user, &client.session, reason);
+                        return Ok(());
+                    }
+                }
+                let (user, session) = (&client.user, &client.session);
+                let span = clients::span(&config.topic, msg.client_id, user, session);
+                audit::log(config, msg.client_id, user, session, Event::Subscribe);
+                INFO!(
+                    "Client {} subscribe to channel {}",
+                    msg.client_id,
+                    &config.topic
+                );
+                client.span = Some(span);
+                self.clients.insert(msg.client_id, client);
+                clients::welcome(msg.client_id, config, topic)?;
+            }
+            MsgKind::ChannelUnsubscribe => {
+                self.unsubscribe(msg.client_id, config, topic)?;
+            }
+            MsgKind::ChannelUnsubscribeAll => self.unsubscribe_all(config, topic)?,
+            MsgKind::ChannelData => {
+                let client = match self.clients.get_mut(&msg.client_id) {
+                    None => {
+                        WARN!("Client {} is not in the list", msg.client_id);
+                        return Ok(());
+                    }
+                    Some(client) => client,
+                };
+                // the data waits for a connection on its way
+                if client.stream.is_none() && client.connecting.is_none() {
+                    let reason = "Not connected, choose a destination first";
+                    return frame::send(topic, &frame::error(msg.client_id, reason));
+                }
+                metrics::add(Counter::BytesIn, msg.data.len() as u64);
+                client.pending.extend_from_slice(&msg.data);
+                if !client.flush().class(Class::ChildIo)? && client.pending.len() > MAX_PENDING {
+                    WARN!(
+                        "Service does not read the data of client {}, {} bytes pending",
+                        msg.client_id,
+                        client.pending.len()
+                    );
+                    if let Some(c) = self.remove(msg.client_id, topic) {
+                        let reason = Event::Unsubscribe("stalled");
+                        audit::log(config, msg.client_id, &c.user, &c.session, reason);
+                    }
+                    clients::expel(msg.client_id, "Service not responding", topic)?;
+                }
+            }
+            MsgKind::ChannelCtrl if stats::is_query(&msg.data) => {
+                self.status(msg.client_id, topic)?;
+            }
+            MsgKind::ChannelCtrl => {
+                // {"connect": "host:port"}
+                let client = match self.clients.get_mut(&msg.client_id) {
+                    None => {
+                        WARN!("Client {} is not in the list", msg.client_id);
+                        return Ok(());
+                    }
+                    Some(client) => client,
+                };
+                let data = match msg.data.last() {
+                    Some(0) => &msg.data[0..msg.data.len() - 1],
+                    _ => &msg.data[..],
+                };
+                let request: Value = serde_json::from_slice(data).class(Class::Protocol)?;
+                let destination = match request.get("connect").and_then(Value::as_str) {
+                    Some(destination) if config.mode == Mode::Tcp => destination,
+                    _ => {
+                        WARN!("Invalid request of client {}", msg.client_id);
+                        return frame::send(topic, &frame::error(msg.client_id, "Invalid request"));
+                    }
+                };
+                if client.stream.is_some() || client.connecting.is_some() {
+                    let reason = "Already connected";
+                    return frame::send(topic, &frame::error(msg.client_id, reason));
+                }
+                if !config.tcp.is_allowed(destination) {
+                    WARN!(
+                        "Client {} is not allowed to connect to {}",
+                        msg.client_id,
+                        destination
+                    );
+                    let reason = Event::Refuse("destination not allowed");
+                    audit::log(config, msg.client_id, &client.user, &client.session, reason);
+                    let reason = "Destination not allowed";
+                    return frame::send(topic, &frame::error(msg.client_id, reason));
+                }
+                Proxy::connect(msg.client_id, client, destination, config, topic)?;
+            }
+            _ => {
+                WARN!(
+                    "Receive message kind {} from client {}",
+                    msg.kind,
+                    msg.client_id
+                );
+            }
+        };
+        Ok(())
+    }
+
+    /// Relay what the daemon has written on a connection to
+    /// its client
+    fn relay(
+        &mut self,
+        fd: RawFd,
+        config: &Config,
+        topic: &mut Topic,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let owns = |c: &Client| c.stream.as_ref().map(|s| s.fd()) == Some(fd);
+        let key = match self.clients.iter().find(|(_, c)| owns(c)) {
+            Some((key, _)) => *key,
+            None => {
+                DEBUG!("Ignore event of descriptor {}: no client owns it", fd);
+                return Ok(());
+            }
+        };
+        let client = self.clients.get_mut(&key).ok_or("no client")?;
+        let _context = log::context(&config.topic, key, &client.user, &client.session);
+        let mut buf = vec![0; config.buffer_size];
+        let output = match client.stream.as_mut() {
+            Some(stream) => stream.read(&mut buf[..]),
+            None => Ok(None),
+        };
+        match output {
+            Ok(Some(n)) if n > 0 => {
+                if let Some((reads, bytes)) = log::throttle(Level::Debug, "send", key, n) {
+                    DEBUG!(
+                        "Sending {} bytes of raw data to client {} in {} read(s)",
+                        bytes,
+                        key,
+                        reads
+                    );
+                }
+                client.stats.sent(n);
+                metrics::add(Counter::BytesOut, n as u64);
+                let msg = Msg::create(MsgKind::ChannelData, 0, key, buf[0..n].to_vec());
+                frame::send(topic, &msg)?;
+            }
+            Ok(Some(_)) => {
+                INFO!("Service closed the connection of client {}", key);
+                if let Some(c) = self.remove(key, topic) {
+                    audit::log(
+                        config,
+                        key,
+                        &c.user,
+                        &c.session,
+                        Event::Unsubscribe("closed"),
+                    );
+                }
+                clients::expel(key, "Connection closed by the service", topic)?;
+            }
+            Ok(None) => {}
+            Err(e) => {
+                ERROR!(
+                    "Unable to read the connection of client {}: {}: {}",
+                    key,
+                    error::report(&e, Class::ChildIo),
+                    e
+                );
+                self.drop_client(key, config, topic)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Roster for Proxy {
+    type Client = Client;
+
+    fn table(&self) -> &HashMap<u16, Client> {
+        &self.clients
+    }
+
+    fn table_mut(&mut self) -> &mut HashMap<u16, Client> {
+        &mut self.clients
+    }
+
+    fn release(
+        &mut self,
+        client_id: u16,
+        _config: &Config,
+        topic: &mut Topic,
+    ) -> Result<Option<Client>, Box<dyn std::error::Error>> {
+        Ok(self.remove(client_id, topic))
+    }
+}
+
+impl Backend for Proxy {
+    fn fds(&self) -> Vec<RawFd> {
+        self.clients
+            .values()
+            .filter_map(|c| c.stream.as_ref().map(|s| s.fd()))
+            .collect()
+    }
+
+    fn clients(&self) -> usize {
+        self.clients.len()
+    }
+
+    fn dump(&self) -> Vec<String> {
+        let mut lines = vec![format!("{} client(s)", self.clients.len())];
+        let mut keys: Vec<&u16> = self.clients.keys().collect();
+        keys.sort();
+        for key in keys {
+            let c = &self.clients[key];
+            lines.push(format!(
+                "client {}: user {}, {}, {} bytes pending, {}",
+                key,
+                c.user,
+                match (&c.stream, &c.connecting) {
+                    (Some(_), _) => format!("connected to {}", c.destination),
+                    (None, Some(_)) => format!("connecting to {}", c.destination),
+                    (None, None) => String::from("not connected"),
+                },
+                c.pending.len(),
+                c.stats.describe()
+            ));
+        }
+        lines
+    }
+
+    fn step_handle(
+        &mut self,
+        evt: &CallbackEvent,
+        config: &Config,
+        topic: &mut Topic,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if let Some(msg) = evt.msg {
+            self.scoped(msg, config, topic, |proxy, topic| {
+                proxy.handle_message(msg, config, topic)
+            })?;
+        }
+        self.progress(config, topic)?;
+        // the data left by a busy daemon
+        let mut failed = Vec::new();
+        for (key, client) in self.clients.iter_mut() {
+            if let Err(e) = client.flush() {
+                ERROR!(
+                    "Unable to write the connection of client {}: {}: {}",
+                    key,
+                    error::report(&e, Class::ChildIo),
+                    e
+                );
+                failed.push(*key);
+            }
+        }
+        for key in failed {
+            self.drop_client(key, config, topic)?;
+        }
+        if let (Some(event), Some(fd)) = (evt.event, evt.fd) {
+            if event.is_readable() {
+                self.relay(fd, config, topic)?;
+            }
+        }
+        self.enforce_windows(config, topic)?;
+        self.reap_idle(config, topic)?;
+        Ok(())
+    }
+
+    fn children_exited(
+        &mut self,
+        _config: &Config,
+        _topic: &mut Topic,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        // the hooks are the only processes, reaped by the caller
+        Ok(())
+    }
+
+    fn disconnected(&mut self, _config: &Config) {
+        // the connections are closed with their clients
+        self.clients.clear();
+    }
+
+    fn shutdown(
+        &mut self,
+        _config: &Config,
+        topic: &mut Topic,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.notify_shutdown(topic)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,858 @@
+//! # Job queue backend
+//!
+//! The clients of `shqueue` submit jobs that are run by a pool
+//! of `queue.workers` processes at most, in the order of their
+//! submission, instead of a process being forked for each
+//! request as in `shexec`. Each job is the configured command,
+//! or one of the menu, with the arguments and the input of the
+//! client, and its owner is told of its progress:
+//!
+//! ```text
+//! -> ctrl {"submit": {"command": "backup", "args": ["--full"], "input": "..."}}
+//! <- ctrl {"job": 7, "state": "queued", "position": 2}
+//! <- ctrl {"job": 7, "state": "started", "pid": 1234}
+//! <- ctrl {"job": 7, "state": "progress", "output": "50%\n"}
+//! <- ctrl {"job": 7, "state": "finished", "exit_code": 0}
+//! ```
+//!
+//! A data frame submits a job of the configured command with
+//! the frame as its input. `{"jobs": true}` lists the jobs of
+//! the client and `{"cancel": 7}` cancels one, whether it is
+//! queued or running. The jobs of a client are cancelled when
+//! it unsubscribes
+//!
+//! **Author**: "Dany LE"
+//!
+use crate::audit::{self, Event};
+use crate::backend::Backend;
+use crate::clients::{self, Roster, Subscription};
+use crate::config::Config;
+use crate::error::{self, Class, Classify};
+use crate::frame;
+use crate::history::{self, Run};
+use crate::log::{self, Level};
+use crate::metrics::{self, Counter};
+use crate::process::Process;
+use crate::profile::{Phase, Timer};
+use crate::spawn;
+use crate::stats::{self, Stats};
+use crate::telemetry::Span;
+use crate::{DEBUG, ERROR, INFO, WARN};
+use latpr::tunnel::{CallbackEvent, IOInterest, Msg, MsgKind, Topic};
+use latpr::utils::{LogLevel, LOG};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, Write};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::process::ExitStatusExt;
+use std::process::{Command, ExitStatus, Stdio};
+use std::time::{Duration, Instant};
+
+/// time given to the processes to exit on shutdown
+const TERMINATE_GRACE: Duration = Duration::from_secs(2);
+/// default of `queue.workers`
+const WORKERS: usize = 2;
+/// default of `queue.max_jobs`
+const MAX_JOBS: usize = 16;
+
+/// Settings of the job queue mode, e.g.
+///
+/// ```toml
+/// [queue]
+/// workers = 4
+/// max_jobs = 8
+/// timeout = 3600
+/// ```
+#[derive(Deserialize, Serialize, Default, Debug, Clone, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct QueueSettings {
+    /// jobs run at once, 2 by default
+    pub workers: Option<usize>,
+    /// jobs a client may have queued or running, 16 by default
+    pub max_jobs: Option<usize>,
+    /// seconds after which a job is killed, no limit when not
+    /// set
+    pub timeout: Option<u64>,
+}
+
+impl QueueSettings {
+    pub fn workers(&self) -> usize {
+        self.workers.unwrap_or(WORKERS)
+    }
+
+    pub fn max_jobs(&self) -> usize {
+        self.max_jobs.unwrap_or(MAX_JOBS)
+    }
+}
+
+/// A job submitted by a client
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct Submit {
+    /// command of the menu, `command` when not set
+    command: Option<String>,
+    /// checked against `args_pattern`
+    args: Vec<String>,
+    input: String,
+}
+
+/// A control frame of a client
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct Request {
+    submit: Option<Submit>,
+    jobs: bool,
+    cancel: Option<u64>,
+}
+
+/// A job waiting for a worker
+struct Job {
+    id: u64,
+    client_id: u16,
+    command: Option<String>,
+    args: Vec<String>,
+    input: Vec<u8>,
+}
+
+/// A job being run
+struct Running {
+    id: u64,
+    client_id: u16,
+    process: Process,
+    run: Run,
+    started_at: Instant,
+    /// input not written yet, the input of the process is
+    /// closed once it is all written
+    input: Vec<u8>,
+    /// why the job has been killed, if it has
+    killed: Option<&'static str>,
+}
+
+impl Running {
+    /// Write as much input as the process takes, then close it
+    fn write_input(&mut self) -> io::Result<()> {
+        let stdin = match self.process.stdin.as_mut() {
+            None => return Ok(()),
+            Some(stdin) => stdin,
+        };
+        while !self.input.is_empty() {
+            match stdin.write(&self.input) {
+                Ok(n) => {
+                    self.input.drain(..n);
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                // the process does not read its input
+                Err(e) if e.kind() == io::ErrorKind::BrokenPipe => self.input.clear(),
+                Err(e) => return Err(e),
+            }
+        }
+        self.process.stdin = None;
+        Ok(())
+    }
+
+    fn kill(&mut self, reason: &'static str) {
+        if self.killed.is_some() {
+            return;
+        }
+        self.killed = Some(reason);
+        if let Err(error) = self.process.kill() {
+            WARN!("Unable to kill job {}: {}", self.id, error);
+        }
+    }
+}
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        if let Ok(None) = self.process.try_wait() {
+            self.kill("dropped");
+        }
+    }
+}
+
+pub struct Client {
+    user: String,
+    /// identifier of the session, see [`telemetry::session_id`]
+    session: String,
+    /// counters of the client
+    stats: Stats,
+    /// span of the subscription, see [`crate::telemetry`]
+    span: Option<Span>,
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        clients::end_span(&mut self.span, &self.stats);
+    }
+}
+
+crate::member!(Client);
+
+/// Backend running the jobs of the clients with a bounded
+/// concurrency
+pub struct Queue {
+    clients: HashMap<u16, Client>,
+    /// jobs waiting for a worker, oldest first
+    pending: VecDeque<Job>,
+    running: Vec<Running>,
+    /// identifier of the next job
+    next_id: u64,
+    /// whether a process may have exited since the last check
+    reap: bool,
+}
+
+impl Default for Queue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Build a control frame about a job, e.g.
+/// `{"job": 7, "state": "started", "pid": 1234}`
+fn state(client_id: u16, id: u64, state: &str, extra: Value) -> Msg {
+    let mut body = json!({ "job": id, "state": state });
+    if let (Some(body), Value::Object(extra)) = (body.as_object_mut(), extra) {
+        body.extend(extra);
+    }
+    Msg::create(
+        MsgKind::ChannelCtrl,
+        0,
+        client_id,
+        body.to_string().into_bytes(),
+    )
+}
+
+/// Spawn the process of a job, its input is written as it
+/// takes it
+fn spawn_job(
+    job: Job,
+    client: &Client,
+    config: &Config,
+) -> Result<Running, Box<dyn std::error::Error>> {
+    let _timer = Timer::start(Phase::Spawn);
+    let profile = config.profile(&client.user);
+    let path = config.allowed_command(config.command_for(&profile, job.command.as_deref())?)?;
+    let mut command = Command::new(&path);
+    command
+        .args(&job.args)
+        .envs(&profile.env)
+        .env("CUSER", &client.user)
+        .env("CID", format!("{}", job.client_id))
+        .env("JOB_ID", format!("{}", job.id))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped());
+    profile.rlimits.apply(&mut command);
+    spawn::die_with_parent(&mut command);
+    let process = Process::from(
+        command
+            .spawn()
+            .inspect_err(|_| metrics::add(Counter::SpawnFailures, 1))
+            .class(Class::Spawn)?,
+    );
+    if let Some(stdin) = process.stdin.as_ref() {
+        unsafe {
+            let fd = stdin.as_raw_fd();
+            let flags = libc::fcntl(fd, libc::F_GETFL);
+            libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK);
+        }
+    }
+    metrics::add(Counter::Spawned, 1);
+    let spawned = Event::Spawn {
+        command: &path,
+        args: &job.args,
+        pid: process.id(),
+    };
+    audit::log(
+        config,
+        job.client_id,
+        &client.user,
+        &client.session,
+        spawned,
+    );
+    DEBUG!(
+        "Run job {} of client {}: {} bytes, pid {}",
+        job.id,
+        job.client_id,
+        job.input.len(),
+        process.id()
+    );
+    Ok(Running {
+        id: job.id,
+        client_id: job.client_id,
+        run: Run::start(job.client_id, &client.session, &path, process.id()),
+        process,
+        started_at: Instant::now(),
+        input: job.input,
+        killed: None,
+    })
+}
+
+impl Queue {
+    pub fn new() -> Queue {
+        Queue {
+            clients: HashMap::new(),
+            pending: VecDeque::new(),
+            running: Vec::new(),
+            next_id: 1,
+            reap: false,
+        }
+    }
+
+    /// Remove a client, its jobs are cancelled
+    fn remove(&mut self, client_id: u16, topic: &mut Topic) -> Option<Client> {
+        self.pending.retain(|j| j.client_id != client_id);
+        for running in self.running.iter_mut().filter(|r| r.client_id == client_id) {
+            if let Some(fd) = running.process.stdout_fd() {
+                if let Err(error) = topic.unregister_io(fd) {
+                    WARN!("Unable to unregister job {}: {}", running.id, error);
+                }
+            }
+            running.kill("unsubscribed");
+        }
+        // the killed processes are reaped once dropped
+        self.running.retain(|r| r.client_id != client_id);
+        self.clients.remove(&client_id)
+    }
+
+    /// Queue a job of a client
+    fn submit(
+        &mut self,
+        client_id: u16,
+        submit: Submit,
+        config: &Config,
+        topic: &mut Topic,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let Some(client) = self.clients.get(&client_id) else {
+            WARN!("Client {} is not in the list", client_id);
+            return Ok(());
+        };
+        let jobs = self
+            .pending
+            .iter()
+            .filter(|j| j.client_id == client_id)
+            .count()
+            + self
+                .running
+                .iter()
+                .filter(|r| r.client_id == client_id)
+                .count();
+        let profile = config.profile(&client.user);
+        let refused = if jobs >= config.queue.max_jobs() {
+            Some(format!(
+                "Too many jobs, maximum is {}",
+                config.queue.max_jobs()
+            ))
+        } else if let Err(error) = config
+            .command_for(&profile, submit.command.as_deref())
+            .and_then(|_| config.check_args(&submit.args))
+        {
+            Some(error.to_string())
+        } else {
+            None
+        };
+        if let Some(reason) = refused {
+            WARN!("Refuse job of client {}: {}", client_id, reason);
+            return frame::send(topic, &frame::error(client_id, &reason));
+        }
+        let id = self.next_id;
+        self.next_id += 1;
+        self.pending.push_back(Job {
+            id,
+            client_id,
+            command: submit.command,
+            args: submit.args,
+            input: submit.input.into_bytes(),
+        });
+        DEBUG!("Client {} queued job {}", client_id, id);
+        let position = self.pending.len();
+        frame::send(
+            topic,
+            &state(client_id, id, "queued", json!({ "position": position })),
+        )
+    }
+
+    /// Cancel a job of a client, queued or running
+    fn cancel(
+        &mut self,
+        client_id: u16,
+        id: u64,
+        topic: &mut Topic,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if let Some(index) = self
+            .pending
+            .iter()
+            .position(|j| j.id == id && j.client_id == client_id)
+        {
+            self.pending.remove(index);
+            INFO!("Client {} cancelled queued job {}", client_id, id);
+            return frame::send(topic, &state(client_id, id, "cancelled", json!({})));
+        }
+        match self
+            .running
+            .iter_mut()
+            .find(|r| r.id == id && r.client_id == client_id)
+        {
+            // the client is told once the process has exited
+            Some(running) => {
+                INFO!("Client {} cancelled running job {}", client_id, id);
+                running.kill("cancelled");
+                Ok(())
+            }
+            None => {
+                let reason = format!("No job {}", id);
+                frame::send(topic, &frame::error(client_id, &reason))
+            }
+        }
+    }
+
+    /// List the jobs of a client
+    fn list(&self, client_id: u16, topic: &mut Topic) -> Result<(), Box<dyn std::error::Error>> {
+        let running = self
+            .running
+            .iter()
+            .filter(|r| r.client_id == client_id)
+            .map(|r| {
+                json!({
+                    "job": r.id,
+                    "state": "running",
+                    "pid": r.process.id(),
+                    "elapsed": r.started_at.elapsed().as_secs(),
+                })
+            });
+        let queued = self
+            .pending
+            .iter()
+            .enumerate()
+            .filter(|(_, j)| j.client_id == client_id)
+            .map(|(i, j)| json!({ "job": j.id, "state": "queued", "position": i + 1 }));
+        let jobs: Vec<Value> = running.chain(queued).collect();
+        let body = json!({ "jobs": jobs });
+        frame::send(
+            topic,
+            &Msg::create(
+                MsgKind::ChannelCtrl,
+                0,
+                client_id,
+                body.to_string().into_bytes(),
+            ),
+        )
+    }
+
+    /// Start the queued jobs while there are free workers
+    fn dispatch(
+        &mut self,
+        config: &Config,
+        topic: &mut Topic,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        while self.running.len() < config.queue.workers() {
+            let Some(job) = self.pending.pop_front() else {
+                return Ok(());
+            };
+            let (id, client_id) = (job.id, job.client_id);
+            let Some(client) = self.clients.get_mut(&client_id) else {
+                continue;
+            };
+            let _context = log::context(&config.topic, client_id, &client.user, &client.session);
+            match spawn_job(job, client, config) {
+                Ok(mut running) => {
+                    if let Some(fd) = running.process.stdout_fd() {
+                        topic.register_io(fd, IOInterest::READABLE)?;
+                    }
+                    running.write_input().class(Class::ChildIo)?;
+                    client.stats.spawns += 1;
+                    let pid = running.process.id();
+                    self.running.push(running);
+                    frame::send(
+                        topic,
+                        &state(client_id, id, "started", json!({ "pid": pid })),
+                    )?;
+                }
+                Err(error) => {
+                    ERROR!(
+                        "Unable to run job {} of client {}: {}: {}",
+                        id,
+                        client_id,
+                        error::report(&*error, Class::Spawn),
+                        error
+                    );
+                    let extra = json!({ "error": "Unable to run the job" });
+                    frame::send(topic, &state(client_id, id, "failed", extra))?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Send the output of the job reading from a descriptor to
+    /// its client
+    fn output(
+        &mut self,
+        fd: RawFd,
+        config: &Config,
+        topic: &mut Topic,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let Some(running) = self
+            .running
+            .iter_mut()
+            .find(|r| r.process.stdout_fd() == Some(fd))
+        else {
+            DEBUG!("Ignore event of descriptor {}: no job owns it", fd);
+            return Ok(());
+        };
+        let mut buf = vec![0; config.buffer_size];
+        let n = match running
+            .process
+            .read_output(&mut buf[..])
+            .class(Class::ChildIo)?
+        {
+            Some(n) if n > 0 => n,
+            _ => return Ok(()),
+        };
+        let key = running.client_id;
+        if let Some((reads, bytes)) = log::throttle(Level::Debug, "send", key, n) {
+            DEBUG!(
+                "Sending {} bytes of job output to client {} in {} read(s)",
+                bytes,
+                key,
+                reads
+            );
+        }
+        metrics::add(Counter::BytesOut, n as u64);
+        if let Some(client) = self.clients.get_mut(&key) {
+            client.stats.sent(n);
+        }
+        let output = String::from_utf8_lossy(&buf[..n]);
+        let extra = json!({ "output": output });
+        frame::send(topic, &state(key, running.id, "progress", extra))
+    }
+
+    /// Move the jobs along: write their input, kill them on
+    /// timeout, report the finished ones and start the next ones
+    fn supervise(
+        &mut self,
+        config: &Config,
+        topic: &mut Topic,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let reap = std::mem::take(&mut self.reap);
+        let timeout = config.queue.timeout.map(Duration::from_secs);
+        let mut finished = Vec::new();
+        for (index, running) in self.running.iter_mut().enumerate() {
+            if let Err(error) = running.write_input() {
+                WARN!("Unable to write the input of job {}: {}", running.id, error);
+                running.input.clear();
+                running.process.stdin = None;
+            }
+            if timeout.is_some_and(|t| running.started_at.elapsed() >= t) {
+                WARN!("Job {} has timed out, kill it", running.id);
+                running.kill("timeout");
+            }
+            if !reap && running.killed.is_none() {
+                continue;
+            }
+            if let Some(status) = running.process.try_wait()? {
+                finished.push((index, status));
+            }
+        }
+        for (index, status) in finished.into_iter().rev() {
+            let mut running = self.running.remove(index);
+            if let Some(fd) = running.process.stdout_fd() {
+                // the end of the output is sent first
+                loop {
+                    let mut buf = vec![0; config.buffer_size];
+                    match running.process.read_output(&mut buf[..]) {
+                        Ok(Some(n)) if n > 0 => {
+                            let output = String::from_utf8_lossy(&buf[..n]);
+                            let extra = json!({ "output": output });
+                            let msg = state(running.client_id, running.id, "progress", extra);
+                            frame::send(topic, &msg)?;
+                        }
+                        _ => break,
+                    }
+                }
+                topic.unregister_io(fd)?;
+            }
+            self.finish(&running, status, config, topic)?;
+        }
+        self.dispatch(config, topic)
+    }
+
+    /// Report a job whose process has exited
+    fn finish(
+        &mut self,
+        running: &Running,
+        status: ExitStatus,
+        config: &Config,
+        topic: &mut Topic,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let key = running.client_id;
+        metrics::add(Counter::Exited, 1);
+        DEBUG!(
+            "Job {} of client {} has exited with status {}",
+            running.id,
+            key,
+            status
+        );
+        if let Some(client) = self.clients.get(&key) {
+            let exit = Event::Exit {
+                pid: running.run.pid,
+                status,
+            };
+            audit::log(config, key, &client.user, &client.session, exit);
+            history::record(config, &client.user, &running.run, status);
+        }
+        let mut extra = match (status.code(), status.signal()) {
+            (Some(code), _) => json!({ "exit_code": code }),
+            (None, signal) => json!({ "signal": signal }),
+        };
+        let name = match running.killed {
+            Some("cancelled") => "cancelled",
+            Some(reason) => {
+                extra["error"] = json!(reason);
+                "failed"
+            }
+            None => "finished",
+        };
+        frame::send(topic, &state(key, running.id, name, extra))
+    }
+
+    fn handle_message(
+        &mut self,
+        msg: &Msg,
+        config: &Config,
+        topic: &mut Topic,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if !matches!(msg.kind, MsgKind::ChannelData) {
+            DEBUG!(
+                "Receive message kind {} from client {}: {} bytes",
+                msg.kind,
+                msg.client_id,
+                msg.size
+            );
+        } else if let Some((frames, bytes)) =
+            log::throttle(Level::Debug, "receive", msg.client_id, msg.size as usize)
+        {
+            DEBUG!(
+                "Receive {} data frame(s) from client {}: {} bytes",
+                frames,
+                msg.client_id,
+                bytes
+            );
+        }
+        if let Some(client) = self.clients.get_mut(&msg.client_id) {
+            client.stats.received();
+        }
+        match msg.kind {
+            MsgKind::ChannelSubscribe => {
+                let Some(Subscription {
+                    user,
+                    session,
+                    context: _context,
+                }) = clients::subscribe(msg, config, topic, |topic| {
+                    Ok(self
+                        .remove(msg.client_id, topic)
+                        .map(|old| old.user.clone()))
+                })?
+                else {
+                    return Ok(());
+                };
+                if !self.make_room(msg.client_id, config, topic)? {
+                    return Ok(());
+                }
+                let span = clients::span(&config.topic, msg.client_id, &user, &session);
+                audit::log(config, msg.client_id, &user, &session, Event::Subscribe);
+                INFO!(
+                    "Client {} subscribe to channel {}",
+                    msg.client_id,
+                    &config.topic
+                );
+                self.clients.insert(
+                    msg.client_id,
+                    Client {
+                        user,
+                        session,
+                        stats: Stats::default(),
+                        span: Some(span),
+                    },
+                );
+                clients::welcome(msg.client_id, config, topic)?;
+            }
+            MsgKind::ChannelUnsubscribe => {
+                self.unsubscribe(msg.client_id, config, topic)?;
+            }
+            MsgKind::ChannelUnsubscribeAll => self.unsubscribe_all(config, topic)?,
+            MsgKind::ChannelData => {
+                if let Some(client) = self.clients.get_mut(&msg.client_id) {
+                    client.stats.bytes_in += msg.data.len() as u64;
+                }
+                metrics::add(Counter::BytesIn, msg.data.len() as u64);
+                let submit = Submit {
+                    input: String::from_utf8_lossy(&msg.data).into_owned(),
+                    ..Submit::default()
+                };
+                self.submit(msg.client_id, submit, config, topic)?;
+                self.dispatch(config, topic)?;
+            }
+            MsgKind::ChannelCtrl if stats::is_query(&msg.data) => {
+                self.status(msg.client_id, topic)?;
+            }
+            MsgKind::ChannelCtrl => match serde_json::from_slice::<Request>(&msg.data) {
+                Ok(Request {
+                    submit: Some(submit),
+                    jobs: false,
+                    cancel: None,
+                }) => {
+                    self.submit(msg.client_id, submit, config, topic)?;
+                    self.dispatch(config, topic)?;
+                }
+                Ok(Request {
+                    submit: None,
+                    jobs: true,
+                    cancel: None,
+                }) => self.list(msg.client_id, topic)?,
+                Ok(Request {
+                    submit: None,
+                    jobs: false,
+                    cancel: Some(id),
+                }) => self.cancel(msg.client_id, id, topic)?,
+                Ok(_) => {
+                    let reason = "Invalid request: expected one of submit, jobs, cancel";
+                    frame::send(topic, &frame::error(msg.client_id, reason))?;
+                }
+                Err(error) => {
+                    WARN!("Invalid request of client {}: {}", msg.client_id, error);
+                    let reason = format!("Invalid request: {}", error);
+                    frame::send(topic, &frame::error(msg.client_id, &reason))?;
+                }
+            },
+            _ => {
+                WARN!(
+                    "Receive message kind {} from client {}",
+                    msg.kind,
+                    msg.client_id
+                );
+            }
+        };
+        Ok(())
+    }
+}
+
+impl Roster for Queue {
+    type Client = Client;
+
+    fn table(&self) -> &HashMap<u16, Client> {
+        &self.clients
+    }
+
+    fn table_mut(&mut self) -> &mut HashMap<u16, Client> {
+        &mut self.clients
+    }
+
+    fn release(
+        &mut self,
+        client_id: u16,
+        _config: &Config,
+        topic: &mut Topic,
+    ) -> Result<Option<Client>, Box<dyn std::error::Error>> {
+        Ok(self.remove(client_id, topic))
+    }
+
+    // a client waiting for its jobs is not idle
+    fn busy(&self, client_id: u16) -> bool {
+        self.running.iter().any(|r| r.client_id == client_id)
+            || self.pending.iter().any(|j| j.client_id == client_id)
+    }
+}
+
+impl Backend for Queue {
+    fn fds(&self) -> Vec<RawFd> {
+        self.running
+            .iter()
+            .filter_map(|r| r.process.stdout_fd())
+            .collect()
+    }
+
+    fn clients(&self) -> usize {
+        self.clients.len()
+    }
+
+    fn dump(&self) -> Vec<String> {
+        let mut lines = vec![format!(
+            "{} client(s), {} job(s) running, {} queued",
+            self.clients.len(),
+            self.running.len(),
+            self.pending.len()
+        )];
+        for r in self.running.iter() {
+            lines.push(format!(
+                "job {} of client {}: pid {}, running for {}s",
+                r.id,
+                r.client_id,
+                r.process.id(),
+                r.started_at.elapsed().as_secs()
+            ));
+        }
+        let mut keys: Vec<&u16> = self.clients.keys().collect();
+        keys.sort();
+        for key in keys {
+            let c = &self.clients[key];
+            lines.push(format!(
+                "client {}: user {}, {}",
+                key,
+                c.user,
+                c.stats.describe()
+            ));
+        }
+        lines
+    }
+
+    fn step_handle(
+        &mut self,
+        evt: &CallbackEvent,
+        config: &Config,
+        topic: &mut Topic,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if let Some(msg) = evt.msg {
+            self.scoped(msg, config, topic, |queue, topic| {
+                queue.handle_message(msg, config, topic)
+            })?;
+        }
+        if let (Some(event), Some(fd)) = (evt.event, evt.fd) {
+            if event.is_readable() {
+                self.output(fd, config, topic)?;
+            }
+        }
+        self.supervise(config, topic)?;
+        self.enforce_windows(config, topic)?;
+        self.reap_idle(config, topic)
+    }
+
+    fn children_exited(
+        &mut self,
+        config: &Config,
+        topic: &mut Topic,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.reap = true;
+        self.supervise(config, topic)
+    }
+
+    fn disconnected(&mut self, _config: &Config) {
+        // the jobs are cancelled with their clients
+        self.pending.clear();
+        self.running.clear();
+        self.clients.clear();
+    }
+
+    fn shutdown(
+        &mut self,
+        _config: &Config,
+        topic: &mut Topic,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.notify_shutdown(topic)?;
+        self.pending.clear();
+        let processes = self.running.iter_mut().map(|r| &mut r.process).collect();
+        spawn::terminate(processes, TERMINATE_GRACE);
+        self.running.clear();
+        self.clients.clear();
+        Ok(())
+    }
+}
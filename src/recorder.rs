@@ -0,0 +1,224 @@
+//! # Session recording
+//!
+//! With `record_dir`, the input and output of each process are
+//! recorded with their timing to an asciicast v2 file, to be
+//! played back with `asciinema play`, see
+//! <https://docs.asciinema.org/manual/asciicast/v2/>
+//!
+//! **Author**: "Dany LE"
+//!
+use crate::config::Config;
+use crate::redact;
+use crate::rotate::{self, Rotation};
+use crate::setup::SessionSetup;
+use crate::WARN;
+use latpr::utils::{LogLevel, LOG};
+use serde_json::{json, Value};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+/// terminal size recorded when the client did not give one
+const DEFAULT_SIZE: (u16, u16) = (80, 24);
+
+pub struct Recorder {
+    file: File,
+    start: Instant,
+    /// trailing bytes of an incomplete UTF-8 sequence, per
+    /// stream, completed by the next data
+    pending_in: Vec<u8>,
+    pending_out: Vec<u8>,
+    /// input of the current line, kept until its end to be
+    /// redacted as a whole, see [`crate::redact`]
+    line_in: String,
+    dir: PathBuf,
+    name: String,
+    header: Value,
+    rotation: Rotation,
+    /// bytes written to the current file
+    size: u64,
+    /// number of the current file, from 0
+    part: usize,
+}
+
+impl Recorder {
+    /// Create the recording file `<name>-<timestamp>.cast` in a
+    /// directory, readable by its owner only. Once rotated, the
+    /// recording goes on in `<name>-<timestamp>.<part>.cast`
+    pub fn create(
+        dir: &Path,
+        name: &str,
+        title: &str,
+        setup: Option<&SessionSetup>,
+        rotation: &Rotation,
+    ) -> io::Result<Recorder> {
+        let timestamp = now();
+        fs::create_dir_all(dir)?;
+        let width = setup.and_then(|s| s.cols).unwrap_or(DEFAULT_SIZE.0);
+        let height = setup.and_then(|s| s.rows).unwrap_or(DEFAULT_SIZE.1);
+        let mut header = json!({
+            "version": 2,
+            "width": width,
+            "height": height,
+            "timestamp": timestamp,
+            "title": title,
+        });
+        if let Some(term) = setup.and_then(|s| s.term.as_ref()) {
+            header["env"] = json!({ "TERM": term });
+        }
+        let name = format!("{}-{}", name, timestamp);
+        let file = open(&dir.join(format!("{}.cast", name)))?;
+        let mut recorder = Recorder {
+            file,
+            start: Instant::now(),
+            pending_in: Vec::new(),
+            pending_out: Vec::new(),
+            line_in: String::new(),
+            dir: dir.to_path_buf(),
+            name,
+            header,
+            rotation: rotation.clone(),
+            size: 0,
+            part: 0,
+        };
+        recorder.write_line(recorder.header.to_string())?;
+        Ok(recorder)
+    }
+
+    /// Go on with the recording in a new file, with its own
+    /// header and timing
+    fn rotate(&mut self) -> io::Result<()> {
+        self.part += 1;
+        self.file = open(&self.dir.join(format!("{}.{}.cast", self.name, self.part)))?;
+        self.start = Instant::now();
+        self.size = 0;
+        self.header["timestamp"] = json!(now());
+        self.write_line(self.header.to_string())?;
+        if let Some(keep) = self.rotation.keep {
+            // the recordings of the topic, see [`start`]
+            let prefix = format!(
+                "{}-",
+                file_name(self.header["title"].as_str().unwrap_or_default())
+            );
+            rotate::prune(&self.dir, &prefix, ".cast", keep)?;
+        }
+        Ok(())
+    }
+
+    /// Record some input of the clients. With redaction, the
+    /// input is recorded line by line, the keystrokes of a secret
+    /// would not match the patterns one by one
+    pub fn input(&mut self, data: &[u8]) {
+        let text = take_text(&mut self.pending_in, data);
+        if !redact::enabled() {
+            self.event("i", text);
+            return;
+        }
+        self.line_in.push_str(&text);
+        if let Some(end) = self.line_in.rfind(['\n', '\r']) {
+            let rest = self.line_in.split_off(end + 1);
+            let line = std::mem::replace(&mut self.line_in, rest);
+            self.event("i", redact::text(&line));
+        }
+    }
+
+    /// Record some output of the process
+    pub fn output(&mut self, data: &[u8]) {
+        let text = take_text(&mut self.pending_out, data);
+        if redact::enabled() {
+            self.event("o", redact::text(&text));
+        } else {
+            self.event("o", text);
+        }
+    }
+
+    fn event(&mut self, kind: &str, text: String) {
+        if text.is_empty() {
+            return;
+        }
+        if self.rotation.due(self.size, self.start.elapsed()) {
+            if let Err(error) = self.rotate() {
+                WARN!("Unable to rotate the session recording: {}", error);
+            }
+        }
+        let time = self.start.elapsed().as_secs_f64();
+        if let Err(error) = self.write_line(json!([time, kind, text]).to_string()) {
+            WARN!("Unable to write the session recording: {}", error);
+        }
+    }
+
+    fn write_line(&mut self, mut line: String) -> io::Result<()> {
+        line.push('\n');
+        self.file.write_all(line.as_bytes())?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+}
+
+fn open(path: &Path) -> io::Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .mode(0o600)
+        .open(path)
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+impl Drop for Recorder {
+    fn drop(&mut self) {
+        let line = std::mem::take(&mut self.line_in);
+        self.event("i", redact::text(&line));
+    }
+}
+
+/// Text of the pending bytes followed by some data, an
+/// incomplete UTF-8 sequence at the end is kept pending, the
+/// invalid sequences are replaced
+fn take_text(pending: &mut Vec<u8>, data: &[u8]) -> String {
+    pending.extend_from_slice(data);
+    let valid = match std::str::from_utf8(pending) {
+        Ok(_) => pending.len(),
+        Err(e) if e.error_len().is_none() => e.valid_up_to(),
+        Err(_) => pending.len(),
+    };
+    let text = String::from_utf8_lossy(&pending[..valid]).into_owned();
+    pending.drain(..valid);
+    text
+}
+
+/// Start the recording of a process when `record_dir` is set,
+/// a failure is logged and the process is not recorded
+pub fn start(config: &Config, name: &str, setup: Option<&SessionSetup>) -> Option<Recorder> {
+    let dir = config.record_dir.as_ref()?;
+    let name = file_name(&format!("{}-{}", config.topic, name));
+    match Recorder::create(dir, &name, &config.topic, setup, &config.record_rotation) {
+        Ok(recorder) => Some(recorder),
+        Err(error) => {
+            WARN!(
+                "Unable to record the session in {}: {}",
+                dir.display(),
+                error
+            );
+            None
+        }
+    }
+}
+
+fn file_name(name: &str) -> String {
+    name.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
@@ -0,0 +1,44 @@
+//! # Secret redaction
+//!
+//! With `redact`, the matches of the patterns, e.g. passwords
+//! or tokens, are masked in the payloads written to the log by
+//! `trace_frames` and in the session recordings
+//!
+//! **Author**: "Dany LE"
+//!
+use regex::Regex;
+use std::sync::RwLock;
+
+/// replacement of the matches
+pub const MASK: &str = "[REDACTED]";
+
+static PATTERNS: RwLock<Vec<Regex>> = RwLock::new(Vec::new());
+
+/// Set the patterns of the process
+pub fn set_patterns(patterns: Vec<Regex>) {
+    if let Ok(mut current) = PATTERNS.write() {
+        *current = patterns;
+    }
+}
+
+/// Whether there is any pattern
+pub fn enabled() -> bool {
+    PATTERNS.read().is_ok_and(|p| !p.is_empty())
+}
+
+/// Mask the matches of the patterns in a text
+pub fn text(text: &str) -> String {
+    let mut text = String::from(text);
+    if let Ok(patterns) = PATTERNS.read() {
+        for pattern in patterns.iter() {
+            text = pattern.replace_all(&text, MASK).into_owned();
+        }
+    }
+    text
+}
+
+/// Mask the matches of the patterns in some data, the invalid
+/// UTF-8 sequences are replaced
+pub fn bytes(data: &[u8]) -> Vec<u8> {
+    text(&String::from_utf8_lossy(data)).into_bytes()
+}
@@ -0,0 +1,64 @@
+//! # Replay buffer of the broadcast process
+//!
+//! Keep the recent output of the broadcast process so that
+//! it can be sent to the clients subscribing afterward
+//!
+//! **Author**: "Dany LE"
+//!
+use crate::config::Config;
+use std::collections::VecDeque;
+
+/// Ring buffer of the recent output, bounded in bytes
+/// (`replay_bytes`) or in lines (`replay_lines`)
+#[derive(Default)]
+pub struct Replay {
+    data: VecDeque<u8>,
+    /// number of line feeds in `data`
+    lines: usize,
+}
+
+impl Replay {
+    /// Append some output and drop the oldest one beyond the
+    /// configured size. The limits are read on each call so
+    /// that a reloaded configuration applies right away
+    pub fn push(&mut self, output: &[u8], config: &Config) {
+        if config.replay_bytes.is_none() && config.replay_lines.is_none() {
+            self.data.clear();
+            self.lines = 0;
+            return;
+        }
+        self.data.extend(output);
+        self.lines += output.iter().filter(|&&b| b == b'\n').count();
+        if let Some(max) = config.replay_bytes {
+            let excess = self.data.len().saturating_sub(max);
+            self.drop_front(excess);
+        }
+        if let Some(max) = config.replay_lines {
+            // keep the last `max` complete lines and the current
+            // incomplete one
+            while self.lines > max {
+                match self.data.iter().position(|&b| b == b'\n') {
+                    Some(i) => self.drop_front(i + 1),
+                    None => break,
+                }
+            }
+        }
+    }
+
+    /// Content of the buffer, none when empty
+    pub fn content(&self) -> Option<Vec<u8>> {
+        if self.data.is_empty() {
+            return None;
+        }
+        Some(self.data.iter().copied().collect())
+    }
+
+    /// Size of the buffer in bytes
+    pub fn size(&self) -> usize {
+        self.data.len()
+    }
+
+    fn drop_front(&mut self, n: usize) {
+        self.lines -= self.data.drain(..n).filter(|&b| b == b'\n').count();
+    }
+}
@@ -0,0 +1,94 @@
+//! # File rotation
+//!
+//! The log file and the session recordings are rotated once
+//! they reach a size or an age, keeping a number of the rotated
+//! files so that a long-running backend does not fill the disk
+//!
+//! **Author**: "Dany LE"
+//!
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+/// When a file is rotated and how many rotated files are kept,
+/// e.g.
+///
+/// ```toml
+/// [log_rotation]
+/// max_size = 10485760
+/// keep = 5
+/// ```
+#[derive(Deserialize, Serialize, Default, Debug, Clone, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Rotation {
+    /// size in bytes beyond which a file is rotated
+    pub max_size: Option<u64>,
+    /// seconds after which a file is rotated
+    pub interval: Option<u64>,
+    /// rotated files kept, the oldest are removed, all are kept
+    /// when not set
+    pub keep: Option<usize>,
+}
+
+impl Rotation {
+    /// Whether a file of the given size, opened for some time,
+    /// is to be rotated
+    pub fn due(&self, size: u64, age: Duration) -> bool {
+        self.max_size.is_some_and(|max| size >= max)
+            || self.interval.is_some_and(|secs| age.as_secs() >= secs)
+    }
+}
+
+fn numbered(path: &Path, n: usize) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(format!(".{}", n));
+    PathBuf::from(name)
+}
+
+/// Rotate a file: `<path>` becomes `<path>.1`, `<path>.1`
+/// becomes `<path>.2` and so on, the files beyond `keep` are
+/// removed
+pub fn shift(path: &Path, keep: Option<usize>) -> io::Result<()> {
+    let mut free = 1;
+    while numbered(path, free).exists() {
+        free += 1;
+    }
+    let last = match keep {
+        Some(0) => return fs::remove_file(path),
+        Some(keep) => free.min(keep),
+        None => free,
+    };
+    for n in last..free {
+        fs::remove_file(numbered(path, n))?;
+    }
+    for n in (1..last).rev() {
+        fs::rename(numbered(path, n), numbered(path, n + 1))?;
+    }
+    fs::rename(path, numbered(path, 1))
+}
+
+/// Remove the oldest files of a directory whose name starts
+/// with `prefix` and ends with `suffix`, beyond `keep`
+pub fn prune(dir: &Path, prefix: &str, suffix: &str, keep: usize) -> io::Result<()> {
+    let mut files: Vec<(SystemTime, PathBuf)> = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        if !name.starts_with(prefix) || !name.ends_with(suffix) {
+            continue;
+        }
+        let modified = entry.metadata()?.modified()?;
+        files.push((modified, entry.path()));
+    }
+    // newest first
+    files.sort_by_key(|(modified, _)| Reverse(*modified));
+    for (_, path) in files.into_iter().skip(keep) {
+        fs::remove_file(path)?;
+    }
+    Ok(())
+}
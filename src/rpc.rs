@@ -0,0 +1,190 @@
+//! # JSON-RPC over a channel
+//!
+//! The clients of `shrpc` send JSON-RPC 2.0 requests in data
+//! frames, each method being mapped to a configured command:
+//!
+//! ```text
+//! -> data {"jsonrpc": "2.0", "method": "disk_usage", "params": ["-h", "/srv"], "id": 1}
+//! <- data {"jsonrpc": "2.0", "result": "Filesystem ...", "id": 1}
+//! ```
+//!
+//! The requests are run as those of shexec, see [`crate::exec`],
+//! this module parses them and builds the replies
+//!
+//! **Author**: "Dany LE"
+//!
+use crate::config::Config;
+use crate::exec::Job;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::process::ExitStatus;
+
+/// Error codes of the JSON-RPC specification
+pub const PARSE_ERROR: i64 = -32700;
+pub const INVALID_REQUEST: i64 = -32600;
+pub const METHOD_NOT_FOUND: i64 = -32601;
+pub const INVALID_PARAMS: i64 = -32602;
+/// the command has failed, i.e. a non zero exit status
+pub const COMMAND_FAILED: i64 = -32000;
+/// the command has not returned valid JSON
+pub const INVALID_OUTPUT: i64 = -32001;
+
+/// How the params of a call are given to the command
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum Params {
+    /// an array of values, appended to the arguments
+    #[default]
+    Args,
+    /// the JSON params, written to the input
+    Stdin,
+    /// no params
+    None,
+}
+
+/// How the output of the command is returned
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum Output {
+    /// as a string
+    #[default]
+    Text,
+    /// as the JSON value it contains
+    Json,
+}
+
+/// A method, e.g.
+///
+/// ```toml
+/// [rpc.methods.disk_usage]
+/// command = "/usr/bin/df"
+/// params = "args"
+/// output = "text"
+/// ```
+#[derive(Deserialize, Serialize, Default, Debug, Clone, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Method {
+    /// absolute path of the command
+    pub command: String,
+    pub params: Params,
+    pub output: Output,
+}
+
+/// Settings of the JSON-RPC mode
+#[derive(Deserialize, Serialize, Default, Debug, Clone, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct RpcSettings {
+    /// methods by name
+    pub methods: BTreeMap<String, Method>,
+}
+
+/// A call being run, for its reply
+#[derive(Debug, Clone)]
+pub struct Call {
+    pub method: String,
+    /// none for a notification, which gets no reply
+    pub id: Option<Value>,
+    pub output: Output,
+}
+
+#[derive(Deserialize)]
+struct Request {
+    jsonrpc: Option<String>,
+    method: Option<String>,
+    #[serde(default)]
+    params: Value,
+    id: Option<Value>,
+}
+
+/// Parse a request into the job running its method, or the
+/// error reply
+pub fn parse(data: &[u8], config: &Config) -> Result<Job, Value> {
+    let request: Request = match serde_json::from_slice(data) {
+        Ok(request) => request,
+        Err(e) if e.is_data() => return Err(error(None, INVALID_REQUEST, "Invalid request")),
+        Err(e) => return Err(error(None, PARSE_ERROR, &e.to_string())),
+    };
+    let id = request.id;
+    let name = match (request.jsonrpc.as_deref(), request.method) {
+        (Some("2.0"), Some(name)) => name,
+        _ => return Err(error(id, INVALID_REQUEST, "Invalid request")),
+    };
+    let method = match config.rpc.methods.get(&name) {
+        None => return Err(error(id, METHOD_NOT_FOUND, "Method not found")),
+        Some(method) => method,
+    };
+    let invalid = |message: String| error(id.clone(), INVALID_PARAMS, &message);
+    let (args, input) = match (method.params, &request.params) {
+        (Params::None, Value::Null) => (Vec::new(), Vec::new()),
+        (Params::None, _) => return Err(invalid(String::from("No params expected"))),
+        (Params::Stdin, params) => (Vec::new(), params.to_string().into_bytes()),
+        (Params::Args, Value::Null) => (Vec::new(), Vec::new()),
+        (Params::Args, Value::Array(values)) => {
+            let args: Vec<String> = values
+                .iter()
+                .map(|v| match v {
+                    Value::String(s) => s.clone(),
+                    v => v.to_string(),
+                })
+                .collect();
+            config
+                .check_args(&args)
+                .map_err(|e| invalid(e.to_string()))?;
+            (args, Vec::new())
+        }
+        (Params::Args, _) => return Err(invalid(String::from("Expected an array of params"))),
+    };
+    Ok(Job {
+        command: Some(method.command.clone()),
+        args,
+        input,
+        call: Some(Call {
+            method: name,
+            id,
+            output: method.output,
+        }),
+        git: None,
+        svc: None,
+    })
+}
+
+/// Reply of a call once its command has exited, none for a
+/// notification
+pub fn reply(call: &Call, status: ExitStatus, output: &[u8], dropped: u64) -> Option<Value> {
+    let id = call.id.clone()?;
+    let text = String::from_utf8_lossy(output);
+    if !status.success() {
+        let data = json!({ "exit_code": status.code(), "output": text });
+        let message = format!("Command failed: {}", status);
+        return Some(json!({
+            "jsonrpc": "2.0",
+            "error": { "code": COMMAND_FAILED, "message": message, "data": data },
+            "id": id,
+        }));
+    }
+    let result = match call.output {
+        Output::Text => Value::String(text.into_owned()),
+        Output::Json if dropped > 0 => {
+            return Some(error(Some(id), INVALID_OUTPUT, "Output truncated"));
+        }
+        Output::Json => match serde_json::from_slice(output) {
+            Ok(value) => value,
+            Err(e) => {
+                let message = format!("Invalid output: {}", e);
+                return Some(error(Some(id), INVALID_OUTPUT, &message));
+            }
+        },
+    };
+    Some(json!({ "jsonrpc": "2.0", "result": result, "id": id }))
+}
+
+/// An error reply, the id is null when the request has none or
+/// could not be read
+pub fn error(id: Option<Value>, code: i64, message: &str) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "error": { "code": code, "message": message },
+        "id": id.unwrap_or(Value::Null),
+    })
+}
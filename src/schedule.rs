@@ -0,0 +1,305 @@
+//! # Time windows of the access policies
+//!
+//! A window is written `[DAYS] HH:MM-HH:MM` in local time,
+//! e.g. `mon-fri 08:00-18:00`, `sat,sun 10:00-12:00` or
+//! `22:00-06:00` (every day, across midnight)
+//!
+//! The schedules of shcron are cron expressions, see [`Cron`]
+//!
+//! **Author**: "Dany LE"
+//!
+
+const DAYS: [&str; 7] = ["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+
+/// A weekly access window
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Window {
+    /// days on which the window opens, indexed from Sunday
+    days: [bool; 7],
+    /// opening time in minutes since midnight
+    start: u32,
+    /// closing time in minutes since midnight, the window
+    /// ends on the next day when not after `start`
+    end: u32,
+}
+
+impl Window {
+    pub fn parse(text: &str) -> Result<Window, Box<dyn std::error::Error>> {
+        let invalid = |reason: &str| format!("Invalid window `{}`: {}", text, reason);
+        let mut parts = text.split_whitespace();
+        let (days, hours) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(hours), None, None) => (None, hours),
+            (Some(days), Some(hours), None) => (Some(days), hours),
+            _ => return Err(invalid("expected `[DAYS] HH:MM-HH:MM`").into()),
+        };
+        let (start, end) = hours
+            .split_once('-')
+            .ok_or_else(|| invalid("expected a time range"))?;
+        let mut window = Window {
+            days: [days.is_none(); 7],
+            start: minutes(start).ok_or_else(|| invalid("bad start time"))?,
+            end: minutes(end).ok_or_else(|| invalid("bad end time"))?,
+        };
+        for range in days
+            .unwrap_or_default()
+            .split(',')
+            .filter(|r| !r.is_empty())
+        {
+            let (first, last) = range.split_once('-').unwrap_or((range, range));
+            let first = day(first).ok_or_else(|| invalid("unknown day"))?;
+            let last = day(last).ok_or_else(|| invalid("unknown day"))?;
+            let mut d = first;
+            loop {
+                window.days[d] = true;
+                if d == last {
+                    break;
+                }
+                d = (d + 1) % 7;
+            }
+        }
+        Ok(window)
+    }
+
+    /// Whether the window is open at a given day of the week
+    /// (from Sunday) and minute of the day
+    fn contains(&self, weekday: usize, minute: u32) -> bool {
+        if self.start < self.end {
+            return self.days[weekday] && minute >= self.start && minute < self.end;
+        }
+        // across midnight, the window belongs to the day it opens
+        (self.days[weekday] && minute >= self.start)
+            || (self.days[(weekday + 6) % 7] && minute < self.end)
+    }
+}
+
+/// A cron expression in local time: `minute hour day month
+/// weekday`, each field being `*`, a value, a range `a-b`, a
+/// step `*/n` or `a-b/n`, or a comma separated list of them,
+/// e.g. `*/5 * * * *` or `0 8-18 * * mon-fri`. As in cron, a
+/// time matches both a day of the month and a day of the week
+/// when both are restricted
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cron {
+    minutes: Vec<bool>,
+    hours: Vec<bool>,
+    days: Vec<bool>,
+    months: Vec<bool>,
+    /// indexed from Sunday, 7 is also Sunday
+    weekdays: Vec<bool>,
+    any_day: bool,
+    any_weekday: bool,
+}
+
+impl Cron {
+    pub fn parse(text: &str) -> Result<Cron, Box<dyn std::error::Error>> {
+        let fields: Vec<&str> = text.split_whitespace().collect();
+        if fields.len() != 5 {
+            return Err(format!(
+                "Invalid schedule `{}`: expected `minute hour day month weekday`",
+                text
+            )
+            .into());
+        }
+        let parse = |i: usize, min: u32, max: u32| {
+            values(fields[i], min, max).map_err(|e| format!("Invalid schedule `{}`: {}", text, e))
+        };
+        let mut weekdays = parse(4, 0, 7)?;
+        weekdays[0] |= weekdays[7];
+        weekdays.truncate(7);
+        Ok(Cron {
+            minutes: parse(0, 0, 59)?,
+            hours: parse(1, 0, 23)?,
+            days: parse(2, 1, 31)?,
+            months: parse(3, 1, 12)?,
+            weekdays,
+            any_day: fields[2] == "*",
+            any_weekday: fields[4] == "*",
+        })
+    }
+
+    /// Whether the expression matches the current minute
+    pub fn is_due(&self) -> bool {
+        let tm = local_now();
+        let day = self.days[tm.tm_mday as usize];
+        let weekday = self.weekdays[tm.tm_wday as usize];
+        let day = match (self.any_day, self.any_weekday) {
+            (false, false) => day || weekday,
+            _ => day && weekday,
+        };
+        self.minutes[tm.tm_min as usize]
+            && self.hours[tm.tm_hour as usize]
+            && self.months[tm.tm_mon as usize + 1]
+            && day
+    }
+}
+
+/// Values of a field of a cron expression, indexed by value
+fn values(text: &str, min: u32, max: u32) -> Result<Vec<bool>, String> {
+    let mut set = vec![false; max as usize + 1];
+    for item in text.split(',') {
+        let (range, step) = match item.split_once('/') {
+            Some((range, step)) => (range, step.parse::<u32>().map_err(|_| "bad step")?),
+            None => (item, 1),
+        };
+        let value = |v: &str| match (max, v.parse::<u32>()) {
+            (_, Ok(n)) if (min..=max).contains(&n) => Ok(n),
+            (7, Err(_)) => day(v).map(|d| d as u32).ok_or("unknown day"),
+            _ => Err("value out of range"),
+        };
+        let (first, last) = match range.split_once('-') {
+            _ if range == "*" => (min, max),
+            Some((first, last)) => (value(first)?, value(last)?),
+            None if step > 1 => (value(range)?, max),
+            None => (value(range)?, value(range)?),
+        };
+        if step == 0 || first > last {
+            return Err(format!("bad range `{}`", item));
+        }
+        for v in (first..=last).step_by(step as usize) {
+            set[v as usize] = true;
+        }
+    }
+    Ok(set)
+}
+
+/// Parse a list of windows
+pub fn parse_all(windows: &[String]) -> Result<Vec<Window>, Box<dyn std::error::Error>> {
+    windows.iter().map(|w| Window::parse(w)).collect()
+}
+
+/// Whether one of the windows is open now, always true when
+/// there is no window
+pub fn is_open(windows: &[Window]) -> bool {
+    if windows.is_empty() {
+        return true;
+    }
+    let (weekday, minute) = now();
+    windows.iter().any(|w| w.contains(weekday, minute))
+}
+
+/// Day of the week and minute of the day in local time
+fn now() -> (usize, u32) {
+    let tm = local_now();
+    (tm.tm_wday as usize, (tm.tm_hour * 60 + tm.tm_min) as u32)
+}
+
+fn local_now() -> libc::tm {
+    unsafe {
+        let t = libc::time(std::ptr::null_mut());
+        let mut tm: libc::tm = std::mem::zeroed();
+        libc::localtime_r(&t, &mut tm);
+        tm
+    }
+}
+
+fn minutes(text: &str) -> Option<u32> {
+    let (h, m) = text.split_once(':')?;
+    let (h, m) = (h.parse::<u32>().ok()?, m.parse::<u32>().ok()?);
+    // 24:00 closes a window at midnight
+    if m > 59 || h > 24 || (h == 24 && m != 0) {
+        return None;
+    }
+    Some(h * 60 + m)
+}
+
+fn day(text: &str) -> Option<usize> {
+    let text = text.to_lowercase();
+    DAYS.iter().position(|d| text.starts_with(d))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SUN: usize = 0;
+    const FRI: usize = 5;
+    const SAT: usize = 6;
+
+    fn at(h: u32, m: u32) -> u32 {
+        h * 60 + m
+    }
+
+    #[test]
+    fn window_within_a_day() {
+        let window = Window::parse("mon-fri 08:00-18:00").unwrap();
+        assert!(window.contains(FRI, at(8, 0)));
+        assert!(window.contains(FRI, at(17, 59)));
+        assert!(!window.contains(FRI, at(18, 0)));
+        assert!(!window.contains(SAT, at(12, 0)));
+    }
+
+    #[test]
+    fn window_across_midnight() {
+        let window = Window::parse("fri 22:00-06:00").unwrap();
+        assert!(!window.contains(FRI, at(21, 59)));
+        assert!(window.contains(FRI, at(22, 0)));
+        assert!(window.contains(SAT, at(0, 0)));
+        assert!(window.contains(SAT, at(5, 59)));
+        assert!(!window.contains(SAT, at(6, 0)));
+        // the early hours of friday belong to thursday
+        assert!(!window.contains(FRI, at(1, 0)));
+        assert!(!window.contains(SAT, at(22, 0)));
+    }
+
+    #[test]
+    fn window_every_day_across_midnight() {
+        let window = Window::parse("22:00-06:00").unwrap();
+        assert!(window.contains(SUN, at(0, 30)));
+        assert!(window.contains(SAT, at(23, 0)));
+        assert!(!window.contains(SUN, at(12, 0)));
+    }
+
+    #[test]
+    fn window_days_across_the_week() {
+        let window = Window::parse("sat-mon 10:00-24:00").unwrap();
+        assert!(window.contains(SAT, at(10, 0)));
+        assert!(window.contains(SUN, at(23, 59)));
+        assert!(window.contains(1, at(10, 0)));
+        assert!(!window.contains(FRI, at(10, 0)));
+    }
+
+    #[test]
+    fn window_invalid() {
+        for text in ["", "08:00", "mon 08:00-25:00", "foo 08:00-09:00", "a b c"] {
+            assert!(Window::parse(text).is_err(), "{}", text);
+        }
+    }
+
+    #[test]
+    fn cron_fields() {
+        let cron = Cron::parse("*/15 8-18 * * mon-fri").unwrap();
+        let set = |v: &[bool]| -> Vec<usize> { (0..v.len()).filter(|i| v[*i]).collect() };
+        assert_eq!(set(&cron.minutes), [0, 15, 30, 45]);
+        assert_eq!(set(&cron.hours), (8..=18).collect::<Vec<usize>>());
+        assert_eq!(set(&cron.days), (1..=31).collect::<Vec<usize>>());
+        assert_eq!(set(&cron.weekdays), [1, 2, 3, 4, 5]);
+        assert!(cron.any_day);
+        assert!(!cron.any_weekday);
+    }
+
+    #[test]
+    fn cron_lists_and_steps() {
+        let cron = Cron::parse("5/20 0,12 1-10/3 * 7").unwrap();
+        let set = |v: &[bool]| -> Vec<usize> { (0..v.len()).filter(|i| v[*i]).collect() };
+        assert_eq!(set(&cron.minutes), [5, 25, 45]);
+        assert_eq!(set(&cron.hours), [0, 12]);
+        assert_eq!(set(&cron.days), [1, 4, 7, 10]);
+        // 7 is also sunday
+        assert_eq!(set(&cron.weekdays), [0]);
+        assert!(!cron.any_day);
+    }
+
+    #[test]
+    fn cron_invalid() {
+        for text in [
+            "* * * *",
+            "60 * * * *",
+            "*/0 * * * *",
+            "5-2 * * * *",
+            "* * 0 * *",
+            "* * * * foo",
+        ] {
+            assert!(Cron::parse(text).is_err(), "{}", text);
+        }
+    }
+}
@@ -0,0 +1,788 @@
+//! # Persistent session backend
+//!
+//! `shsession` keeps named sessions owned by their user, whose
+//! processes run independently of any subscription, as a
+//! tmux-lite: a client creates, lists, attaches, detaches and
+//! kills the sessions of its user with control frames, and the
+//! data frames go to the session it is attached to
+//!
+//! ```text
+//! -> ctrl {"create": "build", "command": "shell"}
+//! <- ctrl {"created": "build"}
+//! -> ctrl {"attach": "build"}
+//! <- ctrl {"attached": "build"}
+//! <- data <recent output>
+//! -> ctrl {"detach": true}
+//! <- ctrl {"detached": "build"}
+//! -> ctrl {"list": true}
+//! <- ctrl {"sessions": [{"name": "build", "pid": 1234, "created": 1700000000, "clients": 0}]}
+//! -> ctrl {"kill": "build"}
+//! <- ctrl {"killed": "build"}
+//! ```
+//!
+//! The recent output of a session is kept, whether clients are
+//! attached or not, and sent to each client attaching to it. A
+//! session lasts until its process exits or it is killed, the
+//! attached clients are then told with e.g.
+//! `{"exited": "build", "exit_code": 0}`
+//!
+//! **Author**: "Dany LE"
+//!
+use crate::audit::{self, Event};
+use crate::backend::Backend;
+use crate::clients::{self, Roster, Subscription};
+use crate::config::Config;
+use crate::error::{self, Class, Classify};
+use crate::frame;
+use crate::history::{self, Run};
+use crate::log::{self, Level};
+use crate::metrics::{self, Counter};
+use crate::process::Process;
+use crate::profile::{Phase, Timer};
+use crate::spawn;
+use crate::stats::{self, Stats};
+use crate::telemetry::{self, Span};
+use crate::{DEBUG, ERROR, INFO, WARN};
+use latpr::tunnel::{CallbackEvent, IOInterest, Msg, MsgKind, Topic};
+use latpr::utils::{LogLevel, LOG};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::os::unix::io::RawFd;
+use std::os::unix::process::ExitStatusExt;
+use std::process::{Command, Stdio};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// time given to the processes to exit on shutdown
+const TERMINATE_GRACE: Duration = Duration::from_secs(2);
+/// default of `session.max_sessions`
+const MAX_SESSIONS: usize = 8;
+/// default of `session.buffer`
+const BUFFER: usize = 64 * 1024;
+/// longest name of a session
+const MAX_NAME: usize = 64;
+
+/// Settings of the persistent session mode, e.g.
+///
+/// ```toml
+/// [session]
+/// max_sessions = 4
+/// buffer = 131072
+/// ```
+#[derive(Deserialize, Serialize, Default, Debug, Clone, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct SessionSettings {
+    /// sessions a user may have at once, 8 by default
+    pub max_sessions: Option<usize>,
+    /// bytes of recent output kept per session, sent to the
+    /// clients attaching to it, 64 KiB by default
+    pub buffer: Option<usize>,
+}
+
+impl SessionSettings {
+    pub fn max_sessions(&self) -> usize {
+        self.max_sessions.unwrap_or(MAX_SESSIONS)
+    }
+
+    pub fn buffer(&self) -> usize {
+        self.buffer.unwrap_or(BUFFER)
+    }
+}
+
+/// A control frame of a client, one of the operations
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct Request {
+    create: Option<String>,
+    /// command of the menu run by a new session, `command` when
+    /// not set
+    command: Option<String>,
+    list: bool,
+    attach: Option<String>,
+    detach: bool,
+    kill: Option<String>,
+}
+
+/// A named session and its process
+struct Session {
+    name: String,
+    user: String,
+    process: Process,
+    run: Run,
+    /// seconds since the epoch
+    created: u64,
+    /// recent output, at most `session.buffer` bytes
+    buffer: Vec<u8>,
+}
+
+impl Session {
+    /// Keep the recent output, the oldest is dropped
+    fn buffer_output(&mut self, output: &[u8], config: &Config) {
+        self.buffer.extend_from_slice(output);
+        let excess = self.buffer.len().saturating_sub(config.session.buffer());
+        self.buffer.drain(..excess);
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        if let Ok(None) = self.process.try_wait() {
+            if let Err(error) = self.process.kill() {
+                WARN!("Unable to kill session {}: {}", self.name, error);
+            }
+        }
+    }
+}
+
+pub struct Client {
+    user: String,
+    /// identifier of the subscription, see [`telemetry::session_id`]
+    session: String,
+    /// session the client is attached to
+    attached: Option<String>,
+    /// counters of the client
+    stats: Stats,
+    /// span of the subscription, see [`crate::telemetry`]
+    span: Option<Span>,
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        clients::end_span(&mut self.span, &self.stats);
+    }
+}
+
+crate::member!(Client);
+
+/// Backend keeping the sessions of the users
+pub struct Sessions {
+    clients: HashMap<u16, Client>,
+    /// sessions by user then name
+    sessions: HashMap<(String, String), Session>,
+    /// whether a process may have exited since the last check
+    reap: bool,
+}
+
+impl Default for Sessions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Build a control frame answering a client
+fn ctrl(client_id: u16, body: Value) -> Msg {
+    Msg::create(
+        MsgKind::ChannelCtrl,
+        0,
+        client_id,
+        body.to_string().into_bytes(),
+    )
+}
+
+/// Check the name of a session
+fn check_name(name: &str) -> Result<(), String> {
+    let valid = |c: char| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.');
+    if name.is_empty() || name.len() > MAX_NAME || !name.chars().all(valid) {
+        return Err(format!(
+            "Invalid session name {:?}: up to {} letters, digits, '-', '_' or '.'",
+            name, MAX_NAME
+        ));
+    }
+    Ok(())
+}
+
+/// Spawn the process of a new session
+fn spawn_session(
+    client_id: u16,
+    client: &Client,
+    name: &str,
+    menu: Option<&str>,
+    config: &Config,
+    topic: &mut Topic,
+) -> Result<Session, Box<dyn std::error::Error>> {
+    let _timer = Timer::start(Phase::Spawn);
+    let profile = config.profile(&client.user);
+    let path = config.allowed_command(config.command_for(&profile, menu)?)?;
+    let mut command = Command::new(&path);
+    command
+        .envs(&profile.env)
+        .env("CUSER", &client.user)
+        .env("CSESSION", name)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped());
+    profile.rlimits.apply(&mut command);
+    spawn::die_with_parent(&mut command);
+    let process = Process::from(
+        command
+            .spawn()
+            .inspect_err(|_| metrics::add(Counter::SpawnFailures, 1))
+            .class(Class::Spawn)?,
+    );
+    if let Some(fd) = process.stdout_fd() {
+        topic.register_io(fd, IOInterest::READABLE)?;
+    }
+    metrics::add(Counter::Spawned, 1);
+    // the session outlives the subscription that created it, it
+    // has its own identifier
+    let run = Run::start(client_id, &telemetry::session_id(), &path, process.id());
+    let spawned = Event::Spawn {
+        command: &path,
+        args: &[],
+        pid: process.id(),
+    };
+    audit::log(config, client_id, &client.user, &run.session, spawned);
+    if let Some(script) = config.init_script()? {
+        process.write_input(&script).class(Class::ChildIo)?;
+    }
+    Ok(Session {
+        name: name.to_string(),
+        user: client.user.clone(),
+        process,
+        run,
+        created: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs()),
+        buffer: Vec::new(),
+    })
+}
+
+impl Sessions {
+    pub fn new() -> Sessions {
+        Sessions {
+            clients: HashMap::new(),
+            sessions: HashMap::new(),
+            reap: false,
+        }
+    }
+
+    /// Handle a control request of a client
+    fn request(
+        &mut self,
+        client_id: u16,
+        request: Request,
+        config: &Config,
+        topic: &mut Topic,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let Some(client) = self.clients.get_mut(&client_id) else {
+            WARN!("Client {} is not in the list", client_id);
+            return Ok(());
+        };
+        let user = client.user.clone();
+        match request {
+            Request {
+                create: Some(name),
+                command,
+                list: false,
+                attach: None,
+                detach: false,
+                kill: None,
+            } => {
+                if let Err(reason) = check_name(&name) {
+                    return frame::send(topic, &frame::error(client_id, &reason));
+                }
+                let key = (user.clone(), name.clone());
+                if self.sessions.contains_key(&key) {
+                    let reason = format!("Session {} already exists", name);
+                    return frame::send(topic, &frame::error(client_id, &reason));
+                }
+                let owned = self.sessions.keys().filter(|(u, _)| *u == user).count();
+                if owned >= config.session.max_sessions() {
+                    let reason = format!(
+                        "Too many sessions, maximum is {}",
+                        config.session.max_sessions()
+                    );
+                    return frame::send(topic, &frame::error(client_id, &reason));
+                }
+                let client = &self.clients[&client_id];
+                match spawn_session(client_id, client, &name, command.as_deref(), config, topic) {
+                    Ok(session) => {
+                        INFO!(
+                            "Client {} created session {} of user {}: pid {}",
+                            client_id,
+                            name,
+                            user,
+                            session.process.id()
+                        );
+                        self.sessions.insert(key, session);
+                        frame::send(topic, &ctrl(client_id, json!({ "created": name })))?;
+                    }
+                    Err(error) => {
+                        ERROR!(
+                            "Unable to create session {} of client {}: {}: {}",
+                            name,
+                            client_id,
+                            error::report(&*error, Class::Spawn),
+                            error
+                        );
+                        let reason = format!("Unable to create the session: {}", error);
+                        frame::send(topic, &frame::error(client_id, &reason))?;
+                    }
+                }
+            }
+            Request {
+                create: None,
+                command: None,
+                list: true,
+                attach: None,
+                detach: false,
+                kill: None,
+            } => {
+                let mut sessions: Vec<&Session> =
+                    self.sessions.values().filter(|s| s.user == user).collect();
+                sessions.sort_by_key(|s| s.created);
+                let list: Vec<Value> = sessions
+                    .iter()
+                    .map(|s| {
+                        let attached = self
+                            .clients
+                            .values()
+                            .filter(|c| c.user == user && c.attached.as_ref() == Some(&s.name))
+                            .count();
+                        json!({
+                            "name": s.name,
+                            "pid": s.process.id(),
+                            "created": s.created,
+                            "clients": attached,
+                        })
+                    })
+                    .collect();
+                frame::send(topic, &ctrl(client_id, json!({ "sessions": list })))?;
+            }
+            Request {
+                create: None,
+                command: None,
+                list: false,
+                attach: Some(name),
+                detach: false,
+                kill: None,
+            } => {
+                let Some(session) = self.sessions.get(&(user.clone(), name.clone())) else {
+                    let reason = format!("No session {}", name);
+                    return frame::send(topic, &frame::error(client_id, &reason));
+                };
+                INFO!("Client {} attached to session {}", client_id, name);
+                client.attached = Some(name.clone());
+                frame::send(topic, &ctrl(client_id, json!({ "attached": name })))?;
+                // catch up with the recent output
+                for chunk in session.buffer.chunks(config.buffer_size) {
+                    let msg = Msg::create(MsgKind::ChannelData, 0, client_id, chunk.to_vec());
+                    frame::send(topic, &msg)?;
+                    client.stats.sent(chunk.len());
+                }
+            }
+            Request {
+                create: None,
+                command: None,
+                list: false,
+                attach: None,
+                detach: true,
+                kill: None,
+            } => match client.attached.take() {
+                None => frame::send(topic, &frame::error(client_id, "No session attached"))?,
+                Some(name) => {
+                    INFO!("Client {} detached from session {}", client_id, name);
+                    frame::send(topic, &ctrl(client_id, json!({ "detached": name })))?;
+                }
+            },
+            Request {
+                create: None,
+                command: None,
+                list: false,
+                attach: None,
+                detach: false,
+                kill: Some(name),
+            } => {
+                let Some(session) = self.sessions.get_mut(&(user.clone(), name.clone())) else {
+                    let reason = format!("No session {}", name);
+                    return frame::send(topic, &frame::error(client_id, &reason));
+                };
+                INFO!("Client {} killed session {}", client_id, name);
+                let (pid, signal) = (session.process.id(), libc::SIGTERM);
+                let reason = "killed by its user";
+                let event = Event::Signal {
+                    pid,
+                    signal,
+                    reason,
+                };
+                audit::log(config, client_id, &user, &client.session, event);
+                if let Err(error) = session.process.signal(signal) {
+                    WARN!("Unable to kill session {}: {}", name, error);
+                }
+                // the attached clients are told once it has exited
+                frame::send(topic, &ctrl(client_id, json!({ "killed": name })))?;
+            }
+            _ => {
+                let reason = "Invalid request: expected one of create, list, attach, detach, kill";
+                frame::send(topic, &frame::error(client_id, reason))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Write the data of a client to its session
+    fn input(&mut self, msg: &Msg, topic: &mut Topic) -> Result<(), Box<dyn std::error::Error>> {
+        let client = match self.clients.get_mut(&msg.client_id) {
+            None => {
+                WARN!("Client {} is not in the list", msg.client_id);
+                return Ok(());
+            }
+            Some(client) => client,
+        };
+        let session = match client.attached.as_ref() {
+            None => return frame::send(topic, &frame::error(msg.client_id, "No session attached")),
+            Some(name) => self.sessions.get(&(client.user.clone(), name.clone())),
+        };
+        let Some(session) = session else {
+            client.attached = None;
+            return frame::send(topic, &frame::error(msg.client_id, "No session attached"));
+        };
+        client.stats.bytes_in += msg.data.len() as u64;
+        metrics::add(Counter::BytesIn, msg.data.len() as u64);
+        if !session
+            .process
+            .write_input(&msg.data)
+            .class(Class::ChildIo)?
+        {
+            client.stats.dropped_in += msg.data.len() as u64;
+            metrics::add(Counter::BytesDropped, msg.data.len() as u64);
+            let reason = "Input pipe full";
+            frame::send(
+                topic,
+                &frame::dropped(msg.client_id, reason, msg.data.len() as u64),
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Read the output of the session reading from a descriptor,
+    /// keep it and send it to the attached clients
+    fn output(
+        &mut self,
+        fd: RawFd,
+        config: &Config,
+        topic: Option<&mut Topic>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let Some(session) = self
+            .sessions
+            .values_mut()
+            .find(|s| s.process.stdout_fd() == Some(fd))
+        else {
+            DEBUG!("Ignore event of descriptor {}: no session owns it", fd);
+            return Ok(());
+        };
+        let mut buf = vec![0; config.buffer_size];
+        let n = match session
+            .process
+            .read_output(&mut buf[..])
+            .class(Class::ChildIo)?
+        {
+            Some(n) if n > 0 => n,
+            _ => return Ok(()),
+        };
+        metrics::add(Counter::BytesOut, n as u64);
+        session.buffer_output(&buf[..n], config);
+        let Some(topic) = topic else {
+            return Ok(());
+        };
+        for (key, client) in self.clients.iter_mut() {
+            if client.user != session.user || client.attached.as_ref() != Some(&session.name) {
+                continue;
+            }
+            if let Some((reads, bytes)) = log::throttle(Level::Debug, "send", *key, n) {
+                DEBUG!(
+                    "Sending {} bytes of session {} to client {} in {} read(s)",
+                    bytes,
+                    session.name,
+                    key,
+                    reads
+                );
+            }
+            client.stats.sent(n);
+            let msg = Msg::create(MsgKind::ChannelData, 0, *key, buf[..n].to_vec());
+            frame::send(topic, &msg)?;
+        }
+        Ok(())
+    }
+
+    /// Remove the sessions whose process has exited, the
+    /// attached clients are told
+    fn reap_sessions(
+        &mut self,
+        config: &Config,
+        mut topic: Option<&mut Topic>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if !std::mem::take(&mut self.reap) {
+            return Ok(());
+        }
+        let mut exited = Vec::new();
+        for (key, session) in self.sessions.iter_mut() {
+            if let Some(status) = session.process.try_wait()? {
+                exited.push((key.clone(), status));
+            }
+        }
+        for (key, status) in exited {
+            let Some(session) = self.sessions.remove(&key) else {
+                continue;
+            };
+            if let (Some(fd), Some(topic)) = (session.process.stdout_fd(), topic.as_mut()) {
+                topic.unregister_io(fd)?;
+            }
+            INFO!(
+                "Session {} of user {} has exited with status {}",
+                session.name,
+                session.user,
+                status
+            );
+            let exit = Event::Exit {
+                pid: session.run.pid,
+                status,
+            };
+            audit::log(
+                config,
+                session.run.client_id,
+                &session.user,
+                &session.run.session,
+                exit,
+            );
+            metrics::add(Counter::Exited, 1);
+            history::record(config, &session.user, &session.run, status);
+            let Some(topic) = topic.as_mut() else {
+                continue;
+            };
+            let mut body = json!({ "exited": session.name });
+            match (status.code(), status.signal()) {
+                (Some(code), _) => body["exit_code"] = json!(code),
+                (None, signal) => body["signal"] = json!(signal),
+            }
+            for (client_id, client) in self.clients.iter_mut() {
+                if client.user == session.user && client.attached.as_ref() == Some(&session.name) {
+                    client.attached = None;
+                    frame::send(topic, &ctrl(*client_id, body.clone()))?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn handle_message(
+        &mut self,
+        msg: &Msg,
+        config: &Config,
+        topic: &mut Topic,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if !matches!(msg.kind, MsgKind::ChannelData) {
+            DEBUG!(
+                "Receive message kind {} from client {}: {} bytes",
+                msg.kind,
+                msg.client_id,
+                msg.size
+            );
+        } else if let Some((frames, bytes)) =
+            log::throttle(Level::Debug, "receive", msg.client_id, msg.size as usize)
+        {
+            DEBUG!(
+                "Receive {} data frame(s) from client {}: {} bytes",
+                frames,
+                msg.client_id,
+                bytes
+            );
+        }
+        if let Some(client) = self.clients.get_mut(&msg.client_id) {
+            client.stats.received();
+        }
+        match msg.kind {
+            MsgKind::ChannelSubscribe => {
+                let Some(Subscription {
+                    user,
+                    session,
+                    context: _context,
+                }) = clients::subscribe(msg, config, topic, |_| {
+                    Ok(self
+                        .clients
+                        .remove(&msg.client_id)
+                        .map(|old| old.user.clone()))
+                })?
+                else {
+                    return Ok(());
+                };
+                if !self.make_room(msg.client_id, config, topic)? {
+                    return Ok(());
+                }
+                let span = clients::span(&config.topic, msg.client_id, &user, &session);
+                audit::log(config, msg.client_id, &user, &session, Event::Subscribe);
+                INFO!(
+                    "Client {} subscribe to channel {}",
+                    msg.client_id,
+                    &config.topic
+                );
+                self.clients.insert(
+                    msg.client_id,
+                    Client {
+                        user,
+                        session,
+                        attached: None,
+                        stats: Stats::default(),
+                        span: Some(span),
+                    },
+                );
+                clients::welcome(msg.client_id, config, topic)?;
+            }
+            MsgKind::ChannelUnsubscribe => {
+                // its session, if any, goes on
+                self.unsubscribe(msg.client_id, config, topic)?;
+            }
+            MsgKind::ChannelUnsubscribeAll => self.unsubscribe_all(config, topic)?,
+            MsgKind::ChannelData => self.input(msg, topic)?,
+            MsgKind::ChannelCtrl if stats::is_query(&msg.data) => {
+                self.status(msg.client_id, topic)?;
+            }
+            MsgKind::ChannelCtrl => match serde_json::from_slice::<Request>(&msg.data) {
+                Ok(request) => self.request(msg.client_id, request, config, topic)?,
+                Err(error) => {
+                    WARN!("Invalid request of client {}: {}", msg.client_id, error);
+                    let reason = format!("Invalid request: {}", error);
+                    frame::send(topic, &frame::error(msg.client_id, &reason))?;
+                }
+            },
+            _ => {
+                WARN!(
+                    "Receive message kind {} from client {}",
+                    msg.kind,
+                    msg.client_id
+                );
+            }
+        };
+        Ok(())
+    }
+}
+
+impl Roster for Sessions {
+    type Client = Client;
+
+    fn table(&self) -> &HashMap<u16, Client> {
+        &self.clients
+    }
+
+    fn table_mut(&mut self) -> &mut HashMap<u16, Client> {
+        &mut self.clients
+    }
+}
+
+impl Backend for Sessions {
+    fn fds(&self) -> Vec<RawFd> {
+        self.sessions
+            .values()
+            .filter_map(|s| s.process.stdout_fd())
+            .collect()
+    }
+
+    fn clients(&self) -> usize {
+        self.clients.len()
+    }
+
+    fn dump(&self) -> Vec<String> {
+        let mut lines = vec![format!(
+            "{} client(s), {} session(s)",
+            self.clients.len(),
+            self.sessions.len()
+        )];
+        let mut keys: Vec<&(String, String)> = self.sessions.keys().collect();
+        keys.sort();
+        for key in keys {
+            let s = &self.sessions[key];
+            lines.push(format!(
+                "session {} of user {}: pid {}, {} bytes buffered",
+                s.name,
+                s.user,
+                s.process.id(),
+                s.buffer.len()
+            ));
+        }
+        let mut keys: Vec<&u16> = self.clients.keys().collect();
+        keys.sort();
+        for key in keys {
+            let c = &self.clients[key];
+            lines.push(format!(
+                "client {}: user {}, attached to {}, {}",
+                key,
+                c.user,
+                c.attached.as_deref().unwrap_or("none"),
+                c.stats.describe()
+            ));
+        }
+        lines
+    }
+
+    fn step_handle(
+        &mut self,
+        evt: &CallbackEvent,
+        config: &Config,
+        topic: &mut Topic,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if let Some(msg) = evt.msg {
+            self.scoped(msg, config, topic, |sessions, topic| {
+                sessions.handle_message(msg, config, topic)
+            })?;
+        }
+        if let (Some(event), Some(fd)) = (evt.event, evt.fd) {
+            if event.is_readable() {
+                self.output(fd, config, Some(topic))?;
+            }
+        }
+        self.reap_sessions(config, Some(topic))?;
+        self.enforce_windows(config, topic)?;
+        self.reap_idle(config, topic)?;
+        Ok(())
+    }
+
+    fn children_exited(
+        &mut self,
+        config: &Config,
+        topic: &mut Topic,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.reap = true;
+        self.reap_sessions(config, Some(topic))
+    }
+
+    fn disconnected(&mut self, _config: &Config) {
+        // the sessions outlive the connection to the hub
+        self.clients.clear();
+    }
+
+    fn step_offline(&mut self, config: &Config) {
+        // the output goes to the buffers of the sessions, for the
+        // clients attaching once the hub is back
+        let fds = self.fds();
+        for fd in fds {
+            if let Err(error) = self.output(fd, config, None) {
+                WARN!("Unable to read the output of a session: {}", error);
+            }
+        }
+        self.reap = true;
+        if let Err(error) = self.reap_sessions(config, None) {
+            WARN!("Unable to check the sessions: {}", error);
+        }
+    }
+
+    fn shutdown(
+        &mut self,
+        config: &Config,
+        topic: &mut Topic,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.notify_shutdown(topic)?;
+        self.clients.clear();
+        self.terminate(config);
+        Ok(())
+    }
+
+    fn terminate(&mut self, config: &Config) {
+        let processes = self.sessions.values_mut().map(|s| &mut s.process).collect();
+        spawn::terminate(processes, TERMINATE_GRACE);
+        for session in self.sessions.values_mut() {
+            if let Ok(Some(status)) = session.process.try_wait() {
+                history::record(config, &session.user, &session.run, status);
+            }
+        }
+        self.sessions.clear();
+    }
+}
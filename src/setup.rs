@@ -0,0 +1,61 @@
+//! # Session setup sent by the client before any data
+//!
+//! **Author**: "Dany LE"
+//!
+use crate::error::{Class, Classify};
+use serde::Deserialize;
+use std::collections::HashMap;
+
+/// Session parameters sent by the client in a `ChannelCtrl`
+/// frame, encoded as a JSON object, e.g:
+///
+/// ```json
+/// {"term": "xterm-256color", "cols": 80, "rows": 24, "command": "tail", "args": ["/var/log/syslog"]}
+/// ```
+#[derive(Deserialize, Default, Debug, Clone)]
+pub struct SessionSetup {
+    /// terminal type, exported as `TERM`
+    pub term: Option<String>,
+    /// terminal width, exported as `COLUMNS`
+    pub cols: Option<u16>,
+    /// terminal height, exported as `LINES`
+    pub rows: Option<u16>,
+    /// name of the command to run, selected from the
+    /// configured command menu
+    pub command: Option<String>,
+    /// extra arguments appended to the command, checked
+    /// against the configured policy
+    #[serde(default)]
+    pub args: Vec<String>,
+    /// environment variables for the child, filtered
+    /// through the configured allowlist
+    #[serde(default)]
+    pub env: HashMap<String, String>,
+}
+
+impl SessionSetup {
+    /// Parse the setup from a control frame payload, the payload
+    /// may be terminated by a null byte
+    pub fn parse(data: &[u8]) -> Result<SessionSetup, Box<dyn std::error::Error>> {
+        let data = match data.last() {
+            Some(0) => &data[0..data.len() - 1],
+            _ => data,
+        };
+        serde_json::from_slice(data).class(Class::Protocol)
+    }
+
+    /// Environment variables to export to the child process
+    pub fn envs(&self) -> Vec<(String, String)> {
+        let mut envs = Vec::new();
+        if let Some(term) = self.term.as_ref() {
+            envs.push((String::from("TERM"), term.clone()));
+        }
+        if let Some(cols) = self.cols {
+            envs.push((String::from("COLUMNS"), format!("{}", cols)));
+        }
+        if let Some(rows) = self.rows {
+            envs.push((String::from("LINES"), format!("{}", rows)));
+        }
+        envs
+    }
+}
@@ -0,0 +1,11 @@
+//! # Relay a topic to another one
+//!
+//! Same as `shbackend --mode bridge`
+//!
+//! **Author**: "Dany LE"
+//!
+use shellbackend::config::Mode;
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    shellbackend::app::main(Some(Mode::Bridge))
+}
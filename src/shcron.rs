@@ -0,0 +1,11 @@
+//! # Run a command on a schedule, its output is sent to all clients
+//!
+//! Same as `shbackend --mode cron`
+//!
+//! **Author**: "Dany LE"
+//!
+use shellbackend::config::Mode;
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    shellbackend::app::main(Some(Mode::Cron))
+}
@@ -0,0 +1,9 @@
+//! # Admin command line tool
+//!
+//! Send a command to the admin socket of a running backend
+//!
+//! **Author**: "Dany LE"
+//!
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    shellbackend::ctl::main()
+}
@@ -0,0 +1,11 @@
+//! # Run a process per request of a client
+//!
+//! Same as `shbackend --mode exec`
+//!
+//! **Author**: "Dany LE"
+//!
+use shellbackend::config::Mode;
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    shellbackend::app::main(Some(Mode::Exec))
+}
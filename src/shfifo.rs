@@ -0,0 +1,11 @@
+//! # Relay two named pipes to all clients
+//!
+//! Same as `shbackend --mode fifo`
+//!
+//! **Author**: "Dany LE"
+//!
+use shellbackend::config::Mode;
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    shellbackend::app::main(Some(Mode::Fifo))
+}
@@ -0,0 +1,11 @@
+//! # File transfers below a root directory of each user
+//!
+//! Same as `shbackend --mode file`
+//!
+//! **Author**: "Dany LE"
+//!
+use shellbackend::config::Mode;
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    shellbackend::app::main(Some(Mode::File))
+}
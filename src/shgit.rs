@@ -0,0 +1,11 @@
+//! # Run git operations for the clients
+//!
+//! Same as `shbackend --mode git`
+//!
+//! **Author**: "Dany LE"
+//!
+use shellbackend::config::Mode;
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    shellbackend::app::main(Some(Mode::Git))
+}
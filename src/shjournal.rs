@@ -0,0 +1,11 @@
+//! # Follow the systemd journal
+//!
+//! Same as `shbackend --mode journal`
+//!
+//! **Author**: "Dany LE"
+//!
+use shellbackend::config::Mode;
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    shellbackend::app::main(Some(Mode::Journal))
+}
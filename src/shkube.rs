@@ -0,0 +1,11 @@
+//! # Attach each client to a Kubernetes container
+//!
+//! Same as `shbackend --mode kube`
+//!
+//! **Author**: "Dany LE"
+//!
+use shellbackend::config::Mode;
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    shellbackend::app::main(Some(Mode::Kube))
+}
@@ -0,0 +1,11 @@
+//! # Host many streams in each subscription
+//!
+//! Same as `shbackend --mode mux`
+//!
+//! **Author**: "Dany LE"
+//!
+use shellbackend::config::Mode;
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    shellbackend::app::main(Some(Mode::Mux))
+}
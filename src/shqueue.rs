@@ -0,0 +1,11 @@
+//! # Run the jobs of the clients from a queue
+//!
+//! Same as `shbackend --mode queue`
+//!
+//! **Author**: "Dany LE"
+//!
+use shellbackend::config::Mode;
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    shellbackend::app::main(Some(Mode::Queue))
+}
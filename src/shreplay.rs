@@ -0,0 +1,11 @@
+//! # Play the session recordings back to the clients
+//!
+//! Same as `shbackend --mode playback`
+//!
+//! **Author**: "Dany LE"
+//!
+use shellbackend::config::Mode;
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    shellbackend::app::main(Some(Mode::Playback))
+}
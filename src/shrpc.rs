@@ -0,0 +1,11 @@
+//! # Run the JSON-RPC calls of the clients
+//!
+//! Same as `shbackend --mode rpc`
+//!
+//! **Author**: "Dany LE"
+//!
+use shellbackend::config::Mode;
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    shellbackend::app::main(Some(Mode::Rpc))
+}
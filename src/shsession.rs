@@ -0,0 +1,11 @@
+//! # Keep named sessions for the users
+//!
+//! Same as `shbackend --mode session`
+//!
+//! **Author**: "Dany LE"
+//!
+use shellbackend::config::Mode;
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    shellbackend::app::main(Some(Mode::Session))
+}
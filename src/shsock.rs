@@ -0,0 +1,11 @@
+//! # Relay each client to a Unix domain socket
+//!
+//! Same as `shbackend --mode sock`
+//!
+//! **Author**: "Dany LE"
+//!
+use shellbackend::config::Mode;
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    shellbackend::app::main(Some(Mode::Sock))
+}
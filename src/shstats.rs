@@ -0,0 +1,11 @@
+//! # Send the statistics of the host to the clients
+//!
+//! Same as `shbackend --mode sysstats`
+//!
+//! **Author**: "Dany LE"
+//!
+use shellbackend::config::Mode;
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    shellbackend::app::main(Some(Mode::Sysstats))
+}
@@ -0,0 +1,11 @@
+//! # Control the services for the clients
+//!
+//! Same as `shbackend --mode svc`
+//!
+//! **Author**: "Dany LE"
+//!
+use shellbackend::config::Mode;
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    shellbackend::app::main(Some(Mode::Svc))
+}
@@ -0,0 +1,11 @@
+//! # Follow a file, its new lines are sent to all clients
+//!
+//! Same as `shbackend --mode tail`
+//!
+//! **Author**: "Dany LE"
+//!
+use shellbackend::config::Mode;
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    shellbackend::app::main(Some(Mode::Tail))
+}
@@ -0,0 +1,11 @@
+//! # Relay each client to a TCP destination
+//!
+//! Same as `shbackend --mode tcp`
+//!
+//! **Author**: "Dany LE"
+//!
+use shellbackend::config::Mode;
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    shellbackend::app::main(Some(Mode::Tcp))
+}
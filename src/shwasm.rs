@@ -0,0 +1,11 @@
+//! # Run a WebAssembly module per client
+//!
+//! Same as `shbackend --mode wasm`
+//!
+//! **Author**: "Dany LE"
+//!
+use shellbackend::config::Mode;
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    shellbackend::app::main(Some(Mode::Wasm))
+}
@@ -0,0 +1,11 @@
+//! # Send the changes of the watched paths to the clients
+//!
+//! Same as `shbackend --mode watch`
+//!
+//! **Author**: "Dany LE"
+//!
+use shellbackend::config::Mode;
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    shellbackend::app::main(Some(Mode::Watch))
+}
@@ -0,0 +1,100 @@
+//! # Signals handled by the backends
+//!
+//! **Author**: "Dany LE"
+//!
+use signal_hook::consts::{SIGCHLD, SIGHUP, SIGINT, SIGQUIT, SIGTERM, SIGUSR1, SIGUSR2};
+use std::io::Read;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// Flags raised by the signal handlers and consumed
+/// from the event loop
+pub struct Signals {
+    reload: Arc<AtomicBool>,
+    terminate: Arc<AtomicBool>,
+    upgrade: Arc<AtomicBool>,
+    dump: Arc<AtomicBool>,
+    drain: Arc<AtomicBool>,
+    /// read end of the self-pipe written on SIGCHLD, watched by
+    /// the event loop
+    child: UnixStream,
+}
+
+impl Signals {
+    pub fn register() -> Result<Signals, Box<dyn std::error::Error>> {
+        let reload = Arc::new(AtomicBool::new(false));
+        signal_hook::flag::register(SIGHUP, Arc::clone(&reload))?;
+        let terminate = Arc::new(AtomicBool::new(false));
+        for signal in [SIGTERM, SIGINT] {
+            signal_hook::flag::register(signal, Arc::clone(&terminate))?;
+        }
+        let upgrade = Arc::new(AtomicBool::new(false));
+        signal_hook::flag::register(SIGUSR2, Arc::clone(&upgrade))?;
+        let dump = Arc::new(AtomicBool::new(false));
+        signal_hook::flag::register(SIGUSR1, Arc::clone(&dump))?;
+        let drain = Arc::new(AtomicBool::new(false));
+        signal_hook::flag::register(SIGQUIT, Arc::clone(&drain))?;
+        let (child, wakeup) = UnixStream::pair()?;
+        child.set_nonblocking(true)?;
+        wakeup.set_nonblocking(true)?;
+        signal_hook::low_level::pipe::register(SIGCHLD, wakeup)?;
+        Ok(Signals {
+            reload,
+            terminate,
+            upgrade,
+            dump,
+            drain,
+            child,
+        })
+    }
+
+    /// Whether a configuration reload (SIGHUP) has been
+    /// requested since the last call
+    pub fn reload_requested(&self) -> bool {
+        self.reload.swap(false, Ordering::Relaxed)
+    }
+
+    /// Whether a replacement of the backend by a new instance
+    /// (SIGUSR2) has been requested since the last call
+    pub fn upgrade_requested(&self) -> bool {
+        self.upgrade.swap(false, Ordering::Relaxed)
+    }
+
+    /// Whether a dump of the state of the backend to the log
+    /// (SIGUSR1) has been requested since the last call
+    pub fn dump_requested(&self) -> bool {
+        self.dump.swap(false, Ordering::Relaxed)
+    }
+
+    /// Whether the drain mode (SIGQUIT) has been requested since
+    /// the last call
+    pub fn drain_requested(&self) -> bool {
+        self.drain.swap(false, Ordering::Relaxed)
+    }
+
+    /// Descriptor readable once a child has exited
+    pub fn child_fd(&self) -> RawFd {
+        self.child.as_raw_fd()
+    }
+
+    /// Whether a child has exited (SIGCHLD) since the last call,
+    /// the pending wake-ups are consumed
+    pub fn child_exited(&self) -> bool {
+        let mut buf = [0; 64];
+        let mut exited = false;
+        while let Ok(n) = (&self.child).read(&mut buf) {
+            if n == 0 {
+                break;
+            }
+            exited = true;
+        }
+        exited
+    }
+
+    /// Whether a shutdown (SIGTERM or SIGINT) has been requested
+    pub fn terminate_requested(&self) -> bool {
+        self.terminate.load(Ordering::Relaxed)
+    }
+}
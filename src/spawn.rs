@@ -0,0 +1,186 @@
+//! # Helpers to spawn the child processes
+//!
+//! **Author**: "Dany LE"
+//!
+use crate::process::Process;
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::fs;
+use std::io;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Resolve a command to the absolute path of its executable,
+/// searching the `PATH` when the command has no `/`
+pub fn resolve(cmd: &str) -> Result<PathBuf, Box<dyn std::error::Error>> {
+    if cmd.contains('/') {
+        return Ok(fs::canonicalize(cmd)?);
+    }
+    match search(Path::new(cmd))? {
+        Some(path) => Ok(fs::canonicalize(path)?),
+        None => Err(format!("Command not found: {}", cmd).into()),
+    }
+}
+
+/// Absolute path of the executable of a command as found,
+/// searching the `PATH` when the command has no `/`. Only its
+/// directory is resolved: a link to a multicall binary, e.g. a
+/// busybox applet, is not taken for the binary
+pub fn locate(cmd: &str) -> Result<PathBuf, Box<dyn std::error::Error>> {
+    let path = if cmd.contains('/') {
+        PathBuf::from(cmd)
+    } else {
+        search(Path::new(cmd))?.ok_or(format!("Command not found: {}", cmd))?
+    };
+    fs::metadata(&path)?;
+    Ok(real_dir(&path)?)
+}
+
+/// The path with its directory resolved, the file kept as named
+pub fn real_dir(path: &Path) -> io::Result<PathBuf> {
+    let name = path
+        .file_name()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no file name"))?;
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    Ok(fs::canonicalize(dir)?.join(name))
+}
+
+/// First executable of the `PATH` with the given name, as
+/// found, without following the links
+pub fn search(name: &Path) -> Result<Option<PathBuf>, Box<dyn std::error::Error>> {
+    let paths = env::var_os("PATH").ok_or("PATH is not set")?;
+    Ok(env::split_paths(&paths)
+        .map(|dir| dir.join(name))
+        .find(|candidate| is_executable(candidate)))
+}
+
+/// Whether the path is a regular file with an execute bit set
+pub fn is_executable(path: &Path) -> bool {
+    match fs::metadata(path) {
+        Ok(meta) => meta.is_file() && meta.permissions().mode() & 0o111 != 0,
+        Err(_) => false,
+    }
+}
+
+/// Have the child killed when the backend dies, whatever the
+/// cause. Must be set after any change of user, which clears it
+pub fn die_with_parent(cmd: &mut Command) {
+    unsafe {
+        cmd.pre_exec(|| {
+            if libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL) < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+}
+
+/// Whether a spawn error may go away by itself, e.g. when the
+/// system or the user runs out of processes or descriptors, as
+/// opposed to a missing or non executable command
+pub fn is_transient(error: &io::Error) -> bool {
+    matches!(
+        error.raw_os_error(),
+        Some(libc::EAGAIN)
+            | Some(libc::ENOMEM)
+            | Some(libc::EMFILE)
+            | Some(libc::ENFILE)
+            | Some(libc::EINTR)
+            | Some(libc::ETXTBSY)
+    )
+}
+
+/// Ask the processes to terminate with SIGTERM, then kill the
+/// ones still running after the grace period
+pub fn terminate(children: Vec<&mut Process>, grace: Duration) {
+    let mut children = children;
+    for child in children.iter_mut() {
+        let _ = child.signal(libc::SIGTERM);
+    }
+    let deadline = Instant::now() + grace;
+    let mut running = children;
+    while !running.is_empty() {
+        running.retain_mut(|child| matches!(child.try_wait(), Ok(None)));
+        if Instant::now() >= deadline {
+            break;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    for child in running {
+        let _ = child.kill();
+        let _ = child.wait();
+    }
+}
+
+/// Resource limits applied to the child processes, each
+/// limit sets both the soft and the hard value
+#[derive(Deserialize, Serialize, Default, Debug, Clone, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Rlimits {
+    /// CPU time in seconds
+    pub cpu: Option<u64>,
+    /// number of open files
+    pub nofile: Option<u64>,
+    /// number of processes of the user
+    pub nproc: Option<u64>,
+    /// size of the address space in bytes
+    pub address_space: Option<u64>,
+    /// size of the created files in bytes
+    pub fsize: Option<u64>,
+    /// size of the core dumps in bytes
+    pub core: Option<u64>,
+}
+
+impl Rlimits {
+    /// Override the limits with the ones set in `other`
+    pub fn merge(&mut self, other: &Rlimits) {
+        self.cpu = other.cpu.or(self.cpu);
+        self.nofile = other.nofile.or(self.nofile);
+        self.nproc = other.nproc.or(self.nproc);
+        self.address_space = other.address_space.or(self.address_space);
+        self.fsize = other.fsize.or(self.fsize);
+        self.core = other.core.or(self.core);
+    }
+
+    /// Set the limits in the child before it executes the command
+    pub fn apply(&self, cmd: &mut Command) {
+        if *self == Rlimits::default() {
+            return;
+        }
+        let limits = self.clone();
+        // only async-signal-safe calls are made in the child
+        unsafe {
+            cmd.pre_exec(move || limits.set());
+        }
+    }
+
+    fn set(&self) -> io::Result<()> {
+        let limits = [
+            (libc::RLIMIT_CPU, self.cpu),
+            (libc::RLIMIT_NOFILE, self.nofile),
+            (libc::RLIMIT_NPROC, self.nproc),
+            (libc::RLIMIT_AS, self.address_space),
+            (libc::RLIMIT_FSIZE, self.fsize),
+            (libc::RLIMIT_CORE, self.core),
+        ];
+        for (resource, value) in limits {
+            if let Some(value) = value {
+                let limit = libc::rlimit {
+                    rlim_cur: value as libc::rlim_t,
+                    rlim_max: value as libc::rlim_t,
+                };
+                if unsafe { libc::setrlimit(resource, &limit) } != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,108 @@
+//! # Disk spool of the broadcast output
+//!
+//! When the tunnel is slower than the broadcast process, its
+//! output is queued in a file instead of stalling the event
+//! loop, and sent as the tunnel catches up
+//!
+//! **Author**: "Dany LE"
+//!
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+
+/// Queue of output in an unlinked file, bounded in bytes: the
+/// oldest output is dropped beyond the bound
+pub struct Spool {
+    file: File,
+    /// offset of the oldest queued byte
+    start: u64,
+    /// offset of the end of the queue
+    end: u64,
+    max: u64,
+    /// bytes dropped since the last call of [`Spool::take_dropped`]
+    dropped: u64,
+}
+
+impl Spool {
+    /// Create the spool file in a directory, the file is
+    /// unlinked right away so that it never outlives the backend
+    pub fn create(dir: &Path, name: &str, max: usize) -> io::Result<Spool> {
+        fs::create_dir_all(dir)?;
+        let path = dir.join(format!(".{}.{}.spool", name, std::process::id()));
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)?;
+        fs::remove_file(&path)?;
+        Ok(Spool {
+            file,
+            start: 0,
+            end: 0,
+            max: max as u64,
+            dropped: 0,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.start == self.end
+    }
+
+    /// Queued bytes
+    pub fn size(&self) -> u64 {
+        self.end - self.start
+    }
+
+    /// Queue some output, dropping the oldest one beyond the bound
+    pub fn push(&mut self, data: &[u8]) -> io::Result<()> {
+        self.file.write_all_at(data, self.end)?;
+        self.end += data.len() as u64;
+        let excess = self.size().saturating_sub(self.max);
+        self.start += excess;
+        self.dropped += excess;
+        self.compact()
+    }
+
+    /// Take the oldest queued output, up to the size of the buffer
+    pub fn pop(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = (buf.len() as u64).min(self.size()) as usize;
+        self.file.read_exact_at(&mut buf[..len], self.start)?;
+        self.start += len as u64;
+        self.compact()?;
+        Ok(len)
+    }
+
+    /// Bytes dropped since the last call
+    pub fn take_dropped(&mut self) -> u64 {
+        std::mem::take(&mut self.dropped)
+    }
+
+    /// Reclaim the space of the consumed output: the file is
+    /// emptied once drained, and the queue is moved to the
+    /// beginning of the file once the consumed part exceeds the
+    /// bound, the two parts never overlap then
+    fn compact(&mut self) -> io::Result<()> {
+        if self.is_empty() {
+            self.start = 0;
+            self.end = 0;
+            return self.file.set_len(0);
+        }
+        if self.start <= self.max {
+            return Ok(());
+        }
+        let mut buf = vec![0; 65536];
+        let mut copied = 0;
+        while copied < self.size() {
+            let len = (buf.len() as u64).min(self.size() - copied) as usize;
+            self.file
+                .read_exact_at(&mut buf[..len], self.start + copied)?;
+            self.file.write_all_at(&buf[..len], copied)?;
+            copied += len as u64;
+        }
+        self.end = self.size();
+        self.start = 0;
+        self.file.set_len(self.end)
+    }
+}
@@ -0,0 +1,135 @@
+//! # Session state kept across restarts of the backend
+//!
+//! The sessions of `shp2p` are saved to the `state_file` so
+//! that a new instance of the backend can take them over.
+//! Each session may also have its own pidfile under the
+//! `runtime_dir`, so that the processes left by a crashed
+//! instance are found and killed
+//!
+//! **Author**: "Dany LE"
+//!
+use crate::process;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::os::unix::io::RawFd;
+use std::path::{Path, PathBuf};
+
+/// A session with a running process
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    pub client_id: u16,
+    pub user: String,
+    /// session of the client, kept across an upgrade
+    #[serde(default)]
+    pub session: String,
+    pub pid: u32,
+    /// start time of the process, see [`crate::process::start_time`]
+    pub start_time: u64,
+    /// pipes of the process, valid in a new instance started
+    /// with `exec`
+    pub stdin: RawFd,
+    pub stdout: RawFd,
+}
+
+/// Content of a pidfile
+#[derive(Deserialize, Serialize)]
+struct Pidfile {
+    topic: String,
+    #[serde(flatten)]
+    record: Record,
+}
+
+/// Save the sessions, the file is replaced atomically
+pub fn save(path: &Path, records: &[Record]) -> Result<(), Box<dyn std::error::Error>> {
+    save_json(path, &records)
+}
+
+fn save_json<T: Serialize>(path: &Path, value: &T) -> Result<(), Box<dyn std::error::Error>> {
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, serde_json::to_vec_pretty(value)?)?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+/// Read the sessions saved by the previous instance, none
+/// when there is no state file
+pub fn load(path: &Path) -> Result<Vec<Record>, Box<dyn std::error::Error>> {
+    match fs::read(path) {
+        Ok(data) => Ok(serde_json::from_slice(&data)?),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(format!("{}: {}", path.display(), e).into()),
+    }
+}
+
+/// Path of the pidfile of a process, named after the topic and
+/// the pid
+fn pidfile(dir: &Path, topic: &str, pid: u32) -> PathBuf {
+    let topic: String = topic
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    dir.join(format!("{}.{}.pid", topic, pid))
+}
+
+/// Write the pidfile of a session, the runtime directory is
+/// created if necessary
+pub fn write_pidfile(
+    dir: &Path,
+    topic: &str,
+    record: &Record,
+) -> Result<(), Box<dyn std::error::Error>> {
+    fs::create_dir_all(dir)?;
+    let pidfile = Pidfile {
+        topic: String::from(topic),
+        record: record.clone(),
+    };
+    save_json(&self::pidfile(dir, topic, record.pid), &pidfile)
+}
+
+/// Remove the pidfile of a session once its process is gone
+pub fn remove_pidfile(dir: &Path, topic: &str, pid: u32) -> io::Result<()> {
+    match fs::remove_file(pidfile(dir, topic, pid)) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// Read and remove the pidfiles of the topic left by the
+/// previous instance, the sessions taken over get new ones
+pub fn take_pidfiles(dir: &Path, topic: &str) -> Result<Vec<Record>, Box<dyn std::error::Error>> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(format!("{}: {}", dir.display(), e).into()),
+    };
+    let mut records = Vec::new();
+    for entry in entries {
+        let path = entry?.path();
+        if path.extension().is_none_or(|ext| ext != "pid") {
+            continue;
+        }
+        let pidfile: Pidfile = match fs::read(&path)
+            .ok()
+            .and_then(|data| serde_json::from_slice(&data).ok())
+        {
+            Some(pidfile) => pidfile,
+            None => continue,
+        };
+        if pidfile.topic != topic {
+            continue;
+        }
+        fs::remove_file(&path)?;
+        records.push(pidfile.record);
+    }
+    Ok(records)
+}
+
+/// Kill the process of a session of a previous instance, unless
+/// its pid has been reused since. Return whether it was killed
+pub fn kill_orphan(record: &Record) -> bool {
+    if process::start_time(record.pid) != Some(record.start_time) {
+        return false;
+    }
+    unsafe { libc::kill(record.pid as libc::pid_t, libc::SIGKILL) == 0 }
+}
@@ -0,0 +1,108 @@
+//! # Statistics of the clients
+//!
+//! Counters kept for each subscribed client, shown in the state
+//! dump and on the admin topic, and sent to a client asking for
+//! them with the control frame `{"query": "status"}`
+//!
+//! **Author**: "Dany LE"
+//!
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::time::Instant;
+
+pub struct Stats {
+    /// bytes written to the processes
+    pub bytes_in: u64,
+    /// bytes sent to the client
+    pub bytes_out: u64,
+    /// frames received from the client
+    pub frames_in: u64,
+    /// data frames sent to the client
+    pub frames_out: u64,
+    /// bytes of the client dropped, e.g. when its process does
+    /// not read its input
+    pub dropped_in: u64,
+    /// bytes of output dropped before reaching the client
+    pub dropped_out: u64,
+    /// processes spawned for the client
+    pub spawns: u32,
+    /// when the client subscribed
+    pub since: Instant,
+    /// last message of the client, see `idle_timeout`
+    pub last_active: Instant,
+}
+
+impl Default for Stats {
+    fn default() -> Stats {
+        Stats {
+            bytes_in: 0,
+            bytes_out: 0,
+            frames_in: 0,
+            frames_out: 0,
+            dropped_in: 0,
+            dropped_out: 0,
+            spawns: 0,
+            since: Instant::now(),
+            last_active: Instant::now(),
+        }
+    }
+}
+
+impl Stats {
+    /// Count a message of the client
+    pub fn received(&mut self) {
+        self.frames_in += 1;
+        self.last_active = Instant::now();
+    }
+
+    /// Count some output sent to the client
+    pub fn sent(&mut self, n: usize) {
+        self.frames_out += 1;
+        self.bytes_out += n as u64;
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "bytes_in": self.bytes_in,
+            "bytes_out": self.bytes_out,
+            "frames_in": self.frames_in,
+            "frames_out": self.frames_out,
+            "dropped_in": self.dropped_in,
+            "dropped_out": self.dropped_out,
+            "spawns": self.spawns,
+            "uptime": self.since.elapsed().as_secs(),
+            "idle": self.last_active.elapsed().as_secs(),
+        })
+    }
+
+    /// One line summary, for the state dump
+    pub fn describe(&self) -> String {
+        format!(
+            "{} bytes in, {} bytes out, {} frames in, {} frames out, {} bytes dropped in, {} bytes dropped out, {} spawn(s), up {}s, idle {}s",
+            self.bytes_in,
+            self.bytes_out,
+            self.frames_in,
+            self.frames_out,
+            self.dropped_in,
+            self.dropped_out,
+            self.spawns,
+            self.since.elapsed().as_secs(),
+            self.last_active.elapsed().as_secs()
+        )
+    }
+}
+
+#[derive(Deserialize)]
+struct Query {
+    query: String,
+}
+
+/// Whether the payload of a control frame is a status query,
+/// the payload may be terminated by a null byte
+pub fn is_query(data: &[u8]) -> bool {
+    let data = match data.last() {
+        Some(0) => &data[0..data.len() - 1],
+        _ => data,
+    };
+    serde_json::from_slice::<Query>(data).is_ok_and(|q| q.query == "status")
+}
@@ -0,0 +1,194 @@
+//! # Service control over a channel
+//!
+//! The clients of `shsvc` send requests in data frames to query
+//! and control the configured systemd units with `systemctl`,
+//! e.g. for the admin UI of the frontend:
+//!
+//! ```text
+//! -> data {"id": 1, "op": "status", "unit": "nginx.service"}
+//! <- data {"id": 1, "result": {"unit": "nginx.service", "load": "loaded", "active": "active", "sub": "running", "enabled": "enabled", "pid": 812, "since": "Tue 2026-10-13 09:12:01 UTC"}}
+//! -> data {"id": 2, "op": "restart", "unit": "nginx.service"}
+//! <- data {"id": 2, "result": {"unit": "nginx.service"}}
+//! ```
+//!
+//! Only the operators may start, stop or restart a unit, none
+//! when no operator is set. The requests are run as those of shexec, see [`crate::exec`],
+//! this module parses them and builds the replies
+//!
+//! **Author**: "Dany LE"
+//!
+use crate::account;
+use crate::exec::Job;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use std::process::ExitStatus;
+
+/// default of `svc.command`
+const SYSTEMCTL: &str = "/usr/bin/systemctl";
+/// properties of a unit in a status, with their keys in the
+/// reply
+const PROPERTIES: [(&str, &str); 6] = [
+    ("LoadState", "load"),
+    ("ActiveState", "active"),
+    ("SubState", "sub"),
+    ("UnitFileState", "enabled"),
+    ("MainPID", "pid"),
+    ("ActiveEnterTimestamp", "since"),
+];
+
+/// Settings of the service control mode, e.g.
+///
+/// ```toml
+/// [svc]
+/// units = ["nginx.service", "antd.service"]
+/// operators = ["admin"]
+/// operator_groups = ["wheel"]
+/// ```
+#[derive(Deserialize, Serialize, Default, Debug, Clone, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct SvcSettings {
+    /// absolute path of systemctl, `/usr/bin/systemctl` when not
+    /// set
+    pub command: Option<String>,
+    /// units the clients can query and control, by their full
+    /// name
+    pub units: Vec<String>,
+    /// users allowed to start, stop and restart the units, with
+    /// neither operators nor groups the units are only queried
+    pub operators: Vec<String>,
+    /// groups whose members are operators
+    pub operator_groups: Vec<String>,
+}
+
+impl SvcSettings {
+    pub fn command(&self) -> &str {
+        self.command.as_deref().unwrap_or(SYSTEMCTL)
+    }
+
+    /// Whether a user may start, stop and restart the units, none
+    /// when no operator is set
+    pub fn is_operator(&self, user: &str) -> bool {
+        self.operators.iter().any(|u| u == user)
+            || (!self.operator_groups.is_empty()
+                && account::groups_of(user)
+                    .iter()
+                    .any(|g| self.operator_groups.contains(g)))
+    }
+}
+
+/// An operation of a request
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Op {
+    Start,
+    Stop,
+    Restart,
+    Status,
+}
+
+impl Op {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Op::Start => "start",
+            Op::Stop => "stop",
+            Op::Restart => "restart",
+            Op::Status => "status",
+        }
+    }
+}
+
+/// A request being run, for its reply
+#[derive(Debug, Clone)]
+pub struct Call {
+    pub id: Value,
+    pub op: Op,
+    pub unit: String,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Request {
+    #[serde(default)]
+    id: Value,
+    op: Op,
+    unit: String,
+}
+
+/// Parse a request of a user into the job running systemctl,
+/// or the error reply
+pub fn parse(data: &[u8], user: &str, settings: &SvcSettings) -> Result<Job, Value> {
+    let request: Request = match serde_json::from_slice(data) {
+        Ok(request) => request,
+        Err(e) => return Err(error(&Value::Null, &format!("Invalid request: {}", e))),
+    };
+    let id = request.id;
+    if !settings.units.contains(&request.unit) {
+        return Err(error(&id, &format!("Unknown unit {}", request.unit)));
+    }
+    let args = match request.op {
+        Op::Status => {
+            let properties: Vec<&str> = PROPERTIES.iter().map(|(p, _)| *p).collect();
+            vec![
+                "show".into(),
+                format!("--property={}", properties.join(",")),
+                "--".into(),
+                request.unit.clone(),
+            ]
+        }
+        op if !settings.is_operator(user) => {
+            let message = format!("Not allowed to {} {}", op.name(), request.unit);
+            return Err(error(&id, &message));
+        }
+        op => vec![
+            "--no-ask-password".into(),
+            op.name().into(),
+            "--".into(),
+            request.unit.clone(),
+        ],
+    };
+    Ok(Job {
+        command: Some(String::from(settings.command())),
+        args,
+        input: Vec::new(),
+        call: None,
+        git: None,
+        svc: Some(Call {
+            id,
+            op: request.op,
+            unit: request.unit,
+        }),
+    })
+}
+
+/// Reply of a request once systemctl has exited
+pub fn reply(call: &Call, status: ExitStatus, output: &[u8]) -> Value {
+    if !status.success() {
+        let message = format!("{} of {} failed: {}", call.op.name(), call.unit, status);
+        return error(&call.id, &message);
+    }
+    let mut result = Map::new();
+    result.insert(String::from("unit"), json!(call.unit));
+    if call.op == Op::Status {
+        // e.g. `ActiveState=active`, one property per line
+        for line in String::from_utf8_lossy(output).lines() {
+            let Some((property, value)) = line.split_once('=') else {
+                continue;
+            };
+            let Some((_, key)) = PROPERTIES.iter().find(|(p, _)| *p == property) else {
+                continue;
+            };
+            let value = match (*key, value.parse::<u64>()) {
+                ("pid", Ok(pid)) => json!(pid),
+                (_, _) if value.is_empty() => Value::Null,
+                (_, _) => json!(value),
+            };
+            result.insert(String::from(*key), value);
+        }
+    }
+    json!({ "id": call.id, "result": result })
+}
+
+/// An error reply, e.g. `{"id": 1, "error": {"message": "..."}}`
+pub fn error(id: &Value, message: &str) -> Value {
+    json!({ "id": id, "error": { "message": message } })
+}
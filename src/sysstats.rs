@@ -0,0 +1,509 @@
+//! # System statistics backend
+//!
+//! `shstats` owns no process: it samples the statistics of the
+//! host from `/proc` at an interval and sends each sample to all
+//! the subscribers as a JSON data frame, e.g.
+//!
+//! ```json
+//! {
+//!   "time": 1700000000, "uptime": 86400.5, "load": [0.5, 0.4, 0.3],
+//!   "cpu": {"usage": 12.5, "cores": [10.0, 15.0]},
+//!   "memory": {"total": 8261718016, "available": 5261718016, "used": 3000000000,
+//!              "swap_total": 2147483648, "swap_used": 0},
+//!   "disks": [{"path": "/", "total": 100000000000, "available": 60000000000, "used": 40000000000}],
+//!   "network": [{"interface": "eth0", "rx_bytes": 123456, "tx_bytes": 654321,
+//!                "rx_rate": 1024.0, "tx_rate": 512.0}]
+//! }
+//! ```
+//!
+//! The CPU usage and the network rates are computed since the
+//! previous sample, the subscribers get the last sample right
+//! away
+//!
+//! **Author**: "Dany LE"
+//!
+use crate::audit::{self, Event};
+use crate::backend::Backend;
+use crate::clients::{self, Roster, Subscription};
+use crate::config::Config;
+use crate::frame;
+use crate::log::{self, Level};
+use crate::metrics::{self, Counter};
+use crate::stats::{self, Stats};
+use crate::telemetry::Span;
+use crate::{DEBUG, INFO, WARN};
+use latpr::tunnel::{CallbackEvent, Msg, MsgKind, Topic};
+use latpr::utils::{LogLevel, LOG};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::fs;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// seconds between two samples when none is configured
+const INTERVAL: u64 = 5;
+
+/// Settings of the system statistics mode, e.g.
+///
+/// ```toml
+/// [sysstats]
+/// interval = 2
+/// disks = ["/", "/srv"]
+/// interfaces = ["eth0"]
+/// ```
+#[derive(Deserialize, Serialize, Default, Debug, Clone, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct SysStatsSettings {
+    /// seconds between two samples, 5 by default
+    pub interval: Option<u64>,
+    /// mount points whose usage is sampled, `/` by default
+    pub disks: Vec<PathBuf>,
+    /// network interfaces sampled, all but the loopback by
+    /// default
+    pub interfaces: Vec<String>,
+}
+
+impl SysStatsSettings {
+    /// Time between two samples
+    pub fn interval(&self) -> Duration {
+        Duration::from_secs(self.interval.unwrap_or(INTERVAL))
+    }
+}
+
+/// Cumulative counters of the host, the rates of a sample are
+/// computed from the counters of the previous one
+#[derive(Default)]
+struct Counters {
+    /// busy and total jiffies of all the CPUs, then of each one
+    cpu: Vec<(u64, u64)>,
+    /// received and sent bytes per interface
+    network: HashMap<String, (u64, u64)>,
+    at: Option<Instant>,
+}
+
+/// CPU time of all the CPUs then of each one, as busy and total
+/// jiffies, from `/proc/stat`
+fn cpu_times() -> Option<Vec<(u64, u64)>> {
+    let stat = fs::read_to_string("/proc/stat").ok()?;
+    let mut times = Vec::new();
+    for line in stat.lines().filter(|l| l.starts_with("cpu")) {
+        let values: Vec<u64> = line
+            .split_whitespace()
+            .skip(1)
+            .filter_map(|v| v.parse().ok())
+            .collect();
+        // user nice system idle iowait irq softirq steal, the
+        // guest times are already counted in user and nice
+        let total: u64 = values.iter().take(8).sum();
+        let idle = values.get(3).copied().unwrap_or(0) + values.get(4).copied().unwrap_or(0);
+        times.push((total - idle, total));
+    }
+    Some(times)
+}
+
+/// Usage in percent of a CPU between two samples
+fn usage(previous: Option<&(u64, u64)>, current: &(u64, u64)) -> f64 {
+    let (busy, total) = previous.copied().unwrap_or((0, 0));
+    let elapsed = current.1.saturating_sub(total);
+    if elapsed == 0 {
+        return 0.0;
+    }
+    current.0.saturating_sub(busy) as f64 * 100.0 / elapsed as f64
+}
+
+/// Fields of `/proc/meminfo` in bytes
+fn meminfo() -> Option<HashMap<String, u64>> {
+    let text = fs::read_to_string("/proc/meminfo").ok()?;
+    Some(
+        text.lines()
+            .filter_map(|line| {
+                let (name, value) = line.split_once(':')?;
+                let kb: u64 = value.split_whitespace().next()?.parse().ok()?;
+                Some((name.to_string(), kb * 1024))
+            })
+            .collect(),
+    )
+}
+
+/// Received and sent bytes per interface, from `/proc/net/dev`
+fn network() -> Option<HashMap<String, (u64, u64)>> {
+    let text = fs::read_to_string("/proc/net/dev").ok()?;
+    Some(
+        text.lines()
+            .skip(2)
+            .filter_map(|line| {
+                let (name, values) = line.split_once(':')?;
+                let values: Vec<u64> = values
+                    .split_whitespace()
+                    .filter_map(|v| v.parse().ok())
+                    .collect();
+                Some((name.trim().to_string(), (*values.first()?, *values.get(8)?)))
+            })
+            .collect(),
+    )
+}
+
+/// Total, used and available bytes of a file system, the
+/// blocks reserved to root are neither used nor available
+fn disk(path: &Path) -> Option<(u64, u64, u64)> {
+    let path = CString::new(path.as_os_str().as_bytes()).ok()?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } < 0 {
+        return None;
+    }
+    let size = stat.f_frsize as u64;
+    Some((
+        stat.f_blocks as u64 * size,
+        (stat.f_blocks - stat.f_bfree) as u64 * size,
+        stat.f_bavail as u64 * size,
+    ))
+}
+
+/// Load averages and uptime in seconds
+fn load() -> (Vec<f64>, Option<f64>) {
+    let load = fs::read_to_string("/proc/loadavg")
+        .map(|text| {
+            text.split_whitespace()
+                .take(3)
+                .filter_map(|v| v.parse().ok())
+                .collect()
+        })
+        .unwrap_or_default();
+    let uptime = fs::read_to_string("/proc/uptime")
+        .ok()
+        .and_then(|text| text.split_whitespace().next()?.parse().ok());
+    (load, uptime)
+}
+
+impl Counters {
+    /// Take a sample of the host, the counters are updated for
+    /// the next one
+    fn sample(&mut self, config: &Config) -> Value {
+        let settings = &config.sysstats;
+        let now = Instant::now();
+        let elapsed = self
+            .at
+            .map_or(0.0, |at| now.duration_since(at).as_secs_f64());
+        let cpu = cpu_times().unwrap_or_default();
+        let mut usages = cpu
+            .iter()
+            .enumerate()
+            .map(|(i, times)| usage(self.cpu.get(i), times));
+        let total = usages.next().unwrap_or(0.0);
+        let cores: Vec<f64> = usages.collect();
+        let memory = meminfo().unwrap_or_default();
+        let field = |name: &str| memory.get(name).copied().unwrap_or(0);
+        let paths = if settings.disks.is_empty() {
+            vec![PathBuf::from("/")]
+        } else {
+            settings.disks.clone()
+        };
+        let disks: Vec<Value> = paths
+            .iter()
+            .filter_map(|path| {
+                let (total, used, available) = disk(path)?;
+                Some(json!({
+                    "path": path,
+                    "total": total,
+                    "available": available,
+                    "used": used,
+                }))
+            })
+            .collect();
+        let counters = network().unwrap_or_default();
+        let mut names: Vec<&String> = counters
+            .keys()
+            .filter(|name| {
+                if settings.interfaces.is_empty() {
+                    name.as_str() != "lo"
+                } else {
+                    settings.interfaces.contains(name)
+                }
+            })
+            .collect();
+        names.sort();
+        let rate = |current: u64, previous: Option<u64>| match previous {
+            Some(previous) if elapsed > 0.0 => current.saturating_sub(previous) as f64 / elapsed,
+            _ => 0.0,
+        };
+        let interfaces: Vec<Value> = names
+            .into_iter()
+            .map(|name| {
+                let (rx, tx) = counters[name];
+                let previous = self.network.get(name);
+                json!({
+                    "interface": name,
+                    "rx_bytes": rx,
+                    "tx_bytes": tx,
+                    "rx_rate": rate(rx, previous.map(|p| p.0)),
+                    "tx_rate": rate(tx, previous.map(|p| p.1)),
+                })
+            })
+            .collect();
+        let (load, uptime) = load();
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        self.cpu = cpu;
+        self.network = counters;
+        self.at = Some(now);
+        json!({
+            "time": time,
+            "uptime": uptime,
+            "load": load,
+            "cpu": { "usage": total, "cores": cores },
+            "memory": {
+                "total": field("MemTotal"),
+                "available": field("MemAvailable"),
+                "used": field("MemTotal").saturating_sub(field("MemAvailable")),
+                "swap_total": field("SwapTotal"),
+                "swap_used": field("SwapTotal").saturating_sub(field("SwapFree")),
+            },
+            "disks": disks,
+            "network": interfaces,
+        })
+    }
+}
+
+pub struct Subscriber {
+    user: String,
+    /// identifier of the subscription, see [`telemetry::session_id`]
+    session: String,
+    /// counters of the subscriber
+    stats: Stats,
+    /// span of the subscription, see [`crate::telemetry`]
+    span: Option<Span>,
+}
+
+impl Drop for Subscriber {
+    fn drop(&mut self) {
+        clients::end_span(&mut self.span, &self.stats);
+    }
+}
+
+crate::member!(Subscriber);
+
+/// Backend sampling the statistics of the host
+pub struct SysStats {
+    clients: HashMap<u16, Subscriber>,
+    counters: Counters,
+    /// JSON of the last sample
+    last: Option<Vec<u8>>,
+    samples: u64,
+}
+
+impl Default for SysStats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SysStats {
+    pub fn new() -> SysStats {
+        SysStats {
+            clients: HashMap::new(),
+            counters: Counters::default(),
+            last: None,
+            samples: 0,
+        }
+    }
+
+    /// Take a sample when it is due and send it to all the
+    /// subscribers
+    fn step_sample(
+        &mut self,
+        config: &Config,
+        topic: &mut Topic,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let due = self
+            .counters
+            .at
+            .is_none_or(|at| at.elapsed() >= config.sysstats.interval());
+        if !due {
+            return Ok(());
+        }
+        let data = self.counters.sample(config).to_string().into_bytes();
+        self.samples += 1;
+        if !self.clients.is_empty() {
+            if let Some((samples, bytes)) = log::throttle(Level::Debug, "send", 0, data.len()) {
+                DEBUG!(
+                    "Sending {} sample(s) to {} client(s): {} bytes",
+                    samples,
+                    self.clients.len(),
+                    bytes
+                );
+            }
+        }
+        for (key, subscriber) in self.clients.iter_mut() {
+            let msg = Msg::create(MsgKind::ChannelData, 0, *key, data.clone());
+            frame::send(topic, &msg)?;
+            subscriber.stats.sent(data.len());
+            metrics::add(Counter::BytesOut, data.len() as u64);
+        }
+        self.last = Some(data);
+        Ok(())
+    }
+
+    fn handle_message(
+        &mut self,
+        msg: &Msg,
+        config: &Config,
+        topic: &mut Topic,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        DEBUG!(
+            "Receive message kind {} from client {}: {} bytes",
+            msg.kind,
+            msg.client_id,
+            msg.size
+        );
+        if let Some(client) = self.clients.get_mut(&msg.client_id) {
+            client.stats.received();
+        }
+        match msg.kind {
+            MsgKind::ChannelSubscribe => {
+                let Some(Subscription {
+                    user,
+                    session,
+                    context: _context,
+                }) = clients::subscribe(msg, config, topic, |_| {
+                    Ok(self
+                        .clients
+                        .remove(&msg.client_id)
+                        .map(|old| old.user.clone()))
+                })?
+                else {
+                    return Ok(());
+                };
+                if !self.make_room(msg.client_id, config, topic)? {
+                    return Ok(());
+                }
+                let span = clients::span(&config.topic, msg.client_id, &user, &session);
+                audit::log(config, msg.client_id, &user, &session, Event::Subscribe);
+                let mut subscriber = Subscriber {
+                    user,
+                    session,
+                    stats: Stats::default(),
+                    span: Some(span),
+                };
+                INFO!(
+                    "Client {} subscribe to channel {}",
+                    msg.client_id,
+                    &config.topic
+                );
+                clients::welcome(msg.client_id, config, topic)?;
+                if let Some(data) = self.last.clone() {
+                    subscriber.stats.sent(data.len());
+                    let msg = Msg::create(MsgKind::ChannelData, 0, msg.client_id, data);
+                    frame::send(topic, &msg)?;
+                }
+                self.clients.insert(msg.client_id, subscriber);
+            }
+            MsgKind::ChannelUnsubscribe => {
+                self.unsubscribe(msg.client_id, config, topic)?;
+            }
+            MsgKind::ChannelUnsubscribeAll => self.unsubscribe_all(config, topic)?,
+            MsgKind::ChannelData => {
+                WARN!("Refuse data of client {}: read-only", msg.client_id);
+                frame::send(
+                    topic,
+                    &frame::error(msg.client_id, "Read-only subscription"),
+                )?;
+            }
+            MsgKind::ChannelCtrl if stats::is_query(&msg.data) => {
+                self.status(msg.client_id, topic)?;
+            }
+            _ => {
+                WARN!(
+                    "Receive message kind {} from client {}",
+                    msg.kind,
+                    msg.client_id
+                );
+            }
+        };
+        Ok(())
+    }
+}
+
+impl Roster for SysStats {
+    type Client = Subscriber;
+
+    fn table(&self) -> &HashMap<u16, Subscriber> {
+        &self.clients
+    }
+
+    fn table_mut(&mut self) -> &mut HashMap<u16, Subscriber> {
+        &mut self.clients
+    }
+}
+
+impl Backend for SysStats {
+    fn clients(&self) -> usize {
+        self.clients.len()
+    }
+
+    fn dump(&self) -> Vec<String> {
+        let mut lines = vec![format!(
+            "{} client(s), {} sample(s), last one {}",
+            self.clients.len(),
+            self.samples,
+            match self.counters.at {
+                Some(at) => format!("{}s ago", at.elapsed().as_secs()),
+                None => String::from("never"),
+            }
+        )];
+        let mut keys: Vec<&u16> = self.clients.keys().collect();
+        keys.sort();
+        for key in keys {
+            let s = &self.clients[key];
+            lines.push(format!(
+                "client {}: user {}, {}",
+                key,
+                s.user,
+                s.stats.describe()
+            ));
+        }
+        lines
+    }
+
+    fn step_handle(
+        &mut self,
+        evt: &CallbackEvent,
+        config: &Config,
+        topic: &mut Topic,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if let Some(msg) = evt.msg {
+            self.scoped(msg, config, topic, |sysstats, topic| {
+                sysstats.handle_message(msg, config, topic)
+            })?;
+        }
+        self.step_sample(config, topic)?;
+        self.enforce_windows(config, topic)?;
+        self.reap_idle(config, topic)?;
+        Ok(())
+    }
+
+    fn children_exited(
+        &mut self,
+        _config: &Config,
+        _topic: &mut Topic,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        Ok(())
+    }
+
+    fn disconnected(&mut self, _config: &Config) {
+        self.clients.clear();
+    }
+
+    fn shutdown(
+        &mut self,
+        _config: &Config,
+        topic: &mut Topic,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.notify_shutdown(topic)?;
+        self.clients.clear();
+        Ok(())
+    }
+}
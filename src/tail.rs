@@ -0,0 +1,439 @@
+//! # File following backend
+//!
+//! `shtail` follows a file as `tail -F` does, without running
+//! any process: the new lines of the file are sent to all the
+//! subscribers. The directory of the file is watched with
+//! inotify, so that a rotated file, i.e. renamed or removed then
+//! created again, or a truncated one is followed as well
+//!
+//! **Author**: "Dany LE"
+//!
+use crate::audit::{self, Event};
+use crate::backend::Backend;
+use crate::clients::{self, Roster, Subscription};
+use crate::config::Config;
+use crate::error::{Class, Classify};
+use crate::frame;
+use crate::log::{self, Level};
+use crate::metrics::{self, Counter};
+use crate::replay::Replay;
+use crate::stats::{self, Stats};
+use crate::telemetry::Span;
+use crate::{DEBUG, INFO, WARN};
+use latpr::tunnel::{CallbackEvent, Msg, MsgKind, Topic};
+use latpr::utils::{LogLevel, LOG};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileExt, MetadataExt};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::path::{Path, PathBuf};
+
+/// end of the file read on startup for the replay buffer
+const BACKLOG: u64 = 64 * 1024;
+
+/// Settings of the tail mode, e.g.
+///
+/// ```toml
+/// [tail]
+/// path = "/var/log/syslog"
+/// ```
+#[derive(Deserialize, Serialize, Default, Debug, Clone, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct TailSettings {
+    /// absolute path of the file to follow, which may not exist
+    /// yet, unlike its directory
+    pub path: Option<PathBuf>,
+}
+
+pub struct Subscriber {
+    user: String,
+    /// identifier of the subscription, see [`telemetry::session_id`]
+    session: String,
+    /// counters of the subscriber
+    stats: Stats,
+    /// span of the subscription, see [`crate::telemetry`]
+    span: Option<Span>,
+}
+
+impl Drop for Subscriber {
+    fn drop(&mut self) {
+        clients::end_span(&mut self.span, &self.stats);
+    }
+}
+
+crate::member!(Subscriber);
+
+/// The file being followed
+struct Followed {
+    file: File,
+    /// device and inode, the path names another file once the
+    /// file is rotated
+    id: (u64, u64),
+    /// offset of the next read
+    offset: u64,
+}
+
+impl Followed {
+    fn open(path: &Path) -> io::Result<Followed> {
+        let file = File::open(path)?;
+        let meta = file.metadata()?;
+        Ok(Followed {
+            file,
+            id: (meta.dev(), meta.ino()),
+            offset: 0,
+        })
+    }
+
+    /// Read the data appended since the last read
+    fn read_new(&mut self, path: &Path, out: &mut Vec<u8>) -> io::Result<()> {
+        if self.file.metadata()?.len() < self.offset {
+            WARN!(
+                "File {} was truncated, follow it from its start",
+                path.display()
+            );
+            self.offset = 0;
+        }
+        let mut buf = vec![0; 64 * 1024];
+        loop {
+            match self.file.read_at(&mut buf, self.offset)? {
+                0 => return Ok(()),
+                n => {
+                    out.extend_from_slice(&buf[..n]);
+                    self.offset += n as u64;
+                }
+            }
+        }
+    }
+}
+
+/// Backend following a file
+pub struct Tail {
+    clients: HashMap<u16, Subscriber>,
+    path: PathBuf,
+    /// watch of the directory of the file
+    inotify: File,
+    followed: Option<Followed>,
+    /// end of the output not terminated by a line feed yet
+    partial: Vec<u8>,
+    /// recent lines for the new subscribers, see `replay_lines`
+    replay: Replay,
+    bytes_out: u64,
+}
+
+impl Tail {
+    pub fn new(config: &Config) -> Result<Tail, Box<dyn std::error::Error>> {
+        let path = config.tail.path.clone().ok_or("tail.path: missing value")?;
+        let dir = path.parent().ok_or("tail.path: no directory")?;
+        let fd = unsafe { libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        let inotify = unsafe { File::from_raw_fd(fd) };
+        let name = CString::new(dir.as_os_str().as_bytes())?;
+        let mask = libc::IN_MODIFY
+            | libc::IN_ATTRIB
+            | libc::IN_CREATE
+            | libc::IN_DELETE
+            | libc::IN_MOVED_FROM
+            | libc::IN_MOVED_TO;
+        if unsafe { libc::inotify_add_watch(fd, name.as_ptr(), mask) } < 0 {
+            let error = io::Error::last_os_error();
+            return Err(format!("Unable to watch {}: {}", dir.display(), error).into());
+        }
+        let mut tail = Tail {
+            clients: HashMap::new(),
+            followed: None,
+            inotify,
+            partial: Vec::new(),
+            replay: Replay::default(),
+            bytes_out: 0,
+            path,
+        };
+        // follow from the end, the last lines go to the replay
+        // buffer only
+        match Followed::open(&tail.path) {
+            Ok(mut followed) => {
+                let size = followed.file.metadata()?.len();
+                followed.offset = size.saturating_sub(BACKLOG);
+                let mut data = Vec::new();
+                followed.read_new(&tail.path, &mut data)?;
+                if followed.offset > data.len() as u64 {
+                    // the first line is likely incomplete
+                    let start = data.iter().position(|&b| b == b'\n').map_or(0, |i| i + 1);
+                    data.drain(..start);
+                }
+                tail.followed = Some(followed);
+                if let Some(lines) = tail.lines(data) {
+                    tail.replay.push(&lines, config);
+                }
+            }
+            Err(error) => WARN!(
+                "Unable to open {}, wait for it: {}",
+                tail.path.display(),
+                error
+            ),
+        }
+        INFO!("Follow {}", tail.path.display());
+        Ok(tail)
+    }
+
+    /// Append some data to the pending output, return the
+    /// complete lines, if any
+    fn lines(&mut self, data: Vec<u8>) -> Option<Vec<u8>> {
+        self.partial.extend(data);
+        let end = self.partial.iter().rposition(|&b| b == b'\n')? + 1;
+        Some(self.partial.drain(..end).collect())
+    }
+
+    /// Read the new content of the file, following it when it
+    /// has been rotated, return the complete lines
+    fn poll(&mut self, config: &Config) -> Result<Option<Vec<u8>>, Box<dyn std::error::Error>> {
+        // the readiness is consumed, the events themselves are
+        // not needed: the file is checked anyway
+        let mut buf = [0u8; 4096];
+        loop {
+            match self.inotify.read(&mut buf) {
+                Ok(0) => break,
+                Ok(_) => continue,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e.into()),
+            }
+        }
+        let mut data = Vec::new();
+        let current = fs::metadata(&self.path).ok().map(|m| (m.dev(), m.ino()));
+        if let Some(followed) = self.followed.as_mut() {
+            // the end of a rotated file is read before the new one
+            followed
+                .read_new(&self.path, &mut data)
+                .class(Class::ChildIo)?;
+            if current != Some(followed.id) {
+                INFO!("File {} was rotated", self.path.display());
+                self.followed = None;
+            }
+        }
+        if self.followed.is_none() && current.is_some() {
+            match Followed::open(&self.path) {
+                Ok(mut followed) => {
+                    INFO!("Follow the new file {}", self.path.display());
+                    followed
+                        .read_new(&self.path, &mut data)
+                        .class(Class::ChildIo)?;
+                    self.followed = Some(followed);
+                }
+                Err(error) => DEBUG!("Unable to open {}: {}", self.path.display(), error),
+            }
+        }
+        if data.is_empty() && self.partial.len() <= config.buffer_size {
+            return Ok(None);
+        }
+        let lines = match self.lines(data) {
+            Some(lines) => Some(lines),
+            None if self.partial.len() > config.buffer_size => {
+                Some(std::mem::take(&mut self.partial))
+            }
+            None => None,
+        };
+        if let Some(lines) = lines.as_ref() {
+            self.bytes_out += lines.len() as u64;
+            metrics::add(Counter::BytesOut, lines.len() as u64);
+            self.replay.push(lines, config);
+        }
+        Ok(lines)
+    }
+
+    /// Send some output to all the subscribers
+    fn send(&mut self, data: &[u8], topic: &mut Topic) -> Result<(), Box<dyn std::error::Error>> {
+        if let Some((reads, bytes)) = log::throttle(Level::Debug, "send", 0, data.len()) {
+            DEBUG!(
+                "Sending {} bytes of lines to all clients in {} read(s)",
+                bytes,
+                reads
+            );
+        }
+        for (key, subscriber) in self.clients.iter_mut() {
+            let msg = Msg::create(MsgKind::ChannelData, 0, *key, data.to_vec());
+            frame::send(topic, &msg)?;
+            subscriber.stats.sent(data.len());
+        }
+        Ok(())
+    }
+
+    fn handle_message(
+        &mut self,
+        msg: &Msg,
+        config: &Config,
+        topic: &mut Topic,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        DEBUG!(
+            "Receive message kind {} from client {}: {} bytes",
+            msg.kind,
+            msg.client_id,
+            msg.size
+        );
+        if let Some(client) = self.clients.get_mut(&msg.client_id) {
+            client.stats.received();
+        }
+        match msg.kind {
+            MsgKind::ChannelSubscribe => {
+                let Some(Subscription {
+                    user,
+                    session,
+                    context: _context,
+                }) = clients::subscribe(msg, config, topic, |_| {
+                    Ok(self
+                        .clients
+                        .remove(&msg.client_id)
+                        .map(|old| old.user.clone()))
+                })?
+                else {
+                    return Ok(());
+                };
+                if !self.make_room(msg.client_id, config, topic)? {
+                    return Ok(());
+                }
+                let span = clients::span(&config.topic, msg.client_id, &user, &session);
+                audit::log(config, msg.client_id, &user, &session, Event::Subscribe);
+                self.clients.insert(
+                    msg.client_id,
+                    Subscriber {
+                        user,
+                        session,
+                        stats: Stats::default(),
+                        span: Some(span),
+                    },
+                );
+                INFO!(
+                    "Client {} subscribe to channel {}",
+                    msg.client_id,
+                    &config.topic
+                );
+                clients::welcome(msg.client_id, config, topic)?;
+                // catch up with the recent lines
+                if let Some(data) = self.replay.content() {
+                    let msg = Msg::create(MsgKind::ChannelData, 0, msg.client_id, data);
+                    frame::send(topic, &msg)?;
+                }
+            }
+            MsgKind::ChannelUnsubscribe => {
+                self.unsubscribe(msg.client_id, config, topic)?;
+            }
+            MsgKind::ChannelUnsubscribeAll => self.unsubscribe_all(config, topic)?,
+            MsgKind::ChannelData => {
+                frame::send(topic, &frame::error(msg.client_id, "Read-only channel"))?;
+            }
+            MsgKind::ChannelCtrl if stats::is_query(&msg.data) => {
+                self.status(msg.client_id, topic)?;
+            }
+            _ => {
+                WARN!(
+                    "Receive message kind {} from client {}",
+                    msg.kind,
+                    msg.client_id
+                );
+            }
+        };
+        Ok(())
+    }
+}
+
+impl Roster for Tail {
+    type Client = Subscriber;
+
+    fn table(&self) -> &HashMap<u16, Subscriber> {
+        &self.clients
+    }
+
+    fn table_mut(&mut self) -> &mut HashMap<u16, Subscriber> {
+        &mut self.clients
+    }
+}
+
+impl Backend for Tail {
+    fn fds(&self) -> Vec<RawFd> {
+        vec![self.inotify.as_raw_fd()]
+    }
+
+    fn clients(&self) -> usize {
+        self.clients.len()
+    }
+
+    fn dump(&self) -> Vec<String> {
+        let mut lines = vec![format!(
+            "{} client(s), file {} at offset {}, {} bytes out, {} bytes of replay",
+            self.clients.len(),
+            self.path.display(),
+            match self.followed.as_ref() {
+                Some(f) => f.offset.to_string(),
+                None => String::from("none"),
+            },
+            self.bytes_out,
+            self.replay.size()
+        )];
+        let mut keys: Vec<&u16> = self.clients.keys().collect();
+        keys.sort();
+        for key in keys {
+            let s = &self.clients[key];
+            lines.push(format!(
+                "client {}: user {}, {}",
+                key,
+                s.user,
+                s.stats.describe()
+            ));
+        }
+        lines
+    }
+
+    fn step_handle(
+        &mut self,
+        evt: &CallbackEvent,
+        config: &Config,
+        topic: &mut Topic,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if let Some(msg) = evt.msg {
+            self.scoped(msg, config, topic, |tail, topic| {
+                tail.handle_message(msg, config, topic)
+            })?;
+        }
+        // the file is also checked on each step, in case an
+        // event was missed, e.g. on a remote file system
+        if let Some(lines) = self.poll(config)? {
+            self.send(&lines, topic)?;
+        }
+        self.enforce_windows(config, topic)?;
+        self.reap_idle(config, topic)
+    }
+
+    fn children_exited(
+        &mut self,
+        _config: &Config,
+        _topic: &mut Topic,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        Ok(())
+    }
+
+    fn disconnected(&mut self, _config: &Config) {
+        self.clients.clear();
+    }
+
+    fn step_offline(&mut self, config: &Config) {
+        // the lines go to the replay buffer, for the clients
+        // subscribing once the hub is back
+        if let Err(error) = self.poll(config) {
+            WARN!("Unable to read {}: {}", self.path.display(), error);
+        }
+    }
+
+    fn shutdown(
+        &mut self,
+        _config: &Config,
+        topic: &mut Topic,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.notify_shutdown(topic)?;
+        self.clients.clear();
+        Ok(())
+    }
+}
@@ -0,0 +1,221 @@
+//! # OpenTelemetry tracing
+//!
+//! The sessions are traced as spans: a `session` span from the
+//! subscription to the unsubscription of a client, with `spawn`
+//! and `process` child spans for each of its processes. With
+//! `otlp_endpoint`, the ended spans are exported in batches to
+//! an OTLP/HTTP collector, in the JSON encoding, see
+//! [`crate::http`]
+//!
+//! **Author**: "Dany LE"
+//!
+use crate::config::Config;
+use crate::http::{self, Batching, Endpoint};
+use serde_json::{json, Value};
+use std::sync::mpsc::SyncSender;
+use std::sync::OnceLock;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// interval between two exports
+const EXPORT_INTERVAL: Duration = Duration::from_secs(5);
+/// spans exported at most at once
+const MAX_BATCH: usize = 512;
+/// path of the endpoint when not given
+pub const TRACES_PATH: &str = "/v1/traces";
+
+/// queue of the exporter thread, none when not started
+static EXPORTER: OnceLock<SyncSender<Value>> = OnceLock::new();
+
+/// Value of a span attribute
+pub enum Attr {
+    Str(String),
+    Int(i64),
+    Bool(bool),
+}
+
+impl From<&str> for Attr {
+    fn from(v: &str) -> Attr {
+        Attr::Str(String::from(v))
+    }
+}
+
+impl From<String> for Attr {
+    fn from(v: String) -> Attr {
+        Attr::Str(v)
+    }
+}
+
+impl From<i64> for Attr {
+    fn from(v: i64) -> Attr {
+        Attr::Int(v)
+    }
+}
+
+impl From<bool> for Attr {
+    fn from(v: bool) -> Attr {
+        Attr::Bool(v)
+    }
+}
+
+impl Attr {
+    fn to_json(&self) -> Value {
+        match self {
+            Attr::Str(v) => json!({ "stringValue": v }),
+            // int64 values are strings in the JSON encoding
+            Attr::Int(v) => json!({ "intValue": v.to_string() }),
+            Attr::Bool(v) => json!({ "boolValue": v }),
+        }
+    }
+}
+
+/// A span, exported once ended
+pub struct Span {
+    trace_id: [u8; 16],
+    span_id: [u8; 8],
+    parent: Option<[u8; 8]>,
+    name: &'static str,
+    start: u64,
+    attributes: Vec<(&'static str, Attr)>,
+    error: Option<String>,
+}
+
+impl Span {
+    /// Start the root span of a new trace
+    pub fn root(name: &'static str) -> Span {
+        let mut trace_id = [0; 16];
+        random(&mut trace_id);
+        Span::start(name, trace_id, None)
+    }
+
+    /// Start a span in the trace of this one
+    pub fn child(&self, name: &'static str) -> Span {
+        Span::start(name, self.trace_id, Some(self.span_id))
+    }
+
+    fn start(name: &'static str, trace_id: [u8; 16], parent: Option<[u8; 8]>) -> Span {
+        let mut span_id = [0; 8];
+        random(&mut span_id);
+        Span {
+            trace_id,
+            span_id,
+            parent,
+            name,
+            start: now(),
+            attributes: Vec::new(),
+            error: None,
+        }
+    }
+
+    pub fn set(&mut self, key: &'static str, value: impl Into<Attr>) {
+        self.attributes.push((key, value.into()));
+    }
+
+    /// Mark the span as failed
+    pub fn fail(&mut self, message: &str) {
+        self.error = Some(String::from(message));
+    }
+
+    /// End the span and queue it for the export
+    pub fn end(self) {
+        let exporter = match EXPORTER.get() {
+            None => return,
+            Some(exporter) => exporter,
+        };
+        let attributes: Vec<Value> = self
+            .attributes
+            .iter()
+            .map(|(k, v)| json!({ "key": k, "value": v.to_json() }))
+            .collect();
+        let status = match self.error.as_ref() {
+            // STATUS_CODE_ERROR
+            Some(message) => json!({ "code": 2, "message": message }),
+            None => json!({}),
+        };
+        let mut span = json!({
+            "traceId": hex(&self.trace_id),
+            "spanId": hex(&self.span_id),
+            "name": self.name,
+            // SPAN_KIND_INTERNAL
+            "kind": 1,
+            "startTimeUnixNano": self.start.to_string(),
+            "endTimeUnixNano": now().to_string(),
+            "attributes": attributes,
+            "status": status,
+        });
+        if let Some(parent) = self.parent {
+            span["parentSpanId"] = json!(hex(&parent));
+        }
+        // the span is dropped when the queue is full
+        let _ = exporter.try_send(span);
+    }
+}
+
+/// Start the exporter thread when `otlp_endpoint` is set
+pub fn init(config: &Config) -> Result<(), Box<dyn std::error::Error>> {
+    let endpoint = match config.otlp_endpoint.as_ref() {
+        None => return Ok(()),
+        Some(endpoint) => endpoint,
+    };
+    let endpoint = Endpoint::parse(endpoint, TRACES_PATH)?;
+    let resource = json!({
+        "attributes": [
+            { "key": "service.name", "value": { "stringValue": env!("CARGO_PKG_NAME") } },
+            { "key": "service.version", "value": { "stringValue": env!("CARGO_PKG_VERSION") } },
+            { "key": "antd.topic", "value": { "stringValue": config.topic } },
+        ]
+    });
+    let batching = Batching {
+        interval: EXPORT_INTERVAL,
+        max_batch: MAX_BATCH,
+        retries: 0,
+    };
+    let sender = http::start("otlp", endpoint, batching, move |spans| {
+        json!({
+            "resourceSpans": [{
+                "resource": resource,
+                "scopeSpans": [{
+                    "scope": { "name": env!("CARGO_PKG_NAME") },
+                    "spans": spans,
+                }],
+            }],
+        })
+    })?;
+    let _ = EXPORTER.set(sender);
+    Ok(())
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_nanos() as u64)
+}
+
+/// New identifier of a session, a random UUID joining the log
+/// and audit records, the hook invocations and the history
+/// entries of the session
+pub fn session_id() -> String {
+    let mut id = [0u8; 16];
+    random(&mut id);
+    // version 4, variant 1
+    id[6] = (id[6] & 0x0f) | 0x40;
+    id[8] = (id[8] & 0x3f) | 0x80;
+    let id = hex(&id);
+    format!(
+        "{}-{}-{}-{}-{}",
+        &id[..8],
+        &id[8..12],
+        &id[12..16],
+        &id[16..20],
+        &id[20..]
+    )
+}
+
+fn random(buf: &mut [u8]) {
+    unsafe {
+        libc::getrandom(buf.as_mut_ptr() as *mut libc::c_void, buf.len(), 0);
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
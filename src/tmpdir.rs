@@ -0,0 +1,91 @@
+//! # Temporary directory of a session
+//!
+//! Each session of `shp2p` may get its own `TMPDIR`, private
+//! to its user and removed when the session ends, optionally
+//! on a tmpfs of limited size
+//!
+//! **Author**: "Dany LE"
+//!
+use std::ffi::CString;
+use std::fs::{self, DirBuilder};
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::DirBuilderExt;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// A temporary directory, removed when dropped
+pub struct TmpDir {
+    path: PathBuf,
+    /// whether a tmpfs is mounted on the directory
+    mounted: bool,
+}
+
+impl TmpDir {
+    /// Create a directory under `base`, owned by `owner` (uid
+    /// and gid) when set. With `size`, a tmpfs of that many bytes
+    /// is mounted on it, which requires `CAP_SYS_ADMIN`
+    pub fn create(
+        base: &Path,
+        name: &str,
+        owner: Option<(libc::uid_t, libc::gid_t)>,
+        size: Option<u64>,
+    ) -> Result<TmpDir, Box<dyn std::error::Error>> {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.subsec_nanos());
+        let path = base.join(format!("{}-{}-{}", name, std::process::id(), nanos));
+        fs::create_dir_all(base)?;
+        DirBuilder::new().mode(0o700).create(&path)?;
+        let mut dir = TmpDir {
+            path,
+            mounted: false,
+        };
+        if let Some(size) = size {
+            let (uid, gid) = owner.unwrap_or_else(|| unsafe { (libc::getuid(), libc::getgid()) });
+            let options = format!("size={},mode=0700,uid={},gid={}", size, uid, gid);
+            dir.mount(&options)
+                .map_err(|e| format!("Unable to mount a tmpfs on {}: {}", dir.path.display(), e))?;
+            dir.mounted = true;
+        } else if let Some((uid, gid)) = owner {
+            std::os::unix::fs::chown(&dir.path, Some(uid), Some(gid))?;
+        }
+        Ok(dir)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn mount(&self, options: &str) -> io::Result<()> {
+        let target = CString::new(self.path.as_os_str().as_bytes())?;
+        let options = CString::new(options)?;
+        let fstype = CString::new("tmpfs")?;
+        let res = unsafe {
+            libc::mount(
+                fstype.as_ptr(),
+                target.as_ptr(),
+                fstype.as_ptr(),
+                libc::MS_NOSUID | libc::MS_NODEV,
+                options.as_ptr() as *const libc::c_void,
+            )
+        };
+        if res < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+impl Drop for TmpDir {
+    fn drop(&mut self) {
+        if self.mounted {
+            if let Ok(target) = CString::new(self.path.as_os_str().as_bytes()) {
+                unsafe {
+                    libc::umount2(target.as_ptr(), libc::MNT_DETACH);
+                }
+            }
+        }
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
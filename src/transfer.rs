@@ -0,0 +1,1131 @@
+//! # File transfer backend
+//!
+//! Instead of running a command, the clients of `shfile` read
+//! and write the files below a root directory of their user,
+//! chunk by chunk with a CRC-32 checksum. The requests and
+//! their answers are JSON control frames, the content of the
+//! chunks follows them in data frames, e.g.
+//!
+//! ```text
+//! -> ctrl {"op": "read", "path": "logs/boot.log", "offset": 0}
+//! <- ctrl {"chunk": {"path": "logs/boot.log", "offset": 0, "size": 65536, "crc32": 3632233996, "eof": false}}
+//! <- data <65536 bytes>
+//! -> ctrl {"op": "write", "path": "notes.txt", "offset": 0, "size": 12, "crc32": 2936552237}
+//! -> data <12 bytes>
+//! <- ctrl {"written": {"path": "notes.txt", "offset": 0, "size": 12}}
+//! ```
+//!
+//! A download streams a range of a file chunk by chunk, a few
+//! chunks per step so that the other clients are still served.
+//! The chunks are numbered from 0 and an interrupted download
+//! is resumed with a new request at the offset of the first
+//! chunk missing, the file must not have been modified since:
+//!
+//! ```text
+//! -> ctrl {"op": "download", "path": "disk.img", "offset": 0}
+//! <- ctrl {"download": {"path": "disk.img", "offset": 0, "length": 1048576, "size": 1048576, "modified": 1700000000}}
+//! <- ctrl {"chunk": {"path": "disk.img", "seq": 0, "offset": 0, "size": 65536, "crc32": 1425479962, "done": false}}
+//! <- data <65536 bytes>
+//! ...
+//! -> ctrl {"op": "download", "path": "disk.img", "offset": 655360, "modified": 1700000000}
+//! ```
+//!
+//! An upload is written to a temporary file next to its
+//! destination, which replaces the destination at once on
+//! commit, once the checksum of the whole file is verified:
+//!
+//! ```text
+//! -> ctrl {"op": "upload", "path": "disk.img", "size": 1048576}
+//! <- ctrl {"upload": {"path": "disk.img", "size": 1048576}}
+//! -> ctrl {"op": "chunk", "offset": 0, "size": 65536, "crc32": 1425479962}
+//! -> data <65536 bytes>
+//! <- ctrl {"written": {"path": "disk.img", "offset": 0, "size": 65536}}
+//! ...
+//! -> ctrl {"op": "commit", "crc32": 2711477844}
+//! <- ctrl {"committed": {"path": "disk.img", "size": 1048576, "crc32": 2711477844}}
+//! ```
+//!
+//! **Author**: "Dany LE"
+//!
+use crate::account::Account;
+use crate::audit::{self, Event};
+use crate::backend::Backend;
+use crate::clients::{self, Roster, Subscription};
+use crate::config::Config;
+use crate::error::{Class, Classify};
+use crate::frame;
+use crate::log::{self, Level};
+use crate::metrics::{self, Counter};
+use crate::stats::{self, Stats};
+use crate::telemetry::Span;
+use crate::{DEBUG, INFO, WARN};
+use latpr::tunnel::{CallbackEvent, Msg, MsgKind, Topic};
+use latpr::utils::{LogLevel, LOG};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::os::unix::fs::{FileExt, OpenOptionsExt};
+use std::path::{Component, Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+/// default size of the chunks
+const CHUNK_SIZE: usize = 64 * 1024;
+/// chunks of a download sent per step
+const DOWNLOAD_WINDOW: usize = 16;
+
+/// Settings of the file transfer mode, e.g.
+///
+/// ```toml
+/// [file]
+/// root = "/srv/files/{user}"
+/// read_only = true
+///
+/// [file.upload_limits]
+/// admin = 10737418240
+/// ```
+#[derive(Deserialize, Serialize, Default, Debug, Clone, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct FileSettings {
+    /// directory of the files of a client, `{user}` is replaced
+    /// with the name of its user
+    pub root: String,
+    /// whether the clients can only read the files
+    pub read_only: bool,
+    /// largest chunk read or written at once, 64 KiB when not set
+    pub chunk_size: Option<usize>,
+    /// largest file a client can write, no limit when not set
+    pub max_file_size: Option<u64>,
+    /// largest upload of a client, `max_file_size` when not set
+    pub max_upload_size: Option<u64>,
+    /// largest upload per user, overriding `max_upload_size`
+    pub upload_limits: HashMap<String, u64>,
+}
+
+impl FileSettings {
+    pub fn chunk_size(&self) -> usize {
+        self.chunk_size.unwrap_or(CHUNK_SIZE)
+    }
+
+    /// Largest upload of a user, if any
+    pub fn max_upload_size(&self, user: &str) -> Option<u64> {
+        self.upload_limits
+            .get(user)
+            .copied()
+            .or(self.max_upload_size)
+            .or(self.max_file_size)
+    }
+
+    /// Root directory of the files of a user
+    fn root_of(&self, user: &str) -> Result<PathBuf, Box<dyn std::error::Error>> {
+        // the name of the user must not walk the tree
+        if user.is_empty() || user == "." || user == ".." || user.contains('/') {
+            return Err(format!("Invalid user name: {}", user).into());
+        }
+        let root = PathBuf::from(self.root.replace("{user}", user));
+        Ok(root
+            .canonicalize()
+            .map_err(|e| format!("{}: {}", root.display(), e))?)
+    }
+}
+
+/// A request of a client, in a control frame
+#[derive(Deserialize)]
+#[serde(tag = "op", rename_all = "kebab-case")]
+enum Request {
+    Stat {
+        path: String,
+    },
+    List {
+        #[serde(default)]
+        path: String,
+    },
+    Read {
+        path: String,
+        #[serde(default)]
+        offset: u64,
+        /// the chunk size when not set
+        length: Option<usize>,
+    },
+    /// the content follows in data frames
+    Write {
+        path: String,
+        #[serde(default)]
+        offset: u64,
+        size: usize,
+        crc32: u32,
+    },
+    /// the chunks are streamed until `length` bytes are sent
+    Download {
+        path: String,
+        #[serde(default)]
+        offset: u64,
+        /// up to the end of the file when not set
+        length: Option<u64>,
+        /// modification time of the file when the download
+        /// started, refused if the file has changed since
+        modified: Option<u64>,
+    },
+    /// stop the download in progress
+    Cancel,
+    /// start an upload of `size` bytes, the chunks follow
+    Upload {
+        path: String,
+        size: u64,
+    },
+    /// a chunk of the upload, the content follows in data frames
+    Chunk {
+        #[serde(default)]
+        offset: u64,
+        size: usize,
+        crc32: u32,
+    },
+    /// replace the destination with the upload
+    Commit {
+        /// of the whole file
+        crc32: u32,
+    },
+    /// drop the upload in progress
+    Abort,
+}
+
+/// A request and its identifier, echoed in the answer
+#[derive(Deserialize)]
+struct Envelope {
+    #[serde(default)]
+    id: Value,
+    #[serde(flatten)]
+    request: Request,
+}
+
+/// A write waiting for its content
+struct Pending {
+    id: Value,
+    /// path of the request
+    name: String,
+    path: PathBuf,
+    offset: u64,
+    size: usize,
+    crc32: u32,
+    data: Vec<u8>,
+    /// whether the chunk is part of an upload
+    upload: bool,
+}
+
+/// An upload being received, the temporary file is removed
+/// unless committed
+struct Upload {
+    /// path of the request
+    name: String,
+    /// destination of the upload
+    path: PathBuf,
+    /// temporary file, next to the destination
+    temp: PathBuf,
+    size: u64,
+}
+
+impl Drop for Upload {
+    fn drop(&mut self) {
+        if let Err(error) = fs::remove_file(&self.temp) {
+            if error.kind() != std::io::ErrorKind::NotFound {
+                WARN!("Unable to remove {}: {}", self.temp.display(), error);
+            }
+        }
+    }
+}
+
+/// A download being streamed
+struct Download {
+    id: Value,
+    /// path of the request
+    name: String,
+    file: File,
+    /// offset of the next chunk
+    offset: u64,
+    /// offset where the download ends
+    end: u64,
+    /// number of the next chunk
+    seq: u64,
+}
+
+pub struct Client {
+    user: String,
+    /// identifier of the session, see [`telemetry::session_id`]
+    session: String,
+    /// canonical root directory of the files of the user
+    root: PathBuf,
+    /// owner of the files created when the backend runs as root
+    owner: Option<(libc::uid_t, libc::gid_t)>,
+    pending: Option<Pending>,
+    download: Option<Download>,
+    upload: Option<Upload>,
+    /// counters of the client
+    stats: Stats,
+    /// span of the subscription, see [`crate::telemetry`]
+    span: Option<Span>,
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        clients::end_span(&mut self.span, &self.stats);
+    }
+}
+
+crate::member!(Client);
+
+impl Client {
+    /// Resolve a path of the client below its root. The path
+    /// may not leave the root, neither with `..` nor through a
+    /// symbolic link
+    fn resolve(&self, name: &str) -> Result<PathBuf, Box<dyn std::error::Error>> {
+        let relative = Path::new(name.trim_start_matches('/'));
+        if !relative
+            .components()
+            .all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
+        {
+            return Err(format!("{}: invalid path", name).into());
+        }
+        let path = self.root.join(relative);
+        // the deepest existing ancestor, the rest is created
+        let mut existing = path.as_path();
+        while fs::symlink_metadata(existing).is_err() {
+            existing = match existing.parent() {
+                Some(parent) => parent,
+                None => break,
+            };
+        }
+        if !existing
+            .canonicalize()
+            .is_ok_and(|real| real.starts_with(&self.root))
+        {
+            return Err(format!("{}: outside of the root directory", name).into());
+        }
+        Ok(path)
+    }
+}
+
+/// Backend of the file transfers
+pub struct FileTransfer {
+    clients: HashMap<u16, Client>,
+}
+
+impl Default for FileTransfer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FileTransfer {
+    pub fn new() -> FileTransfer {
+        FileTransfer {
+            clients: HashMap::new(),
+        }
+    }
+
+    fn handle_message(
+        &mut self,
+        msg: &Msg,
+        config: &Config,
+        topic: &mut Topic,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if !matches!(msg.kind, MsgKind::ChannelData) {
+            DEBUG!(
+                "Receive message kind {} from client {}: {} bytes",
+                msg.kind,
+                msg.client_id,
+                msg.size
+            );
+        } else if let Some((frames, bytes)) =
+            log::throttle(Level::Debug, "receive", msg.client_id, msg.size as usize)
+        {
+            DEBUG!(
+                "Receive {} data frame(s) from client {}: {} bytes",
+                frames,
+                msg.client_id,
+                bytes
+            );
+        }
+        if let Some(client) = self.clients.get_mut(&msg.client_id) {
+            client.stats.received();
+        }
+        match msg.kind {
+            MsgKind::ChannelSubscribe => {
+                let Some(Subscription {
+                    user,
+                    session,
+                    context: _context,
+                }) = clients::subscribe(msg, config, topic, |_| {
+                    Ok(self
+                        .clients
+                        .remove(&msg.client_id)
+                        .map(|old| old.user.clone()))
+                })?
+                else {
+                    return Ok(());
+                };
+                if !self.make_room(msg.client_id, config, topic)? {
+                    return Ok(());
+                }
+                let root = match config.file.root_of(&user) {
+                    Ok(root) => root,
+                    Err(error) => {
+                        WARN!(
+                            "Refuse client {}: no root directory for user {}: {}",
+                            msg.client_id,
+                            &user,
+                            error
+                        );
+                        audit::log(
+                            config,
+                            msg.client_id,
+                            &user,
+                            &session,
+                            Event::Refuse("no root directory"),
+                        );
+                        frame::send(topic, &frame::error(msg.client_id, "No root directory"))?;
+                        return Ok(());
+                    }
+                };
+                // the files created belong to the user
+                let owner = match unsafe { libc::geteuid() } {
+                    0 => Some(Account::lookup(&user)?.ids()),
+                    _ => None,
+                };
+                let span = clients::span(&config.topic, msg.client_id, &user, &session);
+                audit::log(config, msg.client_id, &user, &session, Event::Subscribe);
+                INFO!(
+                    "Client {} subscribe to channel {}, root {}",
+                    msg.client_id,
+                    &config.topic,
+                    root.display()
+                );
+                self.clients.insert(
+                    msg.client_id,
+                    Client {
+                        user,
+                        session,
+                        root,
+                        owner,
+                        pending: None,
+                        download: None,
+                        upload: None,
+                        stats: Stats::default(),
+                        span: Some(span),
+                    },
+                );
+            }
+            MsgKind::ChannelUnsubscribe => {
+                self.unsubscribe(msg.client_id, config, topic)?;
+            }
+            MsgKind::ChannelUnsubscribeAll => self.unsubscribe_all(config, topic)?,
+            MsgKind::ChannelCtrl if stats::is_query(&msg.data) => {
+                self.status(msg.client_id, topic)?;
+            }
+            MsgKind::ChannelCtrl => {
+                let client = match self.clients.get_mut(&msg.client_id) {
+                    None => {
+                        WARN!("Client {} is not in the list", msg.client_id);
+                        return Ok(());
+                    }
+                    Some(client) => client,
+                };
+                let data = match msg.data.last() {
+                    Some(0) => &msg.data[0..msg.data.len() - 1],
+                    _ => &msg.data[..],
+                };
+                let envelope: Envelope = match serde_json::from_slice(data).class(Class::Protocol) {
+                    Ok(envelope) => envelope,
+                    Err(error) => {
+                        WARN!("Invalid request of client {}: {}", msg.client_id, error);
+                        let answer = failure(&Value::Null, &format!("Invalid request: {}", error));
+                        return frame::send(topic, &ctrl(msg.client_id, answer));
+                    }
+                };
+                let id = envelope.id.clone();
+                if let Err(error) = request(msg.client_id, client, envelope, config, topic) {
+                    WARN!("Request of client {} failed: {}", msg.client_id, error);
+                    frame::send(
+                        topic,
+                        &ctrl(msg.client_id, failure(&id, &error.to_string())),
+                    )?;
+                }
+            }
+            MsgKind::ChannelData => {
+                let client = match self.clients.get_mut(&msg.client_id) {
+                    None => {
+                        WARN!("Client {} is not in the list", msg.client_id);
+                        return Ok(());
+                    }
+                    Some(client) => client,
+                };
+                let pending = match client.pending.as_mut() {
+                    None => {
+                        WARN!("Unexpected data from client {}", msg.client_id);
+                        let answer = failure(&Value::Null, "Unexpected data, no write is pending");
+                        return frame::send(topic, &ctrl(msg.client_id, answer));
+                    }
+                    Some(pending) => pending,
+                };
+                pending.data.extend_from_slice(&msg.data);
+                if pending.data.len() < pending.size {
+                    return Ok(());
+                }
+                if let Some(pending) = client.pending.take() {
+                    let id = pending.id.clone();
+                    if let Err(error) = write(msg.client_id, client, pending, config, topic) {
+                        WARN!("Write of client {} failed: {}", msg.client_id, error);
+                        frame::send(
+                            topic,
+                            &ctrl(msg.client_id, failure(&id, &error.to_string())),
+                        )?;
+                    }
+                }
+            }
+            _ => {
+                WARN!(
+                    "Receive message kind {} from client {}",
+                    msg.kind,
+                    msg.client_id
+                );
+            }
+        };
+        Ok(())
+    }
+}
+
+/// Handle a request of a client, the answers are sent by this
+/// function, an error is answered by the caller
+fn request(
+    client_id: u16,
+    client: &mut Client,
+    envelope: Envelope,
+    config: &Config,
+    topic: &mut Topic,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let id = envelope.id;
+    match envelope.request {
+        Request::Stat { path: name } => {
+            let path = client.resolve(&name)?;
+            let meta = fs::metadata(&path).map_err(|e| format!("{}: {}", name, e))?;
+            let body = json!({
+                "path": name,
+                "size": meta.len(),
+                "dir": meta.is_dir(),
+                "modified": modified(&meta),
+            });
+            frame::send(topic, &ctrl(client_id, answer(&id, "stat", body)))
+        }
+        Request::List { path: name } => {
+            let path = client.resolve(&name)?;
+            let mut entries = Vec::new();
+            for entry in fs::read_dir(&path).map_err(|e| format!("{}: {}", name, e))? {
+                let entry = entry?;
+                let meta = match entry.metadata() {
+                    Ok(meta) => meta,
+                    Err(_) => continue,
+                };
+                entries.push(json!({
+                    "name": entry.file_name().to_string_lossy(),
+                    "size": meta.len(),
+                    "dir": meta.is_dir(),
+                }));
+            }
+            entries.sort_by(|a, b| a["name"].as_str().cmp(&b["name"].as_str()));
+            let body = json!({ "path": name, "entries": entries });
+            frame::send(topic, &ctrl(client_id, answer(&id, "list", body)))
+        }
+        Request::Read {
+            path: name,
+            offset,
+            length,
+        } => {
+            let path = client.resolve(&name)?;
+            let file = OpenOptions::new()
+                .read(true)
+                .custom_flags(libc::O_NOFOLLOW)
+                .open(&path)
+                .map_err(|e| format!("{}: {}", name, e))?;
+            let size = file.metadata()?.len();
+            let length = length.unwrap_or(usize::MAX).min(config.file.chunk_size());
+            let data = read_at(&file, offset, length)?;
+            if offset == 0 {
+                let event = Event::Transfer {
+                    op: "read",
+                    path: &path,
+                    offset,
+                    size: data.len() as u64,
+                };
+                audit::log(config, client_id, &client.user, &client.session, event);
+            }
+            let body = json!({
+                "path": name,
+                "offset": offset,
+                "size": data.len(),
+                "crc32": crc32(&data),
+                "eof": offset + data.len() as u64 >= size,
+            });
+            frame::send(topic, &ctrl(client_id, answer(&id, "chunk", body)))?;
+            if !data.is_empty() {
+                client.stats.sent(data.len());
+                metrics::add(Counter::BytesOut, data.len() as u64);
+                frame::send(
+                    topic,
+                    &Msg::create(MsgKind::ChannelData, 0, client_id, data),
+                )?;
+            }
+            Ok(())
+        }
+        Request::Write {
+            path: name,
+            offset,
+            size,
+            crc32,
+        } => {
+            if config.file.read_only {
+                return Err("The files are read-only".into());
+            }
+            let chunk_size = config.file.chunk_size();
+            if size > chunk_size {
+                return Err(format!("Chunk too large, maximum is {} bytes", chunk_size).into());
+            }
+            if let Some(max) = config.file.max_file_size {
+                if offset + size as u64 > max {
+                    return Err(format!("File too large, maximum is {} bytes", max).into());
+                }
+            }
+            let pending = Pending {
+                id,
+                path: client.resolve(&name)?,
+                name,
+                offset,
+                size,
+                crc32,
+                data: Vec::with_capacity(size),
+                upload: false,
+            };
+            // an empty write only creates the file
+            if size == 0 {
+                return write(client_id, client, pending, config, topic);
+            }
+            client.pending = Some(pending);
+            Ok(())
+        }
+        Request::Download {
+            path: name,
+            offset,
+            length,
+            modified: since,
+        } => {
+            if client.download.is_some() {
+                return Err("A download is in progress".into());
+            }
+            let path = client.resolve(&name)?;
+            let file = OpenOptions::new()
+                .read(true)
+                .custom_flags(libc::O_NOFOLLOW)
+                .open(&path)
+                .map_err(|e| format!("{}: {}", name, e))?;
+            let meta = file.metadata()?;
+            if !meta.is_file() {
+                return Err(format!("{}: not a regular file", name).into());
+            }
+            let mtime = modified(&meta);
+            if since.is_some_and(|since| since != mtime) {
+                return Err(format!("{}: modified since the download started", name).into());
+            }
+            if offset > meta.len() {
+                return Err(format!("{}: offset beyond the end of the file", name).into());
+            }
+            let end = match length {
+                Some(length) => offset.saturating_add(length).min(meta.len()),
+                None => meta.len(),
+            };
+            let event = Event::Transfer {
+                op: "download",
+                path: &path,
+                offset,
+                size: end - offset,
+            };
+            audit::log(config, client_id, &client.user, &client.session, event);
+            let body = json!({
+                "path": name,
+                "offset": offset,
+                "length": end - offset,
+                "size": meta.len(),
+                "modified": mtime,
+            });
+            frame::send(topic, &ctrl(client_id, answer(&id, "download", body)))?;
+            client.download = Some(Download {
+                id,
+                name,
+                file,
+                offset,
+                end,
+                seq: 0,
+            });
+            Ok(())
+        }
+        Request::Cancel => {
+            let download = client.download.take().ok_or("No download in progress")?;
+            DEBUG!(
+                "Client {} cancelled the download of {} at offset {}",
+                client_id,
+                download.name,
+                download.offset
+            );
+            let body = json!({ "path": download.name, "offset": download.offset });
+            frame::send(topic, &ctrl(client_id, answer(&id, "cancelled", body)))
+        }
+        Request::Upload { path: name, size } => {
+            if config.file.read_only {
+                return Err("The files are read-only".into());
+            }
+            if client.upload.is_some() {
+                return Err("An upload is in progress".into());
+            }
+            if let Some(max) = config.file.max_upload_size(&client.user) {
+                if size > max {
+                    return Err(format!("Upload too large, maximum is {} bytes", max).into());
+                }
+            }
+            let path = client.resolve(&name)?;
+            if fs::symlink_metadata(&path).is_ok_and(|meta| !meta.is_file()) {
+                return Err(format!("{}: not a regular file", name).into());
+            }
+            let file_name = path
+                .file_name()
+                .ok_or_else(|| format!("{}: invalid path", name))?;
+            // renamed into place on the same filesystem
+            let temp = path.with_file_name(format!(
+                ".{}.{}.upload",
+                file_name.to_string_lossy(),
+                client_id
+            ));
+            let file = OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .mode(0o600)
+                .custom_flags(libc::O_NOFOLLOW)
+                .open(&temp)
+                .map_err(|e| format!("{}: {}", name, e))?;
+            let upload = Upload {
+                name,
+                path,
+                temp,
+                size,
+            };
+            if let Some((uid, gid)) = client.owner {
+                std::os::unix::fs::fchown(&file, Some(uid), Some(gid))?;
+            }
+            DEBUG!(
+                "Client {} uploads {} bytes to {}",
+                client_id,
+                size,
+                upload.temp.display()
+            );
+            let body = json!({ "path": upload.name, "size": size });
+            client.upload = Some(upload);
+            frame::send(topic, &ctrl(client_id, answer(&id, "upload", body)))
+        }
+        Request::Chunk {
+            offset,
+            size,
+            crc32,
+        } => {
+            let upload = client.upload.as_ref().ok_or("No upload in progress")?;
+            let chunk_size = config.file.chunk_size();
+            if size > chunk_size {
+                return Err(format!("Chunk too large, maximum is {} bytes", chunk_size).into());
+            }
+            if offset + size as u64 > upload.size {
+                return Err(
+                    format!("Chunk beyond the size of the upload, {} bytes", upload.size).into(),
+                );
+            }
+            let pending = Pending {
+                id,
+                name: upload.name.clone(),
+                path: upload.temp.clone(),
+                offset,
+                size,
+                crc32,
+                data: Vec::with_capacity(size),
+                upload: true,
+            };
+            if size == 0 {
+                return write(client_id, client, pending, config, topic);
+            }
+            client.pending = Some(pending);
+            Ok(())
+        }
+        Request::Commit { crc32: expected } => {
+            let upload = client.upload.as_ref().ok_or("No upload in progress")?;
+            if client.pending.is_some() {
+                return Err(format!("{}: a chunk is being received", upload.name).into());
+            }
+            let file = OpenOptions::new()
+                .read(true)
+                .custom_flags(libc::O_NOFOLLOW)
+                .open(&upload.temp)
+                .map_err(|e| format!("{}: {}", upload.name, e))?;
+            let received = file.metadata()?.len();
+            if received != upload.size {
+                return Err(format!(
+                    "{}: {} bytes received, {} expected",
+                    upload.name, received, upload.size
+                )
+                .into());
+            }
+            let chunk_size = config.file.chunk_size();
+            let mut checksum = 0;
+            let mut offset = 0;
+            while offset < received {
+                let data = read_at(&file, offset, chunk_size)?;
+                if data.is_empty() {
+                    break;
+                }
+                checksum = crc32_update(checksum, &data);
+                offset += data.len() as u64;
+            }
+            if checksum != expected {
+                return Err(format!("{}: checksum mismatch", upload.name).into());
+            }
+            file.sync_all()?;
+            fs::rename(&upload.temp, &upload.path)
+                .map_err(|e| format!("{}: {}", upload.name, e))?;
+            let event = Event::Transfer {
+                op: "upload",
+                path: &upload.path,
+                offset: 0,
+                size: upload.size,
+            };
+            audit::log(config, client_id, &client.user, &client.session, event);
+            INFO!(
+                "Client {} uploaded {} bytes to {}",
+                client_id,
+                upload.size,
+                upload.path.display()
+            );
+            let body = json!({
+                "path": upload.name,
+                "size": upload.size,
+                "crc32": checksum,
+            });
+            // the temporary file is gone with the rename
+            client.upload = None;
+            frame::send(topic, &ctrl(client_id, answer(&id, "committed", body)))
+        }
+        Request::Abort => {
+            let upload = client.upload.take().ok_or("No upload in progress")?;
+            if client.pending.as_ref().is_some_and(|p| p.upload) {
+                client.pending = None;
+            }
+            DEBUG!("Client {} aborted the upload of {}", client_id, upload.name);
+            let body = json!({ "path": upload.name });
+            frame::send(topic, &ctrl(client_id, answer(&id, "aborted", body)))
+        }
+    }
+}
+
+/// Send the next chunks of the download of a client, the
+/// download ends with the chunk that reaches its end
+fn stream(
+    client_id: u16,
+    client: &mut Client,
+    config: &Config,
+    topic: &mut Topic,
+) -> Result<(), Box<dyn std::error::Error>> {
+    for _ in 0..DOWNLOAD_WINDOW {
+        let Some(download) = client.download.as_mut() else {
+            return Ok(());
+        };
+        let length = (download.end - download.offset).min(config.file.chunk_size() as u64);
+        let data = read_at(&download.file, download.offset, length as usize)
+            .map_err(|e| format!("{}: {}", download.name, e))?;
+        // the file has been truncated while it is downloaded
+        if data.len() as u64 != length {
+            return Err(format!("{}: truncated during the download", download.name).into());
+        }
+        let done = download.offset + length >= download.end;
+        let body = json!({
+            "path": download.name,
+            "seq": download.seq,
+            "offset": download.offset,
+            "size": data.len(),
+            "crc32": crc32(&data),
+            "done": done,
+        });
+        frame::send(topic, &ctrl(client_id, answer(&download.id, "chunk", body)))?;
+        download.offset += length;
+        download.seq += 1;
+        if !data.is_empty() {
+            client.stats.sent(data.len());
+            metrics::add(Counter::BytesOut, data.len() as u64);
+            frame::send(
+                topic,
+                &Msg::create(MsgKind::ChannelData, 0, client_id, data),
+            )?;
+        }
+        if done {
+            client.download = None;
+        }
+    }
+    Ok(())
+}
+
+/// Write the content of a chunk once received
+fn write(
+    client_id: u16,
+    client: &mut Client,
+    pending: Pending,
+    config: &Config,
+    topic: &mut Topic,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if pending.data.len() != pending.size {
+        return Err(format!(
+            "{}: {} bytes received, {} expected",
+            pending.name,
+            pending.data.len(),
+            pending.size
+        )
+        .into());
+    }
+    if crc32(&pending.data) != pending.crc32 {
+        return Err(format!("{}: checksum mismatch", pending.name).into());
+    }
+    let created = fs::symlink_metadata(&pending.path).is_err();
+    let file = OpenOptions::new()
+        .write(true)
+        // the temporary file of an upload is created by the upload
+        .create(!pending.upload)
+        .truncate(false)
+        .custom_flags(libc::O_NOFOLLOW)
+        .open(&pending.path)
+        .map_err(|e| format!("{}: {}", pending.name, e))?;
+    if let (true, Some((uid, gid))) = (created, client.owner) {
+        std::os::unix::fs::fchown(&file, Some(uid), Some(gid))?;
+    }
+    file.write_all_at(&pending.data, pending.offset)?;
+    client.stats.bytes_in += pending.size as u64;
+    metrics::add(Counter::BytesIn, pending.size as u64);
+    // an upload is audited once committed
+    if pending.offset == 0 && !pending.upload {
+        let event = Event::Transfer {
+            op: "write",
+            path: &pending.path,
+            offset: pending.offset,
+            size: pending.size as u64,
+        };
+        audit::log(config, client_id, &client.user, &client.session, event);
+    }
+    let body = json!({
+        "path": pending.name,
+        "offset": pending.offset,
+        "size": pending.size,
+    });
+    frame::send(
+        topic,
+        &ctrl(client_id, answer(&pending.id, "written", body)),
+    )
+}
+
+/// Modification time of a file in seconds since the epoch
+fn modified(meta: &fs::Metadata) -> u64 {
+    meta.modified()
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |d| d.as_secs())
+}
+
+/// Read at most `length` bytes at an offset, less at the end
+/// of the file
+fn read_at(file: &File, offset: u64, length: usize) -> std::io::Result<Vec<u8>> {
+    let mut data = vec![0; length];
+    let mut n = 0;
+    while n < length {
+        match file.read_at(&mut data[n..], offset + n as u64)? {
+            0 => break,
+            read => n += read,
+        }
+    }
+    data.truncate(n);
+    Ok(data)
+}
+
+/// An answer, e.g. `{"stat": {...}, "id": 3}`, the identifier
+/// of the request is left out when it has none
+fn answer(id: &Value, kind: &str, body: Value) -> Value {
+    let mut answer = json!({ kind: body });
+    if !id.is_null() {
+        answer["id"] = id.clone();
+    }
+    answer
+}
+
+/// A failed request, e.g. `{"error": {"message": "..."}}`
+fn failure(id: &Value, message: &str) -> Value {
+    answer(id, "error", json!({ "message": message }))
+}
+
+fn ctrl(client_id: u16, body: Value) -> Msg {
+    Msg::create(
+        MsgKind::ChannelCtrl,
+        0,
+        client_id,
+        body.to_string().into_bytes(),
+    )
+}
+
+/// CRC-32 (IEEE) of some data, as computed by zlib
+pub fn crc32(data: &[u8]) -> u32 {
+    crc32_update(0, data)
+}
+
+/// CRC-32 of some data following the data whose CRC-32 is
+/// `crc`, as `crc32(crc, data)` of zlib
+pub fn crc32_update(crc: u32, data: &[u8]) -> u32 {
+    const TABLE: [u32; 256] = {
+        let mut table = [0u32; 256];
+        let mut i = 0;
+        while i < 256 {
+            let mut c = i as u32;
+            let mut k = 0;
+            while k < 8 {
+                c = if c & 1 != 0 {
+                    0xedb8_8320 ^ (c >> 1)
+                } else {
+                    c >> 1
+                };
+                k += 1;
+            }
+            table[i] = c;
+            i += 1;
+        }
+        table
+    };
+    !data.iter().fold(!crc, |crc, b| {
+        TABLE[((crc ^ *b as u32) & 0xff) as usize] ^ (crc >> 8)
+    })
+}
+
+impl Roster for FileTransfer {
+    type Client = Client;
+
+    fn table(&self) -> &HashMap<u16, Client> {
+        &self.clients
+    }
+
+    fn table_mut(&mut self) -> &mut HashMap<u16, Client> {
+        &mut self.clients
+    }
+
+    // a client receiving a download is not idle
+    fn busy(&self, client_id: u16) -> bool {
+        self.clients[&client_id].download.is_some()
+    }
+}
+
+impl Backend for FileTransfer {
+    fn clients(&self) -> usize {
+        self.clients.len()
+    }
+
+    fn dump(&self) -> Vec<String> {
+        let mut lines = vec![format!("{} client(s)", self.clients.len())];
+        let mut keys: Vec<&u16> = self.clients.keys().collect();
+        keys.sort();
+        for key in keys {
+            let c = &self.clients[key];
+            lines.push(format!(
+                "client {}: user {}, root {}, {}{}{}",
+                key,
+                c.user,
+                c.root.display(),
+                c.stats.describe(),
+                match (c.pending.as_ref(), c.download.as_ref()) {
+                    (Some(p), _) => format!(", writing {}", p.name),
+                    (None, Some(d)) => format!(", downloading {} at {}", d.name, d.offset),
+                    (None, None) => String::new(),
+                },
+                match c.upload.as_ref() {
+                    Some(u) => format!(", uploading {} ({} bytes)", u.name, u.size),
+                    None => String::new(),
+                }
+            ));
+        }
+        lines
+    }
+
+    fn step_handle(
+        &mut self,
+        evt: &CallbackEvent,
+        config: &Config,
+        topic: &mut Topic,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if let Some(msg) = evt.msg {
+            self.scoped(msg, config, topic, |filetransfer, topic| {
+                filetransfer.handle_message(msg, config, topic)
+            })?;
+        }
+        for (key, client) in self.clients.iter_mut() {
+            let Some(id) = client.download.as_ref().map(|d| d.id.clone()) else {
+                continue;
+            };
+            if let Err(error) = stream(*key, client, config, topic) {
+                WARN!("Download of client {} failed: {}", key, error);
+                client.download = None;
+                frame::send(topic, &ctrl(*key, failure(&id, &error.to_string())))?;
+            }
+        }
+        self.enforce_windows(config, topic)?;
+        self.reap_idle(config, topic)
+    }
+
+    fn children_exited(
+        &mut self,
+        _config: &Config,
+        _topic: &mut Topic,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        // the hooks are the only processes, reaped by the caller
+        Ok(())
+    }
+
+    fn disconnected(&mut self, _config: &Config) {
+        self.clients.clear();
+    }
+
+    fn shutdown(
+        &mut self,
+        _config: &Config,
+        topic: &mut Topic,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.notify_shutdown(topic)?;
+        self.clients.clear();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crc32_check_value() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+        assert_eq!(
+            crc32(b"The quick brown fox jumps over the lazy dog"),
+            0x414f_a339
+        );
+    }
+
+    #[test]
+    fn crc32_update_in_chunks() {
+        let data = b"The quick brown fox jumps over the lazy dog";
+        for split in 0..=data.len() {
+            let (head, tail) = data.split_at(split);
+            assert_eq!(crc32_update(crc32(head), tail), crc32(data));
+        }
+    }
+}
@@ -0,0 +1,140 @@
+//! # Generation of systemd service units
+//!
+//! **Author**: "Dany LE"
+//!
+use crate::cli::Cli;
+use crate::config::{Config, Mode};
+use std::env;
+use std::fmt::Write;
+use std::fs;
+use toml::Value;
+
+/// settings of the command line holding a path
+const PATHS: [&str; 4] = ["banner_file", "init_file", "health_socket", "admin_socket"];
+
+/// Build a systemd service unit running the backend with the
+/// current configuration. The settings given on the command
+/// line and by the `ANTD_SH_*` variables are kept, the former
+/// as variables too but for the mode, the socket, the topic
+/// and the command. The configuration file is referenced by
+/// its absolute path
+pub fn generate(cli: &Cli, user: Option<&str>) -> Result<String, Box<dyn std::error::Error>> {
+    let config = Config::load(cli)?;
+    let exe = env::current_exe()?;
+    let mut args = vec![exe.to_string_lossy().into_owned()];
+    if let Some(path) = cli.config.as_ref() {
+        args.push(String::from("--config"));
+        args.push(fs::canonicalize(path)?.to_string_lossy().into_owned());
+    }
+    let mode = serde_json::to_value(config.mode)?;
+    args.push(String::from("--mode"));
+    args.push(String::from(mode.as_str().unwrap_or("p2p")));
+    for (flag, value) in [
+        ("--socket", &config.socket),
+        ("--topic", &config.topic),
+        ("--cmd", &config.command),
+    ] {
+        // no command in the modes that run none
+        if value.is_empty() {
+            continue;
+        }
+        args.push(String::from(flag));
+        args.push(value.clone());
+    }
+    let mut unit = String::new();
+    writeln!(unit, "[Unit]")?;
+    writeln!(
+        unit,
+        "Description=Antd tunnel shell script backend ({})",
+        config.topic
+    )?;
+    writeln!(unit, "After=network.target antd.service")?;
+    writeln!(unit)?;
+    writeln!(unit, "[Service]")?;
+    writeln!(unit, "Type=notify")?;
+    writeln!(unit, "WatchdogSec=30")?;
+    let exec: Vec<String> = args.iter().map(|a| quote(a)).collect();
+    writeln!(unit, "ExecStart={}", exec.join(" "))?;
+    writeln!(unit, "ExecReload=/bin/kill -HUP $MAINPID")?;
+    let mut vars: Vec<(String, String)> = env::vars()
+        .filter(|(name, _)| name.starts_with("ANTD_SH_") && name != "ANTD_SH_CONFIG")
+        .collect();
+    for (key, value) in cli.overrides() {
+        let name = format!("ANTD_SH_{}", key.to_uppercase());
+        // the environment takes precedence over the command line
+        if matches!(key.as_str(), "mode" | "socket" | "topic" | "command")
+            || vars.iter().any(|(n, _)| *n == name)
+        {
+            continue;
+        }
+        vars.push((name, raw(&key, &value)?));
+    }
+    vars.sort();
+    for (name, value) in vars {
+        writeln!(
+            unit,
+            "Environment={}",
+            quote(&format!("{}={}", name, value))
+        )?;
+    }
+    writeln!(unit, "Restart=on-failure")?;
+    writeln!(unit, "RestartSec=2")?;
+    // switching to the subscribed users requires root
+    if config.mode != Mode::P2pUser {
+        if let Some(user) = user {
+            writeln!(unit, "User={}", user)?;
+        }
+        writeln!(unit, "NoNewPrivileges=yes")?;
+    }
+    writeln!(unit, "PrivateTmp=yes")?;
+    writeln!(unit, "ProtectSystem=full")?;
+    writeln!(unit, "ProtectKernelTunables=yes")?;
+    writeln!(unit, "ProtectKernelModules=yes")?;
+    writeln!(unit, "ProtectControlGroups=yes")?;
+    writeln!(unit, "RestrictRealtime=yes")?;
+    writeln!(unit)?;
+    writeln!(unit, "[Install]")?;
+    writeln!(unit, "WantedBy=multi-user.target")?;
+    Ok(unit)
+}
+
+/// A setting of the command line as the value of its
+/// `ANTD_SH_*` variable, the paths made absolute as the unit
+/// runs from `/`
+fn raw(key: &str, value: &Value) -> Result<String, Box<dyn std::error::Error>> {
+    Ok(match value {
+        Value::String(s) if PATHS.contains(&key) => {
+            env::current_dir()?.join(s).to_string_lossy().into_owned()
+        }
+        Value::String(s) => s.clone(),
+        Value::Array(items) => items
+            .iter()
+            .map(|v| {
+                v.as_str()
+                    .map(String::from)
+                    .unwrap_or_else(|| v.to_string())
+            })
+            .collect::<Vec<String>>()
+            .join(","),
+        Value::Table(table) => table
+            .iter()
+            .map(|(k, v)| format!("{}={}", k, v.as_str().unwrap_or_default()))
+            .collect::<Vec<String>>()
+            .join(","),
+        other => other.to_string(),
+    })
+}
+
+/// Quote a word of a systemd command line
+fn quote(word: &str) -> String {
+    let escaped = word
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('%', "%%")
+        .replace('$', "$$");
+    if escaped.contains(char::is_whitespace) || escaped != word {
+        format!("\"{}\"", escaped)
+    } else {
+        escaped
+    }
+}
@@ -0,0 +1,303 @@
+//! # Dry run of the configuration
+//!
+//! Check the configuration without connecting to the hub
+//! and print a report of the checks
+//!
+//! **Author**: "Dany LE"
+//!
+use crate::account::{self, Account};
+use crate::cli::Cli;
+use crate::config::{Config, Mode};
+use crate::spawn;
+use std::ffi::CString;
+use std::fs;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::FileTypeExt;
+use std::path::Path;
+
+/// Result of the checks, printed as they are performed
+struct Report {
+    failures: usize,
+}
+
+impl Report {
+    fn check(&mut self, what: &str, result: Result<(), Box<dyn std::error::Error>>) {
+        match result {
+            Ok(()) => println!("[ OK ] {}", what),
+            Err(error) => {
+                self.failures += 1;
+                println!("[FAIL] {}: {}", what, error);
+            }
+        }
+    }
+
+    /// Print a setting that is valid but likely not the intended
+    /// one, without failing the validation
+    fn warn(&self, what: &str, why: &str) {
+        println!("[WARN] {}: {}", what, why);
+    }
+}
+
+/// Validate the configuration, return whether all checks passed
+pub fn run(cli: &Cli) -> bool {
+    let mut report = Report { failures: 0 };
+    let config = match Config::load(cli) {
+        Ok(config) => {
+            report.check("configuration", Ok(()));
+            config
+        }
+        Err(error) => {
+            report.check("configuration", Err(error));
+            return false;
+        }
+    };
+    report.check(
+        &format!("hub socket {}", config.socket),
+        check_socket(Path::new(&config.socket)),
+    );
+    // in the kube mode, the commands are run in the container
+    let local_commands = config.mode != Mode::Kube;
+    if config.mode.runs_command() && local_commands {
+        report.check(
+            &format!("command {}", config.command),
+            check_command(&config, &config.command),
+        );
+    }
+    if config.mode == Mode::Kube {
+        report.check(
+            &format!("kubectl {}", config.kube.kubectl()),
+            check_command(&config, config.kube.kubectl()),
+        );
+    }
+    for path in config.watch.paths.iter() {
+        report.check(
+            &format!("watched path {}", path.display()),
+            fs::metadata(path).map(|_| ()).map_err(|e| e.into()),
+        );
+    }
+    for path in config.sysstats.disks.iter() {
+        report.check(&format!("disk {}", path.display()), check_dir(path));
+    }
+    if config.mode == Mode::Journal {
+        report.check(
+            &format!("journalctl {}", config.journal.journalctl()),
+            check_command(&config, config.journal.journalctl()),
+        );
+    }
+    if config.mode == Mode::Wasm {
+        report.check(
+            &format!("wasmtime {}", config.wasm.wasmtime()),
+            spawn::resolve(config.wasm.wasmtime()).and_then(|path| check_executable(&path)),
+        );
+        for dir in config.wasm.dirs.iter() {
+            let host = dir.split_once("::").map_or(dir.as_str(), |(host, _)| host);
+            report.check(
+                &format!("module directory {}", host),
+                check_dir(Path::new(host)),
+            );
+        }
+    }
+    if config.mode == Mode::File && !config.file.root.contains("{user}") {
+        report.check(
+            &format!("file root {}", config.file.root),
+            check_dir(Path::new(&config.file.root)),
+        );
+    }
+    if let Some(path) = config.sock.path.as_ref() {
+        report.check(
+            &format!("service socket {}", path.display()),
+            check_socket(path),
+        );
+    }
+    for path in [config.fifo.output.as_ref(), config.fifo.input.as_ref()]
+        .into_iter()
+        .flatten()
+    {
+        report.check(&format!("named pipe {}", path.display()), check_fifo(path));
+    }
+    if let Some(dir) = config.tail.path.as_ref().and_then(|p| p.parent()) {
+        report.check(
+            &format!("directory of the followed file {}", dir.display()),
+            check_dir(dir),
+        );
+    }
+    if config.mode == Mode::Playback {
+        if let Some(dir) = config.playback.dir(&config) {
+            report.check(&format!("recordings {}", dir.display()), check_dir(dir));
+        }
+    }
+    for (name, method) in config.rpc.methods.iter() {
+        report.check(
+            &format!("method {} ({})", name, method.command),
+            check_command(&config, &method.command),
+        );
+    }
+    if config.mode == Mode::Git {
+        report.check(
+            &format!("git ({})", config.git.command()),
+            check_command(&config, config.git.command()),
+        );
+        for (name, repo) in config.git.repos.iter().filter(|(_, r)| r.url.is_none()) {
+            report.check(
+                &format!("repository {} ({})", name, repo.path.display()),
+                check_dir(&repo.path),
+            );
+        }
+    }
+    if config.mode == Mode::Svc {
+        report.check(
+            &format!("systemctl ({})", config.svc.command()),
+            check_command(&config, config.svc.command()),
+        );
+        if config.svc.operators.is_empty() && config.svc.operator_groups.is_empty() {
+            report.warn(
+                "svc operators",
+                "none set, the units can only be queried, not started, stopped or restarted",
+            );
+        }
+    }
+    for (name, stream) in config.mux.streams.iter() {
+        report.check(
+            &format!("stream {} ({})", name, stream.command),
+            check_command(&config, &stream.command),
+        );
+    }
+    for (name, cmd) in config.commands.iter().filter(|_| local_commands) {
+        report.check(
+            &format!("menu command {} ({})", name, cmd),
+            check_command(&config, cmd),
+        );
+    }
+    let sections = config
+        .users
+        .iter()
+        .map(|(name, p)| (format!("user {}", name), p))
+        .chain(
+            config
+                .groups
+                .iter()
+                .map(|(name, p)| (format!("group {}", name), p)),
+        );
+    for (section, profile) in sections {
+        if let Some(cmd) = profile.command.as_ref().filter(|_| local_commands) {
+            report.check(
+                &format!("command of {} ({})", section, cmd),
+                check_command(&config, cmd),
+            );
+        }
+    }
+    for path in config.allowed_commands.iter() {
+        report.check(
+            &format!("allowed command {}", path.display()),
+            match config.mode {
+                Mode::Wasm => check_module(path),
+                _ => check_executable(path),
+            },
+        );
+    }
+    if let Some(path) = config.banner_file.as_ref() {
+        report.check(
+            &format!("banner file {}", path.display()),
+            fs::metadata(path).map(|_| ()).map_err(|e| e.into()),
+        );
+    }
+    if let Some(path) = config.init_file.as_ref() {
+        report.check(
+            &format!("init file {}", path.display()),
+            fs::metadata(path).map(|_| ()).map_err(|e| e.into()),
+        );
+    }
+    if config.mode == Mode::P2pUser {
+        report.check("privileges for p2p-user mode", check_root());
+        // the commands are run as the subscribed users, only
+        // those with a section are known here
+        for user in config.users.keys() {
+            let profile = config.profile(user);
+            let cmd = profile.command.as_deref().unwrap_or(&config.command);
+            report.check(
+                &format!("command of user {} ({}) run as {}", user, cmd, user),
+                check_executable_by(user, cmd),
+            );
+        }
+    }
+    println!(
+        "{} check(s) failed for topic {}",
+        report.failures, config.topic
+    );
+    report.failures == 0
+}
+
+fn check_socket(path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    if !fs::metadata(path)?.file_type().is_socket() {
+        return Err("not a socket".into());
+    }
+    Ok(())
+}
+
+fn check_fifo(path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    if !fs::metadata(path)?.file_type().is_fifo() {
+        return Err("not a named pipe".into());
+    }
+    Ok(())
+}
+
+fn check_dir(path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    if !fs::metadata(path)?.is_dir() {
+        return Err("not a directory".into());
+    }
+    Ok(())
+}
+
+/// Check a command run by the backend, a module in the wasm mode
+fn check_command(config: &Config, cmd: &str) -> Result<(), Box<dyn std::error::Error>> {
+    config.allowed_command(cmd)?;
+    let path = spawn::resolve(cmd)?;
+    match config.mode {
+        Mode::Wasm => check_module(&path),
+        _ => check_executable(&path),
+    }
+}
+
+fn check_module(path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    fs::File::open(path)?;
+    if !fs::metadata(path)?.is_file() {
+        return Err("not a file".into());
+    }
+    Ok(())
+}
+
+/// Check that the user running the backend, who runs the
+/// commands, may execute a file
+fn check_executable(path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    if !fs::metadata(path)?.is_file() {
+        return Err("not an executable file".into());
+    }
+    let name = CString::new(path.as_os_str().as_bytes())?;
+    // with the effective ids, as the commands are spawned
+    if unsafe { libc::faccessat(libc::AT_FDCWD, name.as_ptr(), libc::X_OK, libc::AT_EACCESS) } != 0
+    {
+        return Err(format!(
+            "not executable by {}: {}",
+            account::current_user(),
+            std::io::Error::last_os_error()
+        )
+        .into());
+    }
+    Ok(())
+}
+
+/// Check that a user may execute a command
+fn check_executable_by(user: &str, cmd: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let path = spawn::resolve(cmd)?;
+    if !Account::lookup(user)?.can_execute(&path) {
+        return Err(format!("not executable by {}", user).into());
+    }
+    Ok(())
+}
+
+fn check_root() -> Result<(), Box<dyn std::error::Error>> {
+    if unsafe { libc::geteuid() } != 0 {
+        return Err("switching users requires root".into());
+    }
+    Ok(())
+}
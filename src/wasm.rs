@@ -0,0 +1,80 @@
+//! # WASI sandbox backend
+//!
+//! `shwasm` runs a WebAssembly module per client with the
+//! `wasmtime` runtime instead of a native process, as `shp2p`
+//! does with a local command: the module (or the one chosen
+//! from the menu) gets the stdio of the channel and nothing
+//! else unless configured, i.e. no file, no network and only
+//! the variables of the session
+//!
+//! ```text
+//! wasmtime run [--dir=...] [--env=NAME ...] [-W ...] <module> <args>
+//! ```
+//!
+//! **Author**: "Dany LE"
+//!
+use crate::config::Config;
+use crate::spawn;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+
+/// runtime used when none is configured, looked up in `PATH`
+const WASMTIME: &str = "wasmtime";
+
+/// Settings of the WASI sandbox mode, e.g.
+///
+/// ```toml
+/// command = "/srv/wasm/repl.wasm"
+///
+/// [wasm]
+/// dirs = ["/srv/data::/data"]
+/// max_memory_size = 67108864
+/// fuel = 10000000000
+/// ```
+#[derive(Deserialize, Serialize, Default, Debug, Clone, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct WasmSettings {
+    /// wasmtime executable, `wasmtime` in `PATH` by default
+    pub wasmtime: Option<String>,
+    /// directories of the host given to the module, as
+    /// `HOST_DIR[::GUEST_DIR]`
+    pub dirs: Vec<String>,
+    /// bytes of linear memory a module may use
+    pub max_memory_size: Option<u64>,
+    /// units of fuel a module may consume before being trapped,
+    /// roughly one per instruction
+    pub fuel: Option<u64>,
+}
+
+impl WasmSettings {
+    /// wasmtime executable
+    pub fn wasmtime(&self) -> &str {
+        self.wasmtime.as_deref().unwrap_or(WASMTIME)
+    }
+}
+
+/// Executable and arguments of the `wasmtime run` running a
+/// module. The module is checked against the allowlist, the
+/// variables are given by name and taken by wasmtime from its
+/// own environment
+pub fn command_line(
+    config: &Config,
+    module: &str,
+    args: &[String],
+    env: &[String],
+) -> Result<(PathBuf, Vec<String>), Box<dyn std::error::Error>> {
+    let wasmtime = spawn::resolve(config.wasm.wasmtime())?;
+    let module = config.allowed_command(module)?;
+    let mut line = vec![String::from("run")];
+    line.extend(config.wasm.dirs.iter().map(|dir| format!("--dir={}", dir)));
+    line.extend(env.iter().map(|name| format!("--env={}", name)));
+    if let Some(size) = config.wasm.max_memory_size {
+        line.push(format!("-Wmax-memory-size={}", size));
+    }
+    if let Some(fuel) = config.wasm.fuel {
+        line.push(format!("-Wfuel={}", fuel));
+    }
+    line.push(module.display().to_string());
+    line.extend(args.iter().cloned());
+    Ok((wasmtime, line))
+}
@@ -0,0 +1,516 @@
+//! # Filesystem watch backend
+//!
+//! `shwatch` owns no process: the configured paths are watched
+//! with inotify and each change below them is sent to all the
+//! subscribers as a JSON data frame, so that a file manager can
+//! refresh its view without polling, e.g.
+//!
+//! ```json
+//! {"event": "create", "path": "/srv/www/index.html", "dir": false}
+//! {"event": "move", "path": "/srv/www/new", "from": "/srv/www/old", "dir": true}
+//! ```
+//!
+//! The events are `create`, `modify`, `delete` and `move`. A
+//! file moved in from an unwatched directory is created, one
+//! moved out is deleted. `overflow` is sent when the kernel
+//! queue has overflowed, the clients should then list the
+//! directories again
+//!
+//! **Author**: "Dany LE"
+//!
+use crate::audit::{self, Event};
+use crate::backend::Backend;
+use crate::clients::{self, Roster, Subscription};
+use crate::config::Config;
+use crate::error::{Class, Classify};
+use crate::frame;
+use crate::log::{self, Level};
+use crate::metrics::{self, Counter};
+use crate::stats::{self, Stats};
+use crate::telemetry::Span;
+use crate::{DEBUG, INFO, WARN};
+use latpr::tunnel::{CallbackEvent, Msg, MsgKind, Topic};
+use latpr::utils::{LogLevel, LOG};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::ffi::{CString, OsStr};
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::path::{Path, PathBuf};
+
+/// watches added at most, the subdirectories past it are not
+/// watched
+const MAX_WATCHES: usize = 8192;
+/// size of the header of an inotify event
+const HEADER: usize = std::mem::size_of::<libc::inotify_event>();
+
+/// Settings of the filesystem watch mode, e.g.
+///
+/// ```toml
+/// [watch]
+/// paths = ["/srv/www", "/etc/nginx/nginx.conf"]
+/// recursive = true
+/// ```
+#[derive(Deserialize, Serialize, Default, Debug, Clone, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct WatchSettings {
+    /// absolute paths of the files and directories watched
+    pub paths: Vec<PathBuf>,
+    /// whether the subdirectories are watched as well, including
+    /// the ones created later
+    pub recursive: bool,
+}
+
+pub struct Subscriber {
+    user: String,
+    /// identifier of the subscription, see [`telemetry::session_id`]
+    session: String,
+    /// counters of the subscriber
+    stats: Stats,
+    /// span of the subscription, see [`crate::telemetry`]
+    span: Option<Span>,
+}
+
+impl Drop for Subscriber {
+    fn drop(&mut self) {
+        clients::end_span(&mut self.span, &self.stats);
+    }
+}
+
+crate::member!(Subscriber);
+
+/// An event read from inotify
+struct Raw {
+    wd: i32,
+    mask: u32,
+    cookie: u32,
+    name: Option<PathBuf>,
+}
+
+/// Backend watching the configured paths
+pub struct Watch {
+    clients: HashMap<u16, Subscriber>,
+    inotify: File,
+    /// watched path of each watch descriptor
+    watches: HashMap<i32, PathBuf>,
+    recursive: bool,
+    events: u64,
+}
+
+impl Watch {
+    pub fn new(config: &Config) -> Result<Watch, Box<dyn std::error::Error>> {
+        let fd = unsafe { libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        let mut watch = Watch {
+            clients: HashMap::new(),
+            inotify: unsafe { File::from_raw_fd(fd) },
+            watches: HashMap::new(),
+            recursive: config.watch.recursive,
+            events: 0,
+        };
+        for path in config.watch.paths.iter() {
+            watch
+                .add(path)
+                .map_err(|e| format!("Unable to watch {}: {}", path.display(), e))?;
+            if watch.recursive && path.is_dir() {
+                watch.add_below(path);
+            }
+            INFO!("Watch {}", path.display());
+        }
+        Ok(watch)
+    }
+
+    /// Watch a file or a directory
+    fn add(&mut self, path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+        if self.watches.len() >= MAX_WATCHES {
+            return Err(format!("more than {} watches", MAX_WATCHES).into());
+        }
+        let name = CString::new(path.as_os_str().as_bytes())?;
+        let mask = libc::IN_CREATE
+            | libc::IN_MODIFY
+            | libc::IN_ATTRIB
+            | libc::IN_DELETE
+            | libc::IN_MOVED_FROM
+            | libc::IN_MOVED_TO
+            | libc::IN_DELETE_SELF
+            | libc::IN_MOVE_SELF;
+        let wd = unsafe { libc::inotify_add_watch(self.inotify.as_raw_fd(), name.as_ptr(), mask) };
+        if wd < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        self.watches.insert(wd, path.to_path_buf());
+        Ok(())
+    }
+
+    /// Watch the subdirectories of a directory. The ones that
+    /// cannot be watched are skipped, they are not fatal
+    fn add_below(&mut self, dir: &Path) {
+        let mut pending = vec![dir.to_path_buf()];
+        while let Some(dir) = pending.pop() {
+            let entries = match fs::read_dir(&dir) {
+                Ok(entries) => entries,
+                Err(error) => {
+                    WARN!("Unable to list {}: {}", dir.display(), error);
+                    continue;
+                }
+            };
+            for entry in entries.flatten() {
+                // symbolic links are not followed
+                if !entry.file_type().is_ok_and(|t| t.is_dir()) {
+                    continue;
+                }
+                let path = entry.path();
+                if let Err(error) = self.add(&path) {
+                    WARN!("Unable to watch {}: {}", path.display(), error);
+                    continue;
+                }
+                pending.push(path);
+            }
+        }
+    }
+
+    /// Read the pending events of inotify
+    fn read(&mut self) -> Result<Vec<Raw>, Box<dyn std::error::Error>> {
+        let mut events = Vec::new();
+        let mut buf = vec![0u8; 64 * 1024];
+        loop {
+            let size = match self.inotify.read(&mut buf) {
+                Ok(0) => break,
+                Ok(size) => size,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e.into()),
+            };
+            let mut offset = 0;
+            while offset + HEADER <= size {
+                let field = |at: usize| {
+                    let start = offset + at;
+                    [buf[start], buf[start + 1], buf[start + 2], buf[start + 3]]
+                };
+                let len = u32::from_ne_bytes(field(12)) as usize;
+                // the name is padded with NUL bytes
+                let name = &buf[offset + HEADER..(offset + HEADER + len).min(size)];
+                let name = &name[..name.iter().position(|&b| b == 0).unwrap_or(name.len())];
+                events.push(Raw {
+                    wd: i32::from_ne_bytes(field(0)),
+                    mask: u32::from_ne_bytes(field(4)),
+                    cookie: u32::from_ne_bytes(field(8)),
+                    name: (!name.is_empty()).then(|| PathBuf::from(OsStr::from_bytes(name))),
+                });
+                offset += HEADER + len;
+            }
+        }
+        Ok(events)
+    }
+
+    /// Turn the pending events of inotify into the JSON events
+    /// sent to the clients. The two halves of a move are paired
+    /// by their cookie
+    fn poll(&mut self) -> Result<Vec<Value>, Box<dyn std::error::Error>> {
+        let mut events: Vec<Value> = Vec::new();
+        // event and path of the first halves of the moves
+        let mut moved: HashMap<u32, (usize, PathBuf)> = HashMap::new();
+        for raw in self.read().class(Class::ChildIo)? {
+            if raw.mask & libc::IN_Q_OVERFLOW != 0 {
+                WARN!("The inotify queue has overflowed, events were lost");
+                events.push(json!({ "event": "overflow" }));
+                continue;
+            }
+            if raw.mask & libc::IN_IGNORED != 0 {
+                if let Some(path) = self.watches.remove(&raw.wd) {
+                    DEBUG!("Watch of {} removed", path.display());
+                }
+                continue;
+            }
+            let Some(base) = self.watches.get(&raw.wd) else {
+                continue;
+            };
+            let path = match raw.name.as_ref() {
+                Some(name) => base.join(name),
+                None => base.clone(),
+            };
+            let dir = raw.mask & libc::IN_ISDIR != 0;
+            let name = path.to_string_lossy().to_string();
+            let event = if raw.mask & libc::IN_CREATE != 0 {
+                "create"
+            } else if raw.mask & (libc::IN_MODIFY | libc::IN_ATTRIB) != 0 {
+                // a write is often reported several times
+                let last = events.last();
+                if last.is_some_and(|e| e["event"] == "modify" && e["path"] == name.as_str()) {
+                    continue;
+                }
+                "modify"
+            } else if raw.mask & libc::IN_MOVED_FROM != 0 {
+                moved.insert(raw.cookie, (events.len(), path.clone()));
+                // a delete until its other half is read
+                "delete"
+            } else if raw.mask & libc::IN_MOVED_TO != 0 {
+                if let Some((index, from)) = moved.remove(&raw.cookie) {
+                    let from_name = events[index]["path"].take();
+                    events[index] =
+                        json!({ "event": "move", "path": name, "from": from_name, "dir": dir });
+                    if dir {
+                        self.rename(&from, &path);
+                    }
+                    continue;
+                }
+                "create"
+            } else if raw.mask & libc::IN_DELETE != 0 {
+                "delete"
+            } else if raw.mask & (libc::IN_DELETE_SELF | libc::IN_MOVE_SELF) != 0 {
+                // only the watched paths are reported, the
+                // subdirectories are reported by their parent
+                if !self.is_root(&path) {
+                    continue;
+                }
+                "delete"
+            } else {
+                continue;
+            };
+            if event == "create" && dir && self.recursive {
+                if let Err(error) = self.add(&path) {
+                    WARN!("Unable to watch {}: {}", path.display(), error);
+                } else {
+                    self.add_below(&path);
+                }
+            }
+            events.push(json!({ "event": event, "path": name, "dir": dir }));
+        }
+        // the directories moved out are not watched anymore
+        for (index, path) in moved.into_values() {
+            if events[index]["dir"] == true {
+                self.remove_below(&path);
+            }
+        }
+        self.events += events.len() as u64;
+        Ok(events)
+    }
+
+    /// Whether a path is one of the watched ones, not a
+    /// subdirectory watched with it
+    fn is_root(&self, path: &Path) -> bool {
+        !self
+            .watches
+            .values()
+            .any(|p| p != path && path.starts_with(p))
+    }
+
+    /// Update the watched paths below a moved directory
+    fn rename(&mut self, from: &Path, to: &Path) {
+        for path in self.watches.values_mut() {
+            if let Ok(rest) = path.strip_prefix(from) {
+                *path = to.join(rest);
+            }
+        }
+    }
+
+    /// Remove the watches below a directory moved out of the
+    /// watched ones
+    fn remove_below(&mut self, dir: &Path) {
+        let fd = self.inotify.as_raw_fd();
+        self.watches.retain(|wd, path| {
+            if !path.starts_with(dir) {
+                return true;
+            }
+            unsafe { libc::inotify_rm_watch(fd, *wd) };
+            false
+        });
+    }
+
+    /// Send the events to all the subscribers, one per frame
+    fn send(
+        &mut self,
+        events: &[Value],
+        topic: &mut Topic,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        for event in events {
+            let data = event.to_string().into_bytes();
+            if let Some((count, bytes)) = log::throttle(Level::Debug, "send", 0, data.len()) {
+                DEBUG!(
+                    "Sending {} event(s) to {} client(s): {} bytes",
+                    count,
+                    self.clients.len(),
+                    bytes
+                );
+            }
+            for (key, subscriber) in self.clients.iter_mut() {
+                let msg = Msg::create(MsgKind::ChannelData, 0, *key, data.clone());
+                frame::send(topic, &msg)?;
+                subscriber.stats.sent(data.len());
+                metrics::add(Counter::BytesOut, data.len() as u64);
+            }
+        }
+        Ok(())
+    }
+
+    fn handle_message(
+        &mut self,
+        msg: &Msg,
+        config: &Config,
+        topic: &mut Topic,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        DEBUG!(
+            "Receive message kind {} from client {}: {} bytes",
+            msg.kind,
+            msg.client_id,
+            msg.size
+        );
+        if let Some(client) = self.clients.get_mut(&msg.client_id) {
+            client.stats.received();
+        }
+        match msg.kind {
+            MsgKind::ChannelSubscribe => {
+                let Some(Subscription {
+                    user,
+                    session,
+                    context: _context,
+                }) = clients::subscribe(msg, config, topic, |_| {
+                    Ok(self
+                        .clients
+                        .remove(&msg.client_id)
+                        .map(|old| old.user.clone()))
+                })?
+                else {
+                    return Ok(());
+                };
+                if !self.make_room(msg.client_id, config, topic)? {
+                    return Ok(());
+                }
+                let span = clients::span(&config.topic, msg.client_id, &user, &session);
+                audit::log(config, msg.client_id, &user, &session, Event::Subscribe);
+                self.clients.insert(
+                    msg.client_id,
+                    Subscriber {
+                        user,
+                        session,
+                        stats: Stats::default(),
+                        span: Some(span),
+                    },
+                );
+                INFO!(
+                    "Client {} subscribe to channel {}",
+                    msg.client_id,
+                    &config.topic
+                );
+                clients::welcome(msg.client_id, config, topic)?;
+            }
+            MsgKind::ChannelUnsubscribe => {
+                self.unsubscribe(msg.client_id, config, topic)?;
+            }
+            MsgKind::ChannelUnsubscribeAll => self.unsubscribe_all(config, topic)?,
+            MsgKind::ChannelData => {
+                WARN!("Refuse data of client {}: read-only", msg.client_id);
+                frame::send(
+                    topic,
+                    &frame::error(msg.client_id, "Read-only subscription"),
+                )?;
+            }
+            MsgKind::ChannelCtrl if stats::is_query(&msg.data) => {
+                self.status(msg.client_id, topic)?;
+            }
+            _ => {
+                WARN!(
+                    "Receive message kind {} from client {}",
+                    msg.kind,
+                    msg.client_id
+                );
+            }
+        };
+        Ok(())
+    }
+}
+
+impl Roster for Watch {
+    type Client = Subscriber;
+
+    fn table(&self) -> &HashMap<u16, Subscriber> {
+        &self.clients
+    }
+
+    fn table_mut(&mut self) -> &mut HashMap<u16, Subscriber> {
+        &mut self.clients
+    }
+}
+
+impl Backend for Watch {
+    fn fds(&self) -> Vec<RawFd> {
+        vec![self.inotify.as_raw_fd()]
+    }
+
+    fn clients(&self) -> usize {
+        self.clients.len()
+    }
+
+    fn dump(&self) -> Vec<String> {
+        let mut lines = vec![format!(
+            "{} client(s), {} watch(es), {} event(s)",
+            self.clients.len(),
+            self.watches.len(),
+            self.events
+        )];
+        let mut keys: Vec<&u16> = self.clients.keys().collect();
+        keys.sort();
+        for key in keys {
+            let s = &self.clients[key];
+            lines.push(format!(
+                "client {}: user {}, {}",
+                key,
+                s.user,
+                s.stats.describe()
+            ));
+        }
+        lines
+    }
+
+    fn step_handle(
+        &mut self,
+        evt: &CallbackEvent,
+        config: &Config,
+        topic: &mut Topic,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if let Some(msg) = evt.msg {
+            self.scoped(msg, config, topic, |watch, topic| {
+                watch.handle_message(msg, config, topic)
+            })?;
+        }
+        let events = self.poll()?;
+        self.send(&events, topic)?;
+        self.enforce_windows(config, topic)?;
+        self.reap_idle(config, topic)?;
+        Ok(())
+    }
+
+    fn children_exited(
+        &mut self,
+        _config: &Config,
+        _topic: &mut Topic,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        Ok(())
+    }
+
+    fn disconnected(&mut self, _config: &Config) {
+        self.clients.clear();
+    }
+
+    fn step_offline(&mut self, _config: &Config) {
+        // nobody gets the events, but the new directories must
+        // still be watched and the queue must not overflow
+        if let Err(error) = self.poll() {
+            WARN!("Unable to read the inotify events: {}", error);
+        }
+    }
+
+    fn shutdown(
+        &mut self,
+        _config: &Config,
+        topic: &mut Topic,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.notify_shutdown(topic)?;
+        self.clients.clear();
+        Ok(())
+    }
+}
@@ -0,0 +1,63 @@
+//! # Webhooks
+//!
+//! With `webhook_url`, the events of the audit log are posted as
+//! JSON to an HTTP endpoint, e.g. a chat-ops bridge or an alerting
+//! system. The events are sent in batches by a thread, and a
+//! failed post is retried a few times before the batch is dropped
+//!
+//! **Author**: "Dany LE"
+//!
+use crate::config::Config;
+use crate::http::{self, Batching, Endpoint};
+use serde_json::{json, Value};
+use std::sync::mpsc::SyncSender;
+use std::sync::OnceLock;
+use std::time::Duration;
+
+/// interval between two posts
+const POST_INTERVAL: Duration = Duration::from_secs(2);
+/// events posted at most at once
+const MAX_BATCH: usize = 100;
+/// attempts after a failed post
+const RETRIES: u32 = 3;
+
+/// queue of the webhook thread, none when not started
+static SENDER: OnceLock<SyncSender<Value>> = OnceLock::new();
+
+/// Start the webhook thread when `webhook_url` is set
+pub fn init(config: &Config) -> Result<(), Box<dyn std::error::Error>> {
+    let url = match config.webhook_url.as_ref() {
+        None => return Ok(()),
+        Some(url) => url,
+    };
+    let endpoint = Endpoint::parse(url, "/")?;
+    let topic = config.topic.clone();
+    let batching = Batching {
+        interval: POST_INTERVAL,
+        max_batch: MAX_BATCH,
+        retries: RETRIES,
+    };
+    let sender = http::start(
+        "webhook",
+        endpoint,
+        batching,
+        move |events| json!({ "topic": topic, "events": events }),
+    )?;
+    let _ = SENDER.set(sender);
+    Ok(())
+}
+
+/// Queue an audit record for the webhook if its event is
+/// selected by `webhook_events`, all when empty
+pub fn notify(config: &Config, record: &Value) {
+    let sender = match SENDER.get() {
+        None => return,
+        Some(sender) => sender,
+    };
+    let event = record["event"].as_str().unwrap_or_default();
+    if !config.webhook_events.is_empty() && !config.webhook_events.iter().any(|e| e == event) {
+        return;
+    }
+    // the event is dropped when the queue is full
+    let _ = sender.try_send(record.clone());
+}